@@ -0,0 +1,120 @@
+//! Animated models, whose frames are each matched with the gallery for an animated mosaic.
+
+use crate::error::MosaicError;
+use gif::SetParameter;
+use image::{DynamicImage, ImageBuffer, ImageError, Rgba};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+/// Frame of an animated model, as shown rather than as stored: drawn over the frames before it.
+pub struct Frame {
+    pub image: DynamicImage,
+    /// Milliseconds the frame is shown for.
+    pub delay_ms: u32,
+}
+
+/// Decodes the frames of `path` if it is a GIF of several frames, or returns `None` for it to
+/// be opened as a still image.
+pub fn load_animation(path: &Path) -> Result<Option<Vec<Frame>>, MosaicError> {
+    let is_gif = path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("gif"));
+    if !is_gif {
+        return Ok(None);
+    }
+
+    let mut decoder = gif::Decoder::new(BufReader::new(File::open(path)?));
+    decoder.set(gif::ColorOutput::RGBA);
+    let mut reader = decoder.read_info().map_err(ImageError::from)?;
+    let (w, h) = (u32::from(reader.width()), u32::from(reader.height()));
+    let mut canvas = ImageBuffer::from_pixel(w, h, Rgba([0, 0, 0, 0]));
+    let mut frames = Vec::new();
+    while let Some(frame) = reader.read_next_frame().map_err(ImageError::from)? {
+        let previous = match frame.dispose {
+            gif::DisposalMethod::Previous => Some(canvas.clone()),
+            _ => None,
+        };
+        let (left, top) = (u32::from(frame.left), u32::from(frame.top));
+        let frame_w = u32::from(frame.width);
+        for (i, pixel) in frame.buffer.chunks(4).enumerate() {
+            let (x, y) = (left + i as u32 % frame_w, top + i as u32 / frame_w);
+            // Transparent pixels let the frames below show through.
+            if pixel[3] != 0 && x < w && y < h {
+                canvas.put_pixel(x, y, Rgba([pixel[0], pixel[1], pixel[2], pixel[3]]));
+            }
+        }
+        frames.push(Frame {
+            image: DynamicImage::ImageRgba8(canvas.clone()),
+            delay_ms: u32::from(frame.delay) * 10,
+        });
+
+        match frame.dispose {
+            gif::DisposalMethod::Background => {
+                for y in top..(top + u32::from(frame.height)).min(h) {
+                    for x in left..(left + frame_w).min(w) {
+                        canvas.put_pixel(x, y, Rgba([0, 0, 0, 0]));
+                    }
+                }
+            }
+            gif::DisposalMethod::Previous
+            | gif::DisposalMethod::Any
+            | gif::DisposalMethod::Keep => {}
+        }
+        if let Some(previous) = previous {
+            canvas = previous;
+        }
+    }
+
+    if frames.len() < 2 {
+        return Ok(None);
+    }
+    Ok(Some(frames))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+    use image::GenericImageView;
+
+    /// Frame of `w`x`h` RGBA `pixels` at `left`, disposed of with `dispose`.
+    fn frame(
+        left: u16,
+        w: u16,
+        pixels: &[u8],
+        dispose: gif::DisposalMethod,
+    ) -> gif::Frame<'static> {
+        let mut frame = gif::Frame::from_rgba(w, 1, &mut pixels.to_vec());
+        frame.left = left;
+        frame.dispose = dispose;
+        frame
+    }
+
+    #[test]
+    fn frame_disposed_to_previous_restores_the_canvas() {
+        let path = temp_dir("animation").join("anim.gif");
+        {
+            let mut encoder = gif::Encoder::new(File::create(&path).unwrap(), 2, 1, &[]).unwrap();
+            let red = [255, 0, 0, 255, 255, 0, 0, 255];
+            let frames = [
+                frame(0, 2, &red, gif::DisposalMethod::Keep),
+                frame(0, 1, &[0, 0, 255, 255], gif::DisposalMethod::Previous),
+                frame(1, 1, &[0, 255, 0, 255], gif::DisposalMethod::Keep),
+            ];
+            for frame in &frames {
+                encoder.write_frame(frame).unwrap();
+            }
+        }
+
+        let frames = load_animation(&path).unwrap().unwrap();
+        let pixels = |i: usize| {
+            let image = &frames[i].image;
+            [image.get_pixel(0, 0).data, image.get_pixel(1, 0).data]
+        };
+        assert_eq!(frames.len(), 3);
+        assert_eq!(pixels(1), [[0, 0, 255, 255], [255, 0, 0, 255]]);
+        // The blue pixel of the second frame is gone, the red one below it being restored.
+        assert_eq!(pixels(2), [[255, 0, 0, 255], [0, 255, 0, 255]]);
+    }
+}
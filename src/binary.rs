@@ -0,0 +1,1002 @@
+//! Minimal CBOR and MessagePack encodings of the data model of serde, so that the metadata of
+//! a huge gallery takes less room without pulling in a crate per format. The values are
+//! written and read as serde walks them, the way `serde_json` handles JSON, rather than
+//! through an intermediate tree. Only what JSON can hold is supported: no byte strings and no
+//! indefinite lengths, so that sequences and maps must know their length when written.
+
+use crate::error::MosaicError;
+use serde::de::{
+    self, DeserializeOwned, DeserializeSeed, EnumAccess, IntoDeserializer, MapAccess, SeqAccess,
+    VariantAccess, Visitor,
+};
+use serde::ser::{self, Serialize};
+use serde::{forward_to_deserialize_any, Deserialize};
+use std::fmt::Display;
+
+/// Self-describe tag starting the CBOR documents, so that they are told apart from JSON and
+/// MessagePack whatever their extension.
+pub const CBOR_MAGIC: [u8; 3] = [0xd9, 0xd9, 0xf7];
+/// Arrays and maps nested deeper are rejected rather than overflowing the stack.
+const MAX_DEPTH: usize = 64;
+
+pub fn to_cbor<T: Serialize>(value: &T) -> Result<Vec<u8>, MosaicError> {
+    encode(value, Format::Cbor, CBOR_MAGIC.to_vec())
+}
+
+pub fn from_cbor<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, MosaicError> {
+    decode(bytes, Format::Cbor)
+}
+
+pub fn to_msgpack<T: Serialize>(value: &T) -> Result<Vec<u8>, MosaicError> {
+    encode(value, Format::MessagePack, Vec::new())
+}
+
+pub fn from_msgpack<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, MosaicError> {
+    decode(bytes, Format::MessagePack)
+}
+
+fn encode<T: Serialize>(value: &T, format: Format, out: Vec<u8>) -> Result<Vec<u8>, MosaicError> {
+    let mut encoder = Encoder { out, format };
+    value.serialize(&mut encoder)?;
+    Ok(encoder.out)
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8], format: Format) -> Result<T, MosaicError> {
+    let mut decoder = Decoder {
+        bytes,
+        pos: 0,
+        depth: 0,
+        format,
+    };
+    let value = T::deserialize(&mut decoder)?;
+    decoder.end()?;
+    Ok(value)
+}
+
+/// Errors of the types that can't be encoded and of the values that don't match the type
+/// being decoded, such as a missing field.
+impl ser::Error for MosaicError {
+    fn custom<T: Display>(message: T) -> MosaicError {
+        MosaicError::Invalid(message.to_string())
+    }
+}
+
+impl de::Error for MosaicError {
+    fn custom<T: Display>(message: T) -> MosaicError {
+        MosaicError::Invalid(message.to_string())
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Format {
+    Cbor,
+    MessagePack,
+}
+
+/// Floats that are exactly an `f32` are written as such, half the size of an `f64`.
+fn as_f32(f: f64) -> Option<f32> {
+    let single = f as f32;
+    (f64::from(single) == f).then_some(single)
+}
+
+fn cbor_head(out: &mut Vec<u8>, major: u8, n: u64) {
+    let major = major << 5;
+    if n < 24 {
+        out.push(major | n as u8);
+    } else if n <= 0xff {
+        out.extend_from_slice(&[major | 24, n as u8]);
+    } else if n <= 0xffff {
+        out.push(major | 25);
+        out.extend_from_slice(&(n as u16).to_be_bytes());
+    } else if n <= 0xffff_ffff {
+        out.push(major | 26);
+        out.extend_from_slice(&(n as u32).to_be_bytes());
+    } else {
+        out.push(major | 27);
+        out.extend_from_slice(&n.to_be_bytes());
+    }
+}
+
+/// Writes the marker of a string, array or map of `len` items: `fix` or'ed with the length
+/// if it is below `fix_max`, else the 8 (strings only), 16 or 32-bit form.
+fn msgpack_len(out: &mut Vec<u8>, len: usize, (fix, fix_max): (u8, usize), markers: [u8; 3]) {
+    if len < fix_max {
+        out.push(fix | len as u8);
+    } else if len <= 0xff && markers[0] != 0 {
+        out.extend_from_slice(&[markers[0], len as u8]);
+    } else if len <= 0xffff {
+        out.push(markers[1]);
+        out.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        out.push(markers[2]);
+        out.extend_from_slice(&(len as u32).to_be_bytes());
+    }
+}
+
+/// Serializer writing the values in `format` as serde hands them over. Enums are written as
+/// `serde_json` does: unit variants as their name, the others as a map from their name to
+/// their content.
+struct Encoder {
+    out: Vec<u8>,
+    format: Format,
+}
+
+impl Encoder {
+    fn null(&mut self) {
+        self.out.push(match self.format {
+            Format::Cbor => 0xf6,
+            Format::MessagePack => 0xc0,
+        });
+    }
+
+    fn bool(&mut self, b: bool) {
+        self.out.push(match (self.format, b) {
+            (Format::Cbor, false) => 0xf4,
+            (Format::Cbor, true) => 0xf5,
+            (Format::MessagePack, false) => 0xc2,
+            (Format::MessagePack, true) => 0xc3,
+        });
+    }
+
+    fn uint(&mut self, u: u64) {
+        let out = &mut self.out;
+        match self.format {
+            Format::Cbor => cbor_head(out, 0, u),
+            Format::MessagePack if u < 0x80 => out.push(u as u8),
+            Format::MessagePack if u <= 0xff => out.extend_from_slice(&[0xcc, u as u8]),
+            Format::MessagePack if u <= 0xffff => {
+                out.push(0xcd);
+                out.extend_from_slice(&(u as u16).to_be_bytes());
+            }
+            Format::MessagePack if u <= 0xffff_ffff => {
+                out.push(0xce);
+                out.extend_from_slice(&(u as u32).to_be_bytes());
+            }
+            Format::MessagePack => {
+                out.push(0xcf);
+                out.extend_from_slice(&u.to_be_bytes());
+            }
+        }
+    }
+
+    fn int(&mut self, i: i64) {
+        if i >= 0 {
+            return self.uint(i as u64);
+        }
+        let out = &mut self.out;
+        match self.format {
+            Format::Cbor => cbor_head(out, 1, !i as u64),
+            Format::MessagePack if i >= -32 => out.push(i as u8),
+            Format::MessagePack if i >= i64::from(i8::MIN) => {
+                out.extend_from_slice(&[0xd0, i as u8])
+            }
+            Format::MessagePack if i >= i64::from(i16::MIN) => {
+                out.push(0xd1);
+                out.extend_from_slice(&(i as i16).to_be_bytes());
+            }
+            Format::MessagePack if i >= i64::from(i32::MIN) => {
+                out.push(0xd2);
+                out.extend_from_slice(&(i as i32).to_be_bytes());
+            }
+            Format::MessagePack => {
+                out.push(0xd3);
+                out.extend_from_slice(&i.to_be_bytes());
+            }
+        }
+    }
+
+    fn float(&mut self, f: f64) {
+        let (single, double) = match self.format {
+            Format::Cbor => (0xfa, 0xfb),
+            Format::MessagePack => (0xca, 0xcb),
+        };
+        match as_f32(f) {
+            Some(f) => {
+                self.out.push(single);
+                self.out.extend_from_slice(&f.to_be_bytes());
+            }
+            None => {
+                self.out.push(double);
+                self.out.extend_from_slice(&f.to_be_bytes());
+            }
+        }
+    }
+
+    fn str(&mut self, s: &str) {
+        match self.format {
+            Format::Cbor => cbor_head(&mut self.out, 3, s.len() as u64),
+            Format::MessagePack => {
+                msgpack_len(&mut self.out, s.len(), (0xa0, 32), [0xd9, 0xda, 0xdb])
+            }
+        }
+        self.out.extend_from_slice(s.as_bytes());
+    }
+
+    fn array(&mut self, len: Option<usize>) -> Result<&mut Encoder, MosaicError> {
+        let len = len.ok_or("sequences of unknown length aren't supported")?;
+        match self.format {
+            Format::Cbor => cbor_head(&mut self.out, 4, len as u64),
+            Format::MessagePack => msgpack_len(&mut self.out, len, (0x90, 16), [0, 0xdc, 0xdd]),
+        }
+        Ok(self)
+    }
+
+    fn map(&mut self, len: Option<usize>) -> Result<&mut Encoder, MosaicError> {
+        let len = len.ok_or("maps of unknown length aren't supported")?;
+        match self.format {
+            Format::Cbor => cbor_head(&mut self.out, 5, len as u64),
+            Format::MessagePack => msgpack_len(&mut self.out, len, (0x80, 16), [0, 0xde, 0xdf]),
+        }
+        Ok(self)
+    }
+
+    /// Starts an enum variant with content, a map of one entry from its name.
+    fn variant(&mut self, variant: &str) -> Result<&mut Encoder, MosaicError> {
+        self.map(Some(1))?.str(variant);
+        Ok(self)
+    }
+}
+
+impl ser::Serializer for &mut Encoder {
+    type Ok = ();
+    type Error = MosaicError;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn serialize_bool(self, v: bool) -> Result<(), MosaicError> {
+        self.bool(v);
+        Ok(())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), MosaicError> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), MosaicError> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), MosaicError> {
+        self.serialize_i64(i64::from(v))
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), MosaicError> {
+        self.int(v);
+        Ok(())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), MosaicError> {
+        self.serialize_u64(u64::from(v))
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), MosaicError> {
+        self.serialize_u64(u64::from(v))
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), MosaicError> {
+        self.serialize_u64(u64::from(v))
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), MosaicError> {
+        self.uint(v);
+        Ok(())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), MosaicError> {
+        self.serialize_f64(f64::from(v))
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), MosaicError> {
+        self.float(v);
+        Ok(())
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), MosaicError> {
+        self.serialize_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), MosaicError> {
+        self.str(v);
+        Ok(())
+    }
+
+    fn serialize_bytes(self, _: &[u8]) -> Result<(), MosaicError> {
+        Err("byte strings aren't supported".into())
+    }
+
+    fn serialize_none(self) -> Result<(), MosaicError> {
+        self.null();
+        Ok(())
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<(), MosaicError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), MosaicError> {
+        self.null();
+        Ok(())
+    }
+
+    fn serialize_unit_struct(self, _: &'static str) -> Result<(), MosaicError> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+    ) -> Result<(), MosaicError> {
+        self.serialize_str(variant)
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        value: &T,
+    ) -> Result<(), MosaicError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), MosaicError> {
+        value.serialize(self.variant(variant)?)
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self, MosaicError> {
+        self.array(len)
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Self, MosaicError> {
+        self.array(Some(len))
+    }
+
+    fn serialize_tuple_struct(self, _: &'static str, len: usize) -> Result<Self, MosaicError> {
+        self.array(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self, MosaicError> {
+        self.variant(variant)?.array(Some(len))
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Self, MosaicError> {
+        self.map(len)
+    }
+
+    fn serialize_struct(self, _: &'static str, len: usize) -> Result<Self, MosaicError> {
+        self.map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self, MosaicError> {
+        self.variant(variant)?.map(Some(len))
+    }
+}
+
+impl ser::SerializeSeq for &mut Encoder {
+    type Ok = ();
+    type Error = MosaicError;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), MosaicError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), MosaicError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTuple for &mut Encoder {
+    type Ok = ();
+    type Error = MosaicError;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), MosaicError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), MosaicError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleStruct for &mut Encoder {
+    type Ok = ();
+    type Error = MosaicError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), MosaicError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), MosaicError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleVariant for &mut Encoder {
+    type Ok = ();
+    type Error = MosaicError;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), MosaicError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), MosaicError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for &mut Encoder {
+    type Ok = ();
+    type Error = MosaicError;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<(), MosaicError> {
+        key.serialize(&mut **self)
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<(), MosaicError> {
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), MosaicError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStruct for &mut Encoder {
+    type Ok = ();
+    type Error = MosaicError;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), MosaicError> {
+        self.str(key);
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), MosaicError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStructVariant for &mut Encoder {
+    type Ok = ();
+    type Error = MosaicError;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), MosaicError> {
+        self.str(key);
+        value.serialize(&mut **self)
+    }
+
+    fn end(self) -> Result<(), MosaicError> {
+        Ok(())
+    }
+}
+
+/// Half-precision float of CBOR.
+fn f16_to_f64(bits: u16) -> f64 {
+    let exponent = i32::from(bits >> 10 & 0x1f);
+    let mantissa = f64::from(bits & 0x3ff);
+    let magnitude = match exponent {
+        0 => mantissa * 2f64.powi(-24),
+        31 if mantissa == 0.0 => f64::INFINITY,
+        31 => f64::NAN,
+        _ => (1.0 + mantissa / 1024.0) * 2f64.powi(exponent - 15),
+    };
+    if bits & 0x8000 != 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+/// Start of a value: the whole value for the scalars, the number of items for the arrays and
+/// the maps, whose items follow.
+enum Head<'de> {
+    Null,
+    Bool(bool),
+    Uint(u64),
+    Int(i64),
+    Float(f64),
+    Str(&'de str),
+    Array(usize),
+    Map(usize),
+}
+
+/// Deserializer reading the values of `format` as serde asks for them.
+struct Decoder<'de> {
+    bytes: &'de [u8],
+    pos: usize,
+    /// Arrays and maps being read.
+    depth: usize,
+    format: Format,
+}
+
+impl<'de> Decoder<'de> {
+    fn error<T>(&self, message: &str) -> Result<T, MosaicError> {
+        Err(MosaicError::Invalid(format!(
+            "{} at byte {}",
+            message, self.pos
+        )))
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'de [u8], MosaicError> {
+        if self.bytes.len() - self.pos < n {
+            return self.error("unexpected end of the data");
+        }
+        let taken = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(taken)
+    }
+
+    fn byte(&mut self) -> Result<u8, MosaicError> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Big-endian unsigned integer of `n` bytes.
+    fn uint(&mut self, n: usize) -> Result<u64, MosaicError> {
+        Ok(self
+            .take(n)?
+            .iter()
+            .fold(0, |acc, &b| acc << 8 | u64::from(b)))
+    }
+
+    /// Checks that nothing follows the value.
+    fn end(&self) -> Result<(), MosaicError> {
+        if self.pos < self.bytes.len() {
+            return self.error("trailing data");
+        }
+        Ok(())
+    }
+
+    /// Length of a string, array or map, at most the bytes left so that a corrupt length
+    /// doesn't allocate much.
+    fn len(&self, n: u64) -> Result<usize, MosaicError> {
+        if n > (self.bytes.len() - self.pos) as u64 {
+            return self.error("length past the end of the data");
+        }
+        Ok(n as usize)
+    }
+
+    fn text(&mut self, n: u64) -> Result<&'de str, MosaicError> {
+        let len = self.len(n)?;
+        match std::str::from_utf8(self.take(len)?) {
+            Ok(s) => Ok(s),
+            Err(_) => self.error("invalid UTF-8 string"),
+        }
+    }
+
+    /// Whether the next value is a null, skipping it if so.
+    fn null(&mut self) -> bool {
+        let null: &[u8] = match self.format {
+            Format::Cbor => &[0xf6, 0xf7],
+            Format::MessagePack => &[0xc0],
+        };
+        let is_null = self.bytes.get(self.pos).is_some_and(|b| null.contains(b));
+        if is_null {
+            self.pos += 1;
+        }
+        is_null
+    }
+
+    fn head(&mut self) -> Result<Head<'de>, MosaicError> {
+        match self.format {
+            Format::Cbor => self.cbor_head(),
+            Format::MessagePack => self.msgpack_head(),
+        }
+    }
+
+    fn cbor_head(&mut self) -> Result<Head<'de>, MosaicError> {
+        loop {
+            let initial = self.byte()?;
+            let (major, info) = (initial >> 5, initial & 0x1f);
+            if major == 7 {
+                return match info {
+                    20 => Ok(Head::Bool(false)),
+                    21 => Ok(Head::Bool(true)),
+                    22 | 23 => Ok(Head::Null),
+                    25 => Ok(Head::Float(f16_to_f64(self.uint(2)? as u16))),
+                    26 => Ok(Head::Float(f64::from(f32::from_bits(self.uint(4)? as u32)))),
+                    27 => Ok(Head::Float(f64::from_bits(self.uint(8)?))),
+                    _ => self.error("unsupported simple value"),
+                };
+            }
+            let n = match info {
+                0..=23 => u64::from(info),
+                24 => self.uint(1)?,
+                25 => self.uint(2)?,
+                26 => self.uint(4)?,
+                27 => self.uint(8)?,
+                31 => return self.error("indefinite lengths aren't supported"),
+                _ => return self.error("invalid additional information"),
+            };
+            return match major {
+                0 => Ok(Head::Uint(n)),
+                1 if n <= i64::MAX as u64 => Ok(Head::Int(!(n as i64))),
+                1 => self.error("negative integer out of range"),
+                3 => Ok(Head::Str(self.text(n)?)),
+                4 => Ok(Head::Array(self.len(n)?)),
+                5 => Ok(Head::Map(self.len(n)?)),
+                // The tags, such as the self-describe one, don't change what the values mean
+                // here.
+                6 => continue,
+                _ => self.error("byte strings aren't supported"),
+            };
+        }
+    }
+
+    fn msgpack_head(&mut self) -> Result<Head<'de>, MosaicError> {
+        let marker = self.byte()?;
+        let (kind, n) = match marker {
+            0x00..=0x7f => return Ok(Head::Uint(u64::from(marker))),
+            0xe0..=0xff => return Ok(Head::Int(i64::from(marker as i8))),
+            0xc0 => return Ok(Head::Null),
+            0xc2 => return Ok(Head::Bool(false)),
+            0xc3 => return Ok(Head::Bool(true)),
+            0xca => return Ok(Head::Float(f64::from(f32::from_bits(self.uint(4)? as u32)))),
+            0xcb => return Ok(Head::Float(f64::from_bits(self.uint(8)?))),
+            0xcc => return Ok(Head::Uint(self.uint(1)?)),
+            0xcd => return Ok(Head::Uint(self.uint(2)?)),
+            0xce => return Ok(Head::Uint(self.uint(4)?)),
+            0xcf => return Ok(Head::Uint(self.uint(8)?)),
+            0xd0 => return Ok(Head::Int(i64::from(self.uint(1)? as i8))),
+            0xd1 => return Ok(Head::Int(i64::from(self.uint(2)? as i16))),
+            0xd2 => return Ok(Head::Int(i64::from(self.uint(4)? as i32))),
+            0xd3 => return Ok(Head::Int(self.uint(8)? as i64)),
+            0xa0..=0xbf => (3, u64::from(marker & 0x1f)),
+            0xd9 => (3, self.uint(1)?),
+            0xda => (3, self.uint(2)?),
+            0xdb => (3, self.uint(4)?),
+            0x90..=0x9f => (4, u64::from(marker & 0x0f)),
+            0xdc => (4, self.uint(2)?),
+            0xdd => (4, self.uint(4)?),
+            0x80..=0x8f => (5, u64::from(marker & 0x0f)),
+            0xde => (5, self.uint(2)?),
+            0xdf => (5, self.uint(4)?),
+            _ => return self.error("unsupported MessagePack type"),
+        };
+        match kind {
+            3 => Ok(Head::Str(self.text(n)?)),
+            4 => Ok(Head::Array(self.len(n)?)),
+            _ => Ok(Head::Map(self.len(n)?)),
+        }
+    }
+
+    /// Visits the `len` items of an array, or entries of a map, that was just started, and
+    /// checks that the visitor read them all.
+    fn items<V, F>(&mut self, len: usize, visit: F) -> Result<V, MosaicError>
+    where
+        F: FnOnce(&mut Items<'_, 'de>) -> Result<V, MosaicError>,
+    {
+        if self.depth == MAX_DEPTH {
+            return self.error("too deeply nested");
+        }
+        self.depth += 1;
+        let mut items = Items {
+            decoder: &mut *self,
+            left: len,
+        };
+        let value = visit(&mut items);
+        let left = items.left;
+        self.depth -= 1;
+        let value = value?;
+        if left > 0 {
+            return self.error("more items than expected");
+        }
+        Ok(value)
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut Decoder<'de> {
+    type Error = MosaicError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MosaicError> {
+        match self.head()? {
+            Head::Null => visitor.visit_unit(),
+            Head::Bool(b) => visitor.visit_bool(b),
+            Head::Uint(u) => visitor.visit_u64(u),
+            Head::Int(i) => visitor.visit_i64(i),
+            Head::Float(f) => visitor.visit_f64(f),
+            Head::Str(s) => visitor.visit_borrowed_str(s),
+            Head::Array(len) => self.items(len, |items| visitor.visit_seq(items)),
+            Head::Map(len) => self.items(len, |items| visitor.visit_map(items)),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MosaicError> {
+        if self.null() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, MosaicError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, MosaicError> {
+        match self.head()? {
+            Head::Str(variant) => visitor.visit_enum(variant.into_deserializer()),
+            Head::Map(1) => self.items(1, |items| {
+                items.left = 0;
+                visitor.visit_enum(&mut *items.decoder)
+            }),
+            _ => self.error("expected an enum variant"),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes byte_buf
+        unit unit_struct seq tuple tuple_struct map struct identifier ignored_any
+    }
+}
+
+/// Items of an array, or entries of a map, left to read.
+struct Items<'a, 'de> {
+    decoder: &'a mut Decoder<'de>,
+    left: usize,
+}
+
+impl<'de> SeqAccess<'de> for &mut Items<'_, 'de> {
+    type Error = MosaicError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, MosaicError> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        seed.deserialize(&mut *self.decoder).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.left)
+    }
+}
+
+impl<'de> MapAccess<'de> for &mut Items<'_, 'de> {
+    type Error = MosaicError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, MosaicError> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        seed.deserialize(&mut *self.decoder).map(Some)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, MosaicError> {
+        seed.deserialize(&mut *self.decoder)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.left)
+    }
+}
+
+/// Variant with content, the single entry of a map from its name.
+impl<'de> EnumAccess<'de> for &mut Decoder<'de> {
+    type Error = MosaicError;
+    type Variant = Self;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, Self), MosaicError> {
+        Ok((seed.deserialize(&mut *self)?, self))
+    }
+}
+
+impl<'de> VariantAccess<'de> for &mut Decoder<'de> {
+    type Error = MosaicError;
+
+    fn unit_variant(self) -> Result<(), MosaicError> {
+        <()>::deserialize(self)
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, MosaicError> {
+        seed.deserialize(self)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _: usize, visitor: V) -> Result<V::Value, MosaicError> {
+        de::Deserializer::deserialize_seq(self, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, MosaicError> {
+        de::Deserializer::deserialize_map(self, visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::{ProcessedPictureMetadata, METADATA_VERSION};
+    use crate::preprocess::ColorMode;
+    use crate::testing::picture;
+    use serde_derive::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+
+    type Encode = fn(&Value) -> Result<Vec<u8>, MosaicError>;
+    type Decode = fn(&[u8]) -> Result<Value, MosaicError>;
+
+    const FORMATS: [(Encode, Decode); 2] = [(to_cbor, from_cbor), (to_msgpack, from_msgpack)];
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    enum Variant {
+        Unit,
+        Newtype(u8),
+        Tuple(i8, String),
+        Struct { x: f64 },
+    }
+
+    #[test]
+    fn metadata_round_trips() {
+        let mut full = picture("0.png", [1, 2, 3]);
+        full.color_histogram = Some((0..512).collect());
+        full.source = Some("gallery/é.jpg".to_owned());
+        full.contrast = Some(12.5);
+        full.palette = Some(vec![[1, 2, 3], [250, 251, 252]]);
+        full.phash = Some(u64::MAX);
+        let metadata = ProcessedPictureMetadata {
+            version: METADATA_VERSION,
+            pictures: vec![full, picture("1.png", [4, 5, 6])],
+            contrast_adjustment: 0.1,
+            linear_light: true,
+            color_mode: ColorMode::Dominant,
+        };
+        let json = serde_json::to_value(&metadata).unwrap();
+
+        let cbor = to_cbor(&metadata).unwrap();
+        assert!(cbor.starts_with(&CBOR_MAGIC));
+        let decoded: ProcessedPictureMetadata = from_cbor(&cbor).unwrap();
+        assert_eq!(serde_json::to_value(decoded).unwrap(), json);
+
+        let msgpack = to_msgpack(&metadata).unwrap();
+        // A map, for `MetadataFormat::sniff` to tell MessagePack from JSON.
+        assert_eq!(msgpack[0], 0x85);
+        let decoded: ProcessedPictureMetadata = from_msgpack(&msgpack).unwrap();
+        assert_eq!(serde_json::to_value(decoded).unwrap(), json);
+    }
+
+    #[test]
+    fn values_of_every_kind_round_trip() {
+        let value = json!({
+            "null": null,
+            "bools": [true, false],
+            "uints": [0, 23, 24, 127, 128, 255, 256, 65535, 65536, 4294967296u64, u64::MAX],
+            "ints": [-1, -24, -25, -32, -33, -128, -129, -32768, -32769, i64::MIN],
+            "floats": [0.5, 0.1, -1e300],
+            "strings": ["", "a".repeat(31), "b".repeat(32), "c".repeat(256), "ü".repeat(40000)],
+            "nested": [[[{"a": {}}]], []],
+        });
+        for (encode, decode) in FORMATS.iter() {
+            assert_eq!(decode(&encode(&value).unwrap()).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn enums_are_written_as_serde_json_does() {
+        let variants = vec![
+            Variant::Unit,
+            Variant::Newtype(7),
+            Variant::Tuple(-3, "t".to_owned()),
+            Variant::Struct { x: 0.25 },
+        ];
+        let json = serde_json::to_value(&variants).unwrap();
+        for (encode, decode) in FORMATS.iter() {
+            assert_eq!(decode(&encode(&json).unwrap()).unwrap(), json);
+        }
+        let decoded: Vec<Variant> = from_cbor(&to_cbor(&variants).unwrap()).unwrap();
+        assert_eq!(decoded, variants);
+        let decoded: Vec<Variant> = from_msgpack(&to_msgpack(&variants).unwrap()).unwrap();
+        assert_eq!(decoded, variants);
+    }
+
+    #[test]
+    fn cbor_half_floats_and_tags_are_read() {
+        // Tag 1 (epoch time) around the half float 1.5, then the half float -infinity.
+        let value: Vec<f64> = from_cbor(&[0x82, 0xc1, 0xf9, 0x3e, 0x00, 0xf9, 0xfc, 0x00]).unwrap();
+        assert_eq!(value, [1.5, f64::NEG_INFINITY]);
+    }
+
+    #[test]
+    fn corrupt_data_is_rejected() {
+        let error = |result: Result<Value, MosaicError>| match result {
+            Err(MosaicError::Invalid(message)) => message,
+            other => panic!("unexpected {:?}", other),
+        };
+        let encoded = to_msgpack(&json!({"a": [1, 2, 3]})).unwrap();
+        // The array of 3 items has 2 bytes left.
+        let truncated = error(from_msgpack(&encoded[..encoded.len() - 1]));
+        assert_eq!(truncated, "length past the end of the data at byte 4");
+        let encoded = to_cbor(&0.1).unwrap();
+        let truncated = error(from_cbor(&encoded[..encoded.len() - 1]));
+        assert_eq!(truncated, "unexpected end of the data at byte 4");
+        let trailing = error(from_msgpack(&[0xc0, 0xc0]));
+        assert_eq!(trailing, "trailing data at byte 1");
+        let too_long = error(from_cbor(&[0x9a, 0xff, 0xff, 0xff, 0xff]));
+        assert_eq!(too_long, "length past the end of the data at byte 5");
+        let indefinite = error(from_cbor(&[0x9f, 0xff]));
+        assert_eq!(indefinite, "indefinite lengths aren't supported at byte 1");
+
+        let mut deep = vec![0x91; MAX_DEPTH + 1];
+        deep.push(0xc0);
+        let too_deep = error(from_msgpack(&deep));
+        assert_eq!(
+            too_deep,
+            format!("too deeply nested at byte {}", MAX_DEPTH + 1)
+        );
+        deep.remove(0);
+        assert!(from_msgpack::<Value>(&deep).is_ok());
+    }
+
+    #[test]
+    fn values_not_matching_the_type_are_rejected() {
+        match from_cbor::<[u8; 3]>(&to_cbor(&[1, 2, 3, 4]).unwrap()) {
+            Err(MosaicError::Invalid(message)) => {
+                assert_eq!(message, "more items than expected at byte 7")
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        let missing_field =
+            from_msgpack::<ProcessedPictureMetadata>(&to_msgpack(&json!({})).unwrap());
+        match missing_field {
+            Err(MosaicError::Invalid(message)) => assert_eq!(message, "missing field `pictures`"),
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+        let unknown_variant = from_cbor::<Variant>(&to_cbor(&"Other").unwrap());
+        assert!(matches!(unknown_variant, Err(MosaicError::Invalid(_))));
+    }
+}
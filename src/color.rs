@@ -0,0 +1,295 @@
+//! Colors, contrast and histograms of pictures and chunks.
+
+use crate::{srgb, HISTOGRAM_BINS_PER_CHANNEL, TRANSPARENT_ALPHA};
+use image::{ImageBuffer, Rgba};
+use std::cmp;
+
+/// Color of a fully transparent image, a mid gray rather than the black its pixels usually are.
+const TRANSPARENT_COLOR: [u8; 3] = [128, 128, 128];
+
+/// Averages the color of `img`, each pixel contributing according to its alpha so that
+/// transparent regions don't darken the color, or `TRANSPARENT_COLOR` if it is all transparent.
+/// If `weighted`, each pixel also contributes according to a Gaussian falloff from the center,
+/// matching the center crop of the thumbnails.
+pub(crate) fn compute_main_color(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    weighted: bool,
+    linear_light: bool,
+) -> [u8; 3] {
+    if weighted {
+        return compute_center_weighted_color(img, linear_light);
+    }
+
+    let pixels = img.pixels().map(|pixel| (pixel, f64::from(pixel.data[3])));
+    average_color(pixels, linear_light).unwrap_or(TRANSPARENT_COLOR)
+}
+
+/// Average color of the pixels of `img` that aren't mostly transparent, so that e.g. the soft
+/// shadow of a sticker doesn't darken it. Falls back to `compute_main_color` if there are none.
+pub(crate) fn compute_opaque_color(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    linear_light: bool,
+) -> [u8; 3] {
+    let pixels = img
+        .pixels()
+        .filter(|pixel| pixel.data[3] >= TRANSPARENT_ALPHA)
+        .map(|pixel| (pixel, 1.0));
+    average_color(pixels, linear_light)
+        .unwrap_or_else(|| compute_main_color(img, false, linear_light))
+}
+
+/// Per channel median of the colors of `img`, each pixel counting according to its alpha, or
+/// `TRANSPARENT_COLOR` if it is all transparent. Unlike the average, a few pixels far from the
+/// others, e.g. a bright sky in the corner of a dark picture, don't shift it. If `weighted`,
+/// each pixel also counts according to a Gaussian falloff from the center.
+pub(crate) fn compute_median_color(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    weighted: bool,
+) -> [u8; 3] {
+    let median = if weighted {
+        median_color(center_weighted_pixels(img))
+    } else {
+        median_color(img.pixels().map(|pixel| (pixel, f64::from(pixel.data[3]))))
+    };
+    median.unwrap_or(TRANSPARENT_COLOR)
+}
+
+/// Per channel weighted median of the colors of `pixels`, given with their weight, or `None`
+/// if the weights sum to 0. Being a middle value rather than a sum, it is the same in linear
+/// light and in sRGB.
+pub(crate) fn median_color<'a, I>(pixels: I) -> Option<[u8; 3]>
+where
+    I: Iterator<Item = (&'a Rgba<u8>, f64)>,
+{
+    let mut histograms = [[0f64; 256]; 3];
+    let mut weight_sum = 0f64;
+    for (pixel, weight) in pixels {
+        for (histogram, &channel) in histograms.iter_mut().zip(pixel.data.iter()) {
+            histogram[channel as usize] += weight;
+        }
+        weight_sum += weight;
+    }
+
+    if weight_sum == 0.0 {
+        return None;
+    }
+    Some(histograms.map(|histogram| {
+        let mut below = 0f64;
+        let median = histogram.iter().position(|&weight| {
+            below += weight;
+            below >= weight_sum / 2.0
+        });
+        median.unwrap_or(255) as u8
+    }))
+}
+
+/// Averages the colors of `pixels`, given with their weight, or returns `None` if the weights
+/// sum to 0. In linear light, a checkerboard of black and white averages to 188 rather than to
+/// the darker 127 of the sRGB bytes.
+fn average_color<'a, I>(pixels: I, linear_light: bool) -> Option<[u8; 3]>
+where
+    I: Iterator<Item = (&'a Rgba<u8>, f64)>,
+{
+    let mut color_sums = [0f64; 3];
+    let mut weight_sum = 0f64;
+    for (pixel, weight) in pixels {
+        for (sum, &channel) in color_sums.iter_mut().zip(pixel.data.iter()) {
+            let value = if linear_light {
+                srgb::to_linear(channel)
+            } else {
+                f64::from(channel)
+            };
+            *sum += weight * value;
+        }
+        weight_sum += weight;
+    }
+
+    if weight_sum == 0.0 {
+        return None;
+    }
+    let mut avg_color = [0; 3];
+    for (avg, sum) in avg_color.iter_mut().zip(color_sums.iter()) {
+        let value = sum / weight_sum;
+        *avg = if linear_light {
+            srgb::from_linear(value)
+        } else {
+            value.min(255.0) as u8
+        };
+    }
+    Some(avg_color)
+}
+
+/// Standard deviation of the luma of the pixels of `img`, weighted by their alpha.
+pub(crate) fn compute_contrast(img: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> f32 {
+    let mut sum = 0f64;
+    let mut square_sum = 0f64;
+    let mut weight_sum = 0f64;
+    for pixel in img.pixels() {
+        let weight = f64::from(pixel.data[3]);
+        let y = luma([pixel.data[0], pixel.data[1], pixel.data[2]]);
+        sum += weight * y;
+        square_sum += weight * y * y;
+        weight_sum += weight;
+    }
+
+    if weight_sum == 0.0 {
+        return 0.0;
+    }
+    let mean = sum / weight_sum;
+    (square_sum / weight_sum - mean * mean).max(0.0).sqrt() as f32
+}
+
+/// Sums of the channels of the pixels of a region and of their squares, weighted by alpha,
+/// from which the variance of its colors is computed once merged with the ones of its
+/// neighbours.
+#[derive(Clone, Copy, Default)]
+pub(crate) struct ColorStats {
+    sums: [f64; 3],
+    square_sum: f64,
+    weight: f64,
+}
+
+impl ColorStats {
+    pub(crate) fn of(img: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> ColorStats {
+        let mut stats = ColorStats::default();
+        for pixel in img.pixels() {
+            let weight = f64::from(pixel.data[3]) / 255.0;
+            for (sum, &value) in stats.sums.iter_mut().zip(&pixel.data[..3]) {
+                let value = f64::from(value);
+                *sum += weight * value;
+                stats.square_sum += weight * value * value;
+            }
+            stats.weight += weight;
+        }
+        stats
+    }
+
+    pub(crate) fn merge(mut self, other: &ColorStats) -> ColorStats {
+        for (sum, other) in self.sums.iter_mut().zip(&other.sums) {
+            *sum += other;
+        }
+        self.square_sum += other.square_sum;
+        self.weight += other.weight;
+        self
+    }
+
+    /// Variance of the channels of the colors, averaged over the three of them, 0 if the
+    /// region is transparent.
+    pub(crate) fn variance(&self) -> f64 {
+        if self.weight == 0.0 {
+            return 0.0;
+        }
+        let mean_squares: f64 = self
+            .sums
+            .iter()
+            .map(|sum| (sum / self.weight).powi(2))
+            .sum();
+        ((self.square_sum / self.weight - mean_squares) / 3.0).max(0.0)
+    }
+}
+
+fn compute_center_weighted_color(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    linear_light: bool,
+) -> [u8; 3] {
+    average_color(center_weighted_pixels(img), linear_light).unwrap_or(TRANSPARENT_COLOR)
+}
+
+/// Pixels of `img` with their alpha times a Gaussian falloff from the center as weight.
+pub(crate) fn center_weighted_pixels(
+    img: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+) -> impl Iterator<Item = (&Rgba<u8>, f64)> {
+    let (w, h) = img.dimensions();
+    let sigma = cmp::min(w, h).max(1) as f64 / 2.0;
+    let (center_x, center_y) = (f64::from(w) / 2.0, f64::from(h) / 2.0);
+    img.enumerate_pixels().map(move |(x, y, pixel)| {
+        let dx = f64::from(x) + 0.5 - center_x;
+        let dy = f64::from(y) + 0.5 - center_y;
+        let weight =
+            (-(dx * dx + dy * dy) / (2.0 * sigma * sigma)).exp() * f64::from(pixel.data[3]) / 255.0;
+        (pixel, weight)
+    })
+}
+
+/// Counts the pixels of `img` in a coarse RGB histogram of
+/// `HISTOGRAM_BINS_PER_CHANNEL`^3 bins. The counts saturate, which a thumbnail is too small to
+/// reach.
+pub(crate) fn compute_histogram(img: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> Vec<u16> {
+    let bins = HISTOGRAM_BINS_PER_CHANNEL;
+    let mut histogram = vec![0u16; (bins * bins * bins) as usize];
+    for pixel in img.pixels() {
+        let bin = color_bin([pixel.data[0], pixel.data[1], pixel.data[2]], bins);
+        histogram[bin] = histogram[bin].saturating_add(1);
+    }
+    histogram
+}
+
+/// Index of the bin of `color` in a grid of `bins`^3 bins.
+pub(crate) fn color_bin(color: [u8; 3], bins: u32) -> usize {
+    let bin_width = 256 / bins;
+    let [r, g, b] = color.map(|c| u32::from(c) / bin_width);
+    ((r * bins + g) * bins + b) as usize
+}
+
+/// Hue in degrees, in `0.0..360.0`, then saturation and value in `0.0..=1.0` of `c`. The hue
+/// of a gray is 0.
+pub(crate) fn rgb_to_hsv(c: [u8; 3]) -> [f32; 3] {
+    let [r, g, b] = c.map(|c| f32::from(c) / 255.0);
+    let max = r.max(g).max(b);
+    let delta = max - r.min(g).min(b);
+    let hue = if delta == 0.0 {
+        0.0
+    } else if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let saturation = if max == 0.0 { 0.0 } else { delta / max };
+    [hue, saturation, max]
+}
+
+/// Luma of `c`, as in YCbCr.
+pub(crate) fn luma(c: [u8; 3]) -> f64 {
+    0.299 * f64::from(c[0]) + 0.587 * f64::from(c[1]) + 0.114 * f64::from(c[2])
+}
+
+/// Luma of `c` with the weights of Rec. 709, whose primaries are the ones of sRGB:
+/// Y' = 0.2126 R' + 0.7152 G' + 0.0722 B', on the gamma encoded channels.
+pub(crate) fn luma_709(c: [u8; 3]) -> f64 {
+    0.2126 * f64::from(c[0]) + 0.7152 * f64::from(c[1]) + 0.0722 * f64::from(c[2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checkerboard_averages_in_linear_light() {
+        let checkerboard = ImageBuffer::from_fn(4, 4, |x, y| {
+            let value = if (x + y) % 2 == 0 { 0 } else { 255 };
+            Rgba([value, value, value, 255])
+        });
+        // Half the light of white, rather than half its sRGB value, truncated.
+        assert_eq!(compute_main_color(&checkerboard, false, true), [188; 3]);
+        assert_eq!(compute_main_color(&checkerboard, false, false), [127; 3]);
+    }
+
+    #[test]
+    fn transparent_pixels_dont_darken_the_color() {
+        let img = ImageBuffer::from_fn(2, 2, |x, y| match (x, y) {
+            (0, 0) => Rgba([0, 0, 0, 0]),
+            _ => Rgba([255, 0, 0, 255]),
+        });
+        for linear_light in [false, true] {
+            assert_eq!(compute_main_color(&img, false, linear_light), [255, 0, 0]);
+        }
+    }
+
+    #[test]
+    fn transparent_image_is_gray() {
+        let img = ImageBuffer::from_pixel(2, 2, Rgba([0, 0, 0, 0]));
+        assert_eq!(compute_main_color(&img, false, false), TRANSPARENT_COLOR);
+    }
+}
@@ -0,0 +1,424 @@
+//! Config files giving default values to the options of the command line, in a subset of TOML:
+//! tables, and keys whose value is a string, an integer, a float, a boolean or an array of
+//! those, each on a single line.
+
+use crate::error::MosaicError;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// Value of a key.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+}
+
+/// Keys of a table with their values, in the order of the file.
+pub type Table = Vec<(String, Value)>;
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Config {
+    /// Keys before the first table header.
+    pub root: Table,
+    /// Tables by name, in the order of the file.
+    pub tables: Vec<(String, Table)>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Config, MosaicError> {
+        Ok(Config::parse(&fs::read_to_string(path)?)?)
+    }
+
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let mut config = Config::default();
+        for (i, line) in text.lines().enumerate() {
+            parse_line(&mut config, line).map_err(|e| format!("line {}: {}", i + 1, e))?;
+        }
+        Ok(config)
+    }
+
+    pub fn table(&self, name: &str) -> Option<&Table> {
+        self.tables.iter().find(|t| t.0 == name).map(|t| &t.1)
+    }
+}
+
+fn parse_line(config: &mut Config, line: &str) -> Result<(), String> {
+    let mut cursor = Cursor { rest: line };
+    cursor.skip_whitespace();
+    if cursor.rest.is_empty() || cursor.rest.starts_with('#') {
+        return Ok(());
+    }
+
+    if cursor.eat('[') {
+        if cursor.rest.starts_with('[') {
+            return Err("arrays of tables aren't supported".to_owned());
+        }
+        cursor.skip_whitespace();
+        let name = cursor.key()?;
+        cursor.skip_whitespace();
+        if !cursor.eat(']') {
+            return Err("expected `]` after the table name".to_owned());
+        }
+        cursor.end()?;
+        if config.table(&name).is_some() {
+            return Err(format!("table `{}` defined twice", name));
+        }
+        config.tables.push((name, Vec::new()));
+        return Ok(());
+    }
+
+    let key = cursor.key()?;
+    cursor.skip_whitespace();
+    if cursor.rest.starts_with('.') {
+        return Err("dotted keys aren't supported".to_owned());
+    }
+    if !cursor.eat('=') {
+        return Err(format!("expected `=` after `{}`", key));
+    }
+    cursor.skip_whitespace();
+    let value = cursor.value()?;
+    cursor.end()?;
+
+    let table = match config.tables.last_mut() {
+        Some(table) => &mut table.1,
+        None => &mut config.root,
+    };
+    if table.iter().any(|(k, _)| *k == key) {
+        return Err(format!("key `{}` defined twice", key));
+    }
+    table.push((key, value));
+    Ok(())
+}
+
+/// Remainder of the line being parsed.
+struct Cursor<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Cursor<'a> {
+    fn skip_whitespace(&mut self) {
+        self.rest = self.rest.trim_start_matches([' ', '\t']);
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        if self.rest.starts_with(c) {
+            self.rest = &self.rest[c.len_utf8()..];
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Checks that only a comment is left.
+    fn end(&mut self) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.rest.is_empty() || self.rest.starts_with('#') {
+            Ok(())
+        } else {
+            Err(format!("unexpected `{}`", self.rest))
+        }
+    }
+
+    fn key(&mut self) -> Result<String, String> {
+        if self.rest.starts_with('"') {
+            return self.basic_string();
+        }
+        let len = self
+            .rest
+            .find(|c| !is_bare_key_char(c))
+            .unwrap_or(self.rest.len());
+        if len == 0 {
+            return Err("expected a key".to_owned());
+        }
+        let key = self.rest[..len].to_owned();
+        self.rest = &self.rest[len..];
+        Ok(key)
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        if self.rest.starts_with('"') {
+            return self.basic_string().map(Value::String);
+        }
+        if self.eat('\'') {
+            let len = self
+                .rest
+                .find('\'')
+                .ok_or_else(|| "unterminated string".to_owned())?;
+            let s = self.rest[..len].to_owned();
+            self.rest = &self.rest[len + 1..];
+            return Ok(Value::String(s));
+        }
+        if self.eat('[') {
+            return self.array();
+        }
+
+        let len = self
+            .rest
+            .find([' ', '\t', ',', ']', '#'])
+            .unwrap_or(self.rest.len());
+        let token = &self.rest[..len];
+        self.rest = &self.rest[len..];
+        match token {
+            "" => Err("expected a value".to_owned()),
+            "true" => Ok(Value::Boolean(true)),
+            "false" => Ok(Value::Boolean(false)),
+            _ => {
+                let number = token.replace('_', "");
+                if let Ok(i) = number.parse() {
+                    Ok(Value::Integer(i))
+                } else if let Ok(f) = number.parse() {
+                    Ok(Value::Float(f))
+                } else {
+                    Err(format!("invalid value `{}`, strings need quotes", token))
+                }
+            }
+        }
+    }
+
+    /// Parses the values of an array whose `[` was eaten.
+    fn array(&mut self) -> Result<Value, String> {
+        let mut values = Vec::new();
+        loop {
+            self.skip_whitespace();
+            if self.eat(']') {
+                break;
+            }
+            values.push(self.value()?);
+            self.skip_whitespace();
+            if self.eat(']') {
+                break;
+            }
+            if !self.eat(',') {
+                return Err("expected `,` or `]` in the array".to_owned());
+            }
+        }
+        Ok(Value::Array(values))
+    }
+
+    fn basic_string(&mut self) -> Result<String, String> {
+        self.eat('"');
+        let mut s = String::new();
+        let mut chars = self.rest.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => {
+                    self.rest = &self.rest[i + 1..];
+                    return Ok(s);
+                }
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => s.push('\n'),
+                    Some((_, 't')) => s.push('\t'),
+                    Some((_, 'r')) => s.push('\r'),
+                    Some((_, '"')) => s.push('"'),
+                    Some((_, '\\')) => s.push('\\'),
+                    Some((_, c)) => return Err(format!("unsupported escape `\\{}`", c)),
+                    None => break,
+                },
+                _ => s.push(c),
+            }
+        }
+        Err("unterminated string".to_owned())
+    }
+}
+
+fn is_bare_key_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || c == '-'
+}
+
+fn write_key(f: &mut fmt::Formatter, key: &str) -> fmt::Result {
+    if !key.is_empty() && key.chars().all(is_bare_key_char) {
+        write!(f, "{}", key)
+    } else {
+        write!(f, "{}", Value::String(key.to_owned()))
+    }
+}
+
+fn write_table(f: &mut fmt::Formatter, table: &Table) -> fmt::Result {
+    for (key, value) in table {
+        write_key(f, key)?;
+        writeln!(f, " = {}", value)?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::String(s) => {
+                write!(f, "\"")?;
+                for c in s.chars() {
+                    match c {
+                        '"' => write!(f, "\\\"")?,
+                        '\\' => write!(f, "\\\\")?,
+                        '\n' => write!(f, "\\n")?,
+                        '\t' => write!(f, "\\t")?,
+                        '\r' => write!(f, "\\r")?,
+                        _ => write!(f, "{}", c)?,
+                    }
+                }
+                write!(f, "\"")
+            }
+            Value::Integer(i) => write!(f, "{}", i),
+            Value::Float(x) => write!(f, "{:?}", x),
+            Value::Boolean(b) => write!(f, "{}", b),
+            Value::Array(values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{}", value)?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+/// Writes the config as TOML that `Config::parse` reads back.
+impl fmt::Display for Config {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_table(f, &self.root)?;
+        for (i, (name, table)) in self.tables.iter().enumerate() {
+            if i > 0 || !self.root.is_empty() {
+                writeln!(f)?;
+            }
+            write!(f, "[")?;
+            write_key(f, name)?;
+            writeln!(f, "]")?;
+            write_table(f, table)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> Value {
+        Value::String(s.to_owned())
+    }
+
+    #[test]
+    fn strings_are_quoted_and_escaped() {
+        let config = Config::parse(concat!(
+            "basic = \"a \\\"b\\\" \\\\ c\\n\\td\"\n",
+            "literal = 'C:\\photos\\ \"raw\"'\n",
+            "\"quoted key\" = \"# not a comment\"\n",
+        ))
+        .unwrap();
+        assert_eq!(
+            config.root,
+            vec![
+                ("basic".to_owned(), string("a \"b\" \\ c\n\td")),
+                ("literal".to_owned(), string("C:\\photos\\ \"raw\"")),
+                ("quoted key".to_owned(), string("# not a comment")),
+            ]
+        );
+        for text in ["a = \"open", "a = 'open", "a = \"\\q\"", "a = bare"] {
+            assert!(Config::parse(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn scalars_and_arrays_are_typed() {
+        let config = Config::parse(concat!(
+            "int = 1_000\n",
+            "negative = -3\n",
+            "float = 0.5\n",
+            "flag = true\n",
+            "empty = []\n",
+            "mixed = [ 1, \"two\" ,[false], ]\n",
+        ))
+        .unwrap();
+        let values: Vec<&Value> = config.root.iter().map(|(_, v)| v).collect();
+        assert_eq!(
+            values,
+            [
+                &Value::Integer(1000),
+                &Value::Integer(-3),
+                &Value::Float(0.5),
+                &Value::Boolean(true),
+                &Value::Array(Vec::new()),
+                &Value::Array(vec![
+                    Value::Integer(1),
+                    string("two"),
+                    Value::Array(vec![Value::Boolean(false)]),
+                ]),
+            ]
+        );
+        for text in ["a = [1 2]", "a = [1", "a =", "a = 1 2"] {
+            assert!(Config::parse(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn keys_and_tables_are_defined_once() {
+        let config = Config::parse("a = 1\n[create]\na = 2\n[plan]\na = 3\n").unwrap();
+        assert_eq!(config.root, vec![("a".to_owned(), Value::Integer(1))]);
+        assert_eq!(
+            config.table("plan"),
+            Some(&vec![("a".to_owned(), Value::Integer(3))])
+        );
+
+        let twice = Config::parse("[create]\na = 1\n\n# again\na = 2\n");
+        assert_eq!(twice, Err("line 5: key `a` defined twice".to_owned()));
+        let twice = Config::parse("[create]\n[plan]\n[ create ]\n");
+        assert_eq!(
+            twice,
+            Err("line 3: table `create` defined twice".to_owned())
+        );
+        for text in ["[[create]]", "a.b = 1", "[create", "[create] a = 1"] {
+            assert!(Config::parse(text).is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn comments_are_ignored() {
+        let config = Config::parse(concat!(
+            "# header\n",
+            "\n",
+            "  \t# indented\n",
+            "a = 1 # trailing\n",
+            "b = [1, 2]# no space\n",
+            "[create] # table\n",
+            "c = \"#\" #\n",
+        ))
+        .unwrap();
+        assert_eq!(config.root.len(), 2);
+        assert_eq!(
+            config.table("create"),
+            Some(&vec![("c".to_owned(), string("#"))])
+        );
+    }
+
+    #[test]
+    fn display_is_read_back() {
+        let config = Config {
+            root: vec![
+                ("spacing".to_owned(), Value::Integer(2)),
+                ("odd key".to_owned(), string("\"quoted\"\n\\\t\r")),
+            ],
+            tables: vec![
+                (
+                    "create".to_owned(),
+                    vec![
+                        ("ratio".to_owned(), Value::Float(1.0)),
+                        ("tiny".to_owned(), Value::Float(1e-20)),
+                        ("flip".to_owned(), Value::Boolean(false)),
+                        ("models".to_owned(), Value::Array(vec![string("a b")])),
+                    ],
+                ),
+                ("empty table".to_owned(), Vec::new()),
+            ],
+        };
+        let text = config.to_string();
+        assert_eq!(Config::parse(&text), Ok(config.clone()));
+        assert_eq!(Config::parse(&text).unwrap().to_string(), text);
+    }
+}
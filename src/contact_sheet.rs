@@ -0,0 +1,69 @@
+//! Contact sheet of several mosaics, to compare them side by side.
+
+use image::{imageops, DynamicImage, GenericImage, GenericImageView, ImageBuffer, Rgba};
+use std::cmp;
+
+/// Arranges `images` row by row in a grid of `columns` columns, all scaled to the height of
+/// the shortest one so that none is upscaled, with `padding` pixels of `background` between
+/// and around them.
+pub fn contact_sheet(
+    images: &[DynamicImage],
+    columns: usize,
+    padding: u32,
+    background: Rgba<u8>,
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let height = images.iter().map(|img| img.height()).min().unwrap_or(0);
+    let scaled: Vec<_> = images
+        .iter()
+        .map(|img| {
+            let width = cmp::max(
+                1,
+                (u64::from(img.width()) * u64::from(height) / u64::from(img.height().max(1)))
+                    as u32,
+            );
+            img.resize_exact(width, height, imageops::FilterType::Triangle)
+        })
+        .collect();
+
+    let rows: Vec<_> = scaled.chunks(cmp::max(columns, 1)).collect();
+    let total_w = rows
+        .iter()
+        .map(|row| row.iter().map(|img| img.width() + padding).sum::<u32>())
+        .max()
+        .unwrap_or(0)
+        + padding;
+    let total_h = rows.len() as u32 * (height + padding) + padding;
+
+    let mut res = ImageBuffer::from_pixel(total_w, total_h, background);
+    let mut y = padding;
+    for row in rows {
+        let mut x = padding;
+        for img in row {
+            assert!(res.copy_from(img, x, y));
+            x += img.width() + padding;
+        }
+        y += height + padding;
+    }
+    res
+}
+
+/// Puts `model` scaled to the height of `mosaic` on its left, with `gap` pixels of
+/// `background` between them, to show what the mosaic was made from.
+pub fn comparison(
+    model: &DynamicImage,
+    mosaic: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    gap: u32,
+    background: Rgba<u8>,
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let (mosaic_w, height) = mosaic.dimensions();
+    let width = cmp::max(
+        1,
+        (u64::from(model.width()) * u64::from(height) / u64::from(model.height().max(1))) as u32,
+    );
+    let model = imageops::resize(model, width, height, imageops::FilterType::Triangle);
+
+    let mut res = ImageBuffer::from_pixel(width + gap + mosaic_w, height, background);
+    assert!(res.copy_from(&model, 0, 0));
+    assert!(res.copy_from(mosaic, width + gap, 0));
+    res
+}
@@ -0,0 +1,97 @@
+//! How well the colors of a gallery cover the colors a model needs, binned in a coarse RGB
+//! grid.
+
+use crate::color::color_bin;
+use crate::ProcessedPicture;
+
+/// Bins per channel of the grid, coarser than the histograms so that the report stays short.
+const COVERAGE_BINS_PER_CHANNEL: u32 = 4;
+
+/// Model chunks and gallery pictures whose color falls in a bin of the grid.
+pub struct CoverageBin {
+    /// Color at the center of the bin.
+    pub color: [u8; 3],
+    pub chunks: usize,
+    pub pictures: usize,
+}
+
+impl CoverageBin {
+    /// Whether the model needs colors of this bin that no picture has.
+    pub fn is_missing(&self) -> bool {
+        self.chunks > 0 && self.pictures == 0
+    }
+}
+
+/// Bins the colors of the model chunks and of `pics` in `COVERAGE_BINS_PER_CHANNEL`^3 bins,
+/// leaving out the bins neither of them falls in. Sorted from the
+/// bin with the most chunks to the one with the least.
+pub fn color_coverage(chunk_colors: &[[u8; 3]], pics: &[ProcessedPicture]) -> Vec<CoverageBin> {
+    let bins = COVERAGE_BINS_PER_CHANNEL;
+    let bin_width = 256 / bins;
+    let mut coverage: Vec<_> = (0..bins * bins * bins)
+        .map(|i| {
+            let center = |bin: u32| (bin * bin_width + bin_width / 2) as u8;
+            CoverageBin {
+                color: [
+                    center(i / (bins * bins)),
+                    center(i / bins % bins),
+                    center(i % bins),
+                ],
+                chunks: 0,
+                pictures: 0,
+            }
+        })
+        .collect();
+    for &color in chunk_colors {
+        coverage[color_bin(color, bins)].chunks += 1;
+    }
+    for pic in pics {
+        coverage[color_bin(pic.color_rgb, bins)].pictures += 1;
+    }
+
+    coverage.retain(|bin| bin.chunks > 0 || bin.pictures > 0);
+    coverage.sort_by(|a, b| b.chunks.cmp(&a.chunks).then(b.pictures.cmp(&a.pictures)));
+    coverage
+}
+
+/// Rough name of `color`, such as "bright red" or "dark gray", for the coverage report.
+pub fn color_name(color: [u8; 3]) -> String {
+    let [r, g, b] = color.map(|c| f32::from(c) / 255.0);
+    let max = r.max(g).max(b);
+    let min = r.min(g).min(b);
+    let saturation = if max > 0.0 { (max - min) / max } else { 0.0 };
+    if saturation < 0.25 {
+        return match max {
+            v if v < 0.25 => "black",
+            v if v < 0.5 => "dark gray",
+            v if v < 0.8 => "light gray",
+            _ => "white",
+        }
+        .to_string();
+    }
+
+    let delta = max - min;
+    let hue = if max == r {
+        60.0 * ((g - b) / delta).rem_euclid(6.0)
+    } else if max == g {
+        60.0 * ((b - r) / delta + 2.0)
+    } else {
+        60.0 * ((r - g) / delta + 4.0)
+    };
+    let name = match hue {
+        h if h < 15.0 => "red",
+        h if h < 45.0 => "orange",
+        h if h < 70.0 => "yellow",
+        h if h < 160.0 => "green",
+        h if h < 200.0 => "cyan",
+        h if h < 260.0 => "blue",
+        h if h < 290.0 => "purple",
+        h if h < 340.0 => "magenta",
+        _ => "red",
+    };
+    match max {
+        v if v < 0.5 => format!("dark {}", name),
+        v if v > 0.8 => format!("bright {}", name),
+        _ => name.to_string(),
+    }
+}
@@ -0,0 +1,209 @@
+//! Deep Zoom (DZI) tile pyramid, as read by OpenSeadragon, written from row bands so that the
+//! full resolution image never has to be held in memory.
+
+use crate::error::MosaicError;
+use image::{GenericImageView, ImageBuffer, ImageResult, Rgba};
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+pub const TILE_SIZE: u32 = 256;
+const NAME: &str = "mosaic";
+
+type Band = ImageBuffer<Rgba<u8>, Vec<u8>>;
+
+/// Writes the pyramid of a `width`x`height` image in `out_dir`. `render_band(y, h)` must
+/// return the rows `y..y + h` of the full resolution image, or why they can't be rendered.
+pub fn write_dzi<F>(
+    out_dir: &Path,
+    width: u32,
+    height: u32,
+    mut render_band: F,
+) -> Result<(), MosaicError>
+where
+    F: FnMut(u32, u32) -> ImageResult<Band>,
+{
+    let tiles_dir = out_dir.join(format!("{}_files", NAME));
+    fs::create_dir_all(&tiles_dir)?;
+    write_descriptor(&out_dir.join(format!("{}.dzi", NAME)), width, height)?;
+
+    let max_level = max_level(width, height);
+    let mut levels: Vec<Level> = (0..=max_level)
+        .map(|level| Level::new(&tiles_dir, level, max_level, width))
+        .collect();
+
+    let mut y = 0;
+    while y < height {
+        let band_h = TILE_SIZE.min(height - y);
+        let band = render_band(y, band_h)?;
+        push_band(&mut levels, max_level as usize, band)?;
+        y += band_h;
+    }
+
+    for level in (0..=max_level as usize).rev() {
+        if let Some(band) = levels[level].flush()? {
+            if level > 0 {
+                push_band(&mut levels, level - 1, band)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Level of the full resolution image, the one at which the image is 1x1 being 0.
+fn max_level(width: u32, height: u32) -> u32 {
+    let size = width.max(height).max(1);
+    32 - (size - 1).leading_zeros()
+}
+
+fn write_descriptor(path: &Path, width: u32, height: u32) -> Result<(), MosaicError> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    writeln!(writer, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    writeln!(
+        writer,
+        "<Image xmlns=\"http://schemas.microsoft.com/deepzoom/2008\" Format=\"jpg\" Overlap=\"0\" TileSize=\"{}\">",
+        TILE_SIZE
+    )?;
+    writeln!(
+        writer,
+        "  <Size Width=\"{}\" Height=\"{}\"/>",
+        width, height
+    )?;
+    writeln!(writer, "</Image>")?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Feeds `band` to `level`, and the downsampled bands it completes to the smaller levels.
+fn push_band(levels: &mut [Level], level: usize, band: Band) -> Result<(), MosaicError> {
+    let mut next = levels[level].push(band)?;
+    let mut level = level;
+    while let Some(band) = next {
+        if level == 0 {
+            break;
+        }
+        level -= 1;
+        next = levels[level].push(band)?;
+    }
+    Ok(())
+}
+
+/// Rows of a level not yet written as tiles.
+struct Level {
+    dir: PathBuf,
+    width: u32,
+    pending: Vec<u8>,
+    pending_rows: u32,
+    tile_row: u32,
+}
+
+impl Level {
+    fn new(tiles_dir: &Path, level: u32, max_level: u32, full_width: u32) -> Level {
+        let scale = 1u64 << (max_level - level);
+        Level {
+            dir: tiles_dir.join(level.to_string()),
+            width: u64::from(full_width).div_ceil(scale) as u32,
+            pending: Vec::new(),
+            pending_rows: 0,
+            tile_row: 0,
+        }
+    }
+
+    /// Appends `band` and writes a row of tiles once enough rows are pending, returning it
+    /// downsampled for the next level.
+    fn push(&mut self, band: Band) -> Result<Option<Band>, MosaicError> {
+        if band.width() != self.width {
+            return Err(MosaicError::Invalid(format!(
+                "band of {} px wide rendered for a level {} px wide",
+                band.width(),
+                self.width
+            )));
+        }
+        self.pending_rows += band.height();
+        self.pending.extend_from_slice(&band.into_raw());
+        if self.pending_rows < TILE_SIZE {
+            return Ok(None);
+        }
+        self.write_tile_row(TILE_SIZE).map(Some)
+    }
+
+    /// Writes the remaining rows, if any.
+    fn flush(&mut self) -> Result<Option<Band>, MosaicError> {
+        if self.pending_rows == 0 {
+            return Ok(None);
+        }
+        let rows = self.pending_rows;
+        self.write_tile_row(rows).map(Some)
+    }
+
+    fn write_tile_row(&mut self, rows: u32) -> Result<Band, MosaicError> {
+        let row_len = (self.width * 4) as usize;
+        let rest = self.pending.split_off(rows as usize * row_len);
+        let band = Band::from_raw(self.width, rows, std::mem::replace(&mut self.pending, rest))
+            .expect("the pending rows are as wide as the level");
+        self.pending_rows -= rows;
+
+        fs::create_dir_all(&self.dir)?;
+        let mut x = 0;
+        while x < self.width {
+            let tile_w = TILE_SIZE.min(self.width - x);
+            let tile = band.view(x, 0, tile_w, rows).to_image();
+            let name = format!("{}_{}.jpg", x / TILE_SIZE, self.tile_row);
+            image::DynamicImage::ImageRgba8(tile)
+                .to_rgb()
+                .save(self.dir.join(name))?;
+            x += tile_w;
+        }
+        self.tile_row += 1;
+
+        Ok(downsample(&band))
+    }
+}
+
+/// Halves the dimensions of `band`, rounding up, by averaging blocks of 2x2 pixels.
+fn downsample(band: &Band) -> Band {
+    let (w, h) = band.dimensions();
+    let (half_w, half_h) = (w.div_ceil(2), h.div_ceil(2));
+    ImageBuffer::from_fn(half_w, half_h, |x, y| {
+        let mut sums = [0u32; 4];
+        let mut count = 0;
+        for sy in 2 * y..(2 * y + 2).min(h) {
+            for sx in 2 * x..(2 * x + 2).min(w) {
+                let pixel = band.get_pixel(sx, sy);
+                for (sum, channel) in sums.iter_mut().zip(pixel.data.iter()) {
+                    *sum += u32::from(*channel);
+                }
+                count += 1;
+            }
+        }
+        Rgba([
+            (sums[0] / count) as u8,
+            (sums[1] / count) as u8,
+            (sums[2] / count) as u8,
+            (sums[3] / count) as u8,
+        ])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn band_of_another_width_is_invalid() {
+        let out_dir = temp_dir("dzi");
+        let res = write_dzi(&out_dir, 4, 4, |_, h| Ok(Band::new(3, h)));
+        assert!(matches!(res, Err(MosaicError::Invalid(_))));
+    }
+
+    #[test]
+    fn pyramid_has_a_tile_per_level() {
+        let out_dir = temp_dir("dzi");
+        write_dzi(&out_dir, 4, 4, |_, h| Ok(Band::new(4, h))).unwrap();
+        for level in 0..=2 {
+            let tile = out_dir.join(format!("mosaic_files/{}/0_0.jpg", level));
+            assert!(tile.is_file(), "{} is missing", tile.display());
+        }
+    }
+}
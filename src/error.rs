@@ -0,0 +1,111 @@
+//! Error of the fallible steps of the library, so that the programs embedding it can tell a
+//! missing file from a corrupted one or a cancelled step.
+
+use crate::progress::Cancelled;
+use image::ImageError;
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+#[derive(Debug)]
+pub enum MosaicError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    /// A picture that can't be decoded, or an image that can't be encoded.
+    Image(ImageError),
+    /// Thumbnail of a plan or a tile map that isn't in the preprocessed gallery.
+    MissingTile(String),
+    /// Gallery without any picture.
+    EmptyGallery,
+    /// Version of metadata written by a newer version of the library.
+    UnsupportedVersion(u32),
+    /// File or argument that can't be used, with why, such as a corrupted zip archive.
+    Invalid(String),
+    /// Step stopped by `Progress::is_cancelled`.
+    Cancelled,
+}
+
+impl fmt::Display for MosaicError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MosaicError::Io(e) => write!(f, "{}", e),
+            MosaicError::Json(e) => write!(f, "{}", e),
+            MosaicError::Image(e) => write!(f, "{}", e),
+            MosaicError::MissingTile(path) => {
+                write!(f, "{} isn't in the preprocessed pictures", path)
+            }
+            MosaicError::EmptyGallery => write!(f, "the gallery has no picture"),
+            MosaicError::UnsupportedVersion(version) => write!(
+                f,
+                "metadata version {} is newer than the supported version {}",
+                version,
+                crate::METADATA_VERSION
+            ),
+            MosaicError::Invalid(message) => write!(f, "{}", message),
+            MosaicError::Cancelled => write!(f, "{}", Cancelled),
+        }
+    }
+}
+
+impl Error for MosaicError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MosaicError::Io(e) => Some(e),
+            MosaicError::Json(e) => Some(e),
+            MosaicError::Image(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for MosaicError {
+    fn from(e: io::Error) -> MosaicError {
+        MosaicError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for MosaicError {
+    fn from(e: serde_json::Error) -> MosaicError {
+        MosaicError::Json(e)
+    }
+}
+
+/// Rendering being cancelled through `ImageError`, as documented on `Cancelled`, it is told
+/// apart from the other I/O errors.
+impl From<ImageError> for MosaicError {
+    fn from(e: ImageError) -> MosaicError {
+        match e {
+            ImageError::IoError(e) if e.get_ref().is_some_and(|e| e.is::<Cancelled>()) => {
+                MosaicError::Cancelled
+            }
+            e => MosaicError::Image(e),
+        }
+    }
+}
+
+impl From<png::EncodingError> for MosaicError {
+    fn from(e: png::EncodingError) -> MosaicError {
+        match e {
+            png::EncodingError::IoError(e) => MosaicError::Io(e),
+            png::EncodingError::Format(message) => MosaicError::Invalid(message.into_owned()),
+        }
+    }
+}
+
+impl From<Cancelled> for MosaicError {
+    fn from(_: Cancelled) -> MosaicError {
+        MosaicError::Cancelled
+    }
+}
+
+impl From<String> for MosaicError {
+    fn from(message: String) -> MosaicError {
+        MosaicError::Invalid(message)
+    }
+}
+
+impl From<&str> for MosaicError {
+    fn from(message: &str) -> MosaicError {
+        MosaicError::Invalid(message.to_owned())
+    }
+}
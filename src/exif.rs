@@ -0,0 +1,110 @@
+//! Minimal EXIF reader, only extracting the orientation tag of JPEG files.
+
+use image::DynamicImage;
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::Path;
+
+const ORIENTATION_TAG: u16 = 0x0112;
+
+/// Returns the EXIF orientation (1 to 8) of the JPEG at `path`, if any.
+pub fn read_orientation(path: &Path) -> Option<u16> {
+    let file = File::open(path).ok()?;
+    let app1 = read_exif_segment(&mut BufReader::new(file)).ok()??;
+    parse_orientation(&app1)
+}
+
+/// Returns the EXIF orientation of the JPEG encoded in `data`, if any.
+pub fn read_orientation_from_memory(data: &[u8]) -> Option<u16> {
+    let app1 = read_exif_segment(&mut &data[..]).ok()??;
+    parse_orientation(&app1)
+}
+
+/// Rotates and flips `img` so that it is displayed upright for the given EXIF orientation.
+pub fn apply_orientation(img: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate90(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+/// Walks the JPEG markers until the APP1 segment holding the EXIF data, and returns its
+/// payload without the `Exif\0\0` header.
+fn read_exif_segment<R: Read>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut marker = [0; 2];
+    reader.read_exact(&mut marker)?;
+    if marker != [0xFF, 0xD8] {
+        return Ok(None);
+    }
+
+    loop {
+        reader.read_exact(&mut marker)?;
+        if marker[0] != 0xFF {
+            return Ok(None);
+        }
+
+        // Start of scan, the metadata segments are all behind us.
+        if marker[1] == 0xDA {
+            return Ok(None);
+        }
+
+        let mut len = [0; 2];
+        reader.read_exact(&mut len)?;
+        let len = u16::from_be_bytes(len) as usize;
+        if len < 2 {
+            return Ok(None);
+        }
+
+        let mut segment = vec![0; len - 2];
+        reader.read_exact(&mut segment)?;
+        if marker[1] == 0xE1 && segment.starts_with(b"Exif\0\0") {
+            return Ok(Some(segment.split_off(6)));
+        }
+    }
+}
+
+/// Looks up the orientation tag in the first IFD of a TIFF structure.
+fn parse_orientation(tiff: &[u8]) -> Option<u16> {
+    let big_endian = match tiff.get(0..2)? {
+        b"II" => false,
+        b"MM" => true,
+        _ => return None,
+    };
+    let read_u16 = |offset: usize| -> Option<u16> {
+        let bytes = [*tiff.get(offset)?, *tiff.get(offset + 1)?];
+        Some(if big_endian {
+            u16::from_be_bytes(bytes)
+        } else {
+            u16::from_le_bytes(bytes)
+        })
+    };
+    let read_u32 = |offset: usize| -> Option<u32> {
+        let bytes = [
+            *tiff.get(offset)?,
+            *tiff.get(offset + 1)?,
+            *tiff.get(offset + 2)?,
+            *tiff.get(offset + 3)?,
+        ];
+        Some(if big_endian {
+            u32::from_be_bytes(bytes)
+        } else {
+            u32::from_le_bytes(bytes)
+        })
+    };
+
+    let ifd = read_u32(4)? as usize;
+    let entries = read_u16(ifd)? as usize;
+    for i in 0..entries {
+        let entry = ifd + 2 + i * 12;
+        if read_u16(entry)? == ORIENTATION_TAG {
+            return read_u16(entry + 8).filter(|orientation| (1..=8).contains(orientation));
+        }
+    }
+    None
+}
@@ -0,0 +1,177 @@
+//! Glob patterns selecting the gallery files to preprocess.
+//!
+//! `*` matches any sequence of characters but `/`, `**` any sequence of path components, `?`
+//! any character but `/` and `[...]` (or `[!...]`) a character of (or not of) a set of
+//! characters and ranges such as `a-z`.
+
+use std::path::{Component, Path};
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Char(char),
+    AnyChar,
+    Star,
+    /// `**/`, matching zero or more leading path components.
+    AnyDirs,
+    /// `**` at the end of the pattern.
+    AnyPath,
+    Class {
+        negated: bool,
+        ranges: Vec<(char, char)>,
+    },
+}
+
+#[derive(Debug)]
+pub struct Pattern {
+    tokens: Vec<Token>,
+    /// Whether the pattern has a `/`, in which case it is matched against the whole relative
+    /// path, and else against each of its components.
+    anchored: bool,
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Result<Pattern, String> {
+        let trimmed = pattern.trim_end_matches('/');
+        let chars: Vec<_> = trimmed.chars().collect();
+        let mut tokens = Vec::new();
+        let mut i = 0;
+        while i < chars.len() {
+            match chars[i] {
+                '*' if chars.get(i + 1) == Some(&'*') => {
+                    if chars.get(i + 2) == Some(&'/') {
+                        tokens.push(Token::AnyDirs);
+                        i += 3;
+                    } else {
+                        tokens.push(Token::AnyPath);
+                        i += 2;
+                    }
+                }
+                '*' => {
+                    tokens.push(Token::Star);
+                    i += 1;
+                }
+                '?' => {
+                    tokens.push(Token::AnyChar);
+                    i += 1;
+                }
+                '[' => {
+                    let (token, len) = parse_class(&chars[i..])
+                        .ok_or_else(|| format!("unclosed character class in {:?}", pattern))?;
+                    tokens.push(token);
+                    i += len;
+                }
+                c => {
+                    tokens.push(Token::Char(c));
+                    i += 1;
+                }
+            }
+        }
+
+        if tokens.is_empty() {
+            return Err("empty pattern".to_string());
+        }
+        Ok(Pattern {
+            tokens,
+            anchored: trimmed.contains('/'),
+        })
+    }
+
+    /// Whether `path`, relative to the walked folder, matches the pattern.
+    pub fn matches(&self, path: &Path) -> bool {
+        let components: Vec<String> = path
+            .components()
+            .filter_map(|component| match component {
+                Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+                _ => None,
+            })
+            .collect();
+        if self.anchored {
+            let text: Vec<_> = components.join("/").chars().collect();
+            matches(&self.tokens, &text)
+        } else {
+            components.iter().any(|component| {
+                let text: Vec<_> = component.chars().collect();
+                matches(&self.tokens, &text)
+            })
+        }
+    }
+}
+
+/// Parses the class starting at `chars[0]`, a `[`, returning it with its length.
+fn parse_class(chars: &[char]) -> Option<(Token, usize)> {
+    let mut i = 1;
+    let negated = chars.get(i) == Some(&'!');
+    if negated {
+        i += 1;
+    }
+
+    let mut ranges = Vec::new();
+    // A `]` right after the opening bracket is part of the set.
+    let start = i;
+    while i < chars.len() && (chars[i] != ']' || i == start) {
+        let c = chars[i];
+        if chars.get(i + 1) == Some(&'-') && chars.get(i + 2).is_some_and(|&end| end != ']') {
+            ranges.push((c, chars[i + 2]));
+            i += 3;
+        } else {
+            ranges.push((c, c));
+            i += 1;
+        }
+    }
+
+    if i >= chars.len() {
+        return None;
+    }
+    Some((Token::Class { negated, ranges }, i + 1))
+}
+
+fn matches(tokens: &[Token], text: &[char]) -> bool {
+    let token = match tokens.first() {
+        Some(token) => token,
+        None => return text.is_empty(),
+    };
+    let rest = &tokens[1..];
+    match token {
+        Token::Char(c) => text.first() == Some(c) && matches(rest, &text[1..]),
+        Token::AnyChar => text.first().is_some_and(|&c| c != '/') && matches(rest, &text[1..]),
+        Token::Class { negated, ranges } => match text.first() {
+            Some(&c) if c != '/' => {
+                let in_class = ranges.iter().any(|&(lo, hi)| lo <= c && c <= hi);
+                in_class != *negated && matches(rest, &text[1..])
+            }
+            _ => false,
+        },
+        Token::Star => {
+            let component_len = text.iter().position(|&c| c == '/').unwrap_or(text.len());
+            (0..=component_len).any(|i| matches(rest, &text[i..]))
+        }
+        Token::AnyDirs => {
+            matches(rest, text)
+                || text
+                    .iter()
+                    .enumerate()
+                    .any(|(i, &c)| c == '/' && matches(rest, &text[i + 1..]))
+        }
+        Token::AnyPath => (0..=text.len()).any(|i| matches(rest, &text[i..])),
+    }
+}
+
+/// Files excluded from, or the only files included in, the gallery.
+#[derive(Debug, Default)]
+pub struct FileFilter {
+    pub exclude: Vec<Pattern>,
+    /// Patterns one of which the files must match, if any.
+    pub include: Vec<Pattern>,
+}
+
+impl FileFilter {
+    /// Whether the folder or file at `path`, relative to the walked folder, is skipped.
+    pub fn is_excluded(&self, path: &Path, is_dir: bool) -> bool {
+        if self.exclude.iter().any(|pattern| pattern.matches(path)) {
+            return true;
+        }
+        !is_dir
+            && !self.include.is_empty()
+            && !self.include.iter().any(|pattern| pattern.matches(path))
+    }
+}
@@ -0,0 +1,230 @@
+//! Web page showing the mosaic, with the original path of each tile in a tooltip.
+
+use crate::error::MosaicError;
+use crate::manifest::{Manifest, ManifestCell};
+use image::{ImageBuffer, Rgba};
+use std::collections::HashSet;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+const TILES_FOLDER: &str = "tiles";
+const SPRITE_FILENAME: &str = "mosaic.png";
+
+/// Writes `index.html` in `out_dir` as the `<img>` of each tile of `manifest`, referencing
+/// copies of the thumbnails, over a mosaic of `(width, height)` pixels. Each is placed at the
+/// rectangle of its tile, so that the split and merged cells keep their size and the cells
+/// left out of the manifest, such as the masked ones, stay empty rather than shifting the
+/// others.
+pub fn write_tiles_page(
+    out_dir: &Path,
+    manifest: &Manifest,
+    processed_folder: &Path,
+    (width, height): (u32, u32),
+    link: bool,
+) -> Result<(), MosaicError> {
+    let tiles_dir = out_dir.join(TILES_FOLDER);
+    fs::create_dir_all(&tiles_dir)?;
+    let mut copied = HashSet::new();
+    for cell in &manifest.cells {
+        if copied.insert(&cell.path) {
+            fs::copy(
+                processed_folder.join(&cell.path),
+                tiles_dir.join(&cell.path),
+            )?;
+        }
+    }
+
+    // The tiles wrapped around the sides of `Layout::Brick` are cut at the right side.
+    let mut html = page_header(&format!(
+        ".mosaic {{ position: relative; width: {}px; height: {}px; overflow: hidden; }}\n\
+         .mosaic img {{ position: absolute; display: block; }}",
+        width, height
+    ));
+    html.push_str("<div class=\"mosaic\">\n");
+    for cell in &manifest.cells {
+        let img = format!(
+            "<img src=\"{}/{}\" width=\"{}\" height=\"{}\" title=\"{}\" style=\"{}\" alt=\"\">",
+            TILES_FOLDER,
+            escape(&cell.path),
+            cell.width,
+            cell.height,
+            escape(tooltip(cell)),
+            cell_style(cell)
+        );
+        match link_target(cell, link) {
+            Some(href) => html.push_str(&format!("<a href=\"{}\">{}</a>\n", escape(href), img)),
+            None => html.push_str(&format!("{}\n", img)),
+        }
+    }
+    html.push_str("</div>\n");
+    html.push_str(PAGE_FOOTER);
+    save_page(out_dir, &html)
+}
+
+/// Writes `index.html` in `out_dir` showing the whole mosaic as a single image, with an image
+/// map giving each cell its tooltip. Better suited than `write_tiles_page` to large grids.
+pub fn write_sprite_page(
+    out_dir: &Path,
+    manifest: &Manifest,
+    mosaic: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    link: bool,
+) -> Result<(), MosaicError> {
+    fs::create_dir_all(out_dir)?;
+    mosaic.save(out_dir.join(SPRITE_FILENAME))?;
+
+    let mut html = page_header("img { display: block; }");
+    html.push_str(&format!(
+        "<img src=\"{}\" width=\"{}\" height=\"{}\" usemap=\"#tiles\" alt=\"\">\n",
+        SPRITE_FILENAME,
+        mosaic.width(),
+        mosaic.height()
+    ));
+    html.push_str("<map name=\"tiles\">\n");
+    for cell in &manifest.cells {
+        let href = link_target(cell, link)
+            .map(|href| format!(" href=\"{}\"", escape(href)))
+            .unwrap_or_default();
+        html.push_str(&format!(
+            "<area shape=\"rect\" coords=\"{},{},{},{}\" title=\"{}\" alt=\"{}\"{}>\n",
+            cell.x,
+            cell.y,
+            cell.x + cell.width,
+            cell.y + cell.height,
+            escape(tooltip(cell)),
+            escape(&cell.path),
+            href
+        ));
+    }
+    html.push_str("</map>\n");
+    html.push_str(PAGE_FOOTER);
+    save_page(out_dir, &html)
+}
+
+const PAGE_FOOTER: &str = "</body>\n</html>\n";
+
+fn page_header(style: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Photo Mosaic</title>\n\
+         <style>\n{}\n</style>\n</head>\n<body>\n",
+        style
+    )
+}
+
+fn save_page(out_dir: &Path, html: &str) -> Result<(), MosaicError> {
+    let mut writer = BufWriter::new(File::create(out_dir.join("index.html"))?);
+    writer.write_all(html.as_bytes())?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Style placing the thumbnail of `cell` at its tile, turned as in the mosaic, the rightmost
+/// CSS transform being applied first.
+fn cell_style(cell: &ManifestCell) -> String {
+    let mut style = format!("left: {}px; top: {}px", cell.x, cell.y);
+    let mut transforms = Vec::new();
+    if cell.rotation != 0 {
+        transforms.push(format!("rotate({}deg)", cell.rotation));
+    }
+    if cell.mirrored {
+        transforms.push("scaleX(-1)".to_owned());
+    }
+    if !transforms.is_empty() {
+        style.push_str(&format!("; transform: {}", transforms.join(" ")));
+    }
+    style
+}
+
+fn tooltip(cell: &ManifestCell) -> &str {
+    cell.source.as_ref().unwrap_or(&cell.path)
+}
+
+fn link_target(cell: &ManifestCell, link: bool) -> Option<&str> {
+    if link {
+        cell.source.as_deref()
+    } else {
+        None
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matching::{Layout, MosaicOptions};
+    use crate::mosaic::build_manifest;
+    use crate::testing::{flat_gallery, placement, temp_dir};
+
+    /// `<img>` tags of the `index.html` written in `dir`.
+    fn img_tags(dir: &Path) -> Vec<String> {
+        let html = fs::read_to_string(dir.join("index.html")).unwrap();
+        (html.lines())
+            .filter(|line| line.starts_with("<img"))
+            .map(str::to_owned)
+            .collect()
+    }
+
+    #[test]
+    fn masked_cells_leave_their_place_empty() {
+        let folder = temp_dir("html");
+        let pics = flat_gallery(&folder, &[[255, 0, 0], [0, 255, 0], [0, 0, 255]], 4);
+        let mut placement = placement(&pics, (3, 2), Layout::Grid, (4, 4));
+        placement.tiles[1].masked = true;
+        let manifest = build_manifest(&placement, &MosaicOptions::default());
+        let out = folder.join("page");
+        write_tiles_page(&out, &manifest, &folder, (12, 8), false).unwrap();
+
+        let tags = img_tags(&out);
+        let places = [
+            "left: 0px; top: 0px",
+            "left: 8px; top: 0px",
+            "left: 0px; top: 4px",
+        ];
+        assert_eq!(tags.len(), 5);
+        for (tag, place) in tags.iter().zip(places) {
+            assert!(tag.contains(place), "{}", tag);
+        }
+    }
+
+    #[test]
+    fn split_and_merged_cells_keep_their_size() {
+        let folder = temp_dir("html");
+        let pics = flat_gallery(&folder, &[[255, 0, 0], [0, 255, 0], [0, 0, 255]], 4);
+        // The first cell split in four, and the last four merged in a single tile.
+        let details = placement(&pics, (2, 2), Layout::Grid, (4, 4)).tiles;
+        let mut placement = placement(&pics, (4, 2), Layout::Grid, (8, 8));
+        placement.tiles[0].details = details;
+        placement.tiles[2].span = 2;
+        for i in [3, 6, 7] {
+            placement.tiles[i].span = 0;
+        }
+        let manifest = build_manifest(&placement, &MosaicOptions::default());
+        let out = folder.join("page");
+        write_tiles_page(&out, &manifest, &folder, (32, 16), false).unwrap();
+
+        let rects = [
+            (0, 0, 4, 4),
+            (4, 0, 4, 4),
+            (0, 4, 4, 4),
+            (4, 4, 4, 4),
+            (8, 0, 8, 8),
+            (16, 0, 16, 16),
+            (0, 8, 8, 8),
+            (8, 8, 8, 8),
+        ];
+        let tags = img_tags(&out);
+        assert_eq!(tags.len(), rects.len());
+        for (tag, (x, y, w, h)) in tags.iter().zip(rects) {
+            let size = format!("width=\"{}\" height=\"{}\"", w, h);
+            let place = format!("style=\"left: {}px; top: {}px\"", x, y);
+            assert!(tag.contains(&size) && tag.contains(&place), "{}", tag);
+        }
+    }
+}
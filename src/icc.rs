@@ -0,0 +1,187 @@
+//! ICC profiles of the color spaces a mosaic is written in, embedded in the JPEG and PNG
+//! outputs so that color managed viewers and printers show its colors as they are meant.
+//!
+//! They are the smallest profiles of the ICC v2 specification for an RGB display: the
+//! primaries adapted to the D50 illuminant of the profile connection space, and a tone curve.
+
+use crate::mosaic::ColorSpace;
+use crate::srgb;
+
+/// D50 white of the profile connection space.
+const D50: [f64; 3] = [0.9642, 1.0, 0.8249];
+/// XYZ of the red, green and blue primaries of sRGB, adapted to D50 with Bradford.
+const SRGB_PRIMARIES: [[f64; 3]; 3] = [
+    [0.436_074_7, 0.222_504_5, 0.013_932_2],
+    [0.385_064_9, 0.716_878_6, 0.097_104_5],
+    [0.143_080_4, 0.060_616_9, 0.714_173_3],
+];
+/// XYZ of the red, green and blue primaries of Adobe RGB (1998), adapted to D50 with Bradford.
+const ADOBE_RGB_PRIMARIES: [[f64; 3]; 3] = [
+    [0.609_755_9, 0.311_124_2, 0.019_481_1],
+    [0.205_240_1, 0.625_656_0, 0.060_890_2],
+    [0.149_224_0, 0.063_219_7, 0.744_838_7],
+];
+/// Gamma of Adobe RGB (1998), 563/256.
+pub const ADOBE_RGB_GAMMA: f64 = 2.199_218_75;
+/// Linear sRGB to linear Adobe RGB, both of a D65 white, by rows.
+const SRGB_TO_ADOBE_RGB: [[f64; 3]; 3] = [
+    [0.715_162_7, 0.284_837_3, 0.0],
+    [0.0, 1.0, 0.0],
+    [0.0, 0.041_170_5, 0.958_829_5],
+];
+
+/// Name of the profile of `color_space`, as shown by the viewers.
+pub fn profile_name(color_space: ColorSpace) -> &'static str {
+    match color_space {
+        ColorSpace::Srgb => "sRGB IEC61966-2.1",
+        ColorSpace::AdobeRgb => "Adobe RGB (1998)",
+    }
+}
+
+/// ICC profile of `color_space`.
+pub fn profile(color_space: ColorSpace) -> Vec<u8> {
+    let (primaries, curve) = match color_space {
+        ColorSpace::Srgb => (
+            SRGB_PRIMARIES,
+            curve_tag(
+                &(0..=255)
+                    .map(|i| srgb::to_linear(i as u8))
+                    .collect::<Vec<_>>(),
+            ),
+        ),
+        ColorSpace::AdobeRgb => (ADOBE_RGB_PRIMARIES, gamma_tag(ADOBE_RGB_GAMMA)),
+    };
+    let [red, green, blue] = primaries;
+    let elements = [
+        description_tag(profile_name(color_space)),
+        text_tag("No copyright, use freely"),
+        xyz_tag(D50),
+        xyz_tag(red),
+        xyz_tag(green),
+        xyz_tag(blue),
+        curve,
+    ];
+    // Signature of each tag with its element, the three channels sharing the same curve.
+    let tags: [(&[u8; 4], usize); 9] = [
+        (b"desc", 0),
+        (b"cprt", 1),
+        (b"wtpt", 2),
+        (b"rXYZ", 3),
+        (b"gXYZ", 4),
+        (b"bXYZ", 5),
+        (b"rTRC", 6),
+        (b"gTRC", 6),
+        (b"bTRC", 6),
+    ];
+
+    let table_len = 4 + 12 * tags.len();
+    let mut data = Vec::new();
+    let mut offsets = Vec::with_capacity(elements.len());
+    for element in &elements {
+        while !data.len().is_multiple_of(4) {
+            data.push(0);
+        }
+        offsets.push((128 + table_len + data.len()) as u32);
+        data.extend_from_slice(element);
+    }
+    while !data.len().is_multiple_of(4) {
+        data.push(0);
+    }
+    let mut table = (tags.len() as u32).to_be_bytes().to_vec();
+    for (signature, element) in tags {
+        table.extend_from_slice(signature);
+        table.extend_from_slice(&offsets[element].to_be_bytes());
+        table.extend_from_slice(&(elements[element].len() as u32).to_be_bytes());
+    }
+
+    let size = (128 + table_len + data.len()) as u32;
+    let mut profile = Vec::with_capacity(size as usize);
+    profile.extend_from_slice(&size.to_be_bytes());
+    profile.extend_from_slice(&[0; 4]); // Preferred CMM.
+    profile.extend_from_slice(&[2, 0x10, 0, 0]); // Version 2.1.
+    profile.extend_from_slice(b"mntrRGB XYZ ");
+    for part in [2020u16, 1, 1, 0, 0, 0] {
+        profile.extend_from_slice(&part.to_be_bytes());
+    }
+    profile.extend_from_slice(b"acsp");
+    profile.extend_from_slice(&[0; 24]); // Platform, flags, device and attributes.
+    profile.extend_from_slice(&[0; 4]); // Perceptual rendering intent.
+    profile.extend_from_slice(&xyz_numbers(D50));
+    profile.extend_from_slice(&[0; 48]); // Creator and reserved bytes.
+    profile.extend_from_slice(&table);
+    profile.extend_from_slice(&data);
+    profile
+}
+
+/// Converts an sRGB color to Adobe RGB, whose gamut holds all the sRGB colors.
+pub fn srgb_to_adobe_rgb(rgb: [u8; 3]) -> [u8; 3] {
+    linear_to_adobe_rgb(rgb.map(srgb::to_linear)).map(|value| (value * 255.0).round() as u8)
+}
+
+/// `srgb_to_adobe_rgb` of a 16-bit color.
+pub fn srgb_to_adobe_rgb16(rgb: [u16; 3]) -> [u16; 3] {
+    linear_to_adobe_rgb(rgb.map(srgb::to_linear16)).map(|value| (value * 65535.0).round() as u16)
+}
+
+/// Adobe RGB values, between 0 and 1, of a color in linear sRGB.
+fn linear_to_adobe_rgb(linear: [f64; 3]) -> [f64; 3] {
+    SRGB_TO_ADOBE_RGB.map(|row| {
+        let value: f64 = (0..3).map(|c| row[c] * linear[c]).sum();
+        value.clamp(0.0, 1.0).powf(1.0 / ADOBE_RGB_GAMMA)
+    })
+}
+
+fn tag_header(signature: &[u8; 4]) -> Vec<u8> {
+    let mut tag = signature.to_vec();
+    tag.extend_from_slice(&[0; 4]);
+    tag
+}
+
+fn xyz_numbers(xyz: [f64; 3]) -> Vec<u8> {
+    xyz.iter()
+        .flat_map(|&v| ((v * 65536.0).round() as i32).to_be_bytes())
+        .collect()
+}
+
+fn xyz_tag(xyz: [f64; 3]) -> Vec<u8> {
+    let mut tag = tag_header(b"XYZ ");
+    tag.extend_from_slice(&xyz_numbers(xyz));
+    tag
+}
+
+/// Tone curve sampled at evenly spaced inputs, the outputs being between 0 and 1.
+fn curve_tag(samples: &[f64]) -> Vec<u8> {
+    let mut tag = tag_header(b"curv");
+    tag.extend_from_slice(&(samples.len() as u32).to_be_bytes());
+    for &sample in samples {
+        tag.extend_from_slice(&((sample * 65535.0).round() as u16).to_be_bytes());
+    }
+    tag
+}
+
+/// Tone curve of a pure gamma, the single entry of the curve as an 8.8 fixed point number.
+fn gamma_tag(gamma: f64) -> Vec<u8> {
+    let mut tag = tag_header(b"curv");
+    tag.extend_from_slice(&1u32.to_be_bytes());
+    tag.extend_from_slice(&((gamma * 256.0).round() as u16).to_be_bytes());
+    tag
+}
+
+fn text_tag(text: &str) -> Vec<u8> {
+    let mut tag = tag_header(b"text");
+    tag.extend_from_slice(text.as_bytes());
+    tag.push(0);
+    tag
+}
+
+/// ASCII description, without the Unicode and ScriptCode ones the v2 type also holds.
+fn description_tag(text: &str) -> Vec<u8> {
+    let mut tag = tag_header(b"desc");
+    tag.extend_from_slice(&(text.len() as u32 + 1).to_be_bytes());
+    tag.extend_from_slice(text.as_bytes());
+    tag.push(0);
+    tag.extend_from_slice(&[0; 8]); // Unicode language and length.
+    tag.extend_from_slice(&[0; 3]); // ScriptCode code and length.
+    tag.extend_from_slice(&[0; 67]);
+    tag
+}
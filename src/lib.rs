@@ -0,0 +1,94 @@
+//! Photo mosaics: a gallery is preprocessed into thumbnails once, then a model image is
+//! rebuilt from them by matching each of its chunks with the closest thumbnail.
+//!
+//! The steps are split in modules, their main items being re-exported here:
+//! - [`preprocess`] turns a gallery into thumbnails and their [`metadata`],
+//! - [`matching`] picks a thumbnail for each chunk of a model, giving a [`Placement`],
+//! - [`mosaic`] renders a placement as an image.
+
+use num::Integer;
+
+pub mod animation;
+mod binary;
+mod color;
+pub mod config;
+pub mod contact_sheet;
+pub mod coverage;
+pub mod dzi;
+pub mod error;
+mod exif;
+pub mod glob;
+pub mod html;
+mod icc;
+pub mod log;
+pub mod manifest;
+pub mod matching;
+pub mod metadata;
+pub mod mosaic;
+mod palette;
+pub mod plan;
+mod png_stream;
+pub mod preprocess;
+pub mod preview;
+pub mod progress;
+pub mod report;
+mod rng;
+mod srgb;
+#[cfg(test)]
+mod testing;
+pub mod tile_db;
+pub mod timings;
+mod zip;
+
+pub use error::MosaicError;
+pub use matching::{
+    chunk_colors, color_distance, color_distance_histogram, color_distance_hsv,
+    color_distance_luminance, find_closest_pic_by_color, grid_for_size, grid_size, mask_tiles,
+    masked_cells, match_tiles, match_unmasked_tiles, matches_closest_only, prepare_model,
+    stabilize_tiles, subdivide_tiles, FillMode, Layout, MaskFill, MatchMode, ModelOptions,
+    MosaicBuilder, MosaicOptions, PlacedTile, Placement, Rect, RegionOfInterest, Tone, ToneMap,
+    MAX_SPLIT_FACTOR,
+};
+pub use metadata::{
+    diff_metadata, find_near_duplicates, load_metadata, load_metadata_file, load_metadata_with,
+    metadata_path, MetadataDiff, MetadataFormat, NearDuplicate, ProcessedPicture,
+    ProcessedPictureMetadata, METADATA_CBOR_FILENAME, METADATA_FILENAME, METADATA_MSGPACK_FILENAME,
+    METADATA_NDJSON_FILENAME, METADATA_VERSION,
+};
+pub use mosaic::{
+    apply_alpha_mask, build_manifest, build_tile_map, create_mosaic, render_band, render_mosaic,
+    save_gif, save_jpeg, save_png, write_mosaic_in_bands, ColorSpace, PngOptions, Sample,
+    ThumbnailCache,
+};
+pub use preprocess::{
+    dedupe_gallery, dry_run_gallery, files_from_folder, preprocess_gallery, verify_gallery,
+    BadThumbnail, ColorMode, DryRun, PreprocessOptions, ThumbnailProblem, TileFit, WalkOptions,
+};
+pub use tile_db::{write_tile_db, TileDb};
+
+const CONTRAST_ADJUSTMENT: f32 = 20.0;
+/// Size of the preprocessed thumbnails, and of the tiles of the mosaic by default.
+pub const THUMBNAIL_SIZE: u32 = 64;
+const CHUNK_SIZE: u32 = 8;
+/// Bins per channel of the color histograms, 512 bins in all.
+const HISTOGRAM_BINS_PER_CHANNEL: u32 = 8;
+/// Alpha below which a pixel counts as transparent.
+const TRANSPARENT_ALPHA: u8 = 128;
+
+fn compute_ratio(w: u32, h: u32) -> (u32, u32) {
+    let gcd = w.gcd(&h);
+    (w / gcd, h / gcd)
+}
+
+fn ratio_to_dim(ratio: (u32, u32), size: u32) -> (u32, u32) {
+    if ratio.0 == ratio.1 {
+        return (size, size);
+    }
+
+    let ratio_f = ratio.0 as f32 / ratio.1 as f32;
+    if ratio_f > 1.0 {
+        (size, size * ratio.1 / ratio.0)
+    } else {
+        (size * ratio.0 / ratio.1, size)
+    }
+}
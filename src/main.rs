@@ -1,257 +1,2668 @@
-use clap::{App, Arg, SubCommand};
-use image::GenericImageView;
-use image::{self, imageops, DynamicImage, GenericImage, ImageBuffer, Rgba, SubImage};
-use num::Integer;
-use serde_derive::{Deserialize, Serialize};
+use clap::{value_t, App, Arg, ArgMatches, ArgSettings, SubCommand};
+use image::imageops::FilterType;
+use image::{DynamicImage, Rgba};
+use mosaic::animation::{self, Frame};
+use mosaic::config::{Config, Value as ConfigValue};
+use mosaic::contact_sheet::{comparison, contact_sheet};
+use mosaic::glob::{FileFilter, Pattern};
+use mosaic::log::{self, Level};
+use mosaic::manifest::{self, Manifest};
+use mosaic::plan::Plan;
+use mosaic::preview;
+use mosaic::progress::Progress;
+use mosaic::report::{Outcome, PreprocessReport};
+use mosaic::timings::Timings;
+use mosaic::{
+    apply_alpha_mask, build_manifest, build_tile_map, chunk_colors, color_distance, coverage,
+    create_mosaic, debug, dedupe_gallery, diff_metadata, dzi, error, files_from_folder,
+    grid_for_size, grid_size, html, info, mask_tiles, masked_cells, match_tiles,
+    match_unmasked_tiles, matches_closest_only, prepare_model, render_band, render_mosaic,
+    save_gif, save_jpeg, save_png, stabilize_tiles, subdivide_tiles, verify_gallery, warn,
+    write_mosaic_in_bands, ColorMode, ColorSpace, DryRun, FillMode, Layout, MaskFill, MatchMode,
+    MetadataFormat, ModelOptions, MosaicBuilder, MosaicError, MosaicOptions, Placement, PngOptions,
+    PreprocessOptions, ProcessedPicture, ProcessedPictureMetadata, RegionOfInterest, Sample,
+    ThumbnailCache, TileDb, TileFit, Tone, ToneMap, WalkOptions, MAX_SPLIT_FACTOR,
+};
+use std::cell::Cell;
 use std::cmp;
-use std::error::Error;
-use std::fs::{self, File};
-use std::io::{BufReader, BufWriter};
-use std::path::Path;
-use walkdir::{DirEntry, WalkDir};
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::ffi::OsString;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::slice;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-const CONTRAST_ADJUSTMENT: f32 = 20.0;
-const THUMBNAIL_SIZE: u32 = 64;
-const CHUNK_SIZE: u32 = 8;
-const METADATA_FILENAME: &str = "mosaic.json";
+/// Difference of contrast adjustment above which `create` warns about the gallery.
+const CONTRAST_ADJUSTMENT_TOLERANCE: f32 = 1.0;
+/// Size of the mosaic buffer, in bytes, above which it is written one row of cells at a time.
+const LOW_MEMORY_THRESHOLD: u64 = 1 << 30;
+/// Number of most used pictures listed by `create --dry-run`.
+const PLAN_TOP_PICTURES: usize = 10;
+/// Width in characters of the preview of `--preview`.
+const PREVIEW_COLUMNS: u32 = 80;
+/// Number of pixels above which a mosaic is only rendered with `--force`, in case the model
+/// was much larger than intended.
+const DEFAULT_MAX_OUTPUT_PIXELS: u64 = 250_000_000;
+/// Pixels between the model and the mosaic of `--comparison`.
+const COMPARISON_GAP: u32 = 16;
+/// Share by which the square thumbnails can be stretched to the tiles of `--tile-aspect-ratio`
+/// without a warning.
+const MAX_TILE_STRETCH: f64 = 0.2;
+/// Resolution of `--print-size` without `--dpi`, the usual one of photo prints.
+const DEFAULT_PRINT_DPI: u32 = 300;
 
-#[derive(Serialize, Deserialize, Debug)]
-struct ProcessedPictureMetadata {
-    pictures: Vec<ProcessedPicture>,
+/// Prints what `create` would produce from `placement`, without loading any thumbnail.
+fn print_plan(
+    placement: &Placement,
+    options: &MosaicOptions,
+    output_image: &Path,
+    streaming: bool,
+) {
+    let (w, h) = placement.dimensions(options);
+    println!(
+        "grid: {}x{} tiles of {}x{} px",
+        placement.grid_width, placement.grid_height, placement.thumb_dim.0, placement.thumb_dim.1
+    );
+    println!("output resolution: {}x{} px", w, h);
+
+    let pixels = u64::from(w) * u64::from(h);
+    let is_jpeg = output_image
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("jpg") || ext.eq_ignore_ascii_case("jpeg"));
+    // Rough compression ratios of photographic content.
+    let file_size = if is_jpeg {
+        pixels * 3 / 10
+    } else {
+        pixels * 4 / 2
+    };
+    println!("estimated file size: {}", format_bytes(file_size));
+    let buffer_rows = if streaming {
+        u64::from(placement.band_height(options).min(h))
+    } else {
+        u64::from(h)
+    };
+    let mut peak_memory = u64::from(w) * buffer_rows * 4;
+    if options.ghost > 0.0 {
+        // The model is resized to the size of the mosaic.
+        peak_memory *= 2;
+    }
+    println!("estimated peak memory: {}", format_bytes(peak_memory));
+
+    let shown = || (placement.tiles.iter()).filter(|tile| !tile.masked && tile.span > 0);
+    let mut usage: HashMap<&str, usize> = HashMap::new();
+    for tile in shown() {
+        if tile.details.is_empty() {
+            *usage.entry(tile.pic.path.as_str()).or_insert(0) += 1;
+        }
+        for detail in &tile.details {
+            *usage.entry(detail.pic.path.as_str()).or_insert(0) += 1;
+        }
+    }
+    let mut usage: Vec<_> = usage.into_iter().collect();
+    usage.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    let split = shown().filter(|tile| !tile.details.is_empty()).count();
+    println!("split cells: {}", split);
+    println!(
+        "merged tiles: {}",
+        shown().filter(|tile| tile.span > 1).count()
+    );
+    if options.fallback.is_some() {
+        let fallback = shown()
+            .flat_map(|tile| {
+                if tile.details.is_empty() {
+                    slice::from_ref(tile)
+                } else {
+                    &tile.details[..]
+                }
+            })
+            .filter(|tile| tile.falls_back(options))
+            .count();
+        println!("tiles replaced by the fallback color: {}", fallback);
+    }
+    println!("distinct pictures used: {}", usage.len());
+    for (path, count) in usage.iter().take(PLAN_TOP_PICTURES) {
+        println!("  {:>6} {}", count, path);
+    }
+    if usage.len() > PLAN_TOP_PICTURES {
+        println!("  ... {} more", usage.len() - PLAN_TOP_PICTURES);
+    }
+
+    let worst = shown()
+        .map(|tile| color_distance(tile.target_color, tile.pic.color_rgb))
+        .max()
+        .unwrap_or(0);
+    println!("worst color distance: {}", worst);
+}
+
+/// Prints what `preprocess` would do with each file of the gallery, and the space its output
+/// would take.
+fn print_preprocess_plan(dry_run: &DryRun) {
+    for record in &dry_run.report.files {
+        match (record.outcome, &record.message) {
+            (Outcome::Selected, _) => println!("would process {}", record.path),
+            (_, Some(reason)) => println!("would skip {}: {}", record.path, reason),
+            (_, None) => println!("would skip {}", record.path),
+        }
+    }
+    if dry_run.report.hidden > 0 {
+        println!("would skip {} hidden files", dry_run.report.hidden);
+    }
+    println!("thumbnails: {}", dry_run.report.count(Outcome::Selected));
+    println!(
+        "estimated thumbnail size: {}",
+        format_bytes(dry_run.thumbnail_bytes)
+    );
+    println!(
+        "estimated metadata size: {}",
+        format_bytes(dry_run.metadata_bytes)
+    );
+}
+
+/// Prints how many chunks of `model` and pictures of `pics` fall in each region of colors,
+/// and the colors the model needs but the gallery lacks.
+fn print_color_report(model: &DynamicImage, pics: &[ProcessedPicture], options: &MosaicOptions) {
+    let bins = coverage::color_coverage(&chunk_colors(model, options), pics);
+    println!("{:<28} {:>8} {:>8}", "colors", "chunks", "pictures");
+    for bin in &bins {
+        let [r, g, b] = bin.color;
+        let label = format!("{} ({},{},{})", coverage::color_name(bin.color), r, g, b);
+        println!("{:<28} {:>8} {:>8}", label, bin.chunks, bin.pictures);
+    }
+    for bin in bins.iter().filter(|bin| bin.is_missing()) {
+        println!(
+            "missing: the model needs {} for {} chunks but the gallery has none",
+            coverage::color_name(bin.color),
+            bin.chunks
+        );
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct ProcessedPicture {
-    path: String,
-    color_rgb: [u8; 3],
-    ratio_width: u32,
-    ratio_height: u32,
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", value, UNITS[unit])
 }
 
-fn compute_main_color(img: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> [u8; 3] {
-    let mut color_sums: [u32; 3] = [0; 3];
-    for pixel in img.enumerate_pixels() {
-        for i in 0..3 {
-            color_sums[i] += u32::from(pixel.2[i]);
+/// Preprocesses the gallery, writing what became of each file to `report_path` and the
+/// pictures to the database `db`. If `strict`, exits with an error if a file couldn't be
+/// decoded. If `dry_run`, only prints what would be done. If `timings`, prints the time spent
+/// in each phase and the pictures per second.
+fn cmd_preprocess(
+    gallery_folder: &Path,
+    output_folder: &Path,
+    options: &PreprocessOptions,
+    report_path: Option<&Path>,
+    db: Option<&Path>,
+    strict: bool,
+    (dry_run, timings): (bool, bool),
+) {
+    if dry_run {
+        let dry_run = match mosaic::dry_run_gallery(gallery_folder, output_folder, options) {
+            Ok(dry_run) => dry_run,
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
+            }
+        };
+        print_preprocess_plan(&dry_run);
+        if let Some(path) = report_path {
+            save_report(&dry_run.report, path);
+        }
+        return;
+    }
+
+    let (metadata, report) = match mosaic::preprocess_gallery(
+        gallery_folder,
+        output_folder,
+        options,
+        &CliProgress::default(),
+    ) {
+        Ok(preprocessed) => preprocessed,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+    if let Some(path) = report_path {
+        save_report(&report, path);
+    }
+    if let Some(db) = db {
+        if let Err(e) = mosaic::write_tile_db(&metadata, db) {
+            error!("can't write {}: {}", db.display(), e);
+            process::exit(1);
         }
     }
+    if timings {
+        report.timings.print("images");
+    }
+    let undecodable = report.count(Outcome::DecodeError);
+    if strict && undecodable > 0 {
+        error!("{} files couldn't be decoded", undecodable);
+        process::exit(1);
+    }
+}
 
-    let mut avg_color = [0; 3];
-    for i in 0..3 {
-        avg_color[i] = (color_sums[i] / (img.width() * img.height())) as u8;
+/// Saves the preprocessing `report` as JSON to `path`, exiting if it can't be written.
+fn save_report(report: &PreprocessReport, path: &Path) {
+    if let Err(e) = report.save_json(path) {
+        error!("can't write {}: {}", path.display(), e);
+        process::exit(1);
     }
-    avg_color
 }
 
-fn compute_ratio(w: u32, h: u32) -> (u32, u32) {
-    let gcd = w.gcd(&h);
-    (w / gcd, h / gcd)
+/// Parses a size in bytes, optionally suffixed with `K`, `M` or `G`.
+fn parse_size(value: &str) -> Result<u64, String> {
+    let value = value.trim();
+    let (number, shift) = match value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&value[..value.len() - 1], 10),
+        Some('M') => (&value[..value.len() - 1], 20),
+        Some('G') => (&value[..value.len() - 1], 30),
+        _ => (value, 0),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| format!("invalid size {:?}, expected e.g. 500M", value))
 }
 
-fn ratio_to_dim(ratio: (u32, u32), size: u32) -> (u32, u32) {
-    if ratio.0 == ratio.1 {
-        return (size, size);
+/// Parses a rectangle given as `x,y,w,h`.
+fn parse_rect(value: &str) -> Result<(u32, u32, u32, u32), String> {
+    let parts: Vec<_> = value
+        .split(',')
+        .map(|part| part.trim().parse::<u32>())
+        .collect();
+    match parts.as_slice() {
+        [Ok(x), Ok(y), Ok(w), Ok(h)] if *w > 0 && *h > 0 => Ok((*x, *y, *w, *h)),
+        _ => Err(format!("invalid rectangle {:?}, expected x,y,w,h", value)),
     }
+}
 
-    let ratio_f = ratio.0 as f32 / ratio.1 as f32;
-    if ratio_f > 1.0 {
-        (size, (size * ratio.1 / ratio.0) as u32)
-    } else {
-        ((size * ratio.0 / ratio.1) as u32, size)
+/// Parses a region of interest given as `x,y,w,h[,factor]`, the factor being 2 by default.
+fn parse_roi(value: &str) -> Result<RegionOfInterest, String> {
+    let (rect, factor) = match value.splitn(5, ',').nth(4) {
+        Some(factor) => (
+            &value[..value.len() - factor.len() - 1],
+            factor.trim().parse().ok(),
+        ),
+        None => (value, Some(2)),
+    };
+    match (parse_rect(rect), factor) {
+        (Ok(rect), Some(factor)) if (2..=MAX_SPLIT_FACTOR).contains(&factor) => {
+            Ok(RegionOfInterest { rect, factor })
+        }
+        _ => Err(format!(
+            "invalid region {:?}, expected x,y,w,h or x,y,w,h,factor with a factor from 2 to {}",
+            value, MAX_SPLIT_FACTOR
+        )),
+    }
+}
+
+/// Parses physical dimensions given as `WxH` followed by `in`, `cm` or `mm`, in inches.
+fn parse_print_size(value: &str) -> Result<(f64, f64), String> {
+    let units = [("in", 1.0), ("cm", 2.54), ("mm", 25.4)];
+    let parsed = units.iter().find_map(|(unit, per_inch)| {
+        let (w, h) = value.trim().strip_suffix(unit)?.split_once(['x', 'X'])?;
+        let (w, h) = (w.trim().parse::<f64>().ok()?, h.trim().parse::<f64>().ok()?);
+        Some((w / per_inch, h / per_inch))
+    });
+    match parsed {
+        Some((w, h)) if w > 0.0 && h > 0.0 && w.is_finite() && h.is_finite() => Ok((w, h)),
+        _ => Err(format!(
+            "invalid print size {:?}, expected WxH followed by in, cm or mm",
+            value
+        )),
+    }
+}
+
+/// Parses dimensions given as `WxH`.
+fn parse_dim(value: &str) -> Result<(u32, u32), String> {
+    let parts: Vec<_> = value
+        .split(['x', 'X'])
+        .map(|part| part.trim().parse::<u32>())
+        .collect();
+    match parts.as_slice() {
+        [Ok(w), Ok(h)] if *w > 0 && *h > 0 => Ok((*w, *h)),
+        _ => Err(format!("invalid dimensions {:?}, expected WxH", value)),
+    }
+}
+
+/// Parses an aspect ratio given as `w:h`.
+fn parse_ratio(value: &str) -> Result<(u32, u32), String> {
+    let parts: Vec<_> = value
+        .split(':')
+        .map(|part| part.trim().parse::<u32>())
+        .collect();
+    match parts.as_slice() {
+        [Ok(w), Ok(h)] if *w > 0 && *h > 0 => Ok((*w, *h)),
+        _ => Err(format!("invalid aspect ratio {:?}, expected w:h", value)),
     }
 }
 
-fn files_from_folder(folder_path: &Path) -> impl Iterator<Item = DirEntry> {
-    WalkDir::new(folder_path)
+/// Parses a grout given as `width,RRGGBB`.
+fn parse_grout(value: &str) -> Result<(u32, Rgba<u8>), String> {
+    let mut parts = value.splitn(2, ',');
+    let width = parts.next().unwrap().trim().parse::<u32>();
+    match (width, parts.next()) {
+        (Ok(width), Some(color)) => Ok((width, parse_color(color.trim())?)),
+        _ => Err(format!("invalid grout {:?}, expected width,RRGGBB", value)),
+    }
+}
+
+/// Parses a color given as `RRGGBB`.
+fn parse_color(value: &str) -> Result<Rgba<u8>, String> {
+    let value = match value {
+        "white" => "FFFFFF",
+        "black" => "000000",
+        _ => value.trim_start_matches('#'),
+    };
+    match u32::from_str_radix(value, 16) {
+        Ok(rgb) if value.len() == 6 => {
+            Ok(Rgba([(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8, 255]))
+        }
+        _ => Err(format!(
+            "invalid color {:?}, expected RRGGBB, white or black",
+            value
+        )),
+    }
+}
+
+/// Parses a tone given as `gray` or `duotone:RRGGBB,RRGGBB`, from the darkest color to the
+/// lightest.
+fn parse_tone(value: &str) -> Result<Tone, String> {
+    if value == "gray" {
+        return Ok(Tone::Gray);
+    }
+    let rgb = |color: &str| parse_color(color.trim()).map(|c| [c.data[0], c.data[1], c.data[2]]);
+    match value
+        .strip_prefix("duotone:")
+        .and_then(|v| v.split_once(','))
+    {
+        Some((dark, light)) => Ok(Tone::Duotone(rgb(dark)?, rgb(light)?)),
+        None => Err(format!(
+            "invalid tone {:?}, expected gray or duotone:RRGGBB,RRGGBB",
+            value
+        )),
+    }
+}
+
+/// Files describing the mosaic written next to the output image.
+struct CreateOutputs<'a> {
+    manifest: Option<&'a Path>,
+    manifest_csv: Option<&'a Path>,
+    html: Option<&'a Path>,
+    html_sprite: bool,
+    html_link: bool,
+    /// Grayscale image used as the alpha channel of the mosaic.
+    alpha_mask: Option<&'a Path>,
+    /// Folder receiving a Deep Zoom pyramid of the mosaic instead of `output_image`.
+    dzi: Option<&'a Path>,
+    /// Whether to write `<output_image>.map.json`.
+    save_map: bool,
+    /// Whether to write the mosaic one row of cells at a time, whatever its size.
+    low_memory: bool,
+    /// Number of pixels above which the mosaic isn't rendered, unlimited if `None`.
+    max_output_pixels: Option<u64>,
+    /// Whether to show the mosaic in the terminal and ask before saving it.
+    preview: bool,
+    /// Whether to print the plan of the mosaic rather than render it.
+    dry_run: bool,
+    /// Whether to write the model next to the mosaic to `<output_stem>_comparison.<ext>`.
+    comparison: bool,
+    /// Image whose dark or transparent parts leave the cells out of the mosaic, with the share
+    /// of a cell it must cover for the cell to get a tile.
+    mask: Option<(&'a Path, f32)>,
+    /// Image whose light parts get cells split in four finer tiles, with the share of a cell it
+    /// must cover for the cell to be split.
+    detail_mask: Option<(&'a Path, f32)>,
+    png: PngOptions,
+    /// Number of frames and delay between them, in milliseconds, of an animated GIF of the
+    /// mosaic whose tiles are picked again for each frame.
+    animation: Option<(u32, u32)>,
+    /// Thumbnails shared with the other mosaics of a batch.
+    cache: Option<&'a ThumbnailCache>,
+    /// Time spent in each phase, printed with `--timings`.
+    timings: Option<&'a Timings>,
+}
+
+/// Preprocessed pictures of `create` and `plan`: the thumbnails of `folder`, whose metadata is
+/// read from the database `db` if given, else from `folder`.
+#[derive(Clone, Copy)]
+struct Gallery<'a> {
+    folder: &'a Path,
+    db: Option<&'a Path>,
+}
+
+/// Loads the preprocessed pictures and the model, exiting if they can't be matched. Returns
+/// them with `options` completed by `load_gallery`.
+fn load_inputs(
+    gallery: Gallery,
+    model: &Path,
+    model_options: &ModelOptions,
+    options: &MosaicOptions,
+) -> (ProcessedPictureMetadata, DynamicImage, MosaicOptions) {
+    let (mut metadata, options, db) = load_gallery(gallery, options, true);
+    let model = match load_model(model, &metadata.pictures, model_options, &options) {
+        Ok(img) => img,
+        Err(e) => {
+            error!("{}: {}", model.display(), e);
+            process::exit(1);
+        }
+    };
+    if let Some(mut db) = db {
+        metadata.pictures = load_closest_pictures(&mut db, gallery, [&model], &options);
+    }
+    (metadata, model, options)
+}
+
+/// Reads from `db` only the pictures closest to the chunks of `models`, the ones matching
+/// them with `options` can place, exiting if they can't be read.
+fn load_closest_pictures<'a>(
+    db: &mut TileDb,
+    gallery: Gallery,
+    models: impl IntoIterator<Item = &'a DynamicImage>,
+    options: &MosaicOptions,
+) -> Vec<ProcessedPicture> {
+    let colors: Vec<_> = (models.into_iter())
+        .flat_map(|model| chunk_colors(model, options))
+        .collect();
+    match db.load_closest(&colors) {
+        Ok(pictures) => {
+            info!("{} of {} pictures read", pictures.len(), db.len());
+            pictures
+        }
+        Err(e) => {
+            error!(
+                "can't read the metadata of {}: {}",
+                gallery.db.unwrap_or(gallery.folder).display(),
+                e
+            );
+            process::exit(1);
+        }
+    }
+}
+
+/// Loads the preprocessed pictures, exiting if they can't be matched with `options`. Returns
+/// them with `options`, the colors of the chunks being computed like the ones of the pictures
+/// unless `--chunk-color` says otherwise. If the pictures are in a database and `closest_only`,
+/// matching with `options` only placing the closest ones, none is read but the database is
+/// returned for `load_closest_pictures` to read those of the model.
+fn load_gallery(
+    gallery: Gallery,
+    options: &MosaicOptions,
+    closest_only: bool,
+) -> (ProcessedPictureMetadata, MosaicOptions, Option<TileDb>) {
+    // Only the fields matching and rendering use are kept, for the huge galleries.
+    let histograms = options.match_mode == MatchMode::Histogram;
+    let slim = |pic: &mut ProcessedPicture| {
+        pic.palette = None;
+        pic.phash = None;
+        if !histograms {
+            pic.color_histogram = None;
+        }
+        // Matched by luma like the chunks, the tiles being reduced to it when pasted.
+        if options.tone.is_some() {
+            pic.color_rgb = Tone::Gray.apply(pic.color_rgb);
+        }
+    };
+    // The pictures are compared by the colors the database is indexed with.
+    let closest_only = closest_only
+        && matches_closest_only(options)
+        && options.tone.is_none()
+        && !options.report_colors;
+    let loaded = match gallery.db {
+        Some(db) if closest_only => TileDb::open(db).map(|db| (db.metadata(), Some(db))),
+        Some(db) => TileDb::open(db)
+            .and_then(|db| db.load_metadata_with(slim))
+            .map(|metadata| (metadata, None)),
+        None => mosaic::load_metadata_with(gallery.folder, slim).map(|metadata| (metadata, None)),
+    };
+    let (metadata, db) = match loaded {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            error!(
+                "can't read the metadata of {}: {}",
+                gallery.db.unwrap_or(gallery.folder).display(),
+                e
+            );
+            process::exit(1);
+        }
+    };
+    if db
+        .as_ref()
+        .map_or(metadata.pictures.is_empty(), TileDb::is_empty)
+    {
+        error!("{}", MosaicError::EmptyGallery);
+        process::exit(1);
+    }
+    if options.match_mode == MatchMode::Histogram {
+        let missing = (metadata.pictures.iter())
+            .filter(|pic| pic.color_histogram.is_none())
+            .count();
+        if missing > 0 {
+            error!(
+                "{} of {} pictures have no histogram, run preprocess with --histogram",
+                missing,
+                metadata.pictures.len()
+            );
+            process::exit(1);
+        }
+    }
+    if options.match_variance && metadata.pictures.iter().all(|pic| pic.contrast.is_none()) {
+        error!("no contrast found in metadata, run preprocess again");
+        process::exit(1);
+    }
+    if metadata.linear_light != options.linear_light {
+        if metadata.linear_light {
+            error!("the gallery colors were averaged in linear light, remove --no-linear-light");
+        } else {
+            error!(
+                "the gallery colors were averaged in sRGB, preprocess it again or pass --no-linear-light"
+            );
+        }
+        process::exit(1);
+    }
+    if let Some(expected) = options.expected_contrast_adjustment {
+        if (metadata.contrast_adjustment - expected).abs() > CONTRAST_ADJUSTMENT_TOLERANCE {
+            warn!(
+                "the gallery was preprocessed with a contrast adjustment of {}, not {}",
+                metadata.contrast_adjustment, expected
+            );
+        }
+    }
+    let (tile_w, tile_h) = options.tile_ratio;
+    let ratio = f64::from(tile_w) / f64::from(tile_h);
+    let stretch = ratio.max(1.0 / ratio) - 1.0;
+    if stretch > MAX_TILE_STRETCH {
+        warn!(
+            "the square thumbnails are stretched by {:.0}% to the {}:{} tiles",
+            stretch * 100.0,
+            tile_w,
+            tile_h
+        );
+    }
+    let color_mode_name = |mode| match mode {
+        ColorMode::Mean => "mean",
+        ColorMode::Median => "median",
+        ColorMode::Dominant => "dominant",
+    };
+    let chunk_color = match options.chunk_color {
+        Some(chunk_color) if chunk_color != metadata.color_mode => {
+            warn!(
+                "the gallery colors are the {} ones, not the {} ones of the chunks",
+                color_mode_name(metadata.color_mode),
+                color_mode_name(chunk_color)
+            );
+            chunk_color
+        }
+        Some(chunk_color) => chunk_color,
+        None => metadata.color_mode,
+    };
+    debug!("chunk colors: {}", color_mode_name(chunk_color));
+
+    let options = MosaicOptions {
+        chunk_color: Some(chunk_color),
+        ..options.clone()
+    };
+    (metadata, options, db)
+}
+
+/// Opens `model`, each of its frames if it is an animated GIF, and prepares them to be matched
+/// with `pics`. A still model is a single frame.
+fn load_model_frames(
+    model: &Path,
+    pics: &[ProcessedPicture],
+    model_options: &ModelOptions,
+    options: &MosaicOptions,
+) -> Result<Vec<Frame>, MosaicError> {
+    let frames = match animation::load_animation(model)? {
+        Some(frames) => frames,
+        None => {
+            return Ok(vec![Frame {
+                image: load_model(model, pics, model_options, options)?,
+                delay_ms: 0,
+            }])
+        }
+    };
+    info!("{} frames", frames.len());
+    let frames = frames
         .into_iter()
-        .filter_map(Result::ok)
-        .filter(|entry| entry.file_type().is_file())
+        .map(|frame| {
+            Ok(Frame {
+                image: prepare_model(frame.image, model_options, options.tile_ratio)?,
+                delay_ms: frame.delay_ms,
+            })
+        })
+        .collect::<Result<Vec<_>, MosaicError>>()?;
+    if options.report_colors {
+        print_color_report(&frames[0].image, pics, options);
+    }
+    Ok(frames)
+}
+
+/// Opens `model` and prepares it to be matched with `pics`.
+fn load_model(
+    model: &Path,
+    pics: &[ProcessedPicture],
+    model_options: &ModelOptions,
+    options: &MosaicOptions,
+) -> Result<DynamicImage, MosaicError> {
+    let model = prepare_model(image::open(model)?, model_options, options.tile_ratio)?;
+    if options.report_colors {
+        print_color_report(&model, pics, options);
+    }
+    Ok(model)
+}
+
+/// Exits if `outputs` can't be written, else returns whether the output image can be written
+/// one band at a time.
+fn check_outputs(output_image: &Path, outputs: &CreateOutputs, options: &MosaicOptions) -> bool {
+    // The image crate only decodes WebP, the save would fail once the mosaic is rendered.
+    if outputs.dzi.is_none()
+        && output_image
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("webp"))
+    {
+        error!("WebP output isn't supported, save the mosaic as PNG or JPEG");
+        process::exit(1);
+    }
+    let is_png = output_image
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("png"));
+    let tagged = outputs.png.sixteen_bit || outputs.png.dpi.is_some();
+    if tagged && (!is_png || outputs.dzi.is_some()) {
+        error!("--bit-depth 16, --dpi and --print-size need a PNG output image");
+        process::exit(1);
+    }
+    let profiled = is_png || has_extension(output_image, &["jpg", "jpeg"]);
+    if outputs.png.color_space != ColorSpace::Srgb && (!profiled || outputs.dzi.is_some()) {
+        error!("--output-colorspace adobe-rgb needs a PNG or JPEG output image");
+        process::exit(1);
+    }
+    if outputs.animation.is_some() {
+        let is_gif = output_image
+            .extension()
+            .is_some_and(|ext| ext.eq_ignore_ascii_case("gif"));
+        if !is_gif {
+            error!("--animate-frames needs a GIF output image");
+            process::exit(1);
+        }
+        if outputs.animation.is_some_and(|(frames, _)| frames == 0) {
+            error!("--animate-frames needs at least one frame");
+            process::exit(1);
+        }
+        if options.randomize_top_k < 2 {
+            error!(
+                "--animate-frames needs --randomize-top-k 2 or more, the frames would be the same"
+            );
+            process::exit(1);
+        }
+    }
+    if outputs
+        .mask
+        .is_some_and(|(_, threshold)| !(0.0..=1.0).contains(&threshold))
+    {
+        error!("--mask-threshold must be between 0 and 1");
+        process::exit(1);
+    }
+    if outputs
+        .detail_mask
+        .is_some_and(|(_, threshold)| !(0.0..=1.0).contains(&threshold))
+    {
+        error!("--detail-threshold must be between 0 and 1");
+        process::exit(1);
+    }
+    let uneven = outputs.detail_mask.is_some()
+        || !options.regions_of_interest.is_empty()
+        || options.adaptive.is_some();
+    if uneven && outputs.html.is_some() && !outputs.html_sprite {
+        error!(
+            "--html shows a picture per cell, pass --html-sprite with --detail-mask, --roi or --adaptive"
+        );
+        process::exit(1);
+    }
+    if options.layout != Layout::Grid {
+        if let Err(e) = check_layout_outputs(outputs, options, options.layout) {
+            error!("{}", e);
+            process::exit(1);
+        }
+    }
+    if outputs.dzi.is_some() && outputs.mask.is_some() && options.mask_fill == MaskFill::Model {
+        error!("--dzi can't fill the masked cells with the model, pass --mask-fill transparent");
+        process::exit(1);
+    }
+    if outputs.comparison && outputs.animation.is_some() {
+        error!("--comparison needs a still mosaic, it can't be used with --animate-frames");
+        process::exit(1);
+    }
+    if outputs.alpha_mask.is_some() && !is_png {
+        error!("--alpha-mask needs a PNG output image to keep the transparency");
+        process::exit(1);
+    }
+    let can_stream = is_png
+        && options.ghost == 0.0
+        && options.feather_edges == 0
+        && outputs.alpha_mask.is_none()
+        && !outputs.html_sprite
+        && !outputs.preview
+        && !outputs.comparison
+        && !(outputs.mask.is_some() && options.mask_fill == MaskFill::Model);
+    if outputs.low_memory && !can_stream {
+        error!(
+            "--low-memory needs a PNG output image and can't be used with --ghost or --feather-edges"
+        );
+        process::exit(1);
+    }
+    can_stream
+}
+
+/// Checks that the outputs and the rendering options needing the cells of a grid aren't used
+/// with the hexagons of `--layout hex` or the shifted rows of `--layout brick`.
+fn check_layout_outputs(
+    outputs: &CreateOutputs,
+    options: &MosaicOptions,
+    layout: Layout,
+) -> Result<(), String> {
+    let rectangular = [
+        ("--mask", outputs.mask.is_some()),
+        ("--detail-mask", outputs.detail_mask.is_some()),
+        (
+            "--html without --html-sprite",
+            outputs.html.is_some() && !outputs.html_sprite,
+        ),
+        ("--grout", options.grout.is_some()),
+        ("--feather-edges", options.feather_edges > 0),
+    ];
+    let layout = match layout {
+        Layout::Hex => "hex",
+        _ => "brick",
+    };
+    match rectangular.iter().find(|(_, given)| *given) {
+        Some((name, _)) => Err(format!(
+            "{} works on the cells of a grid, it can't be used with --layout {}",
+            name, layout
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Whether `--augment` lists `transform`.
+fn is_augmented(matches: &ArgMatches, transform: &str) -> bool {
+    matches
+        .values_of("augment")
+        .is_some_and(|mut values| values.any(|value| value == transform))
+}
+
+/// Whether the extension of `path` is one of `extensions`, whatever its case.
+fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .is_some_and(|ext| extensions.iter().any(|e| ext.eq_ignore_ascii_case(e)))
+}
+
+/// Adds the time since `start` to `phase` of the `--timings`, if asked.
+fn record_phase(outputs: &CreateOutputs, phase: &'static str, start: Instant) {
+    if let Some(timings) = outputs.timings {
+        timings.add(phase, start.elapsed());
+    }
+}
+
+/// Whether the output image is written one band at a time, because it was asked or because
+/// it is too large.
+fn is_streamed(
+    placement: &Placement,
+    outputs: &CreateOutputs,
+    options: &MosaicOptions,
+    can_stream: bool,
+) -> bool {
+    let (w, h) = placement.dimensions(options);
+    can_stream && (outputs.low_memory || u64::from(w) * u64::from(h) * 4 > LOW_MEMORY_THRESHOLD)
+}
+
+fn cmd_create(
+    gallery: Gallery,
+    model: &Path,
+    output_image: &Path,
+    outputs: &CreateOutputs,
+    model_options: &ModelOptions,
+    options: &MosaicOptions,
+) {
+    let can_stream = check_outputs(output_image, outputs, options);
+    let start = Instant::now();
+    // The cells split by the detail mask are matched with the whole gallery.
+    let closest_only = outputs.detail_mask.is_none();
+    let (mut metadata, options, db) = load_gallery(gallery, options, closest_only);
+    let options = &options;
+    let frames = match load_model_frames(model, &metadata.pictures, model_options, options) {
+        Ok(frames) => frames,
+        Err(e) => {
+            error!("{}: {}", model.display(), e);
+            process::exit(1);
+        }
+    };
+    if let Some(mut db) = db {
+        let models = frames.iter().map(|frame| &frame.image);
+        metadata.pictures = load_closest_pictures(&mut db, gallery, models, options);
+    }
+    record_phase(outputs, "decode", start);
+    info!("{} pictures available", metadata.pictures.len());
+    if let Err(e) = create_from_model(
+        gallery.folder,
+        &metadata.pictures,
+        &frames,
+        output_image,
+        outputs,
+        options,
+        can_stream,
+    ) {
+        error!("{}", e);
+        process::exit(1);
+    }
+}
+
+/// Model paths given to `create`, the files of a folder being taken in name order.
+fn expand_models(paths: &[&str]) -> Vec<PathBuf> {
+    let mut models = Vec::new();
+    for path in paths.iter().map(Path::new) {
+        if !path.is_dir() {
+            models.push(path.to_owned());
+            continue;
+        }
+        let entries = match fs::read_dir(path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                error!("can't read {}: {}", path.display(), e);
+                process::exit(1);
+            }
+        };
+        let mut files: Vec<PathBuf> = entries
+            .filter_map(Result::ok)
+            .filter(|entry| entry.file_type().is_ok_and(|t| t.is_file()))
+            .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+            .map(|entry| entry.path())
+            .collect();
+        files.sort();
+        models.extend(files);
+    }
+    models
+}
+
+/// Creates the mosaic of each of `models` in `output_folder`, named after `template` where
+/// `{model_stem}` is the file name of the model without its extension. The gallery is loaded
+/// once and its thumbnails decoded once for all the mosaics. A model that fails is reported
+/// and the next ones created anyway. Returns the paths of the mosaics created.
+fn cmd_create_batch(
+    gallery: Gallery,
+    models: &[PathBuf],
+    output_folder: &Path,
+    template: &str,
+    outputs: &CreateOutputs,
+    model_options: &ModelOptions,
+    options: &MosaicOptions,
+) -> Vec<PathBuf> {
+    let single_files = [
+        ("--manifest", outputs.manifest.is_some()),
+        ("--manifest-csv", outputs.manifest_csv.is_some()),
+        ("--html", outputs.html.is_some()),
+        ("--dzi", outputs.dzi.is_some()),
+        ("--preview", outputs.preview),
+    ];
+    if let Some((name, _)) = single_files.iter().find(|(_, given)| *given) {
+        error!(
+            "{} writes a single mosaic, it can't be used with several models",
+            name
+        );
+        process::exit(1);
+    }
+    if models.is_empty() {
+        error!("no model found");
+        process::exit(1);
+    }
+
+    let output_images: Vec<PathBuf> = models
+        .iter()
+        .map(|model| {
+            let stem = model.file_stem().unwrap_or_default().to_string_lossy();
+            output_folder.join(template.replace("{model_stem}", &stem))
+        })
+        .collect();
+    for (i, output_image) in output_images.iter().enumerate() {
+        if let Some(j) = output_images[..i].iter().position(|o| o == output_image) {
+            error!(
+                "the mosaics of {} and {} would both be saved to {}, name them apart with {{model_stem}} in --output-template",
+                models[j].display(),
+                models[i].display(),
+                output_image.display()
+            );
+            process::exit(1);
+        }
+    }
+    // The outputs all have the extension of the template.
+    let can_stream = check_outputs(&output_images[0], outputs, options);
+    if !outputs.dry_run {
+        if let Err(e) = fs::create_dir_all(output_folder) {
+            error!("can't create {}: {}", output_folder.display(), e);
+            process::exit(1);
+        }
+    }
+
+    let start = Instant::now();
+    let closest_only = outputs.detail_mask.is_none();
+    let (metadata, options, mut db) = load_gallery(gallery, options, closest_only);
+    let options = &options;
+    record_phase(outputs, "decode", start);
+    info!("{} pictures available", metadata.pictures.len());
+    let cache = ThumbnailCache::new();
+    let outputs = CreateOutputs {
+        cache: Some(&cache),
+        ..*outputs
+    };
+
+    let mut created = Vec::new();
+    let mut timings = Vec::new();
+    for (i, (model, output_image)) in models.iter().zip(&output_images).enumerate() {
+        info!("[{}/{}] {}", i + 1, models.len(), model.display());
+        let start = Instant::now();
+        let res = load_model_frames(model, &metadata.pictures, model_options, options).and_then(
+            |frames| {
+                record_phase(&outputs, "decode", start);
+                let closest;
+                let pics = match &mut db {
+                    Some(db) => {
+                        let models = frames.iter().map(|frame| &frame.image);
+                        closest = load_closest_pictures(db, gallery, models, options);
+                        &closest
+                    }
+                    None => &metadata.pictures,
+                };
+                create_from_model(
+                    gallery.folder,
+                    pics,
+                    &frames,
+                    output_image,
+                    &outputs,
+                    options,
+                    can_stream,
+                )
+            },
+        );
+        match res {
+            Ok(()) => {
+                timings.push((model, Some(start.elapsed())));
+                created.push(output_image.clone());
+            }
+            Err(e) => {
+                error!("{}: {}", model.display(), e);
+                timings.push((model, None));
+            }
+        }
+    }
+
+    let failed = timings.iter().filter(|t| t.1.is_none()).count();
+    for (model, elapsed) in &timings {
+        match elapsed {
+            Some(elapsed) => info!("{:<30} {:>8.2} s", model.display(), elapsed.as_secs_f32()),
+            None => info!("{:<30} {:>10}", model.display(), "failed"),
+        }
+    }
+    info!(
+        "{} mosaics created, {} failed, {} thumbnails decoded",
+        timings.len() - failed,
+        failed,
+        cache.len()
+    );
+    created
+}
+
+/// Checks that the gallery has enough pictures to fill the cells of the grid of `model` left
+/// out by none of `masked` with each used at most `options.max_uses` times, rather than
+/// repeating them anyway.
+fn check_gallery_size(
+    model: &DynamicImage,
+    pics: &[ProcessedPicture],
+    masked: &[bool],
+    options: &MosaicOptions,
+) -> Result<(), String> {
+    let max_uses = match options.max_uses {
+        Some(max_uses) => max_uses,
+        None => return Ok(()),
+    };
+    let (grid_w, grid_h) = grid_size(model, options.tile_ratio, options.layout);
+    let cells = grid_w * grid_h - masked.iter().filter(|&&masked| masked).count();
+    let needed = cells.div_ceil(max_uses as usize);
+    if pics.len() >= needed {
+        return Ok(());
+    }
+    let limit = if max_uses == 1 {
+        "--no-repeat".to_string()
+    } else {
+        format!("--max-uses {}", max_uses)
+    };
+    Err(format!(
+        "need at least {} pictures for {} at this {}x{} grid, the gallery has {}; \
+         raise --max-uses or shrink the grid with --resize-model-auto {}",
+        needed,
+        limit,
+        grid_w,
+        grid_h,
+        pics.len(),
+        pics.len() * max_uses as usize
+    ))
+}
+
+/// Matches the tiles of the frames of a model, prepared by `load_model_frames`, then writes
+/// its mosaic, animated if it has several frames, or prints its plan with `outputs.dry_run`.
+fn create_from_model(
+    preprocessed_folder: &Path,
+    pics: &[ProcessedPicture],
+    frames: &[Frame],
+    output_image: &Path,
+    outputs: &CreateOutputs,
+    options: &MosaicOptions,
+    can_stream: bool,
+) -> Result<(), MosaicError> {
+    if frames.len() > 1 {
+        check_animated_outputs(output_image, outputs)?;
+    }
+    let model = &frames[0].image;
+    let mask = match outputs.mask {
+        Some((path, threshold)) => Some((image::open(path)?, threshold)),
+        None => None,
+    };
+    // Known before matching, so that the masked cells use up none of the pictures.
+    let masked = match &mask {
+        Some((mask, threshold)) => {
+            let grid = grid_size(model, options.tile_ratio, options.layout);
+            masked_cells(mask, grid, *threshold)
+        }
+        None => Vec::new(),
+    };
+    check_gallery_size(model, pics, &masked, options)?;
+    let start = Instant::now();
+    let mut placement = match_unmasked_tiles(model, pics, options.tile_ratio, options, &masked)?;
+    if let Some((mask, threshold)) = &mask {
+        let masked = mask_tiles(&mut placement, mask, *threshold);
+        info!(
+            "{} of {} cells masked",
+            masked,
+            placement.grid_width * placement.grid_height
+        );
+    }
+    if let Some((path, threshold)) = outputs.detail_mask {
+        let mask = image::open(path)?;
+        let split = subdivide_tiles(&mut placement, model, pics, &mask, threshold, options)?;
+        info!("{} cells subdivided", split);
+    }
+    record_phase(outputs, "match", start);
+    if let Some(timings) = outputs.timings {
+        // Each frame of an animation is matched again.
+        let mosaics = frames.len() * outputs.animation.map_or(1, |(n, _)| n as usize);
+        timings.add_items(placement.grid_width * placement.grid_height * mosaics);
+    }
+    if outputs.dry_run {
+        let streaming = frames.len() == 1 && is_streamed(&placement, outputs, options, can_stream);
+        print_plan(&placement, options, output_image, streaming);
+        if frames.len() > 1 {
+            info!("{} frames, each matched like the first", frames.len());
+        }
+        return Ok(());
+    }
+    if frames.len() > 1 {
+        check_output_pixels(&placement, outputs, options)?;
+        let start = Instant::now();
+        let res = write_animated_model(
+            preprocessed_folder,
+            frames,
+            pics,
+            placement,
+            output_image,
+            outputs.cache,
+            options,
+        );
+        record_phase(outputs, "animation", start);
+        return res;
+    }
+    if let Some((frames, delay_ms)) = outputs.animation {
+        check_output_pixels(&placement, outputs, options)?;
+        let start = Instant::now();
+        let res = write_animation(
+            preprocessed_folder,
+            model,
+            pics,
+            output_image,
+            (frames, delay_ms),
+            outputs.cache,
+            options,
+        );
+        record_phase(outputs, "animation", start);
+        return res;
+    }
+
+    write_outputs(
+        preprocessed_folder,
+        Some(model),
+        &placement,
+        output_image,
+        outputs,
+        options,
+        can_stream,
+    )
+}
+
+/// Refuses the outputs an animated model can't have, its mosaic being an animated GIF.
+fn check_animated_outputs(output_image: &Path, outputs: &CreateOutputs) -> Result<(), String> {
+    let is_gif = output_image
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("gif"));
+    if !is_gif {
+        return Err("the model is an animated GIF, its mosaic needs a GIF output image".into());
+    }
+    let single_images = [
+        ("--animate-frames", outputs.animation.is_some()),
+        ("--manifest", outputs.manifest.is_some()),
+        ("--manifest-csv", outputs.manifest_csv.is_some()),
+        ("--html", outputs.html.is_some()),
+        ("--save-map", outputs.save_map),
+        ("--alpha-mask", outputs.alpha_mask.is_some()),
+        ("--dzi", outputs.dzi.is_some()),
+        ("--low-memory", outputs.low_memory),
+        ("--preview", outputs.preview),
+        ("--comparison", outputs.comparison),
+        ("--mask", outputs.mask.is_some()),
+        ("--detail-mask", outputs.detail_mask.is_some()),
+    ];
+    match single_images.iter().find(|(_, given)| *given) {
+        Some((name, _)) => Err(format!(
+            "{} is for a still mosaic, it can't be used with an animated GIF model",
+            name
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Writes an animated GIF of the mosaics of the frames of an animated model, each shown as
+/// long as its frame. The tiles of each frame are stabilized against the ones of the frame
+/// before, the first frame being matched as `first`.
+fn write_animated_model<'a>(
+    preprocessed_folder: &Path,
+    frames: &[Frame],
+    pics: &'a [ProcessedPicture],
+    first: Placement<'a>,
+    output_image: &Path,
+    cache: Option<&ThumbnailCache>,
+    options: &MosaicOptions,
+) -> Result<(), MosaicError> {
+    let mut previous: Option<Placement<'a>> = None;
+    let mut first = Some(first);
+    let mosaics = frames.iter().enumerate().map(|(i, frame)| {
+        info!("frame {}/{}", i + 1, frames.len());
+        let mut placement = match first.take() {
+            Some(first) => first,
+            None => match_tiles(&frame.image, pics, options.tile_ratio, options)?,
+        };
+        if let Some(previous) = &previous {
+            stabilize_tiles(&mut placement, previous, options);
+        }
+        let mosaic = render_mosaic(
+            Some(&frame.image),
+            preprocessed_folder,
+            &placement,
+            options,
+            cache,
+            &CliProgress::default(),
+        )?;
+        previous = Some(placement);
+        Ok((mosaic, frame.delay_ms))
+    });
+    save_gif(mosaics, output_image)
 }
 
-fn image_square_view(img: &DynamicImage) -> SubImage<&DynamicImage> {
-    let (w, h) = img.dimensions();
-    let square_size = cmp::min(w, h);
+/// Writes an animated GIF of `frames` mosaics of `model`, each picking its tiles among the
+/// closest pictures with its own seed so that the photos change from one frame to the next.
+fn write_animation(
+    preprocessed_folder: &Path,
+    model: &DynamicImage,
+    pics: &[ProcessedPicture],
+    output_image: &Path,
+    (frames, delay_ms): (u32, u32),
+    cache: Option<&ThumbnailCache>,
+    options: &MosaicOptions,
+) -> Result<(), MosaicError> {
+    let mosaics = (0..frames).map(|frame| {
+        info!("frame {}/{}", frame + 1, frames);
+        let mut frame_options = options.clone();
+        frame_options.seed = options.seed.map(|seed| seed.wrapping_add(u64::from(frame)));
+        create_mosaic(
+            model,
+            preprocessed_folder,
+            pics,
+            &frame_options,
+            cache,
+            &CliProgress::default(),
+        )
+        .map(|mosaic| (mosaic, delay_ms))
+    });
+    save_gif(mosaics, output_image)
+}
+
+/// Matches the tiles like `create` but only saves their placement, for `render`.
+fn cmd_plan(
+    gallery: Gallery,
+    model: &Path,
+    plan_path: &Path,
+    model_options: &ModelOptions,
+    options: &MosaicOptions,
+) {
+    let (metadata, model, options) = load_inputs(gallery, model, model_options, options);
+    let options = &options;
+    info!("{} pictures available", metadata.pictures.len());
+    if let Err(e) = check_gallery_size(&model, &metadata.pictures, &[], options) {
+        error!("{}", e);
+        process::exit(1);
+    }
+    let placement = match match_tiles(&model, &metadata.pictures, options.tile_ratio, options) {
+        Ok(placement) => placement,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+    if let Err(e) = placement.to_plan().save(plan_path) {
+        error!("can't write {}: {}", plan_path.display(), e);
+        process::exit(1);
+    }
+}
+
+/// Renders the placement saved by `plan`, with tiles of `tile_size` pixels.
+fn cmd_render(
+    plan_path: &Path,
+    preprocessed_folder: &Path,
+    output_image: &Path,
+    model: Option<&Path>,
+    tile_size: u32,
+    outputs: &CreateOutputs,
+    options: &MosaicOptions,
+) {
+    if options.ghost > 0.0 && model.is_none() {
+        error!("--ghost needs the model given with --model");
+        process::exit(1);
+    }
+    if outputs.comparison && model.is_none() {
+        error!("--comparison needs the model given with --model");
+        process::exit(1);
+    }
+    let can_stream = check_outputs(output_image, outputs, options);
+
+    let plan = match Plan::load(plan_path) {
+        Ok(plan) => plan,
+        Err(e) => {
+            error!("can't read {}: {}", plan_path.display(), e);
+            process::exit(1);
+        }
+    };
+    let metadata = match mosaic::load_metadata(preprocessed_folder) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            error!(
+                "can't read the metadata of {}: {}",
+                preprocessed_folder.display(),
+                e
+            );
+            process::exit(1);
+        }
+    };
+    let placement = match Placement::from_plan(&plan, &metadata.pictures, tile_size) {
+        Ok(placement) => placement,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+    if placement.layout != Layout::Grid {
+        if let Err(e) = check_layout_outputs(outputs, options, placement.layout) {
+            error!("{}: {}", plan_path.display(), e);
+            process::exit(1);
+        }
+    }
+    let model = model.map(|path| match image::open(path) {
+        Ok(model) => model,
+        Err(e) => {
+            error!("can't read {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    });
+
+    if let Err(e) = write_outputs(
+        preprocessed_folder,
+        model.as_ref(),
+        &placement,
+        output_image,
+        outputs,
+        options,
+        can_stream,
+    ) {
+        error!("{}", e);
+        process::exit(1);
+    }
+}
+
+/// Exits if the mosaic of `placement` has more pixels than allowed by `outputs`.
+fn check_output_pixels(
+    placement: &Placement,
+    outputs: &CreateOutputs,
+    options: &MosaicOptions,
+) -> Result<(), String> {
+    let (w, h) = placement.dimensions(options);
+    let pixels = u64::from(w) * u64::from(h);
+    match outputs.max_output_pixels.filter(|&max| pixels > max) {
+        Some(max_pixels) => Err(format!(
+            "the mosaic would be {}x{} px, {} pixels, more than the limit of {}, pass --force to render it anyway",
+            w, h, pixels, max_pixels
+        )),
+        None => Ok(()),
+    }
+}
+
+/// Renders `placement` to `output_image`, along with the files describing it.
+fn write_outputs(
+    preprocessed_folder: &Path,
+    model: Option<&DynamicImage>,
+    placement: &Placement,
+    output_image: &Path,
+    outputs: &CreateOutputs,
+    options: &MosaicOptions,
+    can_stream: bool,
+) -> Result<(), MosaicError> {
+    check_output_pixels(placement, outputs, options)?;
+
+    let progress = CliProgress::default();
+    let manifest = build_manifest(placement, options);
+    if let Some(dir) = outputs.dzi {
+        save_manifests(&manifest, placement, output_image, outputs)?;
+        let (w, h) = placement.dimensions(options);
+        let start = Instant::now();
+        let res = dzi::write_dzi(dir, w, h, |y, band_h| {
+            render_band(
+                preprocessed_folder,
+                placement,
+                options,
+                y,
+                band_h,
+                None,
+                &progress,
+            )
+        });
+        record_phase(outputs, "bands", start);
+        return res;
+    }
+
+    let start = Instant::now();
+    if is_streamed(placement, outputs, options, can_stream) {
+        save_manifests(&manifest, placement, output_image, outputs)?;
+        write_mosaic_in_bands(
+            output_image,
+            preprocessed_folder,
+            placement,
+            options,
+            &outputs.png,
+            &progress,
+        )?;
+        debug!(
+            "rendered and written in bands in {} ms",
+            start.elapsed().as_millis()
+        );
+        record_phase(outputs, "bands", start);
+        if let Some(dir) = outputs.html {
+            html::write_tiles_page(
+                dir,
+                &manifest,
+                preprocessed_folder,
+                placement.dimensions(options),
+                outputs.html_link,
+            )?;
+        }
+        return Ok(());
+    }
+
+    // Rendered in 16 bits for the blends to keep their precision in the 16-bit PNG.
+    match outputs.png.sixteen_bit {
+        true => write_rendered::<u16>(
+            preprocessed_folder,
+            model,
+            placement,
+            &manifest,
+            output_image,
+            outputs,
+            options,
+        ),
+        false => write_rendered::<u8>(
+            preprocessed_folder,
+            model,
+            placement,
+            &manifest,
+            output_image,
+            outputs,
+            options,
+        ),
+    }
+}
+
+/// Renders `placement` in memory in samples of `S`, and writes it to `output_image` along
+/// with the files describing it.
+fn write_rendered<S: Sample>(
+    preprocessed_folder: &Path,
+    model: Option<&DynamicImage>,
+    placement: &Placement,
+    manifest: &Manifest,
+    output_image: &Path,
+    outputs: &CreateOutputs,
+    options: &MosaicOptions,
+) -> Result<(), MosaicError> {
+    let start = Instant::now();
+    let mut mosaic = render_mosaic::<S>(
+        model,
+        preprocessed_folder,
+        placement,
+        options,
+        outputs.cache,
+        &CliProgress::default(),
+    )?;
+    debug!("rendered in {} ms", start.elapsed().as_millis());
+    record_phase(outputs, "composite", start);
+    if let Some(path) = outputs.alpha_mask {
+        apply_alpha_mask(&mut mosaic, &image::open(path)?);
+    }
+    if outputs.preview {
+        preview::print_preview(&S::narrow(&mosaic), PREVIEW_COLUMNS)?;
+        if !confirm("save the mosaic?") {
+            info!("the mosaic wasn't saved");
+            return Ok(());
+        }
+    }
+    save_manifests(manifest, placement, output_image, outputs)?;
+    let start = Instant::now();
+    if has_extension(output_image, &["png"]) {
+        save_png(&mosaic, output_image, &outputs.png)?;
+    } else if has_extension(output_image, &["jpg", "jpeg"]) {
+        save_jpeg(&S::narrow(&mosaic), output_image, outputs.png.color_space)?;
+    } else {
+        S::narrow(&mosaic).save(output_image)?;
+    }
+    debug!(
+        "{}: encoded in {} ms",
+        output_image.display(),
+        start.elapsed().as_millis()
+    );
+    record_phase(outputs, "encode", start);
+    if let Some(model) = model.filter(|_| outputs.comparison) {
+        let path = comparison_path(output_image);
+        let mosaic = S::narrow(&mosaic);
+        comparison(model, &mosaic, COMPARISON_GAP, Rgba([255, 255, 255, 255])).save(&path)?;
+        info!("comparison saved to {}", path.display());
+    }
+
+    if let Some(dir) = outputs.html {
+        if outputs.html_sprite {
+            html::write_sprite_page(dir, manifest, &S::narrow(&mosaic), outputs.html_link)?;
+        } else {
+            html::write_tiles_page(
+                dir,
+                manifest,
+                preprocessed_folder,
+                placement.dimensions(options),
+                outputs.html_link,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// `<output_stem>_comparison.<ext>` next to `output_image`.
+fn comparison_path(output_image: &Path) -> PathBuf {
+    let mut name = output_image.file_stem().unwrap_or_default().to_owned();
+    name.push("_comparison");
+    let mut path = output_image.with_file_name(name);
+    if let Some(ext) = output_image.extension() {
+        path.set_extension(ext);
+    }
+    path
+}
+
+/// Writes the files listing the tiles of the mosaic asked in `outputs`.
+fn save_manifests(
+    manifest: &Manifest,
+    placement: &Placement,
+    output_image: &Path,
+    outputs: &CreateOutputs,
+) -> Result<(), MosaicError> {
+    if let Some(path) = outputs.manifest {
+        if path == Path::new("-") {
+            manifest.write_json(io::stdout().lock())?;
+        } else {
+            manifest.save_json(path)?;
+        }
+    }
+    if let Some(path) = outputs.manifest_csv {
+        if path == Path::new("-") {
+            manifest.write_csv(io::stdout().lock())?;
+        } else {
+            manifest.save_csv(path)?;
+        }
+    }
+    if outputs.save_map {
+        let mut map_path = output_image.as_os_str().to_owned();
+        map_path.push(".map.json");
+        manifest::save_tile_map(&build_tile_map(placement), Path::new(&map_path))?;
+    }
+    Ok(())
+}
+
+/// Logs the picture being preprocessed, and shows how many tiles are rendered every percent,
+/// unless quiet or tracing the tiles one per line.
+#[derive(Default)]
+struct CliProgress {
+    tiles_done: AtomicUsize,
+}
+
+impl Progress for CliProgress {
+    fn on_file(&self, i: usize, total: usize, path: &Path) {
+        debug!("[{}/{}] {}", i, total, path.display());
+    }
+
+    fn on_tile(&self, _i: usize, total: usize) {
+        if !log::enabled(Level::Info) || log::enabled(Level::Trace) {
+            return;
+        }
+        let done = self.tiles_done.fetch_add(1, Ordering::Relaxed) + 1;
+        if done.is_multiple_of(cmp::max(total / 100, 1)) || done == total {
+            eprint!("\rrendered {}/{} tiles", done, total);
+        }
+        if done == total {
+            eprintln!();
+        }
+    }
+}
+
+/// Asks `question` on the terminal, returning whether it was answered yes.
+fn confirm(question: &str) -> bool {
+    // On stderr, so that stdout only has the outputs asked to be written there.
+    eprint!("{} [y/N] ", question);
+    let mut answer = String::new();
+    if let Err(e) = io::stderr()
+        .flush()
+        .and_then(|_| io::stdin().read_line(&mut answer))
+    {
+        error!("can't read the answer: {}", e);
+        process::exit(1);
+    }
+    let answer = answer.trim();
+    answer.eq_ignore_ascii_case("y") || answer.eq_ignore_ascii_case("yes")
+}
+
+/// Copies to `output_folder` the gallery pictures the thumbnails of a tile map were made from.
+fn cmd_export(map_path: &Path, gallery_folder: &Path, output_folder: &Path) {
+    let map = match manifest::load_tile_map(map_path) {
+        Ok(map) => map,
+        Err(e) => {
+            error!("can't read {}: {}", map_path.display(), e);
+            process::exit(1);
+        }
+    };
+    let mut tile_paths: Vec<_> = map
+        .iter()
+        .flatten()
+        .map(|cell| cell.path.as_str())
+        .collect();
+    tile_paths.sort_unstable();
+    tile_paths.dedup();
+
+    // Thumbnails are named after the file name of their picture, possibly with another
+    // extension, so the pictures are looked up by file stem.
+    let hidden = Cell::new(0);
+    let mut gallery: Vec<_> = files_from_folder(
+        gallery_folder,
+        &FileFilter::default(),
+        &WalkOptions::default(),
+        &hidden,
+    )
+    .map(|entry| entry.into_path())
+    .collect();
+    gallery.sort();
+
+    fs::create_dir_all(output_folder).unwrap();
+    let mut used_names = HashSet::new();
+    let mut copied = 0;
+    for tile_path in tile_paths {
+        let stem = Path::new(tile_path).file_stem();
+        let sources: Vec<_> = gallery
+            .iter()
+            .filter(|path| path.file_stem() == stem)
+            .collect();
+        if sources.is_empty() {
+            warn!("no picture found in the gallery for {}", tile_path);
+            continue;
+        }
+
+        for source in sources {
+            let dest = unique_destination(output_folder, source, &mut used_names);
+            fs::copy(source, &dest).unwrap();
+            copied += 1;
+        }
+    }
+    info!("{} pictures copied to {}", copied, output_folder.display());
+}
+
+/// Saves to `output_image` a contact sheet of the images at `paths`, `columns` per row.
+fn cmd_combine(
+    paths: &[&Path],
+    output_image: &Path,
+    columns: usize,
+    padding: u32,
+    background: Rgba<u8>,
+) {
+    let mut images = Vec::with_capacity(paths.len());
+    for path in paths {
+        match image::open(path) {
+            Ok(img) => images.push(img),
+            Err(e) => {
+                error!("can't read {}: {}", path.display(), e);
+                process::exit(1);
+            }
+        }
+    }
+    contact_sheet(&images, columns, padding, background)
+        .save(output_image)
+        .unwrap();
+}
+
+/// Prints the pictures added to, removed from and changed in the gallery between the metadata
+/// files `old` and `new`. Exits with 1 if they differ, like diff.
+fn cmd_diff(old: &Path, new: &Path, threshold: u32, json: bool) {
+    let load = |path: &Path| match mosaic::load_metadata_file(path) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            error!("can't read {}: {}", path.display(), e);
+            process::exit(2);
+        }
+    };
+    let diff = diff_metadata(&load(old), &load(new), threshold);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&diff).unwrap());
+    } else {
+        for path in &diff.added {
+            println!("+ {}", path);
+        }
+        for path in &diff.removed {
+            println!("- {}", path);
+        }
+        for pic in &diff.changed {
+            let ([r1, g1, b1], [r2, g2, b2]) = (pic.old_color, pic.new_color);
+            println!(
+                "~ {} ({},{},{}) -> ({},{},{}), distance {}",
+                pic.path, r1, g1, b1, r2, g2, b2, pic.distance
+            );
+        }
+        println!(
+            "{} added, {} removed, {} changed",
+            diff.added.len(),
+            diff.removed.len(),
+            diff.changed.len()
+        );
+    }
+    if !diff.is_empty() {
+        process::exit(1);
+    }
+}
+
+/// Removes the pictures of the preprocessed gallery whose color is within `max_distance` of
+/// another one, printing them.
+fn cmd_dedupe(preprocessed_folder: &Path, max_distance: u32, dry_run: bool) {
+    let total = match mosaic::load_metadata(preprocessed_folder) {
+        Ok(metadata) => metadata.pictures.len(),
+        Err(e) => {
+            error!(
+                "can't read the metadata of {}: {}",
+                preprocessed_folder.display(),
+                e
+            );
+            process::exit(1);
+        }
+    };
+    let duplicates = match dedupe_gallery(preprocessed_folder, max_distance, dry_run) {
+        Ok(duplicates) => duplicates,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+    let verb = if dry_run { "would remove" } else { "removed" };
+    for duplicate in &duplicates {
+        println!(
+            "{} {}, close to {} (distance {})",
+            verb, duplicate.path, duplicate.kept, duplicate.distance
+        );
+    }
+    println!("{} {} of {} pictures", verb, duplicates.len(), total);
+}
+
+/// Checks the thumbnails of the preprocessed gallery against its metadata, printing the ones
+/// that don't match, and exits with 1 if there are some.
+fn cmd_verify(preprocessed_folder: &Path, tolerance: u32) {
+    let total = match mosaic::load_metadata(preprocessed_folder) {
+        Ok(metadata) => metadata.pictures.len(),
+        Err(e) => {
+            error!(
+                "can't read the metadata of {}: {}",
+                preprocessed_folder.display(),
+                e
+            );
+            process::exit(1);
+        }
+    };
+    let bad = match verify_gallery(preprocessed_folder, tolerance) {
+        Ok(bad) => bad,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+    for thumbnail in &bad {
+        println!("{}: {}", thumbnail.path, thumbnail.problem);
+    }
+    if bad.is_empty() {
+        println!("the {} thumbnails match the metadata", total);
+    } else {
+        println!(
+            "{} of {} thumbnails don't match the metadata",
+            bad.len(),
+            total
+        );
+        process::exit(1);
+    }
+}
+
+/// Path in `output_folder` named after `source`, suffixed with a number if the name is
+/// already taken by another exported picture.
+fn unique_destination(
+    output_folder: &Path,
+    source: &Path,
+    used_names: &mut HashSet<PathBuf>,
+) -> PathBuf {
+    let file_name = PathBuf::from(source.file_name().unwrap());
+    let mut name = file_name.clone();
+    let mut i = 1;
+    while !used_names.insert(name.clone()) {
+        let mut numbered = source.file_stem().unwrap().to_owned();
+        numbered.push(format!("_{}", i));
+        name = PathBuf::from(numbered);
+        if let Some(ext) = file_name.extension() {
+            name.set_extension(ext);
+        }
+        i += 1;
+    }
+    output_folder.join(name)
+}
+
+/// Arguments of the subcommands matching the tiles.
+fn matching_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("match_method")
+            .long("match-method")
+            .help(
+                "Sets whether pictures are matched against the model chunks by their mean color \
+                 or by their color histogram",
+            )
+            .possible_values(&["mean", "histogram"])
+            .default_value("mean"),
+        Arg::with_name("luminance_match")
+            .long("luminance-match")
+            .help("Matches the pictures against the model chunks by luminance only")
+            .conflicts_with_all(&["match_method", "color_metric"]),
+        Arg::with_name("color_metric")
+            .long("color-metric")
+            .value_name("metric")
+            .help(
+                "Sets how the mean colors are compared: rgb, luma for the brightness only, or hsv \
+                 for the hue first",
+            )
+            .possible_values(&["rgb", "luma", "hsv"])
+            .default_value("rgb"),
+        Arg::with_name("match_variance")
+            .long("match-variance")
+            .help("Puts the high contrast pictures on the detailed chunks of the model"),
+        Arg::with_name("dither")
+            .long("dither")
+            .help("Diffuses the color error of each tile to its neighbours"),
+        Arg::with_name("allow_rotation")
+            .long("allow-rotation")
+            .help("Same as --augment rot"),
+        Arg::with_name("augment")
+            .long("augment")
+            .value_name("transform,...")
+            .help("Randomly rotates (rot) or mirrors (flip) the tiles for variety, their colors don't depend on it")
+            .use_delimiter(true)
+            .possible_values(&["rot", "flip"]),
+        Arg::with_name("two_pass")
+            .long("two-pass")
+            .help("Swaps neighbour tiles after matching when it smooths the transitions"),
+        Arg::with_name("chunk_color")
+            .long("chunk-color")
+            .value_name("mode")
+            .help("Sets how the color of a model chunk is computed, like the gallery pictures by default")
+            .possible_values(&["mean", "median", "dominant"]),
+        Arg::with_name("center_weighted")
+            .long("center-weighted")
+            .help("Weighs the center of each model chunk more in its color"),
+        Arg::with_name("tone_map")
+            .long("tone-map")
+            .value_name("reference")
+            .help("Shifts the colors of the model toward the ones of this image before matching"),
+        Arg::with_name("roi")
+            .long("roi")
+            .value_name("x,y,w,h[,factor]")
+            .help("Splits the cells of this region of the model in factor rows and columns of finer tiles, 2 by default")
+            .multiple(true)
+            .number_of_values(1)
+            .validator(|value| parse_roi(&value).map(|_| ())),
+        Arg::with_name("adaptive")
+            .long("adaptive")
+            .help("Merges the flat blocks of cells in larger tiles, a quadtree over the grid"),
+        Arg::with_name("max_depth")
+            .long("max-depth")
+            .value_name("D")
+            .help("Sets the depth of the quadtree of --adaptive, merging up to 2^D cells per side, 2 by default")
+            .requires("adaptive"),
+        Arg::with_name("variance_threshold")
+            .long("variance-threshold")
+            .value_name("T")
+            .help("Sets the variance of the model colors under which --adaptive merges a block, 100 by default")
+            .requires("adaptive"),
+        Arg::with_name("model_crop")
+            .long("model-crop")
+            .value_name("x,y,w,h")
+            .help("Only creates the mosaic of this region of the model")
+            .validator(|value| parse_rect(&value).map(|_| ())),
+        Arg::with_name("resize_model")
+            .long("resize-model")
+            .value_name("WxH")
+            .help("Shrinks the model to fit in these dimensions, keeping its aspect ratio")
+            .validator(|value| parse_dim(&value).map(|_| ())),
+        Arg::with_name("resize_model_auto")
+            .long("resize-model-auto")
+            .value_name("max_tiles")
+            .help("Shrinks the model for its mosaic to have at most this many tiles")
+            .validator(|value| match value.parse::<u32>() {
+                Ok(n) if n > 0 => Ok(()),
+                _ => Err(format!("invalid tile count {:?}", value)),
+            }),
+        Arg::with_name("print_size")
+            .long("print-size")
+            .value_name("WxH<unit>")
+            .help("Picks the grid for the mosaic to be printed at this size at --dpi, in in, cm or mm, e.g. 24x36in")
+            .conflicts_with_all(&["resize_model", "resize_model_auto"])
+            .validator(|value| parse_print_size(&value).map(|_| ())),
+        Arg::with_name("chunk_overlap")
+            .long("chunk-overlap")
+            .value_name("PX")
+            .help("Samples the color of each model chunk this far into its neighbours")
+            .default_value("0"),
+        Arg::with_name("fill_mode")
+            .long("fill-mode")
+            .value_name("mode")
+            .help("Sets how a model not a whole number of chunks wide or high is made so")
+            .possible_values(&["crop", "pad", "stretch"])
+            .default_value("crop"),
+        Arg::with_name("tile_aspect_ratio")
+            .long("tile-aspect-ratio")
+            .value_name("w:h")
+            .help("Sets the aspect ratio of the tiles and of the model chunks")
+            .validator(|value| parse_ratio(&value).map(|_| ())),
+        Arg::with_name("square")
+            .long("square")
+            .help("Uses square tiles whatever the ratio of the model and gallery, the default")
+            .conflicts_with("tile_aspect_ratio"),
+        Arg::with_name("layout")
+            .long("layout")
+            .value_name("layout")
+            .help("Lays the tiles out in a grid, in a honeycomb of hexagons, regular with --tile-aspect-ratio 7:8, or in rows shifted by half a tile like brickwork")
+            .possible_values(&["grid", "hex", "brick"])
+            .default_value("grid"),
+        Arg::with_name("randomize_top_k")
+            .long("randomize-top-k")
+            .value_name("k")
+            .help("Randomly picks each tile among the k closest pictures")
+            .default_value("1"),
+        Arg::with_name("max_uses")
+            .long("max-uses")
+            .value_name("n")
+            .help("Uses each picture for at most n tiles, the gallery needing enough pictures for the grid"),
+        Arg::with_name("no_repeat")
+            .long("no-repeat")
+            .help("Uses each picture for at most one tile, like --max-uses 1")
+            .conflicts_with("max_uses"),
+        Arg::with_name("seed")
+            .long("seed")
+            .value_name("u64")
+            .help("Sets the seed of the random choices, for the same mosaic on every run"),
+        Arg::with_name("contrast_adjustment")
+            .long("contrast-adjustment")
+            .value_name("f32")
+            .help("Warns if the gallery was preprocessed with another value"),
+        Arg::with_name("grayscale")
+            .long("grayscale")
+            .help("Converts the model to grayscale before matching the tiles"),
+        Arg::with_name("invert")
+            .long("invert")
+            .help("Inverts the colors of the model before matching the tiles"),
+        Arg::with_name("no_linear_light")
+            .long("no-linear-light")
+            .help("Averages the colors of the model in sRGB, for galleries preprocessed so"),
+        Arg::with_name("report_colors")
+            .long("report-colors")
+            .help("Prints the colors the model needs and how many pictures have them"),
+    ]
+}
 
-    let x_offset = (w - square_size) / 2;
-    let y_offset = (h - square_size) / 2;
-    img.view(x_offset, y_offset, square_size, square_size)
+/// Arguments of the subcommands rendering the tiles.
+fn rendering_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("spacing")
+            .long("spacing")
+            .value_name("PX")
+            .help("Sets the gap between the tiles")
+            .default_value("0"),
+        Arg::with_name("spacing_color")
+            .long("spacing-color")
+            .value_name("RRGGBB")
+            .help("Sets the color of the gap between the tiles, and around the hexagons of --layout hex")
+            .default_value("FFFFFF")
+            .validator(|value| parse_color(&value).map(|_| ())),
+        Arg::with_name("grout")
+            .long("grout")
+            .value_name("width,RRGGBB")
+            .help("Draws a border of this width and color around each tile")
+            .validator(|value| parse_grout(&value).map(|_| ())),
+        Arg::with_name("tile_background")
+            .long("tile-background")
+            .value_name("RRGGBB")
+            .help("Composites transparent tiles over this color")
+            .validator(|value| parse_color(&value).map(|_| ())),
+        Arg::with_name("fallback_color")
+            .long("fallback-color")
+            .value_name("RRGGBB")
+            .help("Fills with this color the cells whose tile is farther than --fallback-distance from the model")
+            .validator(|value| parse_color(&value).map(|_| ())),
+        Arg::with_name("fallback_distance")
+            .long("fallback-distance")
+            .value_name("distance")
+            .help("Sets the color distance between a tile and the model above which --fallback-color is used, 100 by default")
+            .requires("fallback_color"),
+        Arg::with_name("tile_opacity")
+            .long("tile-opacity")
+            .value_name("opacity")
+            .help("Fades the tiles over --background with this opacity, from 0 to 1"),
+        Arg::with_name("background")
+            .long("background")
+            .value_name("color")
+            .help("Sets the color showing through the tiles, white, black or RRGGBB, white by default")
+            .requires("tile_opacity")
+            .validator(|value| parse_color(&value).map(|_| ())),
+        Arg::with_name("tone")
+            .long("tone")
+            .value_name("tone")
+            .help("Reduces the tiles to gray or to a ramp from a dark to a light color, gray or duotone:RRGGBB,RRGGBB, matching them by luma")
+            .validator(|value| parse_tone(&value).map(|_| ())),
+        Arg::with_name("vignette")
+            .long("vignette")
+            .value_name("strength")
+            .help("Darkens the corners of each tile by this strength, from 0 to 1"),
+        Arg::with_name("ghost")
+            .long("ghost")
+            .value_name("opacity")
+            .help("Overlays the model on the mosaic with this opacity, from 0 to 1")
+            .default_value("0"),
+        Arg::with_name("feather_edges")
+            .long("feather-edges")
+            .value_name("PX")
+            .help("Blends the tiles with their neighbours over this width to hide the seams")
+            .default_value("0"),
+        Arg::with_name("manifest")
+            .long("manifest")
+            .value_name("out.json")
+            .help("Writes the picture placed in each cell of the mosaic as JSON"),
+        Arg::with_name("manifest_csv")
+            .long("manifest-csv")
+            .value_name("out.csv")
+            .help("Writes the picture placed in each cell of the mosaic as CSV"),
+        Arg::with_name("html")
+            .long("html")
+            .value_name("out_dir")
+            .help("Writes a web page of the mosaic showing the original of each tile"),
+        Arg::with_name("html_sprite")
+            .long("html-sprite")
+            .requires("html")
+            .help("Uses a single image with an image map in the web page"),
+        Arg::with_name("html_link")
+            .long("html-link")
+            .requires("html")
+            .help("Links each tile of the web page to its original picture"),
+        Arg::with_name("save_map")
+            .long("save-map")
+            .help("Writes the placed tiles to <output_image>.map.json"),
+        Arg::with_name("dzi")
+            .long("dzi")
+            .value_name("out_dir")
+            .help("Writes a Deep Zoom pyramid of the mosaic instead of a single image")
+            .conflicts_with_all(&["ghost", "feather_edges", "html", "alpha_mask"]),
+        Arg::with_name("alpha_mask")
+            .long("alpha-mask")
+            .value_name("image")
+            .help("Uses this grayscale image as the transparency of the mosaic"),
+        Arg::with_name("low_memory")
+            .long("low-memory")
+            .help("Writes the mosaic one row at a time, even if it is small")
+            .conflicts_with_all(&["alpha_mask", "html_sprite"]),
+        Arg::with_name("max_output_pixels")
+            .long("max-output-pixels")
+            .value_name("pixels")
+            .help("Refuses to render a mosaic with more pixels than this, 250 million by default"),
+        Arg::with_name("force")
+            .long("force")
+            .help("Renders the mosaic whatever its number of pixels"),
+        Arg::with_name("preview")
+            .long("preview")
+            .help("Shows the mosaic in the terminal and asks before saving it, in memory")
+            .conflicts_with_all(&["dzi", "low_memory"]),
+        Arg::with_name("comparison")
+            .long("comparison")
+            .help("Also writes the model next to the mosaic to <output_stem>_comparison.<ext>")
+            .conflicts_with_all(&["dzi", "low_memory"]),
+        Arg::with_name("bit_depth")
+            .long("bit-depth")
+            .help("Sets the bits per sample of the PNG output image")
+            .possible_values(&["8", "16"])
+            .default_value("8"),
+        Arg::with_name("output_colorspace")
+            .long("output-colorspace")
+            .value_name("space")
+            .help("Sets the color space the PNG or JPEG output image is written in and tagged with")
+            .possible_values(&["srgb", "adobe-rgb"])
+            .default_value("srgb"),
+        Arg::with_name("srgb")
+            .long("srgb")
+            .help("Tags the output image as sRGB, which it now is by default")
+            .conflicts_with("output_colorspace"),
+        Arg::with_name("dpi")
+            .long("dpi")
+            .value_name("dpi")
+            .help("Tags the PNG output image with this resolution, 300 by default with --print-size")
+            .conflicts_with("dzi")
+            .validator(|value| match value.parse::<u32>() {
+                Ok(dpi) if dpi > 0 => Ok(()),
+                _ => Err(format!("invalid resolution {:?}", value)),
+            }),
+    ]
 }
 
-fn process_pictures(files: &[walkdir::DirEntry], output_folder: &Path) -> Vec<ProcessedPicture> {
-    if !output_folder.exists() {
-        fs::create_dir(&output_folder).unwrap();
+/// Parses the value of the argument `name`, or returns `default` if the subcommand doesn't
+/// have it.
+fn parse_arg<T: FromStr>(matches: &ArgMatches, name: &str, default: T) -> T {
+    if matches.value_of(name).is_none() {
+        return default;
     }
+    value_t!(matches, name, T).unwrap_or_else(|e| e.exit())
+}
 
-    let mut res = Vec::new();
-
-    let files_nb = files.len();
-    for (i, file) in files.iter().enumerate() {
-        let path = file.path();
-        print!("[{}/{}] {} ", i, files_nb, path.display());
-
-        let img = match image::open(path) {
-            Ok(img) => img,
-            Err(_) => {
-                println!("skip");
-                continue;
-            }
-        };
-
-        let ratio = {
-            let (w, h) = img.dimensions();
-            compute_ratio(w, h)
-        };
+fn parse_patterns(matches: &ArgMatches, name: &str) -> Vec<Pattern> {
+    matches.values_of(name).map_or_else(Vec::new, |values| {
+        values.map(|v| Pattern::new(v).unwrap()).collect()
+    })
+}
 
-        let square = image_square_view(&img);
-        let thumb = imageops::thumbnail(&square, THUMBNAIL_SIZE, THUMBNAIL_SIZE);
-        let thumb = imageops::contrast(&thumb, CONTRAST_ADJUSTMENT);
-        let thumb_name = path.file_name().unwrap();
-        let thumb_path = output_folder.join(thumb_name);
-        if thumb.save(&thumb_path).is_err() {
-            println!("skip");
-            continue;
+fn parse_mosaic_options(matches: &ArgMatches) -> MosaicOptions {
+    let match_mode = match matches.value_of("match_method") {
+        Some("histogram") => MatchMode::Histogram,
+        _ if matches.is_present("luminance_match") => MatchMode::Luminance,
+        _ => match matches.value_of("color_metric") {
+            Some("luma") => MatchMode::Luminance,
+            Some("hsv") => MatchMode::Hsv,
+            _ => MatchMode::Color,
+        },
+    };
+    let options = MosaicBuilder::new()
+        .match_mode(match_mode)
+        .match_variance(matches.is_present("match_variance"))
+        .dither(matches.is_present("dither"))
+        .two_pass(matches.is_present("two_pass"))
+        .allow_rotation(matches.is_present("allow_rotation") || is_augmented(matches, "rot"))
+        .allow_mirroring(is_augmented(matches, "flip"))
+        .chunk_color(matches.value_of("chunk_color").map(|v| match v {
+            "median" => ColorMode::Median,
+            "dominant" => ColorMode::Dominant,
+            _ => ColorMode::Mean,
+        }))
+        .center_weighted(matches.is_present("center_weighted"))
+        .chunk_overlap(parse_arg(matches, "chunk_overlap", 0))
+        .tile_ratio(
+            matches
+                .value_of("tile_aspect_ratio")
+                .map_or((1, 1), |v| parse_ratio(v).unwrap()),
+        )
+        .linear_light(!matches.is_present("no_linear_light"))
+        .spacing(parse_arg(matches, "spacing", 0))
+        .spacing_color(
+            matches
+                .value_of("spacing_color")
+                .map_or([255, 255, 255, 255], |v| parse_color(v).unwrap().data),
+        )
+        .grout(
+            matches
+                .value_of("grout")
+                .map(|v| parse_grout(v).unwrap())
+                .map(|(width, color)| (width, color.data)),
+        )
+        .tile_background(
+            matches
+                .value_of("tile_background")
+                .map(|v| parse_color(v).unwrap().data),
+        )
+        .fallback(matches.value_of("fallback_color").map(|v| {
+            (
+                parse_arg(matches, "fallback_distance", 100),
+                parse_color(v).unwrap().data,
+            )
+        }))
+        .tile_opacity(parse_arg(matches, "tile_opacity", 1.0))
+        .vignette(parse_arg(matches, "vignette", 0.0))
+        .opacity_background(
+            matches
+                .value_of("background")
+                .map_or([255, 255, 255, 255], |v| parse_color(v).unwrap().data),
+        )
+        .randomize_top_k(parse_arg(matches, "randomize_top_k", 1))
+        .max_uses(if matches.is_present("no_repeat") {
+            Some(1)
+        } else if matches.is_present("max_uses") {
+            Some(value_t!(matches, "max_uses", u32).unwrap_or_else(|e| e.exit()))
+        } else {
+            None
+        })
+        .seed(if matches.is_present("seed") {
+            Some(value_t!(matches, "seed", u64).unwrap_or_else(|e| e.exit()))
+        } else {
+            None
+        })
+        .temporal_stability(parse_arg(matches, "temporal_stability", 0.0))
+        .mask_fill(match matches.value_of("mask_fill") {
+            Some("transparent") => MaskFill::Transparent,
+            _ => MaskFill::Model,
+        })
+        .ghost(parse_arg(matches, "ghost", 0.0))
+        .layout(match matches.value_of("layout") {
+            Some("hex") => Layout::Hex,
+            Some("brick") => Layout::Brick,
+            _ => Layout::Grid,
+        })
+        .feather_edges(parse_arg(matches, "feather_edges", 0))
+        .expected_contrast_adjustment(if matches.is_present("contrast_adjustment") {
+            Some(value_t!(matches, "contrast_adjustment", f32).unwrap_or_else(|e| e.exit()))
+        } else {
+            None
+        })
+        .report_colors(matches.is_present("report_colors"))
+        .tone(matches.value_of("tone").map(|v| parse_tone(v).unwrap()))
+        .tone_map(matches.value_of("tone_map").map(|path| {
+            let reference = image::open(path).unwrap_or_else(|e| {
+                error!("can't open {}: {}", path, e);
+                process::exit(1);
+            });
+            ToneMap::from_reference(&reference)
+        }))
+        .adaptive(matches.is_present("adaptive").then(|| {
+            (
+                parse_arg(matches, "max_depth", 2),
+                parse_arg(matches, "variance_threshold", 100.0),
+            )
+        }))
+        .regions_of_interest(matches.values_of("roi").map_or_else(Vec::new, |values| {
+            values.map(|v| parse_roi(v).unwrap()).collect()
+        }))
+        .build();
+    match options {
+        Ok(options) => options,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
         }
+    }
+}
 
-        let processed = ProcessedPicture {
-            path: thumb_name.to_string_lossy().to_string(),
-            color_rgb: compute_main_color(&img.to_rgba()),
-            ratio_width: ratio.0,
-            ratio_height: ratio.1,
-        };
-
-        println!(
-            "rgb: ({}, {}, {})",
-            processed.color_rgb[0], processed.color_rgb[1], processed.color_rgb[2]
-        );
-        res.push(processed);
+/// Options of the model, the grid of `--print-size` being derived from the cells of `options`.
+fn parse_model_options(matches: &ArgMatches, options: &MosaicOptions) -> ModelOptions {
+    ModelOptions {
+        crop: matches
+            .value_of("model_crop")
+            .map(|v| parse_rect(v).unwrap()),
+        grayscale: matches.is_present("grayscale"),
+        invert: matches.is_present("invert"),
+        fill_mode: match matches.value_of("fill_mode") {
+            Some("pad") => FillMode::Pad,
+            Some("stretch") => FillMode::Stretch,
+            _ => FillMode::Crop,
+        },
+        max_size: matches
+            .value_of("resize_model")
+            .map(|v| parse_dim(v).unwrap()),
+        max_tiles: matches
+            .value_of("resize_model_auto")
+            .map(|v| v.parse().unwrap()),
+        grid: matches.value_of("print_size").map(|v| {
+            let (w, h) = parse_print_size(v).unwrap();
+            let dpi = f64::from(parse_arg(matches, "dpi", DEFAULT_PRINT_DPI));
+            let size = ((w * dpi).round() as u32, (h * dpi).round() as u32);
+            let grid = grid_for_size(size, options);
+            debug!(
+                "print size of {}x{} px, model of {}x{} chunks",
+                size.0, size.1, grid.0, grid.1
+            );
+            grid
+        }),
     }
+}
 
-    res
+fn parse_outputs<'a>(matches: &'a ArgMatches, timings: &'a Timings) -> CreateOutputs<'a> {
+    CreateOutputs {
+        timings: Some(timings).filter(|_| matches.is_present("timings")),
+        manifest: matches.value_of("manifest").map(Path::new),
+        manifest_csv: matches.value_of("manifest_csv").map(Path::new),
+        html: matches.value_of("html").map(Path::new),
+        html_sprite: matches.is_present("html_sprite"),
+        html_link: matches.is_present("html_link"),
+        save_map: matches.is_present("save_map"),
+        alpha_mask: matches.value_of("alpha_mask").map(Path::new),
+        dzi: matches.value_of("dzi").map(Path::new),
+        low_memory: matches.is_present("low_memory"),
+        preview: matches.is_present("preview"),
+        dry_run: matches.is_present("dry_run"),
+        comparison: matches.is_present("comparison"),
+        mask: matches
+            .value_of("mask")
+            .map(|path| (Path::new(path), parse_arg(matches, "mask_threshold", 0.5))),
+        detail_mask: matches
+            .value_of("detail_mask")
+            .map(|path| (Path::new(path), parse_arg(matches, "detail_threshold", 0.5))),
+        png: PngOptions {
+            sixteen_bit: matches.value_of("bit_depth") == Some("16"),
+            color_space: match matches.value_of("output_colorspace") {
+                Some("adobe-rgb") => ColorSpace::AdobeRgb,
+                _ => ColorSpace::Srgb,
+            },
+            dpi: match matches.value_of("dpi") {
+                Some(_) => Some(parse_arg(matches, "dpi", DEFAULT_PRINT_DPI)),
+                None if matches.is_present("print_size") => Some(DEFAULT_PRINT_DPI),
+                None => None,
+            },
+        },
+        animation: if matches.is_present("animate_frames") {
+            Some((
+                parse_arg(matches, "animate_frames", 1),
+                parse_arg(matches, "frame_delay", 100),
+            ))
+        } else {
+            None
+        },
+        max_output_pixels: if matches.is_present("force") {
+            None
+        } else {
+            Some(parse_arg(
+                matches,
+                "max_output_pixels",
+                DEFAULT_MAX_OUTPUT_PIXELS,
+            ))
+        },
+        cache: None,
+    }
 }
 
-fn save_processed_pictures_metadata(
-    metadata: &ProcessedPictureMetadata,
-    processed_folder: &Path,
-) -> Result<(), Box<Error>> {
-    let path = processed_folder.join(METADATA_FILENAME);
-    let file = File::create(path)?;
-    let writer = BufWriter::new(file);
-    serde_json::to_writer_pretty(writer, metadata)?;
-    Ok(())
+/// Name of the config file read from the current directory when `--config` isn't given.
+const CONFIG_FILENAME: &str = "photo-mosaic.toml";
+
+/// Arguments of all the subcommands, that a config file can't set.
+const GLOBAL_ARGS: [&str; 6] = [
+    "help",
+    "version",
+    "config",
+    "print_config",
+    "verbose",
+    "quiet",
+];
+
+/// Long option of a subcommand that a config file can set.
+struct ConfigOption<'a> {
+    name: &'a str,
+    long: &'a str,
+    is_flag: bool,
+    multiple: bool,
+    /// Arguments that can't be given with this one, or that override it.
+    conflicts: Vec<&'a str>,
 }
 
-fn load_processed_pictures_metadata(
-    processed_folder: &Path,
-) -> Result<ProcessedPictureMetadata, Box<Error>> {
-    let path = processed_folder.join(METADATA_FILENAME);
-    let file = File::open(path)?;
-    let reader = BufReader::new(file);
-    let metadata: ProcessedPictureMetadata = serde_json::from_reader(reader)?;
-    Ok(metadata)
+/// Lists the options of `subcommand`. clap 2 has no public way to list the arguments of an app,
+/// so this reads its hidden parser, which is why clap is pinned to an exact version.
+fn config_options<'a>(subcommand: &'a App) -> Vec<ConfigOption<'a>> {
+    let flags = subcommand.p.flags.iter().map(|f| (&f.b, &f.s, true));
+    let opts = subcommand.p.opts.iter().map(|o| (&o.b, &o.s, false));
+    let mut options: Vec<ConfigOption> = flags
+        .chain(opts)
+        .filter(|(b, _, _)| !GLOBAL_ARGS.contains(&b.name))
+        .filter_map(|(b, s, is_flag)| {
+            Some(ConfigOption {
+                name: b.name,
+                long: s.long?,
+                is_flag,
+                multiple: b.is_set(ArgSettings::Multiple),
+                conflicts: b
+                    .blacklist
+                    .iter()
+                    .chain(b.overrides.iter())
+                    .flatten()
+                    .cloned()
+                    .collect(),
+            })
+        })
+        .collect();
+    options.sort_by_key(|o| o.long);
+    options
 }
 
-fn color_distance(c1: [u8; 3], c2: [u8; 3]) -> u32 {
-    let mut a = 0;
-    for i in 0..3 {
-        a += (i32::from(c1[i]) - i32::from(c2[i])).pow(2);
-    }
-    f64::from(a).sqrt() as u32
+fn find_subcommand<'a, 'b>(app: &'a App<'b, 'b>, name: &str) -> &'a App<'b, 'b> {
+    app.p
+        .subcommands
+        .iter()
+        .find(|s| s.get_name() == name)
+        .unwrap()
 }
 
-fn find_closest_pic_by_color(pics: &[ProcessedPicture], color: [u8; 3]) -> &ProcessedPicture {
-    let mut closest = (&pics[0], color_distance(pics[0].color_rgb, color));
-    for i in 1..pics.len() {
-        let dist = color_distance(pics[i].color_rgb, color);
-        if dist == 0 {
-            return &pics[i];
+/// Converts `value` to the command line arguments setting `option`.
+fn config_args(option: &ConfigOption, value: &ConfigValue) -> Result<Vec<String>, String> {
+    fn scalar(value: &ConfigValue) -> Result<String, String> {
+        match value {
+            ConfigValue::String(s) => Ok(s.clone()),
+            ConfigValue::Array(_) => Err("arrays can't be nested".to_owned()),
+            _ => Ok(value.to_string()),
         }
+    }
 
-        if dist < closest.1 {
-            closest = (&pics[i], dist);
+    if option.is_flag {
+        return match value {
+            ConfigValue::Boolean(true) => Ok(vec![format!("--{}", option.long)]),
+            ConfigValue::Boolean(false) => Ok(Vec::new()),
+            _ => Err(format!(
+                "`{}` is a flag, set it to true or false",
+                option.long
+            )),
+        };
+    }
+    match value {
+        ConfigValue::Array(values) if option.multiple => values
+            .iter()
+            .map(|v| Ok(format!("--{}={}", option.long, scalar(v)?)))
+            .collect(),
+        ConfigValue::Array(values) => {
+            let values: Result<Vec<String>, String> = values.iter().map(scalar).collect();
+            Ok(vec![format!("--{}={}", option.long, values?.join(","))])
         }
+        _ => Ok(vec![format!("--{}={}", option.long, scalar(value)?)]),
     }
-    closest.0
 }
 
-fn compute_main_color_by_chunk(img: &DynamicImage, chunk_w: u32, chunk_h: u32) -> Vec<[u8; 3]> {
-    let mut res = Vec::new();
-    let (w, h) = img.dimensions();
-    let mut y = 0;
-    while y + chunk_h <= h {
-        let mut x = 0;
-        while x + chunk_w <= w {
-            let chunk = img.view(x, y, chunk_w, chunk_h);
-            res.push(compute_main_color(&chunk.to_image()));
-            x += chunk_w;
+/// Warns about the tables and keys of `config` that aren't subcommands and long options.
+fn warn_unknown_keys(app: &App, config: &Config, path: &Path) {
+    let subcommands: Vec<&str> = app.p.subcommands.iter().map(|s| s.get_name()).collect();
+    for (name, table) in &config.tables {
+        if !subcommands.contains(&name.as_str()) {
+            warn!(
+                "unknown table [{}] in {}, the tables are {}",
+                name,
+                path.display(),
+                subcommands.join(", ")
+            );
+            continue;
+        }
+        let options = config_options(find_subcommand(app, name));
+        let longs: Vec<&str> = options.iter().map(|o| o.long).collect();
+        for (key, _) in table {
+            if !longs.contains(&key.as_str()) {
+                warn!(
+                    "unknown key {} in [{}] of {}, the keys are {}",
+                    key,
+                    name,
+                    path.display(),
+                    longs.join(", ")
+                );
+            }
         }
-        y += chunk_h;
     }
-    res
-}
 
-fn create_mosaic(
-    model: &DynamicImage,
-    processed_folder: &Path,
-    pics: &[ProcessedPicture],
-    ratio: (u32, u32),
-) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
-    let chunk_dim = ratio_to_dim(ratio, CHUNK_SIZE);
-    let color_by_chunk = compute_main_color_by_chunk(model, chunk_dim.0, chunk_dim.1);
-
-    let thumb_dim = ratio_to_dim(ratio, THUMBNAIL_SIZE);
+    let mut longs: Vec<&str> = app
+        .p
+        .subcommands
+        .iter()
+        .flat_map(|s| config_options(s).into_iter().map(|o| o.long))
+        .collect();
+    longs.sort_unstable();
+    longs.dedup();
+    for (key, _) in &config.root {
+        if !longs.contains(&key.as_str()) {
+            warn!(
+                "unknown key {} in {}, the keys are {}",
+                key,
+                path.display(),
+                longs.join(", ")
+            );
+        }
+    }
+}
 
-    let mut res = ImageBuffer::new(
-        model.width() / chunk_dim.0 * thumb_dim.0,
-        model.height() / chunk_dim.1 * thumb_dim.1,
-    );
+/// Command line arguments giving the options of the config file given with `--config`, or of
+/// `photo-mosaic.toml` in the current directory, that `matches` doesn't set. The keys before
+/// any table apply to all the subcommands having them, the ones of a table named after a
+/// subcommand to that subcommand only, and override the former.
+fn config_file_args(app: &App, matches: &ArgMatches) -> Vec<OsString> {
+    let (name, sub_matches) = match matches.subcommand() {
+        (name, Some(sub_matches)) => (name, sub_matches),
+        _ => return Vec::new(),
+    };
+    let path = match sub_matches
+        .value_of("config")
+        .or_else(|| matches.value_of("config"))
+    {
+        Some(path) => PathBuf::from(path),
+        None if Path::new(CONFIG_FILENAME).is_file() => {
+            info!("using the options of {}", CONFIG_FILENAME);
+            PathBuf::from(CONFIG_FILENAME)
+        }
+        None => return Vec::new(),
+    };
+    let config = match Config::load(&path) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("can't read {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    };
+    warn_unknown_keys(app, &config, &path);
 
-    let mut x = 0;
-    let mut y = 0;
-    for color in color_by_chunk {
-        let pic = find_closest_pic_by_color(pics, color);
-        let thumb_path = processed_folder.join(&pic.path);
-        let thumb = image::open(thumb_path).unwrap();
-        assert!(res.copy_from(&thumb, x, y));
+    let options = config_options(find_subcommand(app, name));
+    let given: Vec<&str> = options
+        .iter()
+        .filter(|o| sub_matches.occurrences_of(o.name) > 0)
+        .map(|o| o.name)
+        .collect();
+    let table = config.table(name).map_or(&[][..], |t| t.as_slice());
+    let root = config
+        .root
+        .iter()
+        .filter(|(key, _)| !table.iter().any(|(k, _)| k == key));
 
-        x += thumb_dim.0;
-        if x >= res.width() {
-            x = 0;
-            y += thumb_dim.1;
+    let mut args = Vec::new();
+    for (key, value) in table.iter().chain(root) {
+        let option = match options.iter().find(|o| o.long == key) {
+            Some(option) => option,
+            None => continue,
+        };
+        let overridden = given.contains(&option.name)
+            || option.conflicts.iter().any(|c| given.contains(c))
+            || options
+                .iter()
+                .any(|o| given.contains(&o.name) && o.conflicts.contains(&option.name));
+        if overridden {
+            continue;
+        }
+        debug!("{}: {} = {}", path.display(), key, value);
+        match config_args(option, value) {
+            Ok(option_args) => args.extend(option_args.into_iter().map(OsString::from)),
+            Err(e) => {
+                error!("{}: {}", path.display(), e);
+                process::exit(1);
+            }
         }
     }
+    args
+}
+
+/// Argument giving a seed to the subcommands taking one when `matches` sets none, so that it
+/// is logged and saved in the effective config, and a mosaic can be rendered again identically.
+fn seed_arg(app: &App, matches: &ArgMatches) -> Option<OsString> {
+    let (name, sub_matches) = match matches.subcommand() {
+        (name, Some(sub_matches)) => (name, sub_matches),
+        _ => return None,
+    };
+    let takes_seed = config_options(find_subcommand(app, name))
+        .iter()
+        .any(|o| o.name == "seed");
+    if !takes_seed || sub_matches.is_present("seed") {
+        return None;
+    }
 
-    res
+    let seed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| u64::from(d.subsec_nanos()));
+    debug!("seed: {}", seed);
+    Some(OsString::from(format!("--seed={}", seed)))
 }
 
-fn cmd_preprocess(gallery_folder: &Path, output_folder: &Path) {
-    let files: Vec<_> = files_from_folder(gallery_folder).collect();
-    let metadata = ProcessedPictureMetadata { pictures: process_pictures(&files, output_folder) };
-    save_processed_pictures_metadata(&metadata, output_folder).unwrap();
+/// Converts a value of the command line to the config value parsed back to the same string.
+fn to_config_value(value: &str) -> ConfigValue {
+    if let Some(i) = value.parse().ok().filter(|i: &i64| i.to_string() == value) {
+        ConfigValue::Integer(i)
+    } else if let Some(f) = value
+        .parse()
+        .ok()
+        .filter(|f: &f64| f.is_finite() && format!("{:?}", f) == value)
+    {
+        ConfigValue::Float(f)
+    } else {
+        ConfigValue::String(value.to_owned())
+    }
 }
 
-fn cmd_create(preprocessed_folder: &Path, model: &Path, output_image: &Path) {
-    let metadata = load_processed_pictures_metadata(preprocessed_folder).unwrap();
+/// The options the subcommand runs with, from the command line, the config file and their
+/// defaults, as a config file giving the same options.
+fn effective_config(app: &App, matches: &ArgMatches) -> Config {
+    let (name, sub_matches) = match matches.subcommand() {
+        (name, Some(sub_matches)) => (name, sub_matches),
+        _ => return Config::default(),
+    };
+    let mut table = Vec::new();
+    for option in config_options(find_subcommand(app, name)) {
+        if option.is_flag {
+            let value = ConfigValue::Boolean(sub_matches.is_present(option.name));
+            table.push((option.long.to_owned(), value));
+        } else if let Some(values) = sub_matches.values_of(option.name) {
+            let mut values: Vec<ConfigValue> = values.map(to_config_value).collect();
+            let value = if values.len() == 1 && !option.multiple {
+                values.remove(0)
+            } else {
+                ConfigValue::Array(values)
+            };
+            table.push((option.long.to_owned(), value));
+        }
+    }
+    Config {
+        root: Vec::new(),
+        tables: vec![(name.to_owned(), table)],
+    }
+}
 
-    let model = image::open(model).unwrap();
-    let (w, h) = model.dimensions();
-    let ratio = (1, 1); // compute_ratio(w, h);
+/// Writes `config` to `path` so that the outputs can be made again.
+fn save_config(config: &Config, path: &Path) {
+    if let Err(e) = fs::write(path, config.to_string()) {
+        warn!("can't write {}: {}", path.display(), e);
+    }
+}
 
-    println!("{} pictures available", metadata.pictures.len());
-    let mosaic = create_mosaic(&model, preprocessed_folder, &metadata.pictures, ratio);
-    mosaic.save(output_image).unwrap();
+/// Sets how much is logged from `-v` and `--quiet`.
+fn set_log_level(matches: &ArgMatches) {
+    let sub_matches = matches.subcommand().1;
+    let is_present =
+        |name| matches.is_present(name) || sub_matches.is_some_and(|m| m.is_present(name));
+    let verbosity = cmp::max(
+        matches.occurrences_of("verbose"),
+        sub_matches.map_or(0, |m| m.occurrences_of("verbose")),
+    );
+    log::set_max_level(match verbosity {
+        _ if is_present("quiet") => Level::Error,
+        0 => Level::Info,
+        1 => Level::Debug,
+        _ => Level::Trace,
+    });
 }
 
 fn main() {
-    let matches = App::new("Photo Mosaic")
+    let app = App::new("Photo Mosaic")
         .version("0.1")
         .author("verdie-g <gregoire.verdier@gmail.com>")
         .about("Create a photo mosaic")
+        .args(&[
+            Arg::with_name("config")
+                .long("config")
+                .value_name("file")
+                .help("Reads the default options from this file rather than ./photo-mosaic.toml")
+                .global(true),
+            Arg::with_name("print_config")
+                .long("print-config")
+                .help("Prints the options the command would run with as a config file")
+                .global(true),
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .help("Logs more details on stderr, such as decode times, -vv for even more")
+                .multiple(true)
+                .global(true),
+            Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .help("Only logs the errors")
+                .conflicts_with("verbose")
+                .global(true),
+        ])
         .subcommands(vec![
             SubCommand::with_name("preprocess")
                 .about("Recursively traverses your gallery to preprocess all image files")
                 .arg(
                     Arg::with_name("gallery_folder")
-                        .help("Sets the path of your gallery")
+                        .help("Sets the path of your gallery, a folder or a zip archive")
                         .index(1)
                         .required(true),
                 )
@@ -260,6 +2671,204 @@ fn main() {
                         .help("Sets the path of the output folder for the processed images")
                         .index(2)
                         .required(true),
+                )
+                .arg(
+                    Arg::with_name("histogram")
+                        .long("histogram")
+                        .help("Stores a color histogram of each picture"),
+                )
+                .arg(
+                    Arg::with_name("grayscale")
+                        .long("grayscale")
+                        .help("Converts the pictures to grayscale for black-and-white mosaics"),
+                )
+                .arg(
+                    Arg::with_name("thumbnail_format")
+                        .long("thumbnail-format")
+                        .value_name("format")
+                        .help("Saves all thumbnails in this format instead of the original one")
+                        .possible_values(&["png", "jpeg"]),
+                )
+                .arg(
+                    Arg::with_name("contrast_adjustment")
+                        .long("contrast-adjustment")
+                        .visible_alias("contrast")
+                        .value_name("f32")
+                        .help("Sets the contrast adjustment of the thumbnails, 0 to disable it")
+                        .default_value("20.0"),
+                )
+                .arg(
+                    Arg::with_name("saturation_boost")
+                        .long("saturation-boost")
+                        .value_name("f32")
+                        .help("Multiplies the saturation of the thumbnails, 1.5 for 50% more")
+                        .default_value("1.0"),
+                )
+                .arg(
+                    Arg::with_name("color_mode")
+                        .long("color-mode")
+                        .value_name("mode")
+                        .help("Sets how the color of a picture is computed")
+                        .possible_values(&["mean", "median", "dominant"])
+                        .default_value("mean"),
+                )
+                .arg(
+                    Arg::with_name("metadata_format")
+                        .long("metadata-format")
+                        .value_name("format")
+                        .help("Sets the format of the metadata, ndjson to read huge galleries a picture at a time, cbor or messagepack for smaller files")
+                        .possible_values(&["json", "ndjson", "cbor", "messagepack"])
+                        .default_value("json"),
+                )
+                .arg(
+                    Arg::with_name("db")
+                        .long("db")
+                        .value_name("file")
+                        .help("Also writes the pictures to this database indexed by color, for create --db"),
+                )
+                .arg(
+                    Arg::with_name("tile_fit")
+                        .long("tile-fit")
+                        .value_name("fit")
+                        .help("Sets whether the pictures are cropped or padded to a square")
+                        .possible_values(&["crop", "pad"])
+                        .default_value("crop"),
+                )
+                .arg(
+                    Arg::with_name("pad_color")
+                        .long("pad-color")
+                        .value_name("color")
+                        .help("Sets the color of the borders of --tile-fit pad, white, black or RRGGBB, black by default")
+                        .validator(|value| parse_color(&value).map(|_| ())),
+                )
+                .arg(
+                    Arg::with_name("tile_rotate")
+                        .long("tile-rotate")
+                        .value_name("angle")
+                        .help("Rotates all the thumbnails clockwise by this many degrees")
+                        .possible_values(&["0", "90", "180", "270"])
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::with_name("resize_filter")
+                        .long("resize-filter")
+                        .value_name("filter")
+                        .help("Sets the thumbnail resize filter, lanczos3 is slower but sharper")
+                        .possible_values(&["nearest", "bilinear", "lanczos3"]),
+                )
+                .arg(
+                    Arg::with_name("no_skip_hidden")
+                        .long("no-skip-hidden")
+                        .help("Walks the files and folders whose name starts with a dot too"),
+                )
+                .arg(
+                    Arg::with_name("follow_symlinks")
+                        .long("follow-symlinks")
+                        .help("Walks the targets of the symbolic links of the gallery"),
+                )
+                .arg(
+                    Arg::with_name("no_follow_symlinks")
+                        .long("no-follow-symlinks")
+                        .help("Doesn't walk the targets of the symbolic links, the default")
+                        .overrides_with("follow_symlinks"),
+                )
+                .arg(
+                    Arg::with_name("max_depth")
+                        .long("max-depth")
+                        .value_name("N")
+                        .help("Only walks N folders deep, 1 for the root of the gallery only"),
+                )
+                .arg(
+                    Arg::with_name("exclude")
+                        .long("exclude")
+                        .value_name("pattern")
+                        .help("Skips the files and folders matching this glob")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .validator(|value| Pattern::new(&value).map(|_| ())),
+                )
+                .arg(
+                    Arg::with_name("include")
+                        .long("include")
+                        .value_name("pattern")
+                        .help("Only preprocesses the files matching this glob, e.g. *.jpg")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .validator(|value| Pattern::new(&value).map(|_| ())),
+                )
+                .arg(
+                    Arg::with_name("ext")
+                        .long("ext")
+                        .value_name("ext,...")
+                        .help("Only decodes the files with one of these extensions, all if empty")
+                        .use_delimiter(true)
+                        .default_value(
+                            "jpg,jpeg,png,gif,bmp,ico,tif,tiff,webp,pbm,pgm,ppm,pnm,tga",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("max_file_size")
+                        .long("max-file-size")
+                        .value_name("size")
+                        .help("Skips the files larger than this size, such as 500M")
+                        .validator(|value| parse_size(&value).map(|_| ())),
+                )
+                .arg(
+                    Arg::with_name("min_dimension")
+                        .long("min-dimension")
+                        .value_name("PX")
+                        .help("Skips the pictures whose shorter side is smaller than this"),
+                )
+                .arg(
+                    Arg::with_name("report")
+                        .long("report")
+                        .value_name("report.json")
+                        .help("Writes what became of each file of the gallery as JSON"),
+                )
+                .arg(
+                    Arg::with_name("strict")
+                        .long("strict")
+                        .help("Exits with an error if a file of the gallery couldn't be decoded"),
+                )
+                .arg(
+                    Arg::with_name("timings")
+                        .long("timings")
+                        .help("Prints the time spent decoding, resizing, analyzing and saving the pictures, and the pictures per second"),
+                )
+                .arg(
+                    Arg::with_name("dry_run")
+                        .long("dry-run")
+                        .help("Prints the files that would be processed or skipped without writing any")
+                        .conflicts_with("strict"),
+                )
+                .arg(
+                    Arg::with_name("dedupe")
+                        .long("dedupe")
+                        .help("Keeps only the largest copy of pictures that look the same"),
+                )
+                .arg(
+                    Arg::with_name("dedupe_distance")
+                        .long("dedupe-distance")
+                        .value_name("bits")
+                        .help("Sets how many of the 64 hash bits of duplicates may differ, 4 by default")
+                        .requires("dedupe"),
+                )
+                .arg(
+                    Arg::with_name("skip_transparent")
+                        .long("skip-transparent")
+                        .help("Skips the pictures that are more than half transparent"),
+                )
+                .arg(
+                    Arg::with_name("ignore_transparent")
+                        .long("ignore-transparent")
+                        .help("Computes the color of the pictures from their opaque pixels only"),
+                )
+                .arg(
+                    Arg::with_name("no_linear_light")
+                        .long("no-linear-light")
+                        .help(
+                            "Averages the colors of the pictures in sRGB rather than linear light",
+                        ),
                 ),
             SubCommand::with_name("create")
                 .about("Create a photo mosaic from a preprocessed gallery and a model image")
@@ -269,33 +2878,544 @@ fn main() {
                         .index(1)
                         .required(true),
                 )
+                .arg(
+                    Arg::with_name("db")
+                        .long("db")
+                        .value_name("file")
+                        .help("Reads the pictures from this database written by preprocess --db rather than from the metadata of the folder, only the closest ones to the model when matching by color alone"),
+                )
+                .arg(
+                    Arg::with_name("model")
+                        .help("Sets the path of image model, or of several models or folders of models")
+                        .index(2)
+                        .multiple(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("output_image")
+                        .help("Sets the output path of the created mosaic, or of the folder of the mosaics of several models")
+                        .index(3)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("output_template")
+                        .long("output-template")
+                        .value_name("template")
+                        .help("Names the mosaics of several models, {model_stem} being the model file name without extension")
+                        .default_value("{model_stem}_mosaic.png"),
+                )
+                .args(&matching_args())
+                .args(&rendering_args())
+                .arg(
+                    Arg::with_name("dry_run")
+                        .long("dry-run")
+                        .help("Prints the grid, resolution and tile usage without rendering"),
+                )
+                .arg(
+                    Arg::with_name("timings")
+                        .long("timings")
+                        .help("Prints the time spent decoding, matching, compositing and encoding, and the chunks per second"),
+                )
+                .arg(
+                    Arg::with_name("mask")
+                        .long("mask")
+                        .value_name("image")
+                        .help("Only puts tiles where this image, stretched over the model, is light and opaque"),
+                )
+                .arg(
+                    Arg::with_name("mask_threshold")
+                        .long("mask-threshold")
+                        .value_name("coverage")
+                        .help("Sets the share of a cell the mask must cover for it to get a tile, 0.5 by default")
+                        .requires("mask"),
+                )
+                .arg(
+                    Arg::with_name("mask_fill")
+                        .long("mask-fill")
+                        .value_name("fill")
+                        .help("Sets what the masked cells show, the model by default")
+                        .possible_values(&["model", "transparent"])
+                        .requires("mask"),
+                )
+                .arg(
+                    Arg::with_name("detail_mask")
+                        .long("detail-mask")
+                        .value_name("image")
+                        .help("Splits the cells where this image, stretched over the model, is light in four finer tiles"),
+                )
+                .arg(
+                    Arg::with_name("detail_threshold")
+                        .long("detail-threshold")
+                        .value_name("coverage")
+                        .help("Sets the share of a cell the detail mask must cover for it to be split, 0.5 by default")
+                        .requires("detail_mask"),
+                )
+                .arg(
+                    Arg::with_name("animate_frames")
+                        .long("animate-frames")
+                        .value_name("n")
+                        .help("Writes an animated GIF of n mosaics picking other close tiles")
+                        .conflicts_with_all(&[
+                            "dry_run",
+                            "dzi",
+                            "low_memory",
+                            "preview",
+                            "alpha_mask",
+                            "mask",
+                            "detail_mask",
+                            "manifest",
+                            "manifest_csv",
+                            "html",
+                            "save_map",
+                        ]),
+                )
+                .arg(
+                    Arg::with_name("frame_delay")
+                        .long("frame-delay")
+                        .value_name("ms")
+                        .help("Sets how long each frame of --animate-frames is shown, 100 by default")
+                        .requires("animate_frames"),
+                )
+                .arg(
+                    Arg::with_name("temporal_stability")
+                        .long("temporal-stability")
+                        .value_name("weight")
+                        .help("Keeps the tiles of an animated GIF model from a frame to the next unless a picture is this much closer, 0.5 for 50%")
+                        .default_value("0"),
+                ),
+            SubCommand::with_name("plan")
+                .about("Matches the tiles of a mosaic and saves their placement for render")
+                .arg(
+                    Arg::with_name("preprocessed_folder")
+                        .help("Sets the path of the folder with the preprocessed pictures")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("db")
+                        .long("db")
+                        .value_name("file")
+                        .help("Reads the pictures from this database written by preprocess --db rather than from the metadata of the folder, only the closest ones to the model when matching by color alone"),
+                )
                 .arg(
                     Arg::with_name("model")
                         .help("Sets the path of image model")
                         .index(2)
                         .required(true),
                 )
+                .arg(
+                    Arg::with_name("plan")
+                        .help("Sets the output path of the placement file")
+                        .index(3)
+                        .required(true),
+                )
+                .args(&matching_args()),
+            SubCommand::with_name("render")
+                .about("Renders a mosaic from the placement file written by plan")
+                .arg(
+                    Arg::with_name("plan")
+                        .help("Sets the path of the placement file")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("preprocessed_folder")
+                        .help("Sets the path of the folder with the preprocessed pictures")
+                        .index(2)
+                        .required(true),
+                )
                 .arg(
                     Arg::with_name("output_image")
                         .help("Sets the output path of the created mosaic")
                         .index(3)
                         .required(true),
+                )
+                .arg(
+                    Arg::with_name("model")
+                        .long("model")
+                        .value_name("image")
+                        .help("Sets the path of the model overlaid by --ghost"),
+                )
+                .arg(
+                    Arg::with_name("tile_size")
+                        .long("tile-size")
+                        .value_name("PX")
+                        .help("Resizes the tiles to this size instead of the thumbnail one"),
+                )
+                .args(&rendering_args()),
+            SubCommand::with_name("export")
+                .about("Copies the original pictures of the tiles of a mosaic to a folder")
+                .arg(
+                    Arg::with_name("map")
+                        .help("Sets the path of the .map.json written by create --save-map")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("gallery_folder")
+                        .help("Sets the path of the gallery the mosaic was made from")
+                        .index(2)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("output_folder")
+                        .help("Sets the path of the folder receiving the pictures")
+                        .index(3)
+                        .required(true),
                 ),
-        ])
-        .get_matches();
+            SubCommand::with_name("diff")
+                .about("Lists the pictures added, removed or changed between two metadata files")
+                .arg(
+                    Arg::with_name("old")
+                        .help("Sets the path of the old mosaic.json, or of its folder")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("new")
+                        .help("Sets the path of the new mosaic.json, or of its folder")
+                        .index(2)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("threshold")
+                        .long("threshold")
+                        .value_name("distance")
+                        .help("Only reports the colors that moved by more than this distance")
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Prints the differences as JSON"),
+                ),
+            SubCommand::with_name("dedupe")
+                .about("Removes the preprocessed pictures of about the same color as another")
+                .arg(
+                    Arg::with_name("preprocessed_folder")
+                        .help("Sets the path of the folder with the preprocessed pictures")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("threshold")
+                        .long("threshold")
+                        .value_name("distance")
+                        .help("Sets the color distance up to which pictures are duplicates")
+                        .default_value("5"),
+                )
+                .arg(
+                    Arg::with_name("dry_run")
+                        .long("dry-run")
+                        .help("Prints the pictures that would be removed without removing them"),
+                ),
+            SubCommand::with_name("verify")
+                .about("Checks the preprocessed thumbnails against their metadata")
+                .arg(
+                    Arg::with_name("preprocessed_folder")
+                        .help("Sets the path of the folder with the preprocessed pictures")
+                        .index(1)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("tolerance")
+                        .long("tolerance")
+                        .value_name("distance")
+                        .help("Sets the color distance up to which a thumbnail keeps its color")
+                        .default_value("5"),
+                ),
+            SubCommand::with_name("combine")
+                .about("Arranges several mosaics in a contact sheet to compare them")
+                .arg(
+                    Arg::with_name("images")
+                        .help("Sets the paths of the mosaics")
+                        .index(1)
+                        .multiple(true)
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("output")
+                        .long("output")
+                        .value_name("out")
+                        .help("Sets the output path of the contact sheet")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("cols")
+                        .long("cols")
+                        .value_name("n")
+                        .help("Sets the number of mosaics per row")
+                        .default_value("2"),
+                )
+                .arg(
+                    Arg::with_name("padding")
+                        .long("padding")
+                        .value_name("PX")
+                        .help("Sets the gap between the mosaics")
+                        .default_value("0"),
+                )
+                .arg(
+                    Arg::with_name("background")
+                        .long("background")
+                        .value_name("RRGGBB")
+                        .help("Sets the color of the gap between the mosaics")
+                        .default_value("FFFFFF")
+                        .validator(|value| parse_color(&value).map(|_| ())),
+                ),
+        ]);
+    let matches = app.clone().get_matches();
+    set_log_level(&matches);
+    // Parsed again with the options of the config file, then with a seed if it gives none.
+    let mut args: Vec<OsString> = env::args_os().collect();
+    args.extend(config_file_args(&app, &matches));
+    let matches = app.clone().get_matches_from(args.clone());
+    let matches = match seed_arg(&app, &matches) {
+        Some(arg) => {
+            args.push(arg);
+            app.clone().get_matches_from(args)
+        }
+        None => matches,
+    };
+    let config = effective_config(&app, &matches);
+    if matches.is_present("print_config")
+        || matches
+            .subcommand()
+            .1
+            .is_some_and(|m| m.is_present("print_config"))
+    {
+        print!("{}", config);
+        return;
+    }
 
     match matches.subcommand() {
         ("preprocess", Some(cmd_matches)) => {
             let gallery_folder = Path::new(cmd_matches.value_of("gallery_folder").unwrap());
             let output_folder = Path::new(cmd_matches.value_of("output_folder").unwrap());
-            cmd_preprocess(gallery_folder, output_folder);
+            let options = PreprocessOptions {
+                histogram: cmd_matches.is_present("histogram"),
+                grayscale: cmd_matches.is_present("grayscale"),
+                thumbnail_format: cmd_matches.value_of("thumbnail_format").map(String::from),
+                resize_filter: cmd_matches.value_of("resize_filter").map(|v| match v {
+                    "nearest" => FilterType::Nearest,
+                    "bilinear" => FilterType::Triangle,
+                    _ => FilterType::Lanczos3,
+                }),
+                color_mode: match cmd_matches.value_of("color_mode") {
+                    Some("median") => ColorMode::Median,
+                    Some("dominant") => ColorMode::Dominant,
+                    _ => ColorMode::Mean,
+                },
+                metadata_format: match cmd_matches.value_of("metadata_format") {
+                    Some("ndjson") => MetadataFormat::Ndjson,
+                    Some("cbor") => MetadataFormat::Cbor,
+                    Some("messagepack") => MetadataFormat::MessagePack,
+                    _ => MetadataFormat::Json,
+                },
+                tile_fit: match cmd_matches.value_of("tile_fit") {
+                    Some("pad") => TileFit::Pad,
+                    _ => TileFit::Crop,
+                },
+                pad_color: cmd_matches
+                    .value_of("pad_color")
+                    .map_or([0, 0, 0, 255], |v| parse_color(v).unwrap().data),
+                tile_rotation: parse_arg(cmd_matches, "tile_rotate", 0),
+                skip_transparent: cmd_matches.is_present("skip_transparent"),
+                linear_light: !cmd_matches.is_present("no_linear_light"),
+                ignore_transparent: cmd_matches.is_present("ignore_transparent"),
+                filter: FileFilter {
+                    exclude: parse_patterns(cmd_matches, "exclude"),
+                    include: parse_patterns(cmd_matches, "include"),
+                },
+                walk: WalkOptions {
+                    skip_hidden: !cmd_matches.is_present("no_skip_hidden"),
+                    follow_symlinks: cmd_matches.is_present("follow_symlinks"),
+                    max_depth: if cmd_matches.is_present("max_depth") {
+                        Some(value_t!(cmd_matches, "max_depth", usize).unwrap_or_else(|e| e.exit()))
+                    } else {
+                        None
+                    },
+                },
+                extensions: cmd_matches.values_of("ext").map_or(Vec::new(), |values| {
+                    values
+                        .filter(|ext| !ext.is_empty())
+                        .map(|ext| ext.trim_start_matches('.').to_lowercase())
+                        .collect()
+                }),
+                max_file_size: cmd_matches
+                    .value_of("max_file_size")
+                    .map(|v| parse_size(v).unwrap()),
+                dedupe: if cmd_matches.is_present("dedupe") {
+                    Some(parse_arg(cmd_matches, "dedupe_distance", 4))
+                } else {
+                    None
+                },
+                min_dimension: if cmd_matches.is_present("min_dimension") {
+                    Some(value_t!(cmd_matches, "min_dimension", u32).unwrap_or_else(|e| e.exit()))
+                } else {
+                    None
+                },
+                contrast_adjustment: value_t!(cmd_matches, "contrast_adjustment", f32)
+                    .unwrap_or_else(|e| e.exit()),
+                saturation_boost: value_t!(cmd_matches, "saturation_boost", f32)
+                    .unwrap_or_else(|e| e.exit()),
+            };
+            if options.saturation_boost < 0.0 {
+                error!("--saturation-boost can't be negative");
+                process::exit(1);
+            }
+            cmd_preprocess(
+                gallery_folder,
+                output_folder,
+                &options,
+                cmd_matches.value_of("report").map(Path::new),
+                cmd_matches.value_of("db").map(Path::new),
+                cmd_matches.is_present("strict"),
+                (
+                    cmd_matches.is_present("dry_run"),
+                    cmd_matches.is_present("timings"),
+                ),
+            );
+            if !cmd_matches.is_present("dry_run") {
+                save_config(&config, &output_folder.join(CONFIG_FILENAME));
+            }
         }
         ("create", Some(cmd_matches)) => {
+            let gallery = Gallery {
+                folder: Path::new(cmd_matches.value_of("preprocessed_folder").unwrap()),
+                db: cmd_matches.value_of("db").map(Path::new),
+            };
+            let models: Vec<&str> = cmd_matches.values_of("model").unwrap().collect();
+            let output_image = Path::new(cmd_matches.value_of("output_image").unwrap());
+            let is_batch = models.len() > 1 || Path::new(models[0]).is_dir();
+            let models = expand_models(&models);
+            let options = parse_mosaic_options(cmd_matches);
+            let timings = Timings::new();
+            let output_images = if is_batch {
+                cmd_create_batch(
+                    gallery,
+                    &models,
+                    output_image,
+                    cmd_matches.value_of("output_template").unwrap(),
+                    &parse_outputs(cmd_matches, &timings),
+                    &parse_model_options(cmd_matches, &options),
+                    &options,
+                )
+            } else {
+                cmd_create(
+                    gallery,
+                    &models[0],
+                    output_image,
+                    &parse_outputs(cmd_matches, &timings),
+                    &parse_model_options(cmd_matches, &options),
+                    &options,
+                );
+                vec![output_image.to_owned()]
+            };
+            if cmd_matches.is_present("timings") {
+                timings.print("chunks");
+            }
+            if !cmd_matches.is_present("dry_run") {
+                for output_image in &output_images {
+                    let mut config_path = output_image.as_os_str().to_owned();
+                    config_path.push(".toml");
+                    save_config(&config, Path::new(&config_path));
+                }
+            }
+            if output_images.len() < models.len() {
+                process::exit(1);
+            }
+        }
+        ("plan", Some(cmd_matches)) => {
+            let gallery = Gallery {
+                folder: Path::new(cmd_matches.value_of("preprocessed_folder").unwrap()),
+                db: cmd_matches.value_of("db").map(Path::new),
+            };
+            let model = Path::new(cmd_matches.value_of("model").unwrap());
+            let plan = Path::new(cmd_matches.value_of("plan").unwrap());
+            let options = parse_mosaic_options(cmd_matches);
+            cmd_plan(
+                gallery,
+                model,
+                plan,
+                &parse_model_options(cmd_matches, &options),
+                &options,
+            );
+        }
+        ("render", Some(cmd_matches)) => {
+            let plan = Path::new(cmd_matches.value_of("plan").unwrap());
             let preprocessed_folder =
                 Path::new(cmd_matches.value_of("preprocessed_folder").unwrap());
-            let model = Path::new(cmd_matches.value_of("model").unwrap());
             let output_image = Path::new(cmd_matches.value_of("output_image").unwrap());
-            cmd_create(preprocessed_folder, model, output_image);
+            let tile_size = parse_arg(cmd_matches, "tile_size", mosaic::THUMBNAIL_SIZE);
+            if tile_size == 0 {
+                error!("--tile-size must be positive");
+                process::exit(1);
+            }
+            cmd_render(
+                plan,
+                preprocessed_folder,
+                output_image,
+                cmd_matches.value_of("model").map(Path::new),
+                tile_size,
+                // Without --timings, nothing is timed.
+                &parse_outputs(cmd_matches, &Timings::new()),
+                &parse_mosaic_options(cmd_matches),
+            );
+        }
+        ("export", Some(cmd_matches)) => {
+            let map = Path::new(cmd_matches.value_of("map").unwrap());
+            let gallery_folder = Path::new(cmd_matches.value_of("gallery_folder").unwrap());
+            let output_folder = Path::new(cmd_matches.value_of("output_folder").unwrap());
+            cmd_export(map, gallery_folder, output_folder);
+        }
+        ("diff", Some(cmd_matches)) => {
+            let metadata_path = |name| {
+                let path = PathBuf::from(cmd_matches.value_of(name).unwrap());
+                if path.is_dir() {
+                    mosaic::metadata_path(&path)
+                } else {
+                    path
+                }
+            };
+            cmd_diff(
+                &metadata_path("old"),
+                &metadata_path("new"),
+                parse_arg(cmd_matches, "threshold", 0),
+                cmd_matches.is_present("json"),
+            );
+        }
+        ("dedupe", Some(cmd_matches)) => {
+            cmd_dedupe(
+                Path::new(cmd_matches.value_of("preprocessed_folder").unwrap()),
+                parse_arg(cmd_matches, "threshold", 0),
+                cmd_matches.is_present("dry_run"),
+            );
+        }
+        ("verify", Some(cmd_matches)) => {
+            cmd_verify(
+                Path::new(cmd_matches.value_of("preprocessed_folder").unwrap()),
+                parse_arg(cmd_matches, "tolerance", 5),
+            );
+        }
+        ("combine", Some(cmd_matches)) => {
+            let images: Vec<_> = cmd_matches
+                .values_of("images")
+                .unwrap()
+                .map(Path::new)
+                .collect();
+            let output = Path::new(cmd_matches.value_of("output").unwrap());
+            let columns = parse_arg(cmd_matches, "cols", 2);
+            if columns == 0 {
+                error!("--cols must be positive");
+                process::exit(1);
+            }
+            cmd_combine(
+                &images,
+                output,
+                columns,
+                parse_arg(cmd_matches, "padding", 0),
+                parse_color(cmd_matches.value_of("background").unwrap()).unwrap(),
+            );
         }
         _ => panic!(),
     }
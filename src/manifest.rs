@@ -0,0 +1,125 @@
+//! Manifest listing which picture goes in every cell of a mosaic, e.g. to assemble it from
+//! printed photos.
+
+use crate::error::MosaicError;
+use serde_derive::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::path::Path;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Manifest {
+    pub rows: u32,
+    pub columns: u32,
+    pub cells: Vec<ManifestCell>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ManifestCell {
+    pub row: u32,
+    pub column: u32,
+    /// Rectangle of the tile in the output image, in pixels.
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    /// Path of the thumbnail, relative to the preprocessed folder.
+    pub path: String,
+    /// Path of the original picture, if it was recorded during preprocessing.
+    pub source: Option<String>,
+    /// Clockwise rotation of the picture in degrees, 0, 90, 180 or 270.
+    #[serde(default)]
+    pub rotation: u32,
+    /// Whether the picture is mirrored left to right, before being rotated.
+    #[serde(default)]
+    pub mirrored: bool,
+    /// Distance between the color of the chunk and the color of the picture.
+    pub distance: u32,
+}
+
+impl Manifest {
+    pub fn save_json(&self, path: &Path) -> Result<(), MosaicError> {
+        self.write_json(BufWriter::new(File::create(path)?))
+    }
+
+    pub fn write_json<W: Write>(&self, writer: W) -> Result<(), MosaicError> {
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+
+    pub fn save_csv(&self, path: &Path) -> Result<(), MosaicError> {
+        self.write_csv(BufWriter::new(File::create(path)?))
+    }
+
+    pub fn write_csv<W: Write>(&self, mut writer: W) -> Result<(), MosaicError> {
+        writeln!(
+            writer,
+            "row,column,x,y,width,height,path,source,distance,rotation,mirrored"
+        )?;
+        for cell in &self.cells {
+            writeln!(
+                writer,
+                "{},{},{},{},{},{},{},{},{},{},{}",
+                cell.row,
+                cell.column,
+                cell.x,
+                cell.y,
+                cell.width,
+                cell.height,
+                csv_field(&cell.path),
+                csv_field(cell.source.as_ref().map_or("", String::as_str)),
+                cell.distance,
+                cell.rotation,
+                cell.mirrored
+            )?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// Tile placed in a cell of the mosaic, as saved in the `.map.json` sidecar.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct MapCell {
+    pub path: String,
+    /// Color of the model chunk the tile replaces.
+    pub target_color: [u8; 3],
+    /// Color of the placed tile.
+    pub placed_color: [u8; 3],
+    /// Whether the cell is left out by a mask, the tile not being shown.
+    #[serde(default)]
+    pub masked: bool,
+    /// Paths of the tiles of the sub-cells of a split cell, in row-major order, shown instead
+    /// of its tile.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<String>,
+    /// Number of cells per side the tile covers from this one, 0 for the cells covered by the
+    /// tile of another one.
+    #[serde(
+        default = "crate::plan::default_span",
+        skip_serializing_if = "crate::plan::is_single_span"
+    )]
+    pub span: u32,
+}
+
+/// Rows of placed tiles.
+pub type TileMap = Vec<Vec<MapCell>>;
+
+pub fn save_tile_map(map: &[Vec<MapCell>], path: &Path) -> Result<(), MosaicError> {
+    let writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer(writer, map)?;
+    Ok(())
+}
+
+pub fn load_tile_map(path: &Path) -> Result<TileMap, MosaicError> {
+    let reader = BufReader::new(File::open(path)?);
+    Ok(serde_json::from_reader(reader)?)
+}
@@ -0,0 +1,2326 @@
+//! Matching of the chunks of a model with the pictures of a gallery.
+
+use crate::color::{
+    center_weighted_pixels, compute_contrast, compute_histogram, compute_main_color,
+    compute_median_color, luma, luma_709, rgb_to_hsv, ColorStats,
+};
+use crate::error::MosaicError;
+use crate::log::{self, Level};
+use crate::metadata::ProcessedPicture;
+use crate::plan::{Plan, PlanCell};
+use crate::preprocess::{ColorMode, PALETTE_SIZE};
+use crate::rng::SmallRng;
+use crate::{compute_ratio, palette, ratio_to_dim, CHUNK_SIZE, THUMBNAIL_SIZE};
+use crate::{debug, trace};
+use image::{imageops, DynamicImage, GenericImageView, ImageBuffer, Luma, Rgba};
+use rayon::prelude::*;
+use serde_derive::{Deserialize, Serialize};
+use std::cmp;
+use std::collections::HashMap;
+use std::time::Instant;
+
+/// Value of the channels of the padding of `FillMode::Pad`.
+const NEUTRAL_GRAY: u8 = 128;
+/// Weights of the differences of hue, saturation and value in `color_distance_hsv`.
+const HSV_WEIGHTS: [f32; 3] = [4.0, 2.0, 1.0];
+/// Number of times at most the grid is swept for swaps with `MosaicOptions::two_pass`.
+const MAX_COHERENCE_SWEEPS: usize = 8;
+/// Number of rows and columns of sub-cells a cell is split in at most, as many as the pixels
+/// of the side of a model chunk.
+pub const MAX_SPLIT_FACTOR: u32 = 8;
+/// Depth of the quadtree of `MosaicOptions::adaptive` at most, merging blocks of up to 16 cells
+/// per side.
+pub const MAX_ADAPTIVE_DEPTH: u32 = 4;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MatchMode {
+    Color,
+    Histogram,
+    /// Brightness only, so that the tiles keep the value structure of the model whatever their
+    /// hue.
+    Luminance,
+    /// Hue first, saturation then and brightness barely, for colorful mosaics.
+    Hsv,
+}
+
+/// How a model whose dimensions aren't multiples of the chunk dimensions is made so, rather
+/// than the chunks past its right and bottom edges being dropped.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FillMode {
+    /// Trims the model evenly on both sides.
+    Crop,
+    /// Extends the model evenly on both sides with neutral gray.
+    Pad,
+    /// Resizes the model to the closest multiples.
+    Stretch,
+}
+
+/// What the cells left out by a mask show instead of a tile.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MaskFill {
+    /// The model scaled to the mosaic, which needs the model when rendering.
+    Model,
+    Transparent,
+}
+
+/// How the cells of a mosaic, and the chunks of the model they are matched with, are laid out.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Layout {
+    /// Rows and columns of rectangles.
+    #[default]
+    Grid,
+    /// Honeycomb of hexagons pointy on top and bottom, inscribed in the cells. The odd rows are
+    /// shifted right by half a cell, and each row fits in the corners of the one above, a
+    /// quarter of a cell higher than in a grid.
+    Hex,
+    /// Rows of rectangles like brickwork, the odd rows shifted right by half a cell. The last
+    /// cell of an odd row goes past the right side, and the rest of its tile wraps around to
+    /// fill the half cell left on the left side.
+    Brick,
+}
+
+impl Layout {
+    /// Vertical distance between the tops of two rows of cells `cell_h` high, without spacing.
+    pub fn row_pitch(self, cell_h: u32) -> u32 {
+        match self {
+            Layout::Grid | Layout::Brick => cell_h,
+            Layout::Hex => cell_h - cell_h / 4,
+        }
+    }
+
+    /// Position of the cell at `col` and `row` from the top-left corner of the first cell,
+    /// with cells of `cell_dim` and `gap` pixels between them.
+    fn cell_offset(self, col: usize, row: usize, cell_dim: (u32, u32), gap: u32) -> (u32, u32) {
+        let shift = match self {
+            Layout::Hex | Layout::Brick if !row.is_multiple_of(2) => (cell_dim.0 + gap) / 2,
+            _ => 0,
+        };
+        (
+            col as u32 * (cell_dim.0 + gap) + shift,
+            row as u32 * (self.row_pitch(cell_dim.1) + gap),
+        )
+    }
+
+    /// Number of columns and rows of cells of `cell_dim` fitting in `dims`, the shifted rows
+    /// of hexagons included.
+    fn grid(self, dims: (u32, u32), cell_dim: (u32, u32)) -> (usize, usize) {
+        let (cols, rows) = match self {
+            Layout::Grid | Layout::Brick => (dims.0 / cell_dim.0, dims.1 / cell_dim.1),
+            Layout::Hex if dims.1 < cell_dim.1 => (0, 0),
+            Layout::Hex => (
+                dims.0.saturating_sub(cell_dim.0 / 2) / cell_dim.0,
+                (dims.1 - cell_dim.1) / self.row_pitch(cell_dim.1) + 1,
+            ),
+        };
+        (cols as usize, rows as usize)
+    }
+}
+
+/// `(x, y, w, h)` of a rectangle in pixels.
+pub type Rect = (u32, u32, u32, u32);
+
+/// Rectangle of the model, in pixels once cropped and resized, whose cells are split in
+/// `factor` rows and columns of finer tiles, for more detail on a face or a subject.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct RegionOfInterest {
+    /// Region whose overlapped cells are split.
+    pub rect: Rect,
+    pub factor: u32,
+}
+
+/// Transformations applied to the model before it is cut into chunks.
+pub struct ModelOptions {
+    pub crop: Option<(u32, u32, u32, u32)>,
+    pub grayscale: bool,
+    /// Whether to match the tiles against the complementary colors of the model.
+    pub invert: bool,
+    pub fill_mode: FillMode,
+    /// Dimensions the model is shrunk to fit in, keeping its aspect ratio, to match the tiles
+    /// of a huge model faster.
+    pub max_size: Option<(u32, u32)>,
+    /// Number of chunks the model is shrunk to have at most.
+    pub max_tiles: Option<u32>,
+    /// Columns and rows of chunks the model is cropped to the aspect ratio of, then resized to,
+    /// such as the grid of `grid_for_size`.
+    pub grid: Option<(u32, u32)>,
+}
+
+/// Options driving how the mosaic is matched and assembled. Built with `MosaicBuilder` to
+/// check they go together, and serialized with the fields left out taking their default.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct MosaicOptions {
+    pub match_mode: MatchMode,
+    /// Whether the contrast of the pictures is matched with the one of the chunks too, so that
+    /// busy pictures go to detailed chunks rather than flat ones.
+    pub match_variance: bool,
+    pub dither: bool,
+    /// Whether the matched pictures are then swapped between neighbour cells to smooth the
+    /// transitions between the tiles.
+    pub two_pass: bool,
+    /// Whether the tiles are randomly rotated for variety.
+    pub allow_rotation: bool,
+    /// Whether the tiles are randomly mirrored for variety.
+    pub allow_mirroring: bool,
+    /// How the color of a model chunk is computed, which should be the way the colors of the
+    /// gallery pictures were for them to compare. The mean if `None`.
+    pub chunk_color: Option<ColorMode>,
+    /// Whether the pixels at the center of a model chunk weigh more in its color.
+    pub center_weighted: bool,
+    /// Pixels on each side of a model chunk sampled with it for its color, to soften the
+    /// transitions between the tiles. The chunks themselves don't overlap.
+    pub chunk_overlap: u32,
+    /// Aspect ratio of the tiles, and of the model chunks they are matched with.
+    pub tile_ratio: (u32, u32),
+    /// Whether the colors of the model chunks are averaged in linear light, as they must be if
+    /// the gallery was.
+    pub linear_light: bool,
+    /// Gap in pixels between the tiles and around the mosaic.
+    pub spacing: u32,
+    /// RGBA color of the spacing.
+    pub spacing_color: [u8; 4],
+    /// Shape and arrangement of the cells. The background around the hexagons of
+    /// `Layout::Hex` has the color of the spacing.
+    pub layout: Layout,
+    /// Width and RGBA color of the border drawn around each tile.
+    pub grout: Option<(u32, [u8; 4])>,
+    /// RGBA color the transparent thumbnails are composited over, pasted as is if `None`.
+    pub tile_background: Option<[u8; 4]>,
+    /// Color distance between a tile and its chunk above which, and RGBA color with which, the
+    /// cell is filled instead, rather than showing a tile far from the model.
+    pub fallback: Option<(u32, [u8; 4])>,
+    /// Opacity, between 0 and 1, of the tiles over `opacity_background`, for a faded mosaic.
+    pub tile_opacity: f32,
+    /// RGBA color showing through the tiles when `tile_opacity` is below 1.
+    pub opacity_background: [u8; 4],
+    /// Darkening, between 0 and 1, of the corners of each tile, growing linearly with the
+    /// distance to its center, left as is.
+    pub vignette: f32,
+    /// Number of closest pictures among which a tile is randomly picked.
+    pub randomize_top_k: usize,
+    /// Number of tiles each picture can be used for at most, for variety, unlimited if `None`.
+    /// The pictures are then matched cell after cell in row-major order, so the last cells get
+    /// what is left. If the gallery is too small for the grid, the uses start over once all
+    /// the pictures are used up. The merged and split tiles aren't counted, nor the cells left
+    /// out by `match_unmasked_tiles`.
+    pub max_uses: Option<u32>,
+    /// Seed of all the random choices, so that the same inputs and seed give the same mosaic.
+    /// Taken from the current time if `None`.
+    pub seed: Option<u64>,
+    /// How much farther from its chunk than the newly matched picture the tile a cell had in
+    /// the previous frame of an animated model can be and still be kept, 0.5 meaning 50%
+    /// farther. The tiles of the chunks that barely changed are so kept, against flicker,
+    /// while the ones of the chunks that changed a lot are far and replaced.
+    pub temporal_stability: f32,
+    /// What the cells left out by `mask_tiles` show.
+    pub mask_fill: MaskFill,
+    /// Opacity, between 0 and 1, of the model overlaid on the assembled mosaic.
+    pub ghost: f32,
+    /// Width in pixels, on each side of the seams between the tiles, of the blend with the
+    /// neighbour tile.
+    pub feather_edges: u32,
+    /// Contrast adjustment the gallery is expected to be preprocessed with.
+    pub expected_contrast_adjustment: Option<f32>,
+    /// Whether to print how well the colors of the gallery cover the ones of the model.
+    pub report_colors: bool,
+    /// Colors of a reference the colors of the chunks are shifted toward before matching.
+    pub tone_map: Option<ToneMap>,
+    /// Colors the tiles are reduced to when pasted, the chunks being matched by luma only.
+    /// The colors of the pictures given to the matching are expected reduced to gray with
+    /// `Tone::Gray` too.
+    pub tone: Option<Tone>,
+    /// Regions of the model whose cells are split in finer tiles.
+    pub regions_of_interest: Vec<RegionOfInterest>,
+    /// Depth of a quadtree over the grid and variance of the colors of the model under which
+    /// its blocks of 2, 4, ... cells per side are merged in a single tile, for large tiles on
+    /// the flat areas and small ones on the detailed areas.
+    pub adaptive: Option<(u32, f32)>,
+}
+
+impl Default for MosaicOptions {
+    fn default() -> MosaicOptions {
+        MosaicOptions {
+            match_mode: MatchMode::Color,
+            match_variance: false,
+            dither: false,
+            two_pass: false,
+            allow_rotation: false,
+            allow_mirroring: false,
+            chunk_color: None,
+            center_weighted: false,
+            chunk_overlap: 0,
+            tile_ratio: (1, 1),
+            linear_light: true,
+            spacing: 0,
+            spacing_color: [255, 255, 255, 255],
+            layout: Layout::Grid,
+            grout: None,
+            tile_background: None,
+            fallback: None,
+            tile_opacity: 1.0,
+            opacity_background: [255, 255, 255, 255],
+            vignette: 0.0,
+            randomize_top_k: 1,
+            max_uses: None,
+            seed: None,
+            temporal_stability: 0.0,
+            mask_fill: MaskFill::Model,
+            ghost: 0.0,
+            feather_edges: 0,
+            expected_contrast_adjustment: None,
+            report_colors: false,
+            tone_map: None,
+            tone: None,
+            regions_of_interest: Vec::new(),
+            adaptive: None,
+        }
+    }
+}
+
+/// Fluent construction of `MosaicOptions`, each setter setting the field of the same name.
+/// `build` checks the combination, so that e.g. an opacity out of range is reported with the
+/// options rather than showing up in the rendered mosaic.
+#[derive(Default)]
+pub struct MosaicBuilder {
+    options: MosaicOptions,
+}
+
+impl MosaicBuilder {
+    pub fn new() -> MosaicBuilder {
+        MosaicBuilder::default()
+    }
+
+    /// Starts from `options`, such as ones read from a file, to override some of them.
+    pub fn from_options(options: MosaicOptions) -> MosaicBuilder {
+        MosaicBuilder { options }
+    }
+
+    pub fn match_mode(mut self, match_mode: MatchMode) -> MosaicBuilder {
+        self.options.match_mode = match_mode;
+        self
+    }
+
+    pub fn match_variance(mut self, match_variance: bool) -> MosaicBuilder {
+        self.options.match_variance = match_variance;
+        self
+    }
+
+    pub fn dither(mut self, dither: bool) -> MosaicBuilder {
+        self.options.dither = dither;
+        self
+    }
+
+    pub fn two_pass(mut self, two_pass: bool) -> MosaicBuilder {
+        self.options.two_pass = two_pass;
+        self
+    }
+
+    pub fn allow_rotation(mut self, allow_rotation: bool) -> MosaicBuilder {
+        self.options.allow_rotation = allow_rotation;
+        self
+    }
+
+    pub fn allow_mirroring(mut self, allow_mirroring: bool) -> MosaicBuilder {
+        self.options.allow_mirroring = allow_mirroring;
+        self
+    }
+
+    pub fn chunk_color(mut self, chunk_color: Option<ColorMode>) -> MosaicBuilder {
+        self.options.chunk_color = chunk_color;
+        self
+    }
+
+    pub fn center_weighted(mut self, center_weighted: bool) -> MosaicBuilder {
+        self.options.center_weighted = center_weighted;
+        self
+    }
+
+    pub fn chunk_overlap(mut self, chunk_overlap: u32) -> MosaicBuilder {
+        self.options.chunk_overlap = chunk_overlap;
+        self
+    }
+
+    pub fn tile_ratio(mut self, tile_ratio: (u32, u32)) -> MosaicBuilder {
+        self.options.tile_ratio = tile_ratio;
+        self
+    }
+
+    pub fn linear_light(mut self, linear_light: bool) -> MosaicBuilder {
+        self.options.linear_light = linear_light;
+        self
+    }
+
+    pub fn spacing(mut self, spacing: u32) -> MosaicBuilder {
+        self.options.spacing = spacing;
+        self
+    }
+
+    pub fn spacing_color(mut self, spacing_color: [u8; 4]) -> MosaicBuilder {
+        self.options.spacing_color = spacing_color;
+        self
+    }
+
+    pub fn grout(mut self, grout: Option<(u32, [u8; 4])>) -> MosaicBuilder {
+        self.options.grout = grout;
+        self
+    }
+
+    pub fn tile_background(mut self, tile_background: Option<[u8; 4]>) -> MosaicBuilder {
+        self.options.tile_background = tile_background;
+        self
+    }
+
+    pub fn fallback(mut self, fallback: Option<(u32, [u8; 4])>) -> MosaicBuilder {
+        self.options.fallback = fallback;
+        self
+    }
+
+    pub fn tile_opacity(mut self, tile_opacity: f32) -> MosaicBuilder {
+        self.options.tile_opacity = tile_opacity;
+        self
+    }
+
+    pub fn opacity_background(mut self, opacity_background: [u8; 4]) -> MosaicBuilder {
+        self.options.opacity_background = opacity_background;
+        self
+    }
+
+    pub fn vignette(mut self, vignette: f32) -> MosaicBuilder {
+        self.options.vignette = vignette;
+        self
+    }
+
+    pub fn randomize_top_k(mut self, randomize_top_k: usize) -> MosaicBuilder {
+        self.options.randomize_top_k = randomize_top_k;
+        self
+    }
+
+    pub fn max_uses(mut self, max_uses: Option<u32>) -> MosaicBuilder {
+        self.options.max_uses = max_uses;
+        self
+    }
+
+    pub fn seed(mut self, seed: Option<u64>) -> MosaicBuilder {
+        self.options.seed = seed;
+        self
+    }
+
+    pub fn temporal_stability(mut self, temporal_stability: f32) -> MosaicBuilder {
+        self.options.temporal_stability = temporal_stability;
+        self
+    }
+
+    pub fn mask_fill(mut self, mask_fill: MaskFill) -> MosaicBuilder {
+        self.options.mask_fill = mask_fill;
+        self
+    }
+
+    pub fn ghost(mut self, ghost: f32) -> MosaicBuilder {
+        self.options.ghost = ghost;
+        self
+    }
+
+    pub fn layout(mut self, layout: Layout) -> MosaicBuilder {
+        self.options.layout = layout;
+        self
+    }
+
+    pub fn feather_edges(mut self, feather_edges: u32) -> MosaicBuilder {
+        self.options.feather_edges = feather_edges;
+        self
+    }
+
+    pub fn expected_contrast_adjustment(mut self, contrast: Option<f32>) -> MosaicBuilder {
+        self.options.expected_contrast_adjustment = contrast;
+        self
+    }
+
+    pub fn report_colors(mut self, report_colors: bool) -> MosaicBuilder {
+        self.options.report_colors = report_colors;
+        self
+    }
+
+    pub fn tone_map(mut self, tone_map: Option<ToneMap>) -> MosaicBuilder {
+        self.options.tone_map = tone_map;
+        self
+    }
+
+    pub fn tone(mut self, tone: Option<Tone>) -> MosaicBuilder {
+        self.options.tone = tone;
+        self
+    }
+
+    pub fn regions_of_interest(mut self, regions: Vec<RegionOfInterest>) -> MosaicBuilder {
+        self.options.regions_of_interest = regions;
+        self
+    }
+
+    pub fn adaptive(mut self, adaptive: Option<(u32, f32)>) -> MosaicBuilder {
+        self.options.adaptive = adaptive;
+        self
+    }
+
+    /// Returns the options, or why they don't go together.
+    pub fn build(self) -> Result<MosaicOptions, String> {
+        let options = self.options;
+        if options.tile_ratio.0 == 0 || options.tile_ratio.1 == 0 {
+            return Err(format!(
+                "invalid tile ratio {}:{}, both sides must be positive",
+                options.tile_ratio.0, options.tile_ratio.1
+            ));
+        }
+        if options.randomize_top_k == 0 {
+            return Err("the tiles must be picked among at least 1 picture".to_string());
+        }
+        if options.max_uses == Some(0) {
+            return Err("each picture must be usable for at least 1 tile".to_string());
+        }
+        if !(options.temporal_stability >= 0.0 && options.temporal_stability.is_finite()) {
+            return Err(format!(
+                "the temporal stability must be positive, got {}",
+                options.temporal_stability
+            ));
+        }
+        for roi in &options.regions_of_interest {
+            if !(2..=MAX_SPLIT_FACTOR).contains(&roi.factor) {
+                return Err(format!(
+                    "the cells of a region of interest must be split from 2 to {} times, got {}",
+                    MAX_SPLIT_FACTOR, roi.factor
+                ));
+            }
+            if roi.rect.2 == 0 || roi.rect.3 == 0 {
+                return Err("a region of interest must be at least a pixel wide and high".into());
+            }
+        }
+        if let Some((depth, threshold)) = options.adaptive {
+            if !(1..=MAX_ADAPTIVE_DEPTH).contains(&depth) {
+                return Err(format!(
+                    "the depth of the quadtree must be from 1 to {}, got {}",
+                    MAX_ADAPTIVE_DEPTH, depth
+                ));
+            }
+            if !(threshold >= 0.0 && threshold.is_finite()) {
+                return Err(format!(
+                    "the variance threshold must be positive, got {}",
+                    threshold
+                ));
+            }
+            if options.feather_edges > 0 {
+                return Err(
+                    "the seams feathered between the cells would run through the merged tiles"
+                        .to_string(),
+                );
+            }
+        }
+        if !(0.0..=1.0).contains(&options.ghost) {
+            return Err(format!(
+                "the ghost opacity must be between 0 and 1, got {}",
+                options.ghost
+            ));
+        }
+        if !(0.0..=1.0).contains(&options.tile_opacity) {
+            return Err(format!(
+                "the tile opacity must be between 0 and 1, got {}",
+                options.tile_opacity
+            ));
+        }
+        if !(0.0..=1.0).contains(&options.vignette) {
+            return Err(format!(
+                "the vignette must be between 0 and 1, got {}",
+                options.vignette
+            ));
+        }
+        if options.tone_map.is_some() && options.match_mode == MatchMode::Histogram {
+            return Err(
+                "the tone map shifts the colors of the chunks, not their histograms".to_string(),
+            );
+        }
+        if options.tone.is_some() && options.match_mode == MatchMode::Histogram {
+            return Err("the tone matches the pictures by luma, not by histogram".to_string());
+        }
+        if options.layout != Layout::Grid {
+            if options.grout.is_some() || options.feather_edges > 0 {
+                return Err(
+                    "the grout and the feathered edges follow the cells of a grid".to_string(),
+                );
+            }
+            if options.adaptive.is_some() || !options.regions_of_interest.is_empty() {
+                return Err("only the cells of a grid can be merged or split".to_string());
+            }
+        }
+        if options.feather_edges > 0 && options.spacing > 0 {
+            return Err(
+                "feathered edges can't be used with spacing, the tiles aren't adjacent".to_string(),
+            );
+        }
+        Ok(options)
+    }
+}
+
+/// Mean and standard deviation of each channel of a reference image, that the colors of a
+/// model are mapped to as in Reinhard's color transfer, to give the mosaic the tone of the
+/// reference without regrading the model.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct ToneMap {
+    pub mean: [f32; 3],
+    pub std_dev: [f32; 3],
+}
+
+impl ToneMap {
+    pub fn from_reference(reference: &DynamicImage) -> ToneMap {
+        let (mean, std_dev) = channel_statistics(&reference.to_rgba());
+        ToneMap { mean, std_dev }
+    }
+
+    /// Shifts and scales each channel of `colors`, the colors of the chunks of `model`, so that
+    /// the pixels of the model would have the mean and standard deviation of the reference.
+    /// The transform is affine, so applying it to the averages of the chunks is the same as
+    /// averaging the transformed pixels. A flat channel of the model is only shifted.
+    fn apply(&self, model: &DynamicImage, colors: &mut [[u8; 3]]) {
+        let (mean, std_dev) = channel_statistics(&model.to_rgba());
+        for color in colors {
+            for c in 0..3 {
+                let scale = if std_dev[c] > 1.0 {
+                    self.std_dev[c] / std_dev[c]
+                } else {
+                    1.0
+                };
+                let value = (f32::from(color[c]) - mean[c]) * scale + self.mean[c];
+                color[c] = value.round().clamp(0.0, 255.0) as u8;
+            }
+        }
+    }
+}
+
+/// Colors of a black and white or two-color mosaic, the tiles being reduced to their luma.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Tone {
+    Gray,
+    /// Ramp from a dark to a light RGB color, black being mapped to the first and white to the
+    /// second.
+    Duotone([u8; 3], [u8; 3]),
+}
+
+impl Tone {
+    /// Color `c` takes, picked by its Rec. 709 luma.
+    pub fn apply(self, c: [u8; 3]) -> [u8; 3] {
+        let y = luma_709(c);
+        match self {
+            Tone::Gray => [y.round() as u8; 3],
+            Tone::Duotone(dark, light) => [0, 1, 2].map(|i| {
+                let (dark, light) = (f64::from(dark[i]), f64::from(light[i]));
+                (dark + (light - dark) * y / 255.0).round() as u8
+            }),
+        }
+    }
+}
+
+/// Applies `options.tone_map`, then reduces to gray with `options.tone`, the colors of chunks
+/// of `model`.
+fn adjust_chunk_colors(model: &DynamicImage, colors: &mut [[u8; 3]], options: &MosaicOptions) {
+    if let Some(tone_map) = &options.tone_map {
+        tone_map.apply(model, colors);
+    }
+    if options.tone.is_some() {
+        for color in colors {
+            *color = Tone::Gray.apply(*color);
+        }
+    }
+}
+
+/// Mean and standard deviation of each channel of `img`, the pixels weighted by their alpha.
+fn channel_statistics(img: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> ([f32; 3], [f32; 3]) {
+    let mean = compute_main_color(img, false, false).map(f32::from);
+    let mut variance = [0.0; 3];
+    let mut total_weight = 0.0;
+    for pixel in img.pixels() {
+        let weight = f32::from(pixel.data[3]);
+        for c in 0..3 {
+            variance[c] += weight * (f32::from(pixel.data[c]) - mean[c]).powi(2);
+        }
+        total_weight += weight;
+    }
+    let std_dev = variance.map(|v| (v / total_weight.max(1.0)).sqrt());
+    (mean, std_dev)
+}
+
+/// Picture chosen for a chunk of the model.
+#[non_exhaustive]
+pub struct PlacedTile<'a> {
+    pub pic: &'a ProcessedPicture,
+    pub target_color: [u8; 3],
+    /// Clockwise rotation of the thumbnail in degrees, 0, 90, 180 or 270.
+    pub rotation: u32,
+    /// Whether the thumbnail is mirrored left to right, before being rotated.
+    pub mirrored: bool,
+    /// Whether the cell is left out by a mask, showing `MosaicOptions::mask_fill` rather than
+    /// its picture.
+    pub masked: bool,
+    /// Tiles of the sub-cells of the cell, as many rows as columns of them in row-major order,
+    /// shown rather than its picture if it was split for more detail.
+    pub details: Vec<PlacedTile<'a>>,
+    /// Number of cells per side the tile covers from its cell down and right, more than 1 if
+    /// it was merged over a flat block, and 0 for the other cells of the block.
+    pub span: u32,
+}
+
+impl<'a> PlacedTile<'a> {
+    /// Number of rows, and of columns, of sub-cells the cell is split in, 1 if it isn't.
+    pub fn split_factor(&self) -> u32 {
+        cmp::max((self.details.len() as f64).sqrt().round() as u32, 1)
+    }
+
+    /// Whether the cell is filled with the `MosaicOptions::fallback` color, its picture being
+    /// too far from its chunk.
+    pub fn falls_back(&self, options: &MosaicOptions) -> bool {
+        options.fallback.is_some_and(|(max_distance, _)| {
+            color_distance(self.target_color, self.pic.color_rgb) > max_distance
+        })
+    }
+
+    fn to_plan_cell(&self) -> PlanCell {
+        PlanCell {
+            path: self.pic.path.clone(),
+            target_color: self.target_color,
+            rotation: self.rotation,
+            mirrored: self.mirrored,
+            masked: self.masked,
+            details: self.details.iter().map(PlacedTile::to_plan_cell).collect(),
+            span: self.span,
+        }
+    }
+
+    fn from_plan_cell(
+        cell: &PlanCell,
+        pics_by_path: &HashMap<&str, &'a ProcessedPicture>,
+    ) -> Result<PlacedTile<'a>, MosaicError> {
+        if !cell.rotation.is_multiple_of(90) || cell.rotation >= 360 {
+            return Err(MosaicError::Invalid(format!(
+                "invalid rotation {} of {}, expected 0, 90, 180 or 270",
+                cell.rotation, cell.path
+            )));
+        }
+        let factor = (cell.details.len() as f64).sqrt().round() as usize;
+        if !cell.details.is_empty()
+            && (factor * factor != cell.details.len()
+                || !(2..=MAX_SPLIT_FACTOR as usize).contains(&factor))
+        {
+            return Err(MosaicError::Invalid(format!(
+                "the cell of {} has {} sub-cells, expected a square number from 4 to {}",
+                cell.path,
+                cell.details.len(),
+                MAX_SPLIT_FACTOR * MAX_SPLIT_FACTOR
+            )));
+        }
+        match pics_by_path.get(cell.path.as_str()) {
+            Some(pic) => Ok(PlacedTile {
+                pic,
+                target_color: cell.target_color,
+                rotation: cell.rotation,
+                mirrored: cell.mirrored,
+                masked: cell.masked,
+                details: (cell.details.iter())
+                    .map(|detail| PlacedTile::from_plan_cell(detail, pics_by_path))
+                    .collect::<Result<_, _>>()?,
+                span: cell.span,
+            }),
+            None => Err(MosaicError::MissingTile(cell.path.clone())),
+        }
+    }
+}
+
+/// Pictures matched against the chunks of a model, in row-major order.
+#[non_exhaustive]
+pub struct Placement<'a> {
+    pub grid_width: usize,
+    pub grid_height: usize,
+    pub layout: Layout,
+    pub thumb_dim: (u32, u32),
+    pub tiles: Vec<PlacedTile<'a>>,
+}
+
+impl<'a> Placement<'a> {
+    /// Dimensions of a cell, made of a tile and its grout.
+    pub fn cell_dimensions(&self, options: &MosaicOptions) -> (u32, u32) {
+        let grout = options.grout.map_or(0, |(width, _)| width);
+        (self.thumb_dim.0 + 2 * grout, self.thumb_dim.1 + 2 * grout)
+    }
+
+    /// Dimensions of the mosaic, with `options.spacing` pixels between and around the cells.
+    pub fn dimensions(&self, options: &MosaicOptions) -> (u32, u32) {
+        let spacing = options.spacing;
+        let cell_dim = self.cell_dimensions(options);
+        // The bottom corners of the last row of hexagons, and the half cell the odd rows are
+        // shifted by.
+        let overhang = cell_dim.1 - self.layout.row_pitch(cell_dim.1);
+        let shift = match self.layout {
+            Layout::Hex if self.grid_height > 1 => (cell_dim.0 + spacing) / 2,
+            _ => 0,
+        };
+        (
+            self.grid_width as u32 * (cell_dim.0 + spacing) + spacing + shift,
+            self.grid_height as u32 * (self.layout.row_pitch(cell_dim.1) + spacing)
+                + spacing
+                + overhang,
+        )
+    }
+
+    /// Height of the bands a mosaic is written in when it doesn't fit in memory, from the top
+    /// of a row of cells to the top of the next one.
+    pub fn band_height(&self, options: &MosaicOptions) -> u32 {
+        self.layout.row_pitch(self.cell_dimensions(options).1) + options.spacing
+    }
+
+    /// Position of the top-left corner of the `i`-th cell in the mosaic, of the rectangle of
+    /// its hexagon with `Layout::Hex`. The last cell of an odd row of `Layout::Brick` goes
+    /// past the right side of the mosaic.
+    pub fn cell_position(&self, i: usize, options: &MosaicOptions) -> (u32, u32) {
+        let spacing = options.spacing;
+        let (col, row) = (i % self.grid_width, i / self.grid_width);
+        let offset = (self.layout).cell_offset(col, row, self.cell_dimensions(options), spacing);
+        (spacing + offset.0, spacing + offset.1)
+    }
+
+    /// Position of the top-left corner of the `i`-th tile in the mosaic, inside its grout.
+    pub fn tile_position(&self, i: usize, options: &MosaicOptions) -> (u32, u32) {
+        let grout = options.grout.map_or(0, |(width, _)| width);
+        let (x, y) = self.cell_position(i, options);
+        (x + grout, y + grout)
+    }
+
+    /// Rectangle of the `i`-th cell in the mosaic, with its grout, over the cells its tile
+    /// spans and the spacing between them. A cell covered by the tile of another one has its
+    /// own rectangle.
+    pub fn cell_rect(&self, i: usize, options: &MosaicOptions) -> Rect {
+        let (x, y) = self.cell_position(i, options);
+        let (w, h) = self.cell_dimensions(options);
+        let span = cmp::max(self.tiles[i].span, 1);
+        let spanned = |size: u32| span * size + (span - 1) * options.spacing;
+        (x, y, spanned(w), spanned(h))
+    }
+
+    /// Tiles shown in the `i`-th cell with their rectangle in the mosaic: its tile inside its
+    /// grout, or the tiles of its sub-cells if it is split, none if it is covered by the tile
+    /// of another cell.
+    pub fn tile_rects(&self, i: usize, options: &MosaicOptions) -> Vec<(&PlacedTile<'a>, Rect)> {
+        let tile = &self.tiles[i];
+        if tile.span == 0 {
+            return Vec::new();
+        }
+        let grout = options.grout.map_or(0, |(width, _)| width);
+        let (x, y, w, h) = self.cell_rect(i, options);
+        let rect = (x + grout, y + grout, w - 2 * grout, h - 2 * grout);
+        if tile.details.is_empty() {
+            return vec![(tile, rect)];
+        }
+        let factor = tile.split_factor();
+        (tile.details.iter().enumerate())
+            .map(|(j, detail)| (detail, sub_rect(rect, factor, j)))
+            .collect()
+    }
+
+    pub fn to_plan(&self) -> Plan {
+        Plan {
+            grid_width: self.grid_width,
+            grid_height: self.grid_height,
+            layout: self.layout,
+            tile_ratio: compute_ratio(self.thumb_dim.0, self.thumb_dim.1),
+            cells: self.tiles.iter().map(PlacedTile::to_plan_cell).collect(),
+        }
+    }
+
+    /// Placement of the tiles of `plan`, looked up in `pics`, rendered with `tile_size` pixels
+    /// on their longest side.
+    pub fn from_plan(
+        plan: &Plan,
+        pics: &'a [ProcessedPicture],
+        tile_size: u32,
+    ) -> Result<Placement<'a>, MosaicError> {
+        if plan.cells.len() != plan.grid_width * plan.grid_height {
+            return Err(MosaicError::Invalid(format!(
+                "the plan has {} cells instead of {}x{}",
+                plan.cells.len(),
+                plan.grid_width,
+                plan.grid_height
+            )));
+        }
+
+        let pics_by_path: HashMap<_, _> = pics.iter().map(|pic| (pic.path.as_str(), pic)).collect();
+        let tiles: Vec<_> = (plan.cells.iter())
+            .map(|cell| PlacedTile::from_plan_cell(cell, &pics_by_path))
+            .collect::<Result<_, _>>()?;
+        check_spans(&tiles, plan.grid_width, plan.grid_height)?;
+        let rectangular =
+            |tile: &PlacedTile| tile.masked || tile.span != 1 || !tile.details.is_empty();
+        if plan.layout != Layout::Grid && tiles.iter().any(rectangular) {
+            return Err("only the cells of a grid plan can be masked, merged or split".into());
+        }
+        Ok(Placement {
+            grid_width: plan.grid_width,
+            grid_height: plan.grid_height,
+            layout: plan.layout,
+            thumb_dim: ratio_to_dim(plan.tile_ratio, tile_size),
+            tiles,
+        })
+    }
+}
+
+/// Checks that the tiles of a grid spanning several cells stay in it, and cover cells of a
+/// span of 0 that no other tile covers, and that each cell of a span of 0 is covered.
+fn check_spans(
+    tiles: &[PlacedTile],
+    grid_width: usize,
+    grid_height: usize,
+) -> Result<(), MosaicError> {
+    let mut covered = vec![false; tiles.len()];
+    for (i, tile) in tiles.iter().enumerate().filter(|(_, tile)| tile.span > 1) {
+        let (x, y, span) = (i % grid_width, i / grid_width, tile.span as usize);
+        if x + span > grid_width || y + span > grid_height {
+            return Err(format!("the tile of cell {} spans out of the grid", i).into());
+        }
+        for j in block_cells(i, tile.span, grid_width).filter(|&j| j != i) {
+            if tiles[j].span != 0 || covered[j] {
+                return Err(format!("cell {} is covered by several tiles", j).into());
+            }
+            covered[j] = true;
+        }
+    }
+    match (0..tiles.len()).find(|&i| tiles[i].span == 0 && !covered[i]) {
+        Some(i) => Err(format!("cell {} has a span of 0 but no tile covers it", i).into()),
+        None => Ok(()),
+    }
+}
+
+pub fn color_distance(c1: [u8; 3], c2: [u8; 3]) -> u32 {
+    let mut a = 0;
+    for i in 0..3 {
+        a += (i32::from(c1[i]) - i32::from(c2[i])).pow(2);
+    }
+    f64::from(a).sqrt() as u32
+}
+
+/// Difference between the luma of two colors.
+pub fn color_distance_luminance(c1: [u8; 3], c2: [u8; 3]) -> u32 {
+    (luma(c1) - luma(c2)).abs().round() as u32
+}
+
+/// Difference between two colors in HSV, weighted by `HSV_WEIGHTS` so that the hue counts the
+/// most, scaled to the range 0..=458. The hues are compared around the color wheel, 350° being
+/// as close to 10° as to 330°, and count as much as the least colorful of the two is, the hue
+/// of a gray or a black being meaningless.
+pub fn color_distance_hsv(c1: [u8; 3], c2: [u8; 3]) -> u32 {
+    let ([h1, s1, v1], [h2, s2, v2]) = (rgb_to_hsv(c1), rgb_to_hsv(c2));
+    let hue_gap = (h1 - h2).abs();
+    let hue = hue_gap.min(360.0 - hue_gap) / 180.0 * (s1 * v1).min(s2 * v2);
+    let [wh, ws, wv] = HSV_WEIGHTS;
+    let distance =
+        ((wh * hue).powi(2) + (ws * (s1 - s2)).powi(2) + (wv * (v1 - v2)).powi(2)).sqrt();
+    (distance * 100.0).round() as u32
+}
+
+/// Distance between two colors used by `mode`, when no histograms are compared.
+fn mode_distance(mode: MatchMode) -> fn([u8; 3], [u8; 3]) -> u32 {
+    match mode {
+        MatchMode::Luminance => color_distance_luminance,
+        MatchMode::Hsv => color_distance_hsv,
+        MatchMode::Color | MatchMode::Histogram => color_distance,
+    }
+}
+
+/// Histogram intersection distance, 1 minus the share of the pixels two histograms have in
+/// common once normalized by their pixel count, scaled to the range 0..=1000. Unlike the
+/// distance between average colors, a half black half white picture doesn't match gray.
+pub fn color_distance_histogram(h1: &[u16], h2: &[u16]) -> u32 {
+    let total1 = cmp::max(h1.iter().map(|&a| u32::from(a)).sum::<u32>(), 1) as f64;
+    let total2 = cmp::max(h2.iter().map(|&b| u32::from(b)).sum::<u32>(), 1) as f64;
+    let common: f64 = h1
+        .iter()
+        .zip(h2.iter())
+        .map(|(&a, &b)| (f64::from(a) / total1).min(f64::from(b) / total2))
+        .sum();
+    ((1.0 - common).max(0.0) * 1000.0).round() as u32
+}
+
+fn pic_distance(
+    pic: &ProcessedPicture,
+    color: [u8; 3],
+    histogram: Option<&[u16]>,
+    contrast: Option<f32>,
+    mode: MatchMode,
+) -> u32 {
+    let distance = match (histogram, &pic.color_histogram) {
+        (Some(h1), Some(h2)) => color_distance_histogram(h1, h2),
+        _ => mode_distance(mode)(pic.color_rgb, color),
+    };
+    let contrast_penalty = match (contrast, pic.contrast) {
+        (Some(c1), Some(c2)) => (c1 - c2).abs().round() as u32,
+        _ => 0,
+    };
+    distance + contrast_penalty
+}
+
+/// Returns the picture closest to `color`, by the distance of `mode`, or to `histogram` when
+/// both the chunk and the picture have one. The two distances having different scales, either
+/// all the pictures or none of them should have a histogram. If `contrast` is given, the
+/// pictures are also penalized by how much their contrast differs from it. Ties go to the first
+/// picture of `pics`. Errors with `MosaicError::EmptyGallery` if `pics` is empty.
+pub fn find_closest_pic_by_color<'a>(
+    pics: &'a [ProcessedPicture],
+    color: [u8; 3],
+    histogram: Option<&[u16]>,
+    contrast: Option<f32>,
+    mode: MatchMode,
+) -> Result<&'a ProcessedPicture, MosaicError> {
+    let mut closest: Option<(&ProcessedPicture, u32)> = None;
+    for pic in pics {
+        let dist = pic_distance(pic, color, histogram, contrast, mode);
+        if dist == 0 {
+            return Ok(pic);
+        }
+
+        if closest.is_none_or(|(_, closest_dist)| dist < closest_dist) {
+            closest = Some((pic, dist));
+        }
+    }
+    closest.map(|(pic, _)| pic).ok_or(MosaicError::EmptyGallery)
+}
+
+/// Returns one of the `k` pictures closest to `color`, picked with `rng`. Pictures at the same
+/// distance keep their order in `pics`.
+fn find_random_close_pic<'a>(
+    pics: &'a [ProcessedPicture],
+    color: [u8; 3],
+    histogram: Option<&[u16]>,
+    contrast: Option<f32>,
+    mode: MatchMode,
+    k: usize,
+    rng: &mut SmallRng,
+) -> Result<&'a ProcessedPicture, MosaicError> {
+    if k <= 1 || pics.is_empty() {
+        return find_closest_pic_by_color(pics, color, histogram, contrast, mode);
+    }
+
+    let mut candidates: Vec<_> = pics
+        .iter()
+        .map(|pic| (pic_distance(pic, color, histogram, contrast, mode), pic))
+        .collect();
+    let k = cmp::min(k, candidates.len());
+    candidates.sort_by_key(|candidate| candidate.0);
+    Ok(candidates[rng.gen_range(k)].1)
+}
+
+/// Returns one of the `k` pictures closest by `distance` among the ones used less than
+/// `max_uses` times according to `uses`, picked with `rng`, and counts its use. Once all the
+/// pictures are used up, their uses start over.
+fn find_limited_close_pic<'a, F>(
+    pics: &'a [ProcessedPicture],
+    uses: &mut [u32],
+    max_uses: u32,
+    distance: F,
+    k: usize,
+    rng: &mut SmallRng,
+) -> Result<&'a ProcessedPicture, MosaicError>
+where
+    F: Fn(&ProcessedPicture) -> u32,
+{
+    if pics.is_empty() {
+        return Err(MosaicError::EmptyGallery);
+    }
+    if uses.iter().all(|&n| n >= max_uses) {
+        uses.iter_mut().for_each(|n| *n = 0);
+    }
+
+    let available = (0..pics.len()).filter(|&i| uses[i] < max_uses);
+    let i = if k <= 1 {
+        (available.min_by_key(|&i| distance(&pics[i])))
+            .expect("the uses start over once all the pictures are used up")
+    } else {
+        let mut candidates: Vec<_> = available.map(|i| (distance(&pics[i]), i)).collect();
+        let k = cmp::min(k, candidates.len());
+        candidates.sort_by_key(|candidate| candidate.0);
+        candidates[rng.gen_range(k)].1
+    };
+    uses[i] += 1;
+    Ok(&pics[i])
+}
+
+/// Applies `f` to each chunk of `img` laid out by `layout`, in row-major order, extended by
+/// `overlap` pixels on each side within the image. The chunks of `Layout::Hex` are the
+/// rectangles of their hexagons, overlapping at the corners. The last chunk of an odd row of
+/// `Layout::Brick` wraps around, its right part being taken from the left side of the grid.
+fn map_chunks<T, F>(
+    img: &DynamicImage,
+    chunk_w: u32,
+    chunk_h: u32,
+    layout: Layout,
+    overlap: u32,
+    f: F,
+) -> Vec<T>
+where
+    F: Fn(&ImageBuffer<Rgba<u8>, Vec<u8>>) -> T,
+{
+    let (w, h) = img.dimensions();
+    let (cols, rows) = layout.grid((w, h), (chunk_w, chunk_h));
+    let mut res = Vec::with_capacity(cols * rows);
+    for row in 0..rows {
+        for col in 0..cols {
+            let (x, y) = layout.cell_offset(col, row, (chunk_w, chunk_h), 0);
+            let top = y.saturating_sub(overlap);
+            let bottom = cmp::min(y + chunk_h + overlap, h);
+            let left = x.saturating_sub(overlap);
+            let grid_w = cols as u32 * chunk_w;
+            if layout == Layout::Brick && x + chunk_w > grid_w {
+                let wrapped = cmp::min(x + chunk_w + overlap - grid_w, w);
+                let chunk = ImageBuffer::from_fn(grid_w - left + wrapped, bottom - top, |i, j| {
+                    // Past the right side of the grid, the pixels of its left side.
+                    let x = if left + i < grid_w {
+                        left + i
+                    } else {
+                        left + i - grid_w
+                    };
+                    img.get_pixel(x, top + j)
+                });
+                res.push(f(&chunk));
+                continue;
+            }
+            let right = cmp::min(x + chunk_w + overlap, w);
+            let chunk = img.view(left, top, right - left, bottom - top);
+            res.push(f(&chunk.to_image()));
+        }
+    }
+    res
+}
+
+fn compute_main_color_by_chunk(
+    img: &DynamicImage,
+    chunk_w: u32,
+    chunk_h: u32,
+    options: &MosaicOptions,
+) -> Vec<[u8; 3]> {
+    let (layout, overlap) = (options.layout, options.chunk_overlap);
+    map_chunks(img, chunk_w, chunk_h, layout, overlap, |chunk| {
+        compute_chunk_color(chunk, options)
+    })
+}
+
+/// Color of a chunk of the model, computed as `options.chunk_color` says.
+fn compute_chunk_color(chunk: &ImageBuffer<Rgba<u8>, Vec<u8>>, options: &MosaicOptions) -> [u8; 3] {
+    let (weighted, linear_light) = (options.center_weighted, options.linear_light);
+    match options.chunk_color.unwrap_or_default() {
+        ColorMode::Mean => compute_main_color(chunk, weighted, linear_light),
+        ColorMode::Median => compute_median_color(chunk, weighted),
+        ColorMode::Dominant => {
+            let palette = if weighted {
+                palette::palette(center_weighted_pixels(chunk), PALETTE_SIZE, linear_light)
+            } else {
+                let pixels = chunk
+                    .pixels()
+                    .map(|pixel| (pixel, f64::from(pixel.data[3])));
+                palette::palette(pixels, PALETTE_SIZE, linear_light)
+            };
+            palette
+                .first()
+                .copied()
+                .unwrap_or_else(|| compute_main_color(chunk, weighted, linear_light))
+        }
+    }
+}
+
+fn compute_histogram_by_chunk(
+    img: &DynamicImage,
+    chunk_w: u32,
+    chunk_h: u32,
+    layout: Layout,
+    overlap: u32,
+) -> Vec<Vec<u16>> {
+    map_chunks(img, chunk_w, chunk_h, layout, overlap, compute_histogram)
+}
+
+/// Spreads the difference between the color a chunk wanted and the color it got over the
+/// chunks that haven't been matched yet, with the Floyd–Steinberg weights.
+fn diffuse_error(
+    color_by_chunk: &mut [[u8; 3]],
+    grid_width: usize,
+    i: usize,
+    target: [u8; 3],
+    placed: [u8; 3],
+) {
+    let col = i % grid_width;
+    let row = i / grid_width;
+    let grid_height = color_by_chunk.len() / grid_width;
+    let neighbours: [(isize, usize, i32); 4] = [(1, 0, 7), (-1, 1, 3), (0, 1, 5), (1, 1, 1)];
+    for &(dx, dy, weight) in &neighbours {
+        let x = col as isize + dx;
+        let y = row + dy;
+        if x < 0 || x as usize >= grid_width || y >= grid_height {
+            continue;
+        }
+
+        let neighbour = &mut color_by_chunk[y * grid_width + x as usize];
+        for c in 0..3 {
+            let residual = i32::from(target[c]) - i32::from(placed[c]);
+            let value = i32::from(neighbour[c]) + residual * weight / 16;
+            neighbour[c] = value.clamp(0, 255) as u8;
+        }
+    }
+}
+
+/// Colors of the chunks of `model` the tiles are matched with, in row-major order.
+pub fn chunk_colors(model: &DynamicImage, options: &MosaicOptions) -> Vec<[u8; 3]> {
+    let chunk_dim = ratio_to_dim(options.tile_ratio, CHUNK_SIZE);
+    let mut colors = compute_main_color_by_chunk(model, chunk_dim.0, chunk_dim.1, options);
+    adjust_chunk_colors(model, &mut colors, options);
+    colors
+}
+
+/// Number of cells per row and column of the grid of `model`, with tiles of `ratio` laid out
+/// by `layout`.
+pub fn grid_size(model: &DynamicImage, ratio: (u32, u32), layout: Layout) -> (usize, usize) {
+    layout.grid(model.dimensions(), ratio_to_dim(ratio, CHUNK_SIZE))
+}
+
+/// Whether `match_tiles` with `options` only places the picture closest to the color of each
+/// chunk by `color_distance`, or a picture placed elsewhere, so that matching with only the
+/// pictures closest to the chunks, in the order of the gallery, gives the same placement.
+pub fn matches_closest_only(options: &MosaicOptions) -> bool {
+    options.match_mode == MatchMode::Color
+        && !options.match_variance
+        && !options.dither
+        && options.randomize_top_k <= 1
+        && options.max_uses.is_none()
+        && options.adaptive.is_none()
+        && options.regions_of_interest.is_empty()
+}
+
+/// Matches each chunk of `model` with one of `pics`. Errors with `MosaicError::EmptyGallery`
+/// if `pics` is empty, and with `MosaicError::Invalid` if one of them has no histogram to be
+/// matched with `MatchMode::Histogram`, its color distance not comparing with the others.
+pub fn match_tiles<'a>(
+    model: &DynamicImage,
+    pics: &'a [ProcessedPicture],
+    ratio: (u32, u32),
+    options: &MosaicOptions,
+) -> Result<Placement<'a>, MosaicError> {
+    match_unmasked_tiles(model, pics, ratio, options, &[])
+}
+
+/// `match_tiles` leaving out the cells flagged in `masked`, as computed by `masked_cells`, in
+/// row-major order. They get the closest picture but use up none of `MosaicOptions::max_uses`,
+/// aren't dithered nor swapped, and are flagged as masked in the placement.
+pub fn match_unmasked_tiles<'a>(
+    model: &DynamicImage,
+    pics: &'a [ProcessedPicture],
+    ratio: (u32, u32),
+    options: &MosaicOptions,
+    masked: &[bool],
+) -> Result<Placement<'a>, MosaicError> {
+    let is_masked = |i: usize| masked.get(i) == Some(&true);
+    if options.match_mode == MatchMode::Histogram {
+        if let Some(pic) = pics.iter().find(|pic| pic.color_histogram.is_none()) {
+            return Err(MosaicError::Invalid(format!(
+                "{} has no histogram to be matched by histogram",
+                pic.path
+            )));
+        }
+    }
+    let start = Instant::now();
+    let chunk_dim = ratio_to_dim(ratio, CHUNK_SIZE);
+    let mut color_by_chunk = compute_main_color_by_chunk(model, chunk_dim.0, chunk_dim.1, options);
+    adjust_chunk_colors(model, &mut color_by_chunk, options);
+    let (grid_width, grid_height) = grid_size(model, ratio, options.layout);
+    let histogram_by_chunk = match options.match_mode {
+        MatchMode::Color | MatchMode::Luminance | MatchMode::Hsv => None,
+        MatchMode::Histogram => Some(compute_histogram_by_chunk(
+            model,
+            chunk_dim.0,
+            chunk_dim.1,
+            options.layout,
+            options.chunk_overlap,
+        )),
+    };
+    let contrast_by_chunk = if options.match_variance {
+        // Without overlap, the contrast being the texture of the chunk itself.
+        Some(map_chunks(
+            model,
+            chunk_dim.0,
+            chunk_dim.1,
+            options.layout,
+            0,
+            compute_contrast,
+        ))
+    } else {
+        None
+    };
+
+    // The only source of randomness, drawn from in a fixed order so that a seed always gives
+    // the same placement.
+    let mut rng = new_rng(options.seed);
+
+    // Without dithering, randomness nor limit of uses, each chunk is matched independently of
+    // the others.
+    let sequential = options.dither || options.randomize_top_k > 1 || options.max_uses.is_some();
+    let tiles = if !sequential {
+        color_by_chunk
+            .par_iter()
+            .enumerate()
+            .map(|(i, &color)| {
+                let histogram = histogram_by_chunk.as_ref().map(|h| h[i].as_slice());
+                let contrast = contrast_by_chunk.as_ref().map(|c| c[i]);
+                Ok(PlacedTile {
+                    pic: find_closest_pic_by_color(
+                        pics,
+                        color,
+                        histogram,
+                        contrast,
+                        options.match_mode,
+                    )?,
+                    target_color: color,
+                    rotation: 0,
+                    mirrored: false,
+                    masked: is_masked(i),
+                    details: Vec::new(),
+                    span: 1,
+                })
+            })
+            .collect::<Result<_, MosaicError>>()?
+    } else {
+        let mut tiles = Vec::with_capacity(color_by_chunk.len());
+        let mut uses = vec![0; pics.len()];
+        for i in 0..color_by_chunk.len() {
+            let color = color_by_chunk[i];
+            let histogram = histogram_by_chunk.as_ref().map(|h| h[i].as_slice());
+            let contrast = contrast_by_chunk.as_ref().map(|c| c[i]);
+            if is_masked(i) {
+                let mode = options.match_mode;
+                tiles.push(PlacedTile {
+                    pic: find_closest_pic_by_color(pics, color, histogram, contrast, mode)?,
+                    target_color: color,
+                    rotation: 0,
+                    mirrored: false,
+                    masked: true,
+                    details: Vec::new(),
+                    span: 1,
+                });
+                continue;
+            }
+            let pic = match options.max_uses {
+                Some(max_uses) => find_limited_close_pic(
+                    pics,
+                    &mut uses,
+                    max_uses,
+                    |pic| pic_distance(pic, color, histogram, contrast, options.match_mode),
+                    options.randomize_top_k,
+                    &mut rng,
+                )?,
+                None => find_random_close_pic(
+                    pics,
+                    color,
+                    histogram,
+                    contrast,
+                    options.match_mode,
+                    options.randomize_top_k,
+                    &mut rng,
+                )?,
+            };
+            if options.dither {
+                diffuse_error(&mut color_by_chunk, grid_width, i, color, pic.color_rgb);
+            }
+            tiles.push(PlacedTile {
+                pic,
+                target_color: color,
+                rotation: 0,
+                mirrored: false,
+                masked: false,
+                details: Vec::new(),
+                span: 1,
+            });
+        }
+        tiles
+    };
+
+    let mut placement = Placement {
+        grid_width,
+        grid_height,
+        layout: options.layout,
+        thumb_dim: ratio_to_dim(ratio, THUMBNAIL_SIZE),
+        tiles,
+    };
+    if options.two_pass {
+        improve_coherence(&mut placement, options.match_mode);
+    }
+    if options.allow_rotation || options.allow_mirroring {
+        transform_tiles(&mut placement, options, &mut rng);
+    }
+    if let Some((max_depth, threshold)) = options.adaptive {
+        merge_flat_blocks(&mut placement, model, pics, max_depth, threshold, options)?;
+    }
+    for roi in &options.regions_of_interest {
+        subdivide_region(&mut placement, model, pics, roi, options)?;
+    }
+    debug!(
+        "matched {}x{} cells in {} ms",
+        grid_width,
+        grid_height,
+        start.elapsed().as_millis()
+    );
+    if log::enabled(Level::Trace) {
+        trace_tiles(&placement);
+    }
+    Ok(placement)
+}
+
+/// Logs the picture matched with each cell and sub-cell, and how far it is from its chunk.
+fn trace_tiles(placement: &Placement) {
+    let trace_tile = |cell: String, tile: &PlacedTile| {
+        let ([r1, g1, b1], [r2, g2, b2]) = (tile.target_color, tile.pic.color_rgb);
+        trace!(
+            "cell {}: ({},{},{}) -> {} ({},{},{}), distance {}",
+            cell,
+            r1,
+            g1,
+            b1,
+            tile.pic.path,
+            r2,
+            g2,
+            b2,
+            color_distance(tile.target_color, tile.pic.color_rgb)
+        );
+    };
+    for (i, tile) in placement.tiles.iter().enumerate() {
+        let (col, row) = (i % placement.grid_width, i / placement.grid_width);
+        if tile.span == 0 {
+            continue;
+        }
+        if tile.details.is_empty() {
+            trace_tile(format!("{},{}", col, row), tile);
+        }
+        for (j, detail) in tile.details.iter().enumerate() {
+            trace_tile(format!("{},{} #{}", col, row, j), detail);
+        }
+    }
+}
+
+/// Leaves out the cells where `mask`, stretched over the model, covers less than `threshold`,
+/// between 0 and 1, of the cell. The coverage is the luma of the mask times its alpha, so that
+/// both a black and a transparent background mask out. The cells covered by a merged tile are
+/// masked as the cell of the tile. Returns the number of cells masked.
+pub fn mask_tiles(placement: &mut Placement, mask: &DynamicImage, threshold: f32) -> usize {
+    let grid = (placement.grid_width, placement.grid_height);
+    let cells = masked_cells(mask, grid, threshold);
+    let mut masked = 0;
+    for (tile, cell_masked) in placement.tiles.iter_mut().zip(cells) {
+        tile.masked = cell_masked;
+        if tile.masked {
+            masked += 1;
+        }
+    }
+    for i in 0..placement.tiles.len() {
+        let (span, anchor_masked) = (placement.tiles[i].span, placement.tiles[i].masked);
+        for j in block_cells(i, span, placement.grid_width) {
+            if placement.tiles[j].masked != anchor_masked {
+                placement.tiles[j].masked = anchor_masked;
+                masked = if anchor_masked {
+                    masked + 1
+                } else {
+                    masked - 1
+                };
+            }
+        }
+    }
+    masked
+}
+
+/// Indices of the cells of the block of `span` cells per side from the `i`-th cell down and
+/// right, in a grid `grid_width` cells wide.
+fn block_cells(i: usize, span: u32, grid_width: usize) -> impl Iterator<Item = usize> {
+    let (x, y, span) = (i % grid_width, i / grid_width, span as usize);
+    (y..y + span).flat_map(move |row| (x..x + span).map(move |col| row * grid_width + col))
+}
+
+/// Whether each cell of a grid of `(grid_w, grid_h)` cells, in row-major order, is left out
+/// by `mask` and `threshold` as in `mask_tiles`, before the tiles are matched.
+pub fn masked_cells(mask: &DynamicImage, grid: (usize, usize), threshold: f32) -> Vec<bool> {
+    (mask_coverage(mask, grid).pixels())
+        .map(|cell| f32::from(cell.data[0]) < threshold * 255.0)
+        .collect()
+}
+
+/// Luma times alpha of `mask` averaged over each cell of a grid of `(grid_w, grid_h)` cells,
+/// in a pixel per cell.
+fn mask_coverage(
+    mask: &DynamicImage,
+    (grid_w, grid_h): (usize, usize),
+) -> ImageBuffer<Luma<u8>, Vec<u8>> {
+    let coverage = ImageBuffer::from_fn(mask.width(), mask.height(), |x, y| {
+        let [r, g, b, a] = mask.get_pixel(x, y).data;
+        Luma([(luma([r, g, b]) * f64::from(a) / 255.0).round() as u8])
+    });
+    imageops::resize(
+        &coverage,
+        grid_w as u32,
+        grid_h as u32,
+        imageops::FilterType::Triangle,
+    )
+}
+
+/// Rectangle of the `i`-th of the `factor` rows and columns of sub-cells of `rect`, in
+/// row-major order, the odd pixels being spread among them.
+pub(crate) fn sub_rect(rect: Rect, factor: u32, i: usize) -> Rect {
+    let (x, y, w, h) = rect;
+    let (column, row) = (i as u32 % factor, i as u32 / factor);
+    let (left, right) = (w * column / factor, w * (column + 1) / factor);
+    let (top, bottom) = (h * row / factor, h * (row + 1) / factor);
+    (x + left, y + top, right - left, bottom - top)
+}
+
+/// Splits in four quarters with a picture each the cells where `mask`, stretched over `model`,
+/// is at least `threshold` bright, between 0 and 1, for more detail there such as on a face.
+/// The coverage of the mask is taken as in `mask_tiles`, and the masked cells aren't split.
+/// `model` must be the one the tiles of `placement` were matched with. Returns the number of
+/// cells split.
+pub fn subdivide_tiles<'a>(
+    placement: &mut Placement<'a>,
+    model: &DynamicImage,
+    pics: &'a [ProcessedPicture],
+    mask: &DynamicImage,
+    threshold: f32,
+    options: &MosaicOptions,
+) -> Result<usize, MosaicError> {
+    let coverage = mask_coverage(mask, (placement.grid_width, placement.grid_height));
+    let cells: Vec<_> = (coverage.pixels().enumerate())
+        .filter(|(_, cell)| f32::from(cell.data[0]) >= threshold * 255.0)
+        .map(|(i, _)| (i, 2))
+        .collect();
+    split_cells(placement, model, pics, &cells, options)
+}
+
+/// Splits the cells of `placement` whose chunk overlaps `roi`, as `match_tiles` does for each
+/// of `MosaicOptions::regions_of_interest`.
+fn subdivide_region<'a>(
+    placement: &mut Placement<'a>,
+    model: &DynamicImage,
+    pics: &'a [ProcessedPicture],
+    roi: &RegionOfInterest,
+    options: &MosaicOptions,
+) -> Result<usize, MosaicError> {
+    let grid_width = cmp::max(placement.grid_width, 1);
+    let chunk_w = model.width() / grid_width as u32;
+    let chunk_h = model.height() / cmp::max(placement.grid_height, 1) as u32;
+    let (x, y, w, h) = roi.rect;
+    let cells: Vec<_> = (0..placement.tiles.len())
+        .filter(|&i| {
+            let chunk_x = (i % grid_width) as u32 * chunk_w;
+            let chunk_y = (i / grid_width) as u32 * chunk_h;
+            chunk_x < x + w && x < chunk_x + chunk_w && chunk_y < y + h && y < chunk_y + chunk_h
+        })
+        .map(|i| (i, roi.factor))
+        .collect();
+    split_cells(placement, model, pics, &cells, options)
+}
+
+/// Splits the `i`-th cell of `placement` in `factor` rows and columns of sub-cells for each
+/// `(i, factor)` of `cells`, each matched with its part of the chunk of `model`. The factor is
+/// lowered for the parts to be a pixel at least, and the masked cells, the merged ones and the
+/// ones already split as finely are kept. Returns the number of cells split.
+fn split_cells<'a>(
+    placement: &mut Placement<'a>,
+    model: &DynamicImage,
+    pics: &'a [ProcessedPicture],
+    cells: &[(usize, u32)],
+    options: &MosaicOptions,
+) -> Result<usize, MosaicError> {
+    let grid_width = cmp::max(placement.grid_width, 1);
+    let chunk_w = model.width() / grid_width as u32;
+    let chunk_h = model.height() / cmp::max(placement.grid_height, 1) as u32;
+    let cells: Vec<_> = (cells.iter())
+        .map(|&(i, factor)| (i, factor.min(chunk_w).min(chunk_h)))
+        .filter(|&(i, factor)| {
+            let tile = &placement.tiles[i];
+            factor >= 2 && !tile.masked && tile.span == 1 && factor > tile.split_factor()
+        })
+        .collect();
+
+    let mut colors: Vec<[u8; 3]> = cells
+        .par_iter()
+        .flat_map(|&(i, factor)| {
+            let x = (i % grid_width) as u32 * chunk_w;
+            let y = (i / grid_width) as u32 * chunk_h;
+            (0..(factor * factor) as usize)
+                .map(|j| {
+                    let (x, y, w, h) = sub_rect((x, y, chunk_w, chunk_h), factor, j);
+                    let chunk = model.view(x, y, w, h).to_image();
+                    compute_chunk_color(&chunk, options)
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+    adjust_chunk_colors(model, &mut colors, options);
+    let details: Vec<_> = colors
+        .par_iter()
+        .map(|&color| {
+            Ok(PlacedTile {
+                pic: find_closest_pic_by_color(pics, color, None, None, options.match_mode)?,
+                target_color: color,
+                rotation: 0,
+                mirrored: false,
+                masked: false,
+                details: Vec::new(),
+                span: 1,
+            })
+        })
+        .collect::<Result<_, MosaicError>>()?;
+
+    let mut details = details.into_iter();
+    for &(i, factor) in &cells {
+        placement.tiles[i].details = details.by_ref().take((factor * factor) as usize).collect();
+    }
+    Ok(cells.len())
+}
+
+/// Merges the flat blocks of the grid of `placement` in a single tile each: the grid is cut in
+/// blocks of 2^`max_depth` cells per side, and each block whose colors in `model` have a
+/// variance above `threshold`, or that the grid cuts, in four blocks again down to single
+/// cells, a quadtree whose leaves are the tiles. The tile of a merged block is matched with the
+/// whole block. Returns the number of tiles merged.
+fn merge_flat_blocks<'a>(
+    placement: &mut Placement<'a>,
+    model: &DynamicImage,
+    pics: &'a [ProcessedPicture],
+    max_depth: u32,
+    threshold: f32,
+    options: &MosaicOptions,
+) -> Result<usize, MosaicError> {
+    let (grid_width, grid_height) = (placement.grid_width, placement.grid_height);
+    let chunk_w = model.width() / cmp::max(grid_width, 1) as u32;
+    let chunk_h = model.height() / cmp::max(grid_height, 1) as u32;
+    let quadtree = Quadtree {
+        stats: map_chunks(model, chunk_w, chunk_h, Layout::Grid, 0, ColorStats::of),
+        grid_width,
+        grid_height,
+        threshold: f64::from(threshold),
+    };
+    let root = 1 << max_depth;
+    let mut blocks = Vec::new();
+    for y in (0..grid_height).step_by(root) {
+        for x in (0..grid_width).step_by(root) {
+            quadtree.find_flat_blocks(x, y, root, &mut blocks);
+        }
+    }
+
+    let mut colors: Vec<[u8; 3]> = blocks
+        .par_iter()
+        .map(|&(i, span)| {
+            let x = (i % grid_width) as u32 * chunk_w;
+            let y = (i / grid_width) as u32 * chunk_h;
+            let block = model.view(x, y, span * chunk_w, span * chunk_h).to_image();
+            compute_chunk_color(&block, options)
+        })
+        .collect();
+    adjust_chunk_colors(model, &mut colors, options);
+    for (&(i, span), &color) in blocks.iter().zip(&colors) {
+        for j in block_cells(i, span, grid_width) {
+            placement.tiles[j].span = 0;
+            placement.tiles[j].details.clear();
+        }
+        placement.tiles[i] = PlacedTile {
+            pic: find_closest_pic_by_color(pics, color, None, None, options.match_mode)?,
+            target_color: color,
+            rotation: 0,
+            mirrored: false,
+            masked: false,
+            details: Vec::new(),
+            span,
+        };
+    }
+    Ok(blocks.len())
+}
+
+/// Color statistics of the chunks of a grid, cut in flat blocks by `merge_flat_blocks`.
+struct Quadtree {
+    stats: Vec<ColorStats>,
+    grid_width: usize,
+    grid_height: usize,
+    threshold: f64,
+}
+
+impl Quadtree {
+    /// Adds to `blocks` the index of the top-left cell and the span of the flat blocks of
+    /// more than a cell in the block of `span` cells per side at `(x, y)`.
+    fn find_flat_blocks(&self, x: usize, y: usize, span: usize, blocks: &mut Vec<(usize, u32)>) {
+        if span == 1 || x >= self.grid_width || y >= self.grid_height {
+            return;
+        }
+        if x + span <= self.grid_width && y + span <= self.grid_height {
+            let i = y * self.grid_width + x;
+            let stats = block_cells(i, span as u32, self.grid_width)
+                .fold(ColorStats::default(), |stats, j| {
+                    stats.merge(&self.stats[j])
+                });
+            if stats.variance() <= self.threshold {
+                blocks.push((i, span as u32));
+                return;
+            }
+        }
+        let half = span / 2;
+        for (dx, dy) in [(0, 0), (half, 0), (0, half), (half, half)] {
+            self.find_flat_blocks(x + dx, y + dy, half, blocks);
+        }
+    }
+}
+
+/// Keeps the tile, and its rotation, that each cell had in `previous`, the placement of the
+/// frame before, unless the new one is closer to the chunk by more than
+/// `MosaicOptions::temporal_stability`.
+pub fn stabilize_tiles<'a>(
+    placement: &mut Placement<'a>,
+    previous: &Placement<'a>,
+    options: &MosaicOptions,
+) {
+    if options.temporal_stability == 0.0 || placement.tiles.len() != previous.tiles.len() {
+        return;
+    }
+    let distance = mode_distance(options.match_mode);
+    let keep_factor = 1.0 + f64::from(options.temporal_stability);
+    for (tile, previous) in placement.tiles.iter_mut().zip(&previous.tiles) {
+        let new_distance = distance(tile.target_color, tile.pic.color_rgb);
+        let kept_distance = distance(tile.target_color, previous.pic.color_rgb);
+        if f64::from(kept_distance) <= f64::from(new_distance) * keep_factor {
+            tile.pic = previous.pic;
+            tile.rotation = previous.rotation;
+            tile.mirrored = previous.mirrored;
+        }
+    }
+}
+
+fn new_rng(seed: Option<u64>) -> SmallRng {
+    match seed {
+        Some(seed) => SmallRng::seed_from_u64(seed),
+        None => SmallRng::from_time(),
+    }
+}
+
+/// Gives each tile a random rotation, by a quarter turn if the tiles are square and by a half
+/// turn otherwise so that they keep their dimensions, and a random mirroring, as allowed by
+/// `options`, for variety. The colors, histograms and contrasts of the whole thumbnails don't
+/// change with their orientation, so no orientation matches the chunks better than another.
+fn transform_tiles(placement: &mut Placement, options: &MosaicOptions, rng: &mut SmallRng) {
+    let step = if placement.thumb_dim.0 == placement.thumb_dim.1 {
+        90
+    } else {
+        180
+    };
+    for tile in &mut placement.tiles {
+        if options.allow_rotation {
+            tile.rotation = rng.gen_range((360 / step) as usize) as u32 * step;
+        }
+        if options.allow_mirroring {
+            tile.mirrored = rng.gen_range(2) == 1;
+        }
+    }
+}
+
+/// Swaps the pictures of adjacent cells wherever it lowers the distance of the pictures to
+/// their chunk plus the distance between the pictures of neighbour cells, so that a tile
+/// matched greedily doesn't clash with its surroundings. Sweeps the grid until no swap helps,
+/// at most `MAX_COHERENCE_SWEEPS` times.
+fn improve_coherence(placement: &mut Placement, mode: MatchMode) {
+    let (grid_width, grid_height) = (placement.grid_width, placement.grid_height);
+    for _ in 0..MAX_COHERENCE_SWEEPS {
+        let mut swapped = false;
+        for i in 0..placement.tiles.len() {
+            let (col, row) = (i % grid_width, i / grid_width);
+            let right = Some(i + 1).filter(|_| col + 1 < grid_width);
+            let below = Some(i + grid_width).filter(|_| row + 1 < grid_height);
+            for j in right.into_iter().chain(below) {
+                // The masked cells keep their picture, which doesn't count in the uses.
+                if placement.tiles[i].masked || placement.tiles[j].masked {
+                    continue;
+                }
+                let before = cell_energy(placement, i, mode) + cell_energy(placement, j, mode);
+                swap_pics(&mut placement.tiles, i, j);
+                let after = cell_energy(placement, i, mode) + cell_energy(placement, j, mode);
+                if after < before {
+                    swapped = true;
+                } else {
+                    swap_pics(&mut placement.tiles, i, j);
+                }
+            }
+        }
+        if !swapped {
+            break;
+        }
+    }
+}
+
+fn swap_pics(tiles: &mut [PlacedTile], i: usize, j: usize) {
+    let pic = tiles[i].pic;
+    tiles[i].pic = tiles[j].pic;
+    tiles[j].pic = pic;
+}
+
+/// Distance of the picture of the `i`-th cell to its chunk and to the pictures of the four
+/// neighbour cells. The edge between two swapped cells counts in both, but it doesn't change
+/// with the swap.
+fn cell_energy(placement: &Placement, i: usize, mode: MatchMode) -> u32 {
+    let (grid_width, grid_height) = (placement.grid_width, placement.grid_height);
+    let (col, row) = (i % grid_width, i / grid_width);
+    let pic = placement.tiles[i].pic;
+    let mut energy = pic_distance(pic, placement.tiles[i].target_color, None, None, mode);
+    let neighbours = [
+        Some(i.wrapping_sub(1)).filter(|_| col > 0),
+        Some(i + 1).filter(|_| col + 1 < grid_width),
+        Some(i.wrapping_sub(grid_width)).filter(|_| row > 0),
+        Some(i + grid_width).filter(|_| row + 1 < grid_height),
+    ];
+    for j in neighbours
+        .iter()
+        .flatten()
+        .filter(|&&j| !placement.tiles[j].masked)
+    {
+        energy += pic_distance(pic, placement.tiles[*j].pic.color_rgb, None, None, mode);
+    }
+    energy
+}
+
+/// Applies `options` to `model`, which is then cut in chunks of the ratio `tile_ratio`.
+pub fn prepare_model(
+    mut model: DynamicImage,
+    options: &ModelOptions,
+    tile_ratio: (u32, u32),
+) -> Result<DynamicImage, MosaicError> {
+    if let Some((x, y, w, h)) = options.crop {
+        let (model_w, model_h) = model.dimensions();
+        if x.checked_add(w).is_none_or(|right| right > model_w)
+            || y.checked_add(h).is_none_or(|bottom| bottom > model_h)
+        {
+            return Err(MosaicError::Invalid(format!(
+                "crop {},{},{},{} exceeds the model dimensions {}x{}",
+                x, y, w, h, model_w, model_h
+            )));
+        }
+        model = model.crop(x, y, w, h);
+    }
+
+    let chunk_dim = ratio_to_dim(tile_ratio, CHUNK_SIZE);
+    let (w, h) = model.dimensions();
+    let mut scale = 1.0f64;
+    if let Some((max_w, max_h)) = options.max_size {
+        scale = scale
+            .min(max_w as f64 / w as f64)
+            .min(max_h as f64 / h as f64);
+    }
+    if let Some(max_tiles) = options.max_tiles {
+        scale = scale.min(tiles_scale((w, h), chunk_dim, max_tiles, options.fill_mode));
+    }
+    if scale < 1.0 {
+        let (new_w, new_h) = scale_dim((w, h), scale);
+        model = model.resize_exact(new_w, new_h, imageops::FilterType::Lanczos3);
+    }
+    if let Some((columns, rows)) = options.grid {
+        let (grid_w, grid_h) = (columns * chunk_dim.0, rows * chunk_dim.1);
+        // The largest centered region of the aspect ratio of the grid.
+        let (w, h) = model.dimensions();
+        let (w, h) = (u64::from(w), u64::from(h));
+        let (crop_w, crop_h) = if w * u64::from(grid_h) > h * u64::from(grid_w) {
+            (h * u64::from(grid_w) / u64::from(grid_h), h)
+        } else {
+            (w, w * u64::from(grid_h) / u64::from(grid_w))
+        };
+        let (crop_w, crop_h) = (cmp::max(crop_w, 1) as u32, cmp::max(crop_h, 1) as u32);
+        model = model.crop(
+            (w as u32 - crop_w) / 2,
+            (h as u32 - crop_h) / 2,
+            crop_w,
+            crop_h,
+        );
+        model = model.resize_exact(grid_w, grid_h, imageops::FilterType::Lanczos3);
+    }
+
+    if options.grayscale {
+        model = model.grayscale();
+    }
+    if options.invert {
+        model.invert();
+    }
+
+    Ok(fill_model(model, chunk_dim, options.fill_mode)?)
+}
+
+fn scale_dim((w, h): (u32, u32), scale: f64) -> (u32, u32) {
+    let scale = |size: u32| cmp::max((size as f64 * scale) as u32, 1);
+    (scale(w), scale(h))
+}
+
+/// Number of chunks of a model of `dim` once filled as told by `mode`.
+fn chunk_count(dim: (u32, u32), chunk_dim: (u32, u32), mode: FillMode) -> u64 {
+    let count = |size: u32, chunk: u32| match mode {
+        FillMode::Crop => size / chunk,
+        FillMode::Pad => size.div_ceil(chunk),
+        FillMode::Stretch => cmp::max((size + chunk / 2) / chunk, 1),
+    };
+    count(dim.0, chunk_dim.0) as u64 * count(dim.1, chunk_dim.1) as u64
+}
+
+/// Scale of a model of `dim` for it to have at most `max_tiles` chunks. Estimated from the
+/// areas first, then lowered until the fill mode rounds the chunks below it too.
+fn tiles_scale(dim: (u32, u32), chunk_dim: (u32, u32), max_tiles: u32, mode: FillMode) -> f64 {
+    let area = |(w, h): (u32, u32)| w as f64 * h as f64;
+    let mut scale = (max_tiles as f64 * area(chunk_dim) / area(dim)).sqrt();
+    if scale >= 1.0 && chunk_count(dim, chunk_dim, mode) <= max_tiles as u64 {
+        return 1.0;
+    }
+    scale = scale.min(1.0);
+    while scale_dim(dim, scale) != (1, 1)
+        && chunk_count(scale_dim(dim, scale), chunk_dim, mode) > max_tiles as u64
+    {
+        scale *= 0.99;
+    }
+    scale
+}
+
+/// Columns and rows of chunks of the model whose mosaic, with `options`, is the closest to
+/// `size` pixels, one cell of each at least. The tiles are of the thumbnail size, as
+/// `match_tiles` places them, and as many cells as `options.layout` packs in the chunks.
+pub fn grid_for_size(size: (u32, u32), options: &MosaicOptions) -> (u32, u32) {
+    let chunk_dim = ratio_to_dim(options.tile_ratio, CHUNK_SIZE);
+    let dimensions = |columns: u32, rows: u32| {
+        let (grid_width, grid_height) = options
+            .layout
+            .grid((columns * chunk_dim.0, rows * chunk_dim.1), chunk_dim);
+        let placement = Placement {
+            grid_width,
+            grid_height,
+            layout: options.layout,
+            thumb_dim: ratio_to_dim(options.tile_ratio, THUMBNAIL_SIZE),
+            tiles: Vec::new(),
+        };
+        (grid_width, grid_height, placement.dimensions(options))
+    };
+    // The width of the hexagons depends on whether there's a shifted row, so the rows go first.
+    let rows = closest_count(size.1, |rows| {
+        let (_, grid_height, (_, height)) = dimensions(2, rows);
+        Some(height).filter(|_| grid_height > 0)
+    });
+    let columns = closest_count(size.0, |columns| {
+        let (grid_width, _, (width, _)) = dimensions(columns, rows);
+        Some(width).filter(|_| grid_width > 0)
+    });
+    (columns, rows)
+}
+
+/// Count of chunks whose mosaic, `mosaic_size` pixels long along an axis, is the closest to
+/// `size`, the first count giving a cell at least. `mosaic_size` is `None` without a cell
+/// and doesn't shrink with more chunks.
+fn closest_count(size: u32, mosaic_size: impl Fn(u32) -> Option<u32>) -> u32 {
+    let mut count = (1..)
+        .find(|&count| mosaic_size(count).is_some())
+        .expect("enough chunks give a cell");
+    let mut below = mosaic_size(count).unwrap();
+    while below < size {
+        let above = mosaic_size(count + 1).unwrap();
+        if above >= size {
+            return if above - size <= size - below {
+                count + 1
+            } else {
+                count
+            };
+        }
+        count += 1;
+        below = above;
+    }
+    count
+}
+
+/// Makes the dimensions of `model` multiples of `chunk_dim` as told by `mode`.
+fn fill_model(
+    model: DynamicImage,
+    chunk_dim: (u32, u32),
+    mode: FillMode,
+) -> Result<DynamicImage, String> {
+    let (w, h) = model.dimensions();
+    if w % chunk_dim.0 == 0 && h % chunk_dim.1 == 0 {
+        return Ok(model);
+    }
+
+    match mode {
+        FillMode::Crop => {
+            let (new_w, new_h) = (w - w % chunk_dim.0, h - h % chunk_dim.1);
+            if new_w == 0 || new_h == 0 {
+                return Err(format!(
+                    "the model is smaller than a chunk of {}x{} px, use another fill mode",
+                    chunk_dim.0, chunk_dim.1
+                ));
+            }
+            let mut model = model;
+            Ok(model.crop((w - new_w) / 2, (h - new_h) / 2, new_w, new_h))
+        }
+        FillMode::Pad => {
+            let new_w = w.div_ceil(chunk_dim.0) * chunk_dim.0;
+            let new_h = h.div_ceil(chunk_dim.1) * chunk_dim.1;
+            let mut padded = ImageBuffer::from_pixel(
+                new_w,
+                new_h,
+                Rgba([NEUTRAL_GRAY, NEUTRAL_GRAY, NEUTRAL_GRAY, 255]),
+            );
+            imageops::replace(
+                &mut padded,
+                &model.to_rgba(),
+                (new_w - w) / 2,
+                (new_h - h) / 2,
+            );
+            Ok(DynamicImage::ImageRgba8(padded))
+        }
+        FillMode::Stretch => {
+            let round = |size: u32, chunk: u32| cmp::max((size + chunk / 2) / chunk, 1) * chunk;
+            let (new_w, new_h) = (round(w, chunk_dim.0), round(h, chunk_dim.1));
+            Ok(model.resize_exact(new_w, new_h, imageops::FilterType::Triangle))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{image, picture, placement};
+
+    /// Image whose pixels have their column in red and their row in green.
+    fn coordinates_image(w: u32, h: u32) -> DynamicImage {
+        image(w, h, |x, y| [x as u8, y as u8, 0, 255])
+    }
+
+    /// Coordinates of the pixels sampled for each chunk of `img`.
+    fn sampled(img: &DynamicImage, layout: Layout, overlap: u32) -> Vec<Vec<(u8, u8)>> {
+        map_chunks(img, 2, 1, layout, overlap, |chunk| {
+            chunk.pixels().map(|p| (p.data[0], p.data[1])).collect()
+        })
+    }
+
+    #[test]
+    fn brick_wraps_the_last_cell_of_odd_rows_with_an_even_width() {
+        let chunks = sampled(&coordinates_image(4, 2), Layout::Brick, 0);
+        assert_eq!(
+            chunks,
+            vec![
+                vec![(0, 0), (1, 0)],
+                vec![(2, 0), (3, 0)],
+                vec![(1, 1), (2, 1)],
+                vec![(3, 1), (0, 1)],
+            ]
+        );
+    }
+
+    #[test]
+    fn brick_wraps_within_the_grid_with_an_odd_width() {
+        // The fifth column is left out of the grid of 2 cells, so the wrapped cell doesn't
+        // sample it.
+        let chunks = sampled(&coordinates_image(5, 2), Layout::Brick, 0);
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(chunks[2], vec![(1, 1), (2, 1)]);
+        assert_eq!(chunks[3], vec![(3, 1), (0, 1)]);
+    }
+
+    #[test]
+    fn brick_wrapped_cell_samples_the_overlap_on_both_sides() {
+        let chunks = sampled(&coordinates_image(6, 2), Layout::Brick, 1);
+        // Cells of row 1 start at 1, 3 and 5, the last one wrapping to the first column.
+        assert_eq!(
+            chunks[5],
+            vec![
+                (4, 0),
+                (5, 0),
+                (0, 0),
+                (1, 0),
+                (4, 1),
+                (5, 1),
+                (0, 1),
+                (1, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn hex_last_cell_of_odd_rows_is_not_wrapped() {
+        // The odd rows of hexagons are shifted by half a cell that the grid leaves room for.
+        let chunks = sampled(&coordinates_image(5, 2), Layout::Hex, 0);
+        assert_eq!(chunks.len(), 4);
+        assert_eq!(chunks[2], vec![(1, 1), (2, 1)]);
+        assert_eq!(chunks[3], vec![(3, 1), (4, 1)]);
+    }
+
+    #[test]
+    fn grid_cells_are_not_shifted() {
+        let chunks = sampled(&coordinates_image(5, 2), Layout::Grid, 0);
+        assert_eq!(chunks[3], vec![(2, 1), (3, 1)]);
+    }
+
+    #[test]
+    fn closest_pic_ties_go_to_the_first_picture() {
+        let pics = [
+            picture("red.png", [10, 0, 0]),
+            picture("green.png", [0, 10, 0]),
+        ];
+        let closest = find_closest_pic_by_color(&pics, [0, 0, 0], None, None, MatchMode::Color);
+        assert_eq!(closest.unwrap().path, "red.png");
+    }
+
+    #[test]
+    fn closest_pic_exact_ties_go_to_the_first_picture() {
+        let pics = [
+            picture("first.png", [0, 0, 0]),
+            picture("second.png", [0, 0, 0]),
+            picture("gray.png", [100, 100, 100]),
+        ];
+        let closest = find_closest_pic_by_color(&pics, [0, 0, 0], None, None, MatchMode::Color);
+        assert_eq!(closest.unwrap().path, "first.png");
+    }
+
+    #[test]
+    fn closest_pic_of_an_empty_gallery_is_an_error() {
+        let closest = find_closest_pic_by_color(&[], [0, 0, 0], None, None, MatchMode::Color);
+        assert!(matches!(closest, Err(MosaicError::EmptyGallery)));
+    }
+
+    #[test]
+    fn matching_an_empty_gallery_is_an_error() {
+        let model = image(16, 16, |_, _| [0, 0, 0, 255]);
+        let options = MosaicBuilder::new().build().unwrap();
+        let placement = match_tiles(&model, &[], (1, 1), &options);
+        assert!(matches!(placement, Err(MosaicError::EmptyGallery)));
+    }
+
+    fn model_options(crop: Option<Rect>) -> ModelOptions {
+        ModelOptions {
+            crop,
+            grayscale: false,
+            invert: false,
+            fill_mode: FillMode::Crop,
+            max_size: None,
+            max_tiles: None,
+            grid: None,
+        }
+    }
+
+    #[test]
+    fn crop_within_the_model_is_kept() {
+        let model = image(32, 32, |_, _| [0, 0, 0, 255]);
+        let prepared = prepare_model(model, &model_options(Some((8, 0, 16, 24))), (1, 1));
+        assert_eq!(prepared.unwrap().dimensions(), (16, 24));
+    }
+
+    #[test]
+    fn crop_past_the_model_is_invalid() {
+        let model = || image(32, 32, |_, _| [0, 0, 0, 255]);
+        for crop in [(24, 0, 16, 16), (0, 24, 16, 16)] {
+            let prepared = prepare_model(model(), &model_options(Some(crop)), (1, 1));
+            assert!(matches!(prepared, Err(MosaicError::Invalid(_))));
+        }
+    }
+
+    #[test]
+    fn crop_overflowing_u32_is_invalid() {
+        let model = || image(32, 32, |_, _| [0, 0, 0, 255]);
+        for crop in [(u32::MAX, 0, 2, 16), (0, 8, 16, u32::MAX)] {
+            let prepared = prepare_model(model(), &model_options(Some(crop)), (1, 1));
+            assert!(matches!(prepared, Err(MosaicError::Invalid(_))));
+        }
+    }
+
+    #[test]
+    fn print_size_is_the_closest_mosaic_of_every_layout() {
+        let pics = [picture("a.png", [0, 0, 0])];
+        let sizes = (100..1600).step_by(89).map(|size| (size, size + size / 3));
+        for layout in [Layout::Grid, Layout::Brick, Layout::Hex] {
+            for spacing in [0, 5] {
+                let options = MosaicBuilder::new()
+                    .layout(layout)
+                    .spacing(spacing)
+                    .build()
+                    .unwrap();
+                // Half the distance between two sizes the chunks give, two rows of hexagons at
+                // times as the rows of chunks are higher than theirs.
+                let column_pitch = THUMBNAIL_SIZE + spacing;
+                let row_pitch = match layout {
+                    Layout::Hex => 2 * (layout.row_pitch(THUMBNAIL_SIZE) + spacing),
+                    _ => column_pitch,
+                };
+                for size in sizes.clone().chain(Some((10, 10))) {
+                    let model_options = ModelOptions {
+                        grid: Some(grid_for_size(size, &options)),
+                        ..model_options(None)
+                    };
+                    let model = image(50, 30, |_, _| [0, 0, 0, 255]);
+                    let model = prepare_model(model, &model_options, (1, 1)).unwrap();
+                    let cells = grid_size(&model, (1, 1), layout);
+                    let thumb_dim = (THUMBNAIL_SIZE, THUMBNAIL_SIZE);
+                    let dims = placement(&pics, cells, layout, thumb_dim).dimensions(&options);
+                    let message = format!("{:?} of {:?} with {}", dims, size, spacing);
+                    assert!(cells.0 >= 1 && cells.1 >= 1, "{}", message);
+                    if size != (10, 10) {
+                        assert!(dims.0.abs_diff(size.0) <= column_pitch / 2, "{}", message);
+                        assert!(dims.1.abs_diff(size.1) <= row_pitch / 2, "{}", message);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Histogram of as many black as white pixels.
+    fn black_and_white_histogram() -> Vec<u16> {
+        let mut histogram = vec![0; 512];
+        histogram[0] = 50;
+        histogram[511] = 50;
+        histogram
+    }
+
+    #[test]
+    fn histograms_rank_by_the_spread_of_the_colors() {
+        let mut gray = picture("gray.png", [128, 128, 128]);
+        gray.color_histogram = Some({
+            let mut histogram = vec![0; 512];
+            histogram[4 * 64 + 4 * 8 + 4] = 100;
+            histogram
+        });
+        let mut stripes = picture("stripes.png", [100, 100, 100]);
+        stripes.color_histogram = Some(black_and_white_histogram());
+        let pics = [gray, stripes];
+        let chunk = black_and_white_histogram();
+        let gray_chunk = [128, 128, 128];
+
+        let by_color = find_closest_pic_by_color(&pics, gray_chunk, None, None, MatchMode::Color);
+        assert_eq!(by_color.unwrap().path, "gray.png");
+        let by_histogram =
+            find_closest_pic_by_color(&pics, gray_chunk, Some(&chunk), None, MatchMode::Histogram);
+        assert_eq!(by_histogram.unwrap().path, "stripes.png");
+    }
+
+    #[test]
+    fn histogram_matching_needs_a_histogram_for_every_picture() {
+        let mut with_histogram = picture("with.png", [0, 0, 0]);
+        with_histogram.color_histogram = Some(black_and_white_histogram());
+        let pics = [with_histogram, picture("without.png", [0, 0, 0])];
+        let model = image(16, 16, |_, _| [0, 0, 0, 255]);
+        let options = MosaicBuilder::new()
+            .match_mode(MatchMode::Histogram)
+            .build()
+            .unwrap();
+        let placement = match_tiles(&model, &pics, (1, 1), &options);
+        assert!(matches!(placement, Err(MosaicError::Invalid(_))));
+    }
+
+    /// Paths of the pictures matched with the cells of `placement`, in row-major order.
+    fn tile_paths<'a>(placement: &Placement<'a>) -> Vec<&'a str> {
+        (placement.tiles.iter())
+            .map(|tile| tile.pic.path.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn dithering_alternates_the_tiles_of_a_flat_gray() {
+        // 4x2 chunks between black and white, a bit closer to white.
+        let model = image(4 * CHUNK_SIZE, 2 * CHUNK_SIZE, |_, _| [128, 128, 128, 255]);
+        let pics = [
+            picture("black.png", [0, 0, 0]),
+            picture("white.png", [255, 255, 255]),
+        ];
+        let options = MosaicBuilder::new().build().unwrap();
+        let placement = match_tiles(&model, &pics, (1, 1), &options).unwrap();
+        assert_eq!(tile_paths(&placement), ["white.png"; 8]);
+
+        // The first row gets 128, 128 - 127 * 7 / 16 = 73, 128 + 73 * 7 / 16 = 159 and
+        // 128 - 96 * 7 / 16 = 86, and the second row the errors of the first too.
+        let options = MosaicBuilder::new().dither(true).build().unwrap();
+        let placement = match_tiles(&model, &pics, (1, 1), &options).unwrap();
+        let (black, white) = ("black.png", "white.png");
+        assert_eq!(
+            tile_paths(&placement),
+            [white, black, white, black, black, white, black, white]
+        );
+    }
+
+    #[test]
+    fn masked_cells_use_up_no_picture() {
+        // A 4x4 grid of a flat gray whose right half is masked, for 8 pictures used once each.
+        let model = image(4 * CHUNK_SIZE, 4 * CHUNK_SIZE, |_, _| [128, 128, 128, 255]);
+        let mask = image(4, 4, |x, _| if x < 2 { [255; 4] } else { [0, 0, 0, 255] });
+        let masked = masked_cells(&mask, (4, 4), 0.5);
+        assert_eq!(masked.iter().filter(|&&masked| masked).count(), 8);
+        let pics: Vec<_> = (0..8)
+            .map(|i| picture(&format!("{}.png", i), [i * 30; 3]))
+            .collect();
+        let options = MosaicBuilder::new()
+            .max_uses(Some(1))
+            .two_pass(true)
+            .build()
+            .unwrap();
+        let placement = match_unmasked_tiles(&model, &pics, (1, 1), &options, &masked).unwrap();
+        let flags: Vec<_> = placement.tiles.iter().map(|tile| tile.masked).collect();
+        assert_eq!(flags, masked);
+        let mut shown: Vec<_> = (placement.tiles.iter())
+            .filter(|tile| !tile.masked)
+            .map(|tile| tile.pic.path.as_str())
+            .collect();
+        shown.sort_unstable();
+        shown.dedup();
+        assert_eq!(shown.len(), 8, "{:?}", shown);
+    }
+
+    #[test]
+    fn dimensions_of_a_grid_add_the_spacing_between_and_around_the_cells() {
+        let pics = [picture("0.png", [0, 0, 0])];
+        let placement = placement(&pics, (3, 2), Layout::Grid, (8, 6));
+        let options = MosaicBuilder::new().build().unwrap();
+        assert_eq!(placement.dimensions(&options), (24, 12));
+        let options = MosaicBuilder::new().spacing(2).build().unwrap();
+        assert_eq!(placement.dimensions(&options), (3 * 10 + 2, 2 * 8 + 2));
+    }
+
+    #[test]
+    fn dimensions_of_hexagons_add_the_shift_and_the_overhang() {
+        let pics = [picture("0.png", [0, 0, 0])];
+        let placement = placement(&pics, (3, 2), Layout::Hex, (8, 8));
+        // Rows 6 pixels apart, the odd ones shifted by half a cell.
+        let options = MosaicBuilder::new().build().unwrap();
+        assert_eq!(placement.dimensions(&options), (3 * 8 + 4, 2 * 6 + 2));
+        let options = MosaicBuilder::new().spacing(2).build().unwrap();
+        assert_eq!(
+            placement.dimensions(&options),
+            (3 * 10 + 2 + 5, 2 * 8 + 2 + 2)
+        );
+    }
+
+    #[test]
+    fn gray_tone_has_the_rec_709_luma_of_the_color() {
+        assert_eq!(Tone::Gray.apply([255, 0, 0]), [54; 3]);
+        assert_eq!(Tone::Gray.apply([0, 255, 0]), [182; 3]);
+        assert_eq!(Tone::Gray.apply([0, 0, 255]), [18; 3]);
+        let duotone = Tone::Duotone([0, 0, 100], [200, 100, 200]);
+        assert_eq!(duotone.apply([0, 0, 0]), [0, 0, 100]);
+        assert_eq!(duotone.apply([255, 255, 255]), [200, 100, 200]);
+    }
+
+    #[test]
+    fn gray_tone_matches_by_luma() {
+        let model = image(CHUNK_SIZE, CHUNK_SIZE, |_, _| [0, 0, 255, 255]);
+        let mut pics = [
+            picture("teal.png", [0, 128, 255]),
+            picture("black.png", [0; 3]),
+        ];
+        let options = MosaicBuilder::new().build().unwrap();
+        let placement = match_tiles(&model, &pics, (1, 1), &options).unwrap();
+        assert_eq!(tile_paths(&placement), ["teal.png"]);
+        assert_eq!(placement.tiles[0].target_color, [0, 0, 255]);
+
+        // With their colors reduced to gray too, as `create` does.
+        for pic in &mut pics {
+            pic.color_rgb = Tone::Gray.apply(pic.color_rgb);
+        }
+        let options = MosaicBuilder::new().tone(Some(Tone::Gray)).build().unwrap();
+        let placement = match_tiles(&model, &pics, (1, 1), &options).unwrap();
+        assert_eq!(tile_paths(&placement), ["black.png"]);
+        assert_eq!(placement.tiles[0].target_color, [18; 3]);
+    }
+}
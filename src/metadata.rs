@@ -0,0 +1,454 @@
+//! Metadata of a preprocessed gallery: the thumbnails and the colors they are matched by.
+
+use crate::binary::{self, CBOR_MAGIC};
+use crate::error::MosaicError;
+use crate::matching::color_distance;
+use crate::preprocess::ColorMode;
+use crate::CONTRAST_ADJUSTMENT;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+pub const METADATA_FILENAME: &str = "mosaic.json";
+/// Name of the metadata written with `MetadataFormat::Ndjson`.
+pub const METADATA_NDJSON_FILENAME: &str = "mosaic.ndjson";
+/// Name of the metadata written with `MetadataFormat::Cbor`.
+pub const METADATA_CBOR_FILENAME: &str = "mosaic.cbor";
+/// Name of the metadata written with `MetadataFormat::MessagePack`.
+pub const METADATA_MSGPACK_FILENAME: &str = "mosaic.msgpack";
+/// Version of the metadata, bumped when the colors it holds are computed differently.
+pub const METADATA_VERSION: u32 = 3;
+
+#[derive(Serialize, Deserialize, Debug)]
+#[non_exhaustive]
+pub struct ProcessedPictureMetadata {
+    /// 1 for the metadata written before the version was recorded.
+    #[serde(default = "default_version")]
+    pub version: u32,
+    pub pictures: Vec<ProcessedPicture>,
+    /// Contrast adjustment applied to the thumbnails.
+    #[serde(default = "default_contrast_adjustment")]
+    pub contrast_adjustment: f32,
+    /// Whether the colors of the pictures were averaged in linear light.
+    #[serde(default)]
+    pub linear_light: bool,
+    /// How the colors of the pictures were computed, the mean for the metadata written before
+    /// it was recorded.
+    #[serde(default)]
+    pub color_mode: ColorMode,
+}
+
+/// How the metadata of a gallery is written.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MetadataFormat {
+    /// A single JSON document, in `METADATA_FILENAME`.
+    Json,
+    /// Newline-delimited JSON, in `METADATA_NDJSON_FILENAME`: a line with the fields of the
+    /// metadata but the pictures, then a line per picture, so that the pictures of a huge
+    /// gallery can be read one at a time.
+    Ndjson,
+    /// CBOR, in `METADATA_CBOR_FILENAME`, less than two thirds the size of JSON and faster to
+    /// parse.
+    Cbor,
+    /// MessagePack, in `METADATA_MSGPACK_FILENAME`, about as small as CBOR.
+    MessagePack,
+}
+
+impl MetadataFormat {
+    pub const ALL: [MetadataFormat; 4] = [
+        MetadataFormat::Json,
+        MetadataFormat::Ndjson,
+        MetadataFormat::Cbor,
+        MetadataFormat::MessagePack,
+    ];
+
+    pub fn filename(self) -> &'static str {
+        match self {
+            MetadataFormat::Json => METADATA_FILENAME,
+            MetadataFormat::Ndjson => METADATA_NDJSON_FILENAME,
+            MetadataFormat::Cbor => METADATA_CBOR_FILENAME,
+            MetadataFormat::MessagePack => METADATA_MSGPACK_FILENAME,
+        }
+    }
+
+    /// Format of the metadata file at `path`, told by its extension.
+    pub fn of_path(path: &Path) -> MetadataFormat {
+        let extension = path.extension().and_then(|ext| ext.to_str());
+        match extension.map(str::to_ascii_lowercase).as_deref() {
+            Some("ndjson") => MetadataFormat::Ndjson,
+            Some("cbor") => MetadataFormat::Cbor,
+            Some("msgpack") | Some("mpk") => MetadataFormat::MessagePack,
+            _ => MetadataFormat::Json,
+        }
+    }
+
+    /// Format of the metadata starting with `head`, whatever the extension of its file: CBOR
+    /// starts with its self-describe tag and MessagePack with a map, JSON being told from
+    /// ndjson by the extension of `path`.
+    fn sniff(path: &Path, head: &[u8]) -> MetadataFormat {
+        match head {
+            _ if head.starts_with(&CBOR_MAGIC) => MetadataFormat::Cbor,
+            [0x80..=0x8f | 0xde | 0xdf, ..] => MetadataFormat::MessagePack,
+            _ => match MetadataFormat::of_path(path) {
+                MetadataFormat::Ndjson => MetadataFormat::Ndjson,
+                _ => MetadataFormat::Json,
+            },
+        }
+    }
+}
+
+/// First line of the ndjson metadata.
+#[derive(Serialize, Deserialize)]
+struct MetadataHeader {
+    #[serde(default = "default_version")]
+    version: u32,
+    #[serde(default = "default_contrast_adjustment")]
+    contrast_adjustment: f32,
+    #[serde(default)]
+    linear_light: bool,
+    #[serde(default)]
+    color_mode: ColorMode,
+}
+
+fn default_version() -> u32 {
+    1
+}
+
+fn default_contrast_adjustment() -> f32 {
+    CONTRAST_ADJUSTMENT
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[non_exhaustive]
+pub struct ProcessedPicture {
+    pub path: String,
+    pub color_rgb: [u8; 3],
+    pub ratio_width: u32,
+    pub ratio_height: u32,
+    /// Pixel counts of the thumbnail in `HISTOGRAM_BINS_PER_CHANNEL`^3 color bins, with
+    /// `PreprocessOptions::histogram`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub color_histogram: Option<Vec<u16>>,
+    /// Path of the original picture in the gallery.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+    /// Standard deviation of the luma of the thumbnail.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub contrast: Option<f32>,
+    /// Dominant colors, from the most to the least present, with `ColorMode::Dominant`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub palette: Option<Vec<[u8; 3]>>,
+    /// Difference hash of the thumbnail, close for copies of a picture.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phash: Option<u64>,
+}
+
+/// Writes `metadata` in `processed_folder` in `format`, removing the metadata of the other
+/// format so that it isn't read instead.
+pub(crate) fn save_processed_pictures_metadata(
+    metadata: &ProcessedPictureMetadata,
+    processed_folder: &Path,
+    format: MetadataFormat,
+) -> Result<(), MosaicError> {
+    let file = File::create(processed_folder.join(format.filename()))?;
+    let mut writer = BufWriter::new(file);
+    match format {
+        MetadataFormat::Json => serde_json::to_writer_pretty(writer, metadata)?,
+        MetadataFormat::Cbor => writer.write_all(&binary::to_cbor(metadata)?)?,
+        MetadataFormat::MessagePack => writer.write_all(&binary::to_msgpack(metadata)?)?,
+        MetadataFormat::Ndjson => {
+            let header = MetadataHeader {
+                version: metadata.version,
+                contrast_adjustment: metadata.contrast_adjustment,
+                linear_light: metadata.linear_light,
+                color_mode: metadata.color_mode,
+            };
+            serde_json::to_writer(&mut writer, &header)?;
+            writer.write_all(b"\n")?;
+            for pic in &metadata.pictures {
+                serde_json::to_writer(&mut writer, pic)?;
+                writer.write_all(b"\n")?;
+            }
+            writer.flush()?;
+        }
+    }
+
+    for other in MetadataFormat::ALL.iter().filter(|&&other| other != format) {
+        let other_path = processed_folder.join(other.filename());
+        if other_path.exists() {
+            fs::remove_file(other_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Path of the metadata written in `processed_folder` by `preprocess_gallery`, in any format.
+pub fn metadata_path(processed_folder: &Path) -> PathBuf {
+    (MetadataFormat::ALL.iter())
+        .map(|format| processed_folder.join(format.filename()))
+        .find(|path| path.is_file())
+        .unwrap_or_else(|| processed_folder.join(METADATA_FILENAME))
+}
+
+/// Loads the metadata written in `processed_folder` by `preprocess_gallery`.
+pub fn load_metadata(processed_folder: &Path) -> Result<ProcessedPictureMetadata, MosaicError> {
+    load_metadata_file(&metadata_path(processed_folder))
+}
+
+/// Loads the metadata written in `processed_folder` like `load_metadata`, passing each picture
+/// to `f` as it is read so that it can drop the fields the caller doesn't need. With
+/// `MetadataFormat::Ndjson`, only the pictures as left by `f` are held in memory.
+pub fn load_metadata_with<F>(
+    processed_folder: &Path,
+    f: F,
+) -> Result<ProcessedPictureMetadata, MosaicError>
+where
+    F: FnMut(&mut ProcessedPicture),
+{
+    load_metadata_file_with(&metadata_path(processed_folder), f)
+}
+
+/// Loads the metadata file at `path`, `METADATA_FILENAME` or the file of another format of a
+/// preprocessed folder, or a copy of it. The binary formats are recognized whatever the name.
+pub fn load_metadata_file(path: &Path) -> Result<ProcessedPictureMetadata, MosaicError> {
+    load_metadata_file_with(path, |_| {})
+}
+
+fn load_metadata_file_with<F>(
+    path: &Path,
+    mut f: F,
+) -> Result<ProcessedPictureMetadata, MosaicError>
+where
+    F: FnMut(&mut ProcessedPicture),
+{
+    let mut reader = BufReader::new(File::open(path)?);
+    let format = MetadataFormat::sniff(path, reader.fill_buf()?);
+    let metadata = match format {
+        MetadataFormat::Cbor | MetadataFormat::MessagePack => {
+            let mut bytes = Vec::new();
+            reader.read_to_end(&mut bytes)?;
+            let mut metadata: ProcessedPictureMetadata = match format {
+                MetadataFormat::Cbor => binary::from_cbor(&bytes)?,
+                _ => binary::from_msgpack(&bytes)?,
+            };
+            metadata.pictures.iter_mut().for_each(f);
+            metadata
+        }
+        MetadataFormat::Json => {
+            let mut metadata: ProcessedPictureMetadata = serde_json::from_reader(reader)?;
+            metadata.pictures.iter_mut().for_each(f);
+            metadata
+        }
+        MetadataFormat::Ndjson => {
+            // Read a line at a time into the same buffer, each picture going straight to the
+            // metadata.
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                return Err("empty metadata file".into());
+            }
+            let header: MetadataHeader = serde_json::from_str(&line)?;
+            // Checked before the pictures are read, a newer gallery not being read in vain.
+            if header.version > METADATA_VERSION {
+                return Err(MosaicError::UnsupportedVersion(header.version));
+            }
+            let mut metadata = ProcessedPictureMetadata {
+                version: header.version,
+                pictures: Vec::new(),
+                contrast_adjustment: header.contrast_adjustment,
+                linear_light: header.linear_light,
+                color_mode: header.color_mode,
+            };
+            let mut line_number = 1;
+            loop {
+                line.clear();
+                if reader.read_line(&mut line)? == 0 {
+                    break;
+                }
+                line_number += 1;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let mut pic: ProcessedPicture = serde_json::from_str(&line)
+                    .map_err(|e| MosaicError::Invalid(format!("line {}: {}", line_number, e)))?;
+                f(&mut pic);
+                metadata.pictures.push(pic);
+            }
+            metadata
+        }
+    };
+    if metadata.version > METADATA_VERSION {
+        return Err(MosaicError::UnsupportedVersion(metadata.version));
+    }
+    Ok(metadata)
+}
+
+/// Picture of both metadata whose color differs.
+#[derive(Serialize, Debug)]
+pub struct ChangedPicture {
+    pub path: String,
+    pub old_color: [u8; 3],
+    pub new_color: [u8; 3],
+    /// `color_distance` between the two colors.
+    pub distance: u32,
+}
+
+/// Differences between two metadata of a gallery, such as before and after new pictures were
+/// preprocessed. Pictures are identified by the path of their thumbnail.
+#[derive(Serialize, Debug, Default)]
+pub struct MetadataDiff {
+    /// Pictures only in the new metadata.
+    pub added: Vec<String>,
+    /// Pictures only in the old metadata.
+    pub removed: Vec<String>,
+    /// Pictures in both whose color moved by more than the threshold.
+    pub changed: Vec<ChangedPicture>,
+}
+
+impl MetadataDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// Compares the pictures of `old` and `new`, in the order of `new` then of `old` for the
+/// removed ones. The pictures whose color moved by `threshold` or less count as unchanged.
+pub fn diff_metadata(
+    old: &ProcessedPictureMetadata,
+    new: &ProcessedPictureMetadata,
+    threshold: u32,
+) -> MetadataDiff {
+    let old_colors: HashMap<&str, [u8; 3]> = old
+        .pictures
+        .iter()
+        .map(|pic| (pic.path.as_str(), pic.color_rgb))
+        .collect();
+    let new_paths: HashSet<&str> = new.pictures.iter().map(|pic| pic.path.as_str()).collect();
+
+    let mut diff = MetadataDiff::default();
+    for pic in &new.pictures {
+        match old_colors.get(pic.path.as_str()) {
+            None => diff.added.push(pic.path.clone()),
+            Some(&old_color) => {
+                let distance = color_distance(old_color, pic.color_rgb);
+                if distance > threshold {
+                    diff.changed.push(ChangedPicture {
+                        path: pic.path.clone(),
+                        old_color,
+                        new_color: pic.color_rgb,
+                        distance,
+                    });
+                }
+            }
+        }
+    }
+    diff.removed = old
+        .pictures
+        .iter()
+        .filter(|pic| !new_paths.contains(pic.path.as_str()))
+        .map(|pic| pic.path.clone())
+        .collect();
+    diff
+}
+
+/// Picture left out as a near duplicate of another by `find_near_duplicates`.
+#[derive(Serialize, Debug)]
+pub struct NearDuplicate {
+    pub path: String,
+    /// Picture kept in its place.
+    pub kept: String,
+    /// `color_distance` between the two pictures.
+    pub distance: u32,
+}
+
+/// Groups the pictures of the same ratio whose colors are at most `max_distance` apart, such
+/// as bursts of photos, which add no variety to a mosaic. The first picture of each group is
+/// kept and the others are returned.
+pub fn find_near_duplicates(pics: &[ProcessedPicture], max_distance: u32) -> Vec<NearDuplicate> {
+    let mut kept: Vec<&ProcessedPicture> = Vec::new();
+    let mut duplicates = Vec::new();
+    for pic in pics {
+        let closest = kept
+            .iter()
+            .filter(|k| (k.ratio_width, k.ratio_height) == (pic.ratio_width, pic.ratio_height))
+            .map(|k| (k, color_distance(k.color_rgb, pic.color_rgb)))
+            .min_by_key(|&(_, distance)| distance);
+        match closest {
+            Some((k, distance)) if distance <= max_distance => duplicates.push(NearDuplicate {
+                path: pic.path.clone(),
+                kept: k.path.clone(),
+                distance,
+            }),
+            _ => kept.push(pic),
+        }
+    }
+    duplicates
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{picture, temp_dir};
+
+    fn metadata(pictures: Vec<ProcessedPicture>) -> ProcessedPictureMetadata {
+        ProcessedPictureMetadata {
+            version: METADATA_VERSION,
+            pictures,
+            contrast_adjustment: 0.0,
+            linear_light: true,
+            color_mode: ColorMode::Median,
+        }
+    }
+
+    #[test]
+    fn ndjson_pictures_are_passed_to_f_as_they_are_read() {
+        let folder = temp_dir("ndjson");
+        let mut pic = picture("0.png", [1, 2, 3]);
+        pic.color_histogram = Some(vec![1; 512]);
+        let pics = vec![pic, picture("1.png", [4, 5, 6])];
+        save_processed_pictures_metadata(&metadata(pics), &folder, MetadataFormat::Ndjson).unwrap();
+
+        let mut read = 0;
+        let loaded = load_metadata_with(&folder, |pic| {
+            pic.color_histogram = None;
+            read += 1;
+        })
+        .unwrap();
+        assert_eq!(read, 2);
+        assert_eq!(loaded.color_mode, ColorMode::Median);
+        assert!(loaded.linear_light);
+        let pics: Vec<_> = (loaded.pictures.iter())
+            .map(|pic| {
+                (
+                    pic.path.as_str(),
+                    pic.color_rgb,
+                    pic.color_histogram.is_some(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            pics,
+            [("0.png", [1, 2, 3], false), ("1.png", [4, 5, 6], false)]
+        );
+    }
+
+    #[test]
+    fn ndjson_of_a_newer_version_is_rejected_before_its_pictures() {
+        let path = temp_dir("ndjson").join(METADATA_NDJSON_FILENAME);
+        let header = format!("{{\"version\":{}}}\nnot a picture\n", METADATA_VERSION + 1);
+        fs::write(&path, header).unwrap();
+        let loaded = load_metadata_file(&path);
+        assert!(matches!(loaded, Err(MosaicError::UnsupportedVersion(_))));
+    }
+
+    #[test]
+    fn ndjson_errors_tell_the_line() {
+        let path = temp_dir("ndjson").join(METADATA_NDJSON_FILENAME);
+        fs::write(&path, "{\"version\":3}\n\nnot a picture\n").unwrap();
+        match load_metadata_file(&path) {
+            Err(MosaicError::Invalid(message)) => assert!(message.starts_with("line 3:")),
+            other => panic!("unexpected {:?}", other.map(|_| ())),
+        }
+    }
+}
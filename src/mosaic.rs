@@ -0,0 +1,1257 @@
+//! Rendering of a placement as the mosaic image, in one piece or band by band.
+
+use crate::error::MosaicError;
+use crate::icc;
+use crate::manifest::{Manifest, ManifestCell, MapCell, TileMap};
+use crate::matching::{
+    color_distance, match_tiles, Layout, MaskFill, MosaicOptions, Placement, Tone,
+};
+use crate::metadata::ProcessedPicture;
+use crate::png_stream;
+use crate::progress::{Cancelled, Progress};
+use crate::trace;
+use gif::SetParameter;
+use image::{
+    self, imageops, DynamicImage, GenericImage, GenericImageView, ImageBuffer, ImageResult,
+    Primitive, Rgba,
+};
+use rayon::prelude::*;
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Identifier of the APP2 segments of a JPEG holding an ICC profile.
+const ICC_MARKER: &[u8] = b"ICC_PROFILE\0";
+
+/// Type of the samples a mosaic is rendered in: `u8`, or `u16` for a 16-bit PNG whose
+/// blends and color space conversion keep the precision the 8-bit thumbnails don't have.
+pub trait Sample: Primitive + Send + Sync + 'static {
+    /// Value of white and of opaque.
+    const MAX: u32;
+
+    /// Widens an 8-bit sample, 255 staying white.
+    fn from_u8(value: u8) -> Self;
+
+    /// Sample of `value`, which must be at most `MAX`.
+    fn from_u32(value: u32) -> Self;
+
+    fn to_u32(self) -> u32;
+
+    /// Closest sample to `value`, saturating.
+    fn from_f32(value: f32) -> Self;
+
+    /// Converts an sRGB color to Adobe RGB.
+    fn to_adobe_rgb(rgb: [Self; 3]) -> [Self; 3];
+
+    /// Widens the samples of an 8-bit image.
+    fn widen(img: ImageBuffer<Rgba<u8>, Vec<u8>>) -> ImageBuffer<Rgba<Self>, Vec<Self>>;
+
+    /// Rounds the samples of `img` to 8 bits, for the outputs that only have those.
+    fn narrow(img: &ImageBuffer<Rgba<Self>, Vec<Self>>) -> Cow<'_, ImageBuffer<Rgba<u8>, Vec<u8>>>;
+
+    /// Big endian bytes of `samples`, the order of PNG.
+    fn to_png_bytes(samples: &[Self]) -> Cow<'_, [u8]>;
+}
+
+impl Sample for u8 {
+    const MAX: u32 = 255;
+
+    fn from_u8(value: u8) -> u8 {
+        value
+    }
+
+    fn from_u32(value: u32) -> u8 {
+        value as u8
+    }
+
+    fn to_u32(self) -> u32 {
+        u32::from(self)
+    }
+
+    fn from_f32(value: f32) -> u8 {
+        value.round() as u8
+    }
+
+    fn to_adobe_rgb(rgb: [u8; 3]) -> [u8; 3] {
+        icc::srgb_to_adobe_rgb(rgb)
+    }
+
+    fn widen(img: ImageBuffer<Rgba<u8>, Vec<u8>>) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+        img
+    }
+
+    fn narrow(img: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> Cow<'_, ImageBuffer<Rgba<u8>, Vec<u8>>> {
+        Cow::Borrowed(img)
+    }
+
+    fn to_png_bytes(samples: &[u8]) -> Cow<'_, [u8]> {
+        Cow::Borrowed(samples)
+    }
+}
+
+impl Sample for u16 {
+    const MAX: u32 = 65535;
+
+    /// `value * 257`, whose bytes are both `value`.
+    fn from_u8(value: u8) -> u16 {
+        u16::from_be_bytes([value, value])
+    }
+
+    fn from_u32(value: u32) -> u16 {
+        value as u16
+    }
+
+    fn to_u32(self) -> u32 {
+        u32::from(self)
+    }
+
+    fn from_f32(value: f32) -> u16 {
+        value.round() as u16
+    }
+
+    fn to_adobe_rgb(rgb: [u16; 3]) -> [u16; 3] {
+        icc::srgb_to_adobe_rgb16(rgb)
+    }
+
+    fn widen(img: ImageBuffer<Rgba<u8>, Vec<u8>>) -> ImageBuffer<Rgba<u16>, Vec<u16>> {
+        let (w, h) = img.dimensions();
+        let samples = img.into_raw().into_iter().map(u16::from_u8).collect();
+        ImageBuffer::from_raw(w, h, samples).expect("as many samples as the 8-bit image")
+    }
+
+    fn narrow(img: &ImageBuffer<Rgba<u16>, Vec<u16>>) -> Cow<'_, ImageBuffer<Rgba<u8>, Vec<u8>>> {
+        let (w, h) = img.dimensions();
+        let samples = img
+            .iter()
+            .map(|&value| ((u32::from(value) * 255 + 32767) / 65535) as u8)
+            .collect();
+        Cow::Owned(ImageBuffer::from_raw(w, h, samples).expect("as many samples as the image"))
+    }
+
+    fn to_png_bytes(samples: &[u16]) -> Cow<'_, [u8]> {
+        Cow::Owned(
+            samples
+                .iter()
+                .flat_map(|value| value.to_be_bytes())
+                .collect(),
+        )
+    }
+}
+
+/// Pixel of `color` in samples of `S`.
+fn pixel<S: Sample>(color: [u8; 4]) -> Rgba<S> {
+    Rgba(color.map(S::from_u8))
+}
+
+/// Copies the 8-bit `img` in `res` at `(x, y)`, where it must fit.
+fn copy_widened<S, I>(res: &mut ImageBuffer<Rgba<S>, &mut [S]>, img: &I, x: u32, y: u32)
+where
+    S: Sample,
+    I: GenericImageView<Pixel = Rgba<u8>>,
+{
+    for (px, py, color) in img.pixels() {
+        res.put_pixel(x + px, y + py, pixel(color.data));
+    }
+}
+
+/// Thumbnails decoded once and reused by the mosaics rendered with it, such as the ones of a
+/// batch of models made from the same gallery. Holds every thumbnail it opened, so it isn't
+/// meant for the mosaics rendered band by band to save memory.
+#[derive(Default)]
+pub struct ThumbnailCache {
+    thumbs: Mutex<HashMap<PathBuf, DynamicImage>>,
+}
+
+impl ThumbnailCache {
+    pub fn new() -> ThumbnailCache {
+        ThumbnailCache::default()
+    }
+
+    /// Number of thumbnails held.
+    pub fn len(&self) -> usize {
+        self.thumbs.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn open(&self, path: &Path) -> ImageResult<DynamicImage> {
+        if let Some(thumb) = self.thumbs.lock().unwrap().get(path) {
+            return Ok(thumb.clone());
+        }
+        // Decoded without the lock so that the other threads aren't blocked meanwhile.
+        let thumb = image::open(path)?;
+        self.thumbs
+            .lock()
+            .unwrap()
+            .insert(path.to_owned(), thumb.clone());
+        Ok(thumb)
+    }
+}
+
+/// Opens the thumbnail at `path`, from `cache` if given.
+fn open_thumbnail(path: &Path, cache: Option<&ThumbnailCache>) -> ImageResult<DynamicImage> {
+    match cache {
+        Some(cache) => cache.open(path),
+        None => image::open(path),
+    }
+}
+
+/// Copy of `thumb` with its colors reduced by `tone`, the cached thumbnail being left as is.
+fn apply_tone(thumb: &DynamicImage, tone: Tone) -> DynamicImage {
+    let mut toned = thumb.to_rgba();
+    for pixel in toned.pixels_mut() {
+        let [r, g, b] = tone.apply([pixel.data[0], pixel.data[1], pixel.data[2]]);
+        pixel.data = [r, g, b, pixel.data[3]];
+    }
+    DynamicImage::ImageRgba8(toned)
+}
+
+/// Overlays `model`, scaled to the mosaic dimensions, on `mosaic` at the given opacity.
+fn ghost_model<S: Sample>(
+    mosaic: &mut ImageBuffer<Rgba<S>, Vec<S>>,
+    model: &DynamicImage,
+    opacity: f32,
+) {
+    let (w, h) = mosaic.dimensions();
+    let mut ghost = S::widen(imageops::resize(
+        model,
+        w,
+        h,
+        imageops::FilterType::Triangle,
+    ));
+    let alpha = u64::from(S::from_f32(opacity * S::MAX as f32).to_u32());
+    for pixel in ghost.pixels_mut() {
+        let value = u64::from(pixel.data[3].to_u32()) * alpha / u64::from(S::MAX);
+        pixel.data[3] = S::from_u32(value as u32);
+    }
+    imageops::overlay(mosaic, &ghost, 0, 0);
+}
+
+fn fill_rect<S: Sample>(
+    img: &mut ImageBuffer<Rgba<S>, &mut [S]>,
+    rect: (u32, u32, u32, u32),
+    color: Rgba<S>,
+) {
+    let (x, y, w, h) = rect;
+    for py in y..y + h {
+        for px in x..x + w {
+            img.put_pixel(px, py, color);
+        }
+    }
+}
+
+/// Blends `img` over an opaque `background`.
+fn composite_over<S: Sample>(
+    img: &ImageBuffer<Rgba<S>, Vec<S>>,
+    background: Rgba<u8>,
+    opacity: f32,
+) -> ImageBuffer<Rgba<S>, Vec<S>> {
+    let background: Rgba<S> = pixel(background.data);
+    let max = u64::from(S::MAX);
+    let mut res = img.clone();
+    for pixel in res.pixels_mut() {
+        let alpha = u64::from(S::from_f32(pixel.data[3].to_u32() as f32 * opacity).to_u32());
+        for c in 0..3 {
+            let value = u64::from(pixel.data[c].to_u32()) * alpha
+                + u64::from(background.data[c].to_u32()) * (max - alpha);
+            pixel.data[c] = S::from_u32(((value + max / 2) / max) as u32);
+        }
+        pixel.data[3] = S::from_u32(S::MAX);
+    }
+    res
+}
+
+/// Renders the rows `band_y..band_y + band_h` of the mosaic, loading only the thumbnails of
+/// the cells crossing them, from `cache` if given. Errors if one of them can't be opened, or
+/// if `progress` cancels the rendering.
+pub fn render_band<S: Sample>(
+    processed_folder: &Path,
+    placement: &Placement,
+    options: &MosaicOptions,
+    band_y: u32,
+    band_h: u32,
+    cache: Option<&ThumbnailCache>,
+    progress: &dyn Progress,
+) -> ImageResult<ImageBuffer<Rgba<S>, Vec<S>>> {
+    let w = placement.dimensions(options).0;
+    let row_len = w as usize * 4;
+    let mut buffer = vec![S::from_u8(0); row_len * band_h as usize];
+
+    // The band is split in strips made of a row of cells and the spacing below it, rendered
+    // in parallel. The first strip is what lies above the next row of cells.
+    let spacing = options.spacing;
+    let pitch = placement.band_height(options);
+    let next_row_y = if band_y < spacing {
+        spacing
+    } else {
+        spacing + ((band_y - spacing) / pitch + 1) * pitch
+    };
+    let first_h = cmp::min(next_row_y, band_y + band_h) - band_y;
+    let (first, rest) = buffer.split_at_mut(first_h as usize * row_len);
+    let effects = TileEffects::new(placement, options);
+    render_strip(
+        processed_folder,
+        placement,
+        options,
+        (first, band_y),
+        &effects,
+        cache,
+        progress,
+    )?;
+    rest.par_chunks_mut(pitch as usize * row_len)
+        .enumerate()
+        .try_for_each(|(i, strip)| {
+            let strip_y = band_y + first_h + i as u32 * pitch;
+            render_strip(
+                processed_folder,
+                placement,
+                options,
+                (strip, strip_y),
+                &effects,
+                cache,
+                progress,
+            )
+        })?;
+
+    Ok(ImageBuffer::from_raw(w, band_h, buffer).expect("the buffer holds band_h rows"))
+}
+
+/// Renders in `strip` the rows of the mosaic starting at `band_y`, as many as it holds. The
+/// tiles are reported to `progress` by the strip their bottom row falls in.
+fn render_strip<S: Sample>(
+    processed_folder: &Path,
+    placement: &Placement,
+    options: &MosaicOptions,
+    (strip, band_y): (&mut [S], u32),
+    effects: &TileEffects,
+    cache: Option<&ThumbnailCache>,
+    progress: &dyn Progress,
+) -> ImageResult<()> {
+    let w = placement.dimensions(options).0;
+    if w == 0 {
+        return Ok(());
+    }
+    let band_h = (strip.len() / (w as usize * 4)) as u32;
+    if band_h == 0 {
+        return Ok(());
+    }
+    let mut res =
+        ImageBuffer::<Rgba<S>, _>::from_raw(w, band_h, strip).expect("the strip holds band_h rows");
+    if options.spacing > 0 || effects.hexagon {
+        let spacing_color = pixel(options.spacing_color);
+        for pixel in res.pixels_mut() {
+            *pixel = spacing_color;
+        }
+    }
+
+    let band_end = band_y + band_h;
+    let pitch = placement.band_height(options);
+    // The tiles spanning several rows of cells, and the bottom corners of the hexagons, start
+    // in the rows above the band.
+    let max_span = placement
+        .tiles
+        .iter()
+        .map(|tile| tile.span)
+        .max()
+        .unwrap_or(1);
+    let rows_above = max_span.saturating_sub(1) + u32::from(effects.hexagon);
+    let first_row = (band_y.saturating_sub(options.spacing) / pitch).saturating_sub(rows_above);
+    let first_tile = cmp::min(
+        first_row as usize * placement.grid_width,
+        placement.tiles.len(),
+    );
+    for (i, tile) in placement.tiles.iter().enumerate().skip(first_tile) {
+        if progress.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+        let (cell_x, cell_y, cell_w, cell_h) = placement.cell_rect(i, options);
+        if cell_y >= band_end {
+            break;
+        }
+        if cell_y + cell_h <= band_y {
+            continue;
+        }
+        let top = cmp::max(cell_y, band_y);
+        let bottom = cmp::min(cell_y + cell_h, band_end);
+        if bottom == cell_y + cell_h {
+            progress.on_tile(i, placement.tiles.len());
+        }
+        // The cells covered by a merged tile are drawn with it.
+        if tile.span == 0 {
+            continue;
+        }
+
+        if tile.masked {
+            // Left transparent, `render_mosaic` fills it with the model if asked.
+            fill_rect(
+                &mut res,
+                (cell_x, top - band_y, cell_w, bottom - top),
+                pixel([0, 0, 0, 0]),
+            );
+            continue;
+        }
+        if let Some((_, color)) = options.grout {
+            fill_rect(
+                &mut res,
+                (cell_x, top - band_y, cell_w, bottom - top),
+                pixel(color),
+            );
+        }
+        for (shown, rect) in placement.tile_rects(i, options) {
+            let thumb_path = processed_folder.join(&shown.pic.path);
+            let thumb_path = if shown.falls_back(options) {
+                trace!("tile {}: fallback color for {}", i, thumb_path.display());
+                None
+            } else {
+                trace!("tile {}: {}", i, thumb_path.display());
+                Some(thumb_path.as_path())
+            };
+            draw_thumbnail(
+                &mut res,
+                (thumb_path, shown.rotation, shown.mirrored),
+                rect,
+                effects,
+                band_y,
+                cache,
+                options,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+/// Draws the thumbnail at `thumb_path`, mirrored left to right if `mirrored`, rotated
+/// clockwise by `rotation` degrees and resized to `rect`, or the `options.fallback` color if
+/// `None`, in the rows of the mosaic from `band_y` held by `res`, with `effects` applied. The
+/// part of a tile of `Layout::Brick` past the
+/// right side of the mosaic wraps around to its left side.
+fn draw_thumbnail<S: Sample>(
+    res: &mut ImageBuffer<Rgba<S>, &mut [S]>,
+    (thumb_path, rotation, mirrored): (Option<&Path>, u32, bool),
+    rect: (u32, u32, u32, u32),
+    effects: &TileEffects,
+    band_y: u32,
+    cache: Option<&ThumbnailCache>,
+    options: &MosaicOptions,
+) -> ImageResult<()> {
+    let (x, y, w, h) = rect;
+    let top = cmp::max(y, band_y);
+    let bottom = cmp::min(y + h, band_y + res.height());
+    if top >= bottom || w == 0 {
+        return Ok(());
+    }
+    let thumb = match thumb_path {
+        Some(thumb_path) => match options.tone {
+            Some(tone) => apply_tone(&open_thumbnail(thumb_path, cache)?, tone),
+            None => open_thumbnail(thumb_path, cache)?,
+        },
+        None => {
+            let color = options.fallback.map_or([0, 0, 0, 0], |(_, color)| color);
+            DynamicImage::ImageRgba8(ImageBuffer::from_pixel(w, h, Rgba(color)))
+        }
+    };
+    let thumb = if mirrored { thumb.fliph() } else { thumb };
+    let thumb = match rotation {
+        90 => thumb.rotate90(),
+        180 => thumb.rotate180(),
+        270 => thumb.rotate270(),
+        _ => thumb,
+    };
+    let thumb = if thumb.dimensions() != (w, h) {
+        thumb.resize_exact(w, h, imageops::FilterType::Triangle)
+    } else {
+        thumb
+    };
+    let visible = thumb.view(0, top - y, w, bottom - top);
+    let faded = options.tile_opacity < 1.0;
+    let right = res.width() - options.spacing;
+    let wraps = x + w > right;
+    let vignette = effects.vignette((w, h));
+    if options.tile_background.is_none()
+        && !faded
+        && !effects.hexagon
+        && vignette.is_none()
+        && !wraps
+    {
+        copy_widened(res, &visible, x, top - band_y);
+        return Ok(());
+    }
+
+    let mut tile = S::widen(visible.to_image());
+    if let Some(background) = options.tile_background {
+        tile = composite_over(&tile, Rgba(background), 1.0);
+    }
+    if let Some(mask) = vignette {
+        for (px, py, pixel) in tile.enumerate_pixels_mut() {
+            let factor = mask[((top - y + py) * w + px) as usize];
+            for c in 0..3 {
+                pixel.data[c] = S::from_f32(pixel.data[c].to_u32() as f32 * factor);
+            }
+        }
+    }
+    if faded {
+        tile = composite_over(
+            &tile,
+            Rgba(options.opacity_background),
+            options.tile_opacity,
+        );
+    }
+    if effects.hexagon {
+        // The neighbour hexagons fill the corners of the rectangle, they are left untouched.
+        for (px, py, pixel) in tile.enumerate_pixels() {
+            if in_hexagon((px, top - y + py), (w, h)) {
+                res.put_pixel(x + px, top - band_y + py, *pixel);
+            }
+        }
+    } else if wraps {
+        // Wrapped around with the period of the cells, the columns of the tile under the
+        // spacing on the right and left sides aren't shown.
+        let cut = right - x;
+        let hidden = cmp::min(cut + options.spacing, w);
+        let tile_h = tile.height();
+        assert!(res.copy_from(&tile.view(0, 0, cut, tile_h), x, top - band_y));
+        let rest = tile.view(hidden, 0, w - hidden, tile_h);
+        assert!(res.copy_from(&rest, options.spacing, top - band_y));
+    } else {
+        assert!(res.copy_from(&tile, x, top - band_y));
+    }
+    Ok(())
+}
+
+/// What is done to every tile drawn besides resizing it, prepared once for a band.
+struct TileEffects {
+    /// Whether only the hexagon inscribed in the tile is drawn, for `Layout::Hex`.
+    hexagon: bool,
+    vignette: f32,
+    /// Masks of `MosaicOptions::vignette` by tile dimensions, computed on the first tile of
+    /// each.
+    vignettes: Mutex<HashMap<(u32, u32), Arc<VignetteMask>>>,
+}
+
+/// Factors each channel of a tile is multiplied by, in row-major order.
+type VignetteMask = Vec<f32>;
+
+impl TileEffects {
+    fn new(placement: &Placement, options: &MosaicOptions) -> TileEffects {
+        TileEffects {
+            hexagon: placement.layout == Layout::Hex,
+            vignette: options.vignette,
+            vignettes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Vignette of the tiles of `(w, h)`, `None` without one.
+    fn vignette(&self, (w, h): (u32, u32)) -> Option<Arc<VignetteMask>> {
+        if self.vignette <= 0.0 {
+            return None;
+        }
+        let mut vignettes = self.vignettes.lock().unwrap();
+        let mask = vignettes
+            .entry((w, h))
+            .or_insert_with(|| Arc::new(vignette_mask((w, h), self.vignette)));
+        Some(Arc::clone(mask))
+    }
+}
+
+/// Mask of a tile of `(w, h)` keeping its center as is and darkening the pixels by `strength`
+/// times their distance to it, relative to the distance of the corners.
+fn vignette_mask((w, h): (u32, u32), strength: f32) -> VignetteMask {
+    let (center_x, center_y) = (w as f32 / 2.0, h as f32 / 2.0);
+    let max_distance = center_x.hypot(center_y);
+    let mut mask = Vec::with_capacity(w as usize * h as usize);
+    for y in 0..h {
+        for x in 0..w {
+            let distance = (x as f32 + 0.5 - center_x).hypot(y as f32 + 0.5 - center_y);
+            mask.push(1.0 - strength * distance / max_distance);
+        }
+    }
+    mask
+}
+
+/// Whether the pixel at `(x, y)` of a cell of `(w, h)` is in the hexagon of `Layout::Hex`,
+/// whose slanted sides span the top and bottom rows the rows above and below fit in. The
+/// pixels on a side are in the hexagons of both cells, so that no gap is left between them.
+fn in_hexagon((x, y): (u32, u32), (w, h): (u32, u32)) -> bool {
+    let slant = (h - Layout::Hex.row_pitch(h)) as f32;
+    let half_w = w as f32 / 2.0;
+    let dx = ((x as f32 + 0.5) - half_w).abs() / half_w;
+    let y = y as f32 + 0.5;
+    y >= slant * dx && h as f32 - y >= slant * dx
+}
+
+/// Uses the luminance of `mask`, scaled to the mosaic dimensions, as the mosaic alpha channel.
+pub fn apply_alpha_mask<S: Sample>(mosaic: &mut ImageBuffer<Rgba<S>, Vec<S>>, mask: &DynamicImage) {
+    let (w, h) = mosaic.dimensions();
+    let mask = imageops::resize(&mask.to_luma(), w, h, imageops::FilterType::Triangle);
+    for (pixel, alpha) in mosaic.pixels_mut().zip(mask.pixels()) {
+        pixel.data[3] = S::from_u32(pixel.data[3].to_u32() * u32::from(alpha.data[0]) / 255);
+    }
+}
+
+/// Renders `placement` in a single image, overlaid with `model` if `options.ghost` is set.
+/// The masked cells show `model` with `MaskFill::Model`, and are transparent otherwise.
+pub fn render_mosaic<S: Sample>(
+    model: Option<&DynamicImage>,
+    processed_folder: &Path,
+    placement: &Placement,
+    options: &MosaicOptions,
+    cache: Option<&ThumbnailCache>,
+    progress: &dyn Progress,
+) -> ImageResult<ImageBuffer<Rgba<S>, Vec<S>>> {
+    let h = placement.dimensions(options).1;
+    let mut res = render_band(processed_folder, placement, options, 0, h, cache, progress)?;
+
+    if let Some(model) = model.filter(|_| options.mask_fill == MaskFill::Model) {
+        fill_masked_cells(&mut res, placement, options, model);
+    }
+    if options.feather_edges > 0 {
+        feather_seams(&mut res, placement, options);
+    }
+    if let Some(model) = model.filter(|_| options.ghost > 0.0) {
+        ghost_model(&mut res, model, options.ghost);
+    }
+
+    Ok(res)
+}
+
+/// Copies `model`, scaled to the mosaic dimensions, in the masked cells of `img`.
+fn fill_masked_cells<S: Sample>(
+    img: &mut ImageBuffer<Rgba<S>, Vec<S>>,
+    placement: &Placement,
+    options: &MosaicOptions,
+    model: &DynamicImage,
+) {
+    if !placement.tiles.iter().any(|tile| tile.masked) {
+        return;
+    }
+    let (w, h) = img.dimensions();
+    let model = S::widen(imageops::resize(
+        model,
+        w,
+        h,
+        imageops::FilterType::Triangle,
+    ));
+    for (i, _) in placement.tiles.iter().enumerate().filter(|(_, t)| t.masked) {
+        let (x, y, w, h) = placement.cell_rect(i, options);
+        assert!(img.copy_from(&model.view(x, y, w, h), x, y));
+    }
+}
+
+/// Blends each side of the seams between adjacent cells with the mirrored pixels of the other
+/// side, from half and half at the seam to untouched `feather_edges` pixels away from it.
+fn feather_seams<S: Sample>(
+    img: &mut ImageBuffer<Rgba<S>, Vec<S>>,
+    placement: &Placement,
+    options: &MosaicOptions,
+) {
+    let (cell_w, cell_h) = placement.cell_dimensions(options);
+    let (w, h) = img.dimensions();
+    // Limited to half a cell so that the blends of the two seams of a cell don't overlap.
+    let width_x = options.feather_edges.min(cell_w / 2);
+    let width_y = options.feather_edges.min(cell_h / 2);
+
+    for col in 1..placement.grid_width as u32 {
+        let seam = options.spacing + col * cell_w;
+        for y in 0..h {
+            for d in 0..width_x {
+                blend_pair(img, (seam - 1 - d, y), (seam + d, y), d, width_x);
+            }
+        }
+    }
+    for row in 1..placement.grid_height as u32 {
+        let seam = options.spacing + row * cell_h;
+        for x in 0..w {
+            for d in 0..width_y {
+                blend_pair(img, (x, seam - 1 - d), (x, seam + d), d, width_y);
+            }
+        }
+    }
+}
+
+/// Mixes the pixels `a` and `b`, `d` pixels away from the seam between them.
+fn blend_pair<S: Sample>(
+    img: &mut ImageBuffer<Rgba<S>, Vec<S>>,
+    a: (u32, u32),
+    b: (u32, u32),
+    d: u32,
+    width: u32,
+) {
+    let mix = 0.5 * (1.0 - (d as f32 + 0.5) / width as f32);
+    let pixel_a = *img.get_pixel(a.0, a.1);
+    let pixel_b = *img.get_pixel(b.0, b.1);
+    let lerp =
+        |from: S, to: S| S::from_f32(from.to_u32() as f32 * (1.0 - mix) + to.to_u32() as f32 * mix);
+    let mut blended_a = pixel_a;
+    let mut blended_b = pixel_b;
+    for c in 0..4 {
+        blended_a.data[c] = lerp(pixel_a.data[c], pixel_b.data[c]);
+        blended_b.data[c] = lerp(pixel_b.data[c], pixel_a.data[c]);
+    }
+    img.put_pixel(a.0, a.1, blended_a);
+    img.put_pixel(b.0, b.1, blended_b);
+}
+
+/// Matches the chunks of `model` with `pics`, the pictures preprocessed in `processed_folder`,
+/// and renders the mosaic.
+pub fn create_mosaic(
+    model: &DynamicImage,
+    processed_folder: &Path,
+    pics: &[ProcessedPicture],
+    options: &MosaicOptions,
+    cache: Option<&ThumbnailCache>,
+    progress: &dyn Progress,
+) -> Result<ImageBuffer<Rgba<u8>, Vec<u8>>, MosaicError> {
+    let placement = match_tiles(model, pics, options.tile_ratio, options)?;
+    let mosaic = render_mosaic(
+        Some(model),
+        processed_folder,
+        &placement,
+        options,
+        cache,
+        progress,
+    )?;
+    Ok(mosaic)
+}
+
+/// Color space a PNG or JPEG mosaic is written in, and tagged with so that color managed
+/// viewers and printers don't have to guess it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ColorSpace {
+    /// The one of the thumbnails, whose colors are written as they are.
+    #[default]
+    Srgb,
+    /// The wider gamut of print workflows, the colors being converted to it.
+    AdobeRgb,
+}
+
+/// How the PNG of the mosaic is encoded.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PngOptions {
+    /// Whether the samples are written in 16 bits rather than 8, for print workflows that
+    /// edit the image further.
+    pub sixteen_bit: bool,
+    pub color_space: ColorSpace,
+    /// Dots per inch the image is tagged with, for it to be printed at its intended size.
+    pub dpi: Option<u32>,
+}
+
+/// Writes `mosaic` as a PNG encoded with `png`, whose bit depth must be the one of `S`.
+pub fn save_png<S: Sample>(
+    mosaic: &ImageBuffer<Rgba<S>, Vec<S>>,
+    output_image: &Path,
+    png: &PngOptions,
+) -> Result<(), MosaicError> {
+    let writer = BufWriter::new(File::create(output_image)?);
+    let (w, h) = mosaic.dimensions();
+    let mut png = png_stream::PngStreamWriter::new(writer, w, h, png)?;
+    png.write_band(mosaic)?;
+    png.finish()
+}
+
+/// Writes `mosaic` as a JPEG in `color_space`, with its ICC profile.
+pub fn save_jpeg(
+    mosaic: &ImageBuffer<Rgba<u8>, Vec<u8>>,
+    output_image: &Path,
+    color_space: ColorSpace,
+) -> Result<(), MosaicError> {
+    let converted;
+    let mosaic = match color_space {
+        ColorSpace::Srgb => mosaic,
+        ColorSpace::AdobeRgb => {
+            converted = to_color_space(mosaic, color_space);
+            &converted
+        }
+    };
+    let mut jpeg = Vec::new();
+    let (w, h) = mosaic.dimensions();
+    image::jpeg::JPEGEncoder::new(&mut jpeg).encode(mosaic, w, h, image::RGBA(8))?;
+
+    // The profile goes in an APP2 segment right after the APP0 one of JFIF, in a single chunk
+    // as it is far below the 64 KiB of a segment.
+    let profile = icc::profile(color_space);
+    let mut segment = vec![0xFF, 0xE2];
+    segment.extend_from_slice(&((2 + ICC_MARKER.len() + 2 + profile.len()) as u16).to_be_bytes());
+    segment.extend_from_slice(ICC_MARKER);
+    segment.extend_from_slice(&[1, 1]); // First of one chunk.
+    segment.extend_from_slice(&profile);
+    let app0_end = 4 + usize::from(u16::from_be_bytes([jpeg[4], jpeg[5]]));
+    jpeg.splice(app0_end..app0_end, segment);
+
+    let mut writer = BufWriter::new(File::create(output_image)?);
+    writer.write_all(&jpeg)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Converts the sRGB colors of `img` to `color_space`, keeping their alpha.
+pub(crate) fn to_color_space<S: Sample>(
+    img: &ImageBuffer<Rgba<S>, Vec<S>>,
+    color_space: ColorSpace,
+) -> ImageBuffer<Rgba<S>, Vec<S>> {
+    let mut res = img.clone();
+    if color_space == ColorSpace::AdobeRgb {
+        for pixel in res.pixels_mut() {
+            let [r, g, b, a] = pixel.data;
+            let [r, g, b] = S::to_adobe_rgb([r, g, b]);
+            pixel.data = [r, g, b, a];
+        }
+    }
+    res
+}
+
+/// Writes `frames`, the mosaics of an animation all of the same size with the milliseconds
+/// each is shown for, as a looping GIF. The frames are rendered as they are written, so that
+/// only one is held in memory.
+pub fn save_gif<I>(frames: I, output_image: &Path) -> Result<(), MosaicError>
+where
+    I: IntoIterator<Item = Result<(ImageBuffer<Rgba<u8>, Vec<u8>>, u32), MosaicError>>,
+{
+    let mut encoder = None;
+    for frame in frames {
+        let (frame, delay_ms) = frame?;
+        // GIF delays are in hundredths of a second.
+        let delay = cmp::min(delay_ms / 10, u32::from(u16::MAX)) as u16;
+        let (w, h) = frame.dimensions();
+        if w > u32::from(u16::MAX) || h > u32::from(u16::MAX) {
+            return Err(
+                format!("a GIF can't be larger than 65535x65535 px, not {}x{}", w, h).into(),
+            );
+        }
+        if encoder.is_none() {
+            let writer = BufWriter::new(File::create(output_image)?);
+            let mut e = gif::Encoder::new(writer, w as u16, h as u16, &[])?;
+            e.set(gif::Repeat::Infinite)?;
+            encoder = Some(e);
+        }
+        let mut pixels = frame.into_raw();
+        let mut gif_frame = gif::Frame::from_rgba(w as u16, h as u16, &mut pixels);
+        gif_frame.delay = delay;
+        encoder.as_mut().unwrap().write_frame(&gif_frame)?;
+    }
+    Ok(())
+}
+
+/// Writes the mosaic as a PNG encoded with `png` one row of cells at a time, so that only a
+/// band of it is held in memory.
+pub fn write_mosaic_in_bands(
+    output_image: &Path,
+    processed_folder: &Path,
+    placement: &Placement,
+    options: &MosaicOptions,
+    png: &PngOptions,
+    progress: &dyn Progress,
+) -> Result<(), MosaicError> {
+    let (w, h) = placement.dimensions(options);
+    let writer = BufWriter::new(File::create(output_image)?);
+    let mut writer = png_stream::PngStreamWriter::new(writer, w, h, png)?;
+    match png.sixteen_bit {
+        true => write_bands::<u16, _>(&mut writer, processed_folder, placement, options, progress)?,
+        false => write_bands::<u8, _>(&mut writer, processed_folder, placement, options, progress)?,
+    }
+    writer.finish()
+}
+
+/// Renders the mosaic in samples of `S` one row of cells at a time, written to `png`.
+fn write_bands<S: Sample, W: Write>(
+    png: &mut png_stream::PngStreamWriter<W>,
+    processed_folder: &Path,
+    placement: &Placement,
+    options: &MosaicOptions,
+    progress: &dyn Progress,
+) -> Result<(), MosaicError> {
+    let h = placement.dimensions(options).1;
+    let band_height = placement.band_height(options);
+    let mut y = 0;
+    while y < h {
+        let band_h = band_height.min(h - y);
+        png.write_band(&render_band::<S>(
+            processed_folder,
+            placement,
+            options,
+            y,
+            band_h,
+            None,
+            progress,
+        )?)?;
+        y += band_h;
+    }
+    Ok(())
+}
+
+/// Lists the tiles shown in the mosaic, the masked cells and the ones filled with the fallback
+/// color being left out, and the split ones giving a tile per sub-cell.
+pub fn build_manifest(placement: &Placement, options: &MosaicOptions) -> Manifest {
+    let mut cells = Vec::with_capacity(placement.tiles.len());
+    for (i, tile) in placement.tiles.iter().enumerate() {
+        if tile.masked {
+            continue;
+        }
+        for (tile, (x, y, width, height)) in placement.tile_rects(i, options) {
+            if tile.falls_back(options) {
+                continue;
+            }
+            cells.push(ManifestCell {
+                row: (i / placement.grid_width) as u32,
+                column: (i % placement.grid_width) as u32,
+                x,
+                y,
+                width,
+                height,
+                path: tile.pic.path.clone(),
+                source: tile.pic.source.clone(),
+                rotation: tile.rotation,
+                mirrored: tile.mirrored,
+                distance: color_distance(tile.target_color, tile.pic.color_rgb),
+            });
+        }
+    }
+    Manifest {
+        rows: placement.grid_height as u32,
+        columns: placement.grid_width as u32,
+        cells,
+    }
+}
+
+pub fn build_tile_map(placement: &Placement) -> TileMap {
+    placement
+        .tiles
+        .chunks(cmp::max(placement.grid_width, 1))
+        .map(|row| {
+            row.iter()
+                .map(|tile| MapCell {
+                    path: tile.pic.path.clone(),
+                    target_color: tile.target_color,
+                    placed_color: tile.pic.color_rgb,
+                    masked: tile.masked,
+                    details: tile.details.iter().map(|d| d.pic.path.clone()).collect(),
+                    span: tile.span,
+                })
+                .collect()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::progress::NoProgress;
+    use crate::testing::{flat_gallery, image, picture, placement, temp_dir};
+    use crate::MosaicBuilder;
+    use png::HasParameters;
+
+    const RED: [u8; 3] = [255, 0, 0];
+    const GREEN: [u8; 3] = [0, 255, 0];
+    const WHITE: [u8; 3] = [255, 255, 255];
+    const BLUE: [u8; 4] = [0, 0, 255, 255];
+
+    /// Color of the `i`-th cell of a placement of the pictures of `flat_gallery`.
+    fn cell_color(pics: &[ProcessedPicture], i: u32) -> [u8; 4] {
+        let [r, g, b] = pics[i as usize % pics.len()].color_rgb;
+        [r, g, b, 255]
+    }
+
+    #[test]
+    fn band_without_spacing_is_only_tiles() {
+        let folder = temp_dir("spacing");
+        let pics = flat_gallery(&folder, &[RED, GREEN, WHITE], 4);
+        let placement = placement(&pics, (2, 2), Layout::Grid, (4, 4));
+        let options = MosaicBuilder::new().build().unwrap();
+        let band =
+            render_band::<u8>(&folder, &placement, &options, 0, 8, None, &NoProgress).unwrap();
+        assert_eq!(band.dimensions(), (8, 8));
+        for (x, y, pixel) in band.enumerate_pixels() {
+            let expected = cell_color(&pics, y / 4 * 2 + x / 4);
+            assert_eq!(pixel.data, expected, "pixel {},{}", x, y);
+        }
+    }
+
+    #[test]
+    fn band_with_spacing_has_gutters_of_its_color() {
+        let folder = temp_dir("spacing");
+        let pics = flat_gallery(&folder, &[RED, GREEN, WHITE], 4);
+        let placement = placement(&pics, (2, 2), Layout::Grid, (4, 4));
+        let options = MosaicBuilder::new()
+            .spacing(2)
+            .spacing_color(BLUE)
+            .build()
+            .unwrap();
+        let band =
+            render_band::<u8>(&folder, &placement, &options, 0, 14, None, &NoProgress).unwrap();
+        assert_eq!(band.dimensions(), (14, 14));
+        // Cells start every 6 pixels after 2 of spacing.
+        for (x, y, pixel) in band.enumerate_pixels() {
+            let expected = if x % 6 < 2 || y % 6 < 2 {
+                BLUE
+            } else {
+                cell_color(&pics, y / 6 * 2 + x / 6)
+            };
+            assert_eq!(pixel.data, expected, "pixel {},{}", x, y);
+        }
+    }
+
+    /// Renders `placement` whole, then in bands written to a PNG and in bands of `band_h`
+    /// pixels from each row, and checks they all have the same pixels.
+    fn assert_bands_match(folder: &Path, placement: &Placement, options: &MosaicOptions) {
+        let mosaic =
+            render_mosaic::<u8>(None, folder, placement, options, None, &NoProgress).unwrap();
+        let (w, h) = mosaic.dimensions();
+        assert_eq!((w, h), placement.dimensions(options));
+
+        let output = folder.join("bands.png");
+        let png = PngOptions::default();
+        write_mosaic_in_bands(&output, folder, placement, options, &png, &NoProgress).unwrap();
+        let banded = image::open(&output).unwrap().to_rgba();
+        assert_eq!(banded.dimensions(), (w, h));
+        assert!(*banded == *mosaic, "the PNG written in bands differs");
+
+        // Bands starting within the spacing, the cells and the corners of the hexagons.
+        for band_h in [1, 5, 7] {
+            for band_y in 0..h {
+                let band_h = band_h.min(h - band_y);
+                let band = render_band::<u8>(
+                    folder,
+                    placement,
+                    options,
+                    band_y,
+                    band_h,
+                    None,
+                    &NoProgress,
+                )
+                .unwrap();
+                let expected = mosaic.view(0, band_y, w, band_h).to_image();
+                assert_eq!(band.dimensions(), (w, band_h));
+                assert!(*band == *expected, "band {}+{} differs", band_y, band_h);
+            }
+        }
+    }
+
+    #[test]
+    fn bands_match_the_mosaic_of_a_grid_with_spacing() {
+        let folder = temp_dir("bands");
+        let pics = flat_gallery(&folder, &[RED, GREEN, WHITE], 4);
+        let placement = placement(&pics, (3, 3), Layout::Grid, (6, 4));
+        let options = MosaicBuilder::new()
+            .spacing(3)
+            .spacing_color(BLUE)
+            .build()
+            .unwrap();
+        assert_bands_match(&folder, &placement, &options);
+    }
+
+    #[test]
+    fn bands_match_the_mosaic_of_hexagons() {
+        let folder = temp_dir("bands");
+        let pics = flat_gallery(&folder, &[RED, GREEN, WHITE], 16);
+        let placement = placement(&pics, (3, 4), Layout::Hex, (16, 16));
+        for spacing in [0, 2] {
+            let options = MosaicBuilder::new()
+                .layout(Layout::Hex)
+                .spacing(spacing)
+                .spacing_color(BLUE)
+                .build()
+                .unwrap();
+            assert_bands_match(&folder, &placement, &options);
+        }
+    }
+
+    /// Samples of the 16-bit PNG at `path`, in big endian bytes.
+    fn read_png16(path: &Path) -> Vec<u8> {
+        let mut decoder = png::Decoder::new(File::open(path).unwrap());
+        HasParameters::set(&mut decoder, png::Transformations::IDENTITY);
+        let (info, mut reader) = decoder.read_info().unwrap();
+        assert_eq!(info.bit_depth, png::BitDepth::Sixteen);
+        let mut data = vec![0; info.buffer_size()];
+        reader.next_frame(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn sixteen_bit_blends_are_finer_than_the_8_bit_ones() {
+        let folder = temp_dir("sixteen-bit");
+        let pics = flat_gallery(&folder, &[[101, 37, 203], GREEN, WHITE], 8);
+        let placement = placement(&pics, (3, 2), Layout::Grid, (8, 8));
+        let options = MosaicBuilder::new()
+            .tile_opacity(0.5)
+            .vignette(0.3)
+            .build()
+            .unwrap();
+        let feathered = MosaicOptions {
+            feather_edges: 2,
+            ..options.clone()
+        };
+        let mosaic8 =
+            render_mosaic::<u8>(None, &folder, &placement, &feathered, None, &NoProgress).unwrap();
+        let mosaic16 =
+            render_mosaic::<u16>(None, &folder, &placement, &feathered, None, &NoProgress).unwrap();
+        // As close as the rounding of a few 8-bit blends, but not limited to the 8-bit values.
+        for (&sample8, &sample16) in mosaic8.iter().zip(mosaic16.iter()) {
+            let error = (i32::from(sample8) * 257 - i32::from(sample16)).abs();
+            assert!(error <= 2 * 257, "{} and {}", sample8, sample16);
+        }
+        assert!(mosaic16.iter().any(|&sample| sample % 257 != 0));
+
+        let output = folder.join("sixteen-bit.png");
+        let png = PngOptions {
+            sixteen_bit: true,
+            ..PngOptions::default()
+        };
+        save_png(&mosaic16, &output, &png).unwrap();
+        assert!(read_png16(&output) == *u16::to_png_bytes(&mosaic16));
+
+        // Without feathering, which isn't done band by band.
+        let mosaic16 =
+            render_mosaic::<u16>(None, &folder, &placement, &options, None, &NoProgress).unwrap();
+        write_mosaic_in_bands(&output, &folder, &placement, &options, &png, &NoProgress).unwrap();
+        assert!(read_png16(&output) == *u16::to_png_bytes(&mosaic16));
+    }
+
+    #[test]
+    fn sixteen_bit_adobe_rgb_is_finer_than_the_8_bit_one() {
+        for value in 0..=255 {
+            let rgb8 = icc::srgb_to_adobe_rgb([value, 128, 255 - value]);
+            let rgb16 = icc::srgb_to_adobe_rgb16([value, 128, 255 - value].map(u16::from_u8));
+            for (sample8, sample16) in rgb8.iter().zip(rgb16) {
+                let error = (i32::from(*sample8) * 257 - i32::from(sample16)).abs();
+                assert!(error <= 257 / 2 + 1, "{} and {}", sample8, sample16);
+            }
+        }
+    }
+
+    #[test]
+    fn hexagons_fit_in_the_corners_of_the_rows_above() {
+        let folder = temp_dir("hex");
+        let pics = flat_gallery(&folder, &[RED, GREEN, WHITE], 8);
+        let placement = placement(&pics, (2, 2), Layout::Hex, (8, 8));
+        let options = MosaicBuilder::new()
+            .layout(Layout::Hex)
+            .spacing_color(BLUE)
+            .build()
+            .unwrap();
+        let mosaic =
+            render_mosaic::<u8>(None, &folder, &placement, &options, None, &NoProgress).unwrap();
+        // Red, green, white and red hexagons, the background showing the spacing color.
+        let expected = [
+            "...RR......GG.......",
+            ".RRRRRR..GGGGGG.....",
+            "RRRRRRRRGGGGGGGG....",
+            "RRRRRRRRGGGGGGGG....",
+            "RRRRRRRRGGGGGGGG....",
+            "RRRRRRRRGGGGGGGG....",
+            ".RRRRRRWWGGGGGGRR...",
+            "...RRWWWWWWGGRRRRRR.",
+            "....WWWWWWWWRRRRRRRR",
+            "....WWWWWWWWRRRRRRRR",
+            "....WWWWWWWWRRRRRRRR",
+            "....WWWWWWWWRRRRRRRR",
+            ".....WWWWWW..RRRRRR.",
+            ".......WW......RR...",
+        ];
+        let rows: Vec<String> = (0..mosaic.height())
+            .map(|y| {
+                (0..mosaic.width())
+                    .map(|x| match mosaic.get_pixel(x, y).data {
+                        [255, 0, 0, 255] => 'R',
+                        [0, 255, 0, 255] => 'G',
+                        [255, 255, 255, 255] => 'W',
+                        BLUE => '.',
+                        _ => '?',
+                    })
+                    .collect()
+            })
+            .collect();
+        assert_eq!(rows, expected);
+    }
+
+    #[test]
+    fn hexagon_spans_the_corners_with_its_slanted_sides() {
+        // A cell of 8x8 pixels, the rows above and below fitting in 2 rows of its corners.
+        let rows: Vec<String> = (0..8)
+            .map(|y| {
+                (0..8)
+                    .map(|x| if in_hexagon((x, y), (8, 8)) { '#' } else { '.' })
+                    .collect()
+            })
+            .collect();
+        let expected = "\
+...##...
+.######.
+########
+########
+########
+########
+.######.
+...##...";
+        assert_eq!(rows.join("\n"), expected);
+    }
+
+    #[test]
+    fn parallel_rendering_matches_the_serial_one() {
+        let folder = temp_dir("parallel");
+        // Thumbnails that aren't symmetric, so that rotating or mirroring them shows.
+        let colors = [RED, GREEN, WHITE, [0, 0, 255], [40, 40, 40]];
+        let pics: Vec<_> = (colors.iter().enumerate())
+            .map(|(i, &[r, g, b])| {
+                let path = format!("{}.png", i);
+                let thumb = image(8, 8, |x, y| {
+                    let shade = (x * 8 + y * 4) as u8;
+                    [r / 2 + shade, g / 2 + shade, b / 2 + shade, 255]
+                });
+                thumb.save(folder.join(&path)).unwrap();
+                picture(&path, [r, g, b])
+            })
+            .collect();
+        let model = image(64, 64, |x, y| [(x * 4) as u8, (y * 4) as u8, 128, 255]);
+        let options = MosaicBuilder::new()
+            .seed(Some(42))
+            .allow_rotation(true)
+            .allow_mirroring(true)
+            .randomize_top_k(2)
+            .spacing(1)
+            .build()
+            .unwrap();
+
+        let render = |threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(threads)
+                .build()
+                .unwrap();
+            pool.install(|| {
+                let placement = match_tiles(&model, &pics, (1, 1), &options).unwrap();
+                render_mosaic::<u8>(None, &folder, &placement, &options, None, &NoProgress)
+                    .unwrap()
+                    .into_raw()
+            })
+        };
+        let serial = render(1);
+        assert!(serial == render(4), "the parallel rendering differs");
+    }
+
+    #[test]
+    fn gray_tone_renders_gray_tiles() {
+        let folder = temp_dir("tone");
+        let pics = flat_gallery(&folder, &[RED, GREEN, WHITE], 4);
+        let placement = placement(&pics, (3, 1), Layout::Grid, (4, 4));
+
+        let options = MosaicBuilder::new().build().unwrap();
+        let mosaic =
+            render_mosaic::<u8>(None, &folder, &placement, &options, None, &NoProgress).unwrap();
+        for (x, _, pixel) in mosaic.enumerate_pixels() {
+            assert_eq!(pixel.data, cell_color(&pics, x / 4));
+        }
+
+        let options = MosaicBuilder::new().tone(Some(Tone::Gray)).build().unwrap();
+        let mosaic =
+            render_mosaic::<u8>(None, &folder, &placement, &options, None, &NoProgress).unwrap();
+        for (x, _, pixel) in mosaic.enumerate_pixels() {
+            let [r, g, b, a] = cell_color(&pics, x / 4);
+            let [y, _, _] = Tone::Gray.apply([r, g, b]);
+            assert_eq!(pixel.data, [y, y, y, a]);
+        }
+    }
+}
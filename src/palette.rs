@@ -0,0 +1,137 @@
+//! Dominant colors of a picture, found by k-means clustering of its pixels.
+
+use crate::srgb;
+use image::Rgba;
+
+const MAX_ITERATIONS: usize = 16;
+
+/// Clusters `pixels`, given with their weight, in at most `k` colors, returned from the
+/// heaviest cluster to the lightest. Empty if the weights sum to 0.
+pub fn palette<'a, I>(pixels: I, k: usize, linear_light: bool) -> Vec<[u8; 3]>
+where
+    I: Iterator<Item = (&'a Rgba<u8>, f64)>,
+{
+    let decode = |channel: u8| {
+        if linear_light {
+            srgb::to_linear(channel)
+        } else {
+            f64::from(channel) / 255.0
+        }
+    };
+    let mut points: Vec<([f64; 3], f64)> = pixels
+        .filter(|&(_, weight)| weight > 0.0)
+        .map(|(pixel, weight)| {
+            let color = [
+                decode(pixel.data[0]),
+                decode(pixel.data[1]),
+                decode(pixel.data[2]),
+            ];
+            (color, weight)
+        })
+        .collect();
+    if points.is_empty() || k == 0 {
+        return Vec::new();
+    }
+
+    // Seeded with evenly spaced quantiles of the luminance, so that the clustering doesn't
+    // depend on randomness and starts from colors spread over the picture.
+    points.sort_by(|a, b| luminance(a.0).total_cmp(&luminance(b.0)));
+    let k = k.min(points.len());
+    let mut centroids: Vec<[f64; 3]> = (0..k)
+        .map(|i| points[(2 * i + 1) * points.len() / (2 * k)].0)
+        .collect();
+
+    let mut assignments = vec![usize::MAX; points.len()];
+    let mut weights = vec![0.0; k];
+    for _ in 0..MAX_ITERATIONS {
+        let mut changed = false;
+        for (assignment, (color, _)) in assignments.iter_mut().zip(points.iter()) {
+            let closest = closest_centroid(&centroids, *color);
+            if closest != *assignment {
+                *assignment = closest;
+                changed = true;
+            }
+        }
+        if !changed {
+            break;
+        }
+
+        let mut sums = vec![[0.0; 3]; k];
+        weights = vec![0.0; k];
+        for (&assignment, (color, weight)) in assignments.iter().zip(points.iter()) {
+            for c in 0..3 {
+                sums[assignment][c] += weight * color[c];
+            }
+            weights[assignment] += weight;
+        }
+        for ((centroid, sum), &weight) in centroids.iter_mut().zip(sums.iter()).zip(&weights) {
+            // An empty cluster keeps its centroid.
+            if weight > 0.0 {
+                *centroid = [sum[0] / weight, sum[1] / weight, sum[2] / weight];
+            }
+        }
+    }
+
+    let mut clusters: Vec<_> = centroids
+        .iter()
+        .zip(weights.iter())
+        .filter(|&(_, &weight)| weight > 0.0)
+        .collect();
+    clusters.sort_by(|a, b| b.1.total_cmp(a.1));
+    let encode = |value: f64| {
+        if linear_light {
+            srgb::from_linear(value)
+        } else {
+            (value * 255.0).round().clamp(0.0, 255.0) as u8
+        }
+    };
+    clusters
+        .into_iter()
+        .map(|(centroid, _)| {
+            [
+                encode(centroid[0]),
+                encode(centroid[1]),
+                encode(centroid[2]),
+            ]
+        })
+        .collect()
+}
+
+fn luminance(color: [f64; 3]) -> f64 {
+    0.2126 * color[0] + 0.7152 * color[1] + 0.0722 * color[2]
+}
+
+fn closest_centroid(centroids: &[[f64; 3]], color: [f64; 3]) -> usize {
+    let distance = |centroid: &[f64; 3]| {
+        (0..3)
+            .map(|c| (centroid[c] - color[c]).powi(2))
+            .sum::<f64>()
+    };
+    let mut closest = 0;
+    for (i, centroid) in centroids.iter().enumerate().skip(1) {
+        if distance(centroid) < distance(&centroids[closest]) {
+            closest = i;
+        }
+    }
+    closest
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn colors_go_from_the_most_to_the_least_present() {
+        let (red, blue) = (Rgba([255, 0, 0, 255]), Rgba([0, 0, 255, 255]));
+        let pixels = [(&blue, 1.0), (&red, 1.0), (&red, 1.0)];
+        let palette = palette(pixels.iter().copied(), 2, false);
+        assert_eq!(palette, vec![[255, 0, 0], [0, 0, 255]]);
+    }
+
+    #[test]
+    fn pixels_without_weight_have_no_palette() {
+        let pixel = Rgba([255, 0, 0, 0]);
+        let pixels = [(&pixel, 0.0), (&pixel, f64::NAN)];
+        assert!(palette(pixels.iter().copied(), 2, false).is_empty());
+    }
+}
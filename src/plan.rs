@@ -0,0 +1,98 @@
+//! Placement file written by `plan` and read by `render`, so that the tiles can be rendered
+//! again with other options without matching them again.
+//!
+//! It is a JSON object such as:
+//!
+//! ```json
+//! {
+//!   "grid_width": 8,
+//!   "grid_height": 6,
+//!   "layout": "grid",
+//!   "tile_ratio": [4, 3],
+//!   "cells": [{ "path": "p01.png", "target_color": [245, 230, 229], "rotation": 180,
+//!               "masked": false }, ...]
+//! }
+//! ```
+//!
+//! where `cells` lists the cells of the grid in row-major order, each with the path of its
+//! thumbnail, relative to the preprocessed folder, the color of the model chunk it replaces,
+//! the clockwise rotation of the thumbnail in degrees, 0 if missing, whether the thumbnail is
+//! `mirrored` left to right before being rotated, false if missing, and whether a mask left
+//! the cell out of the mosaic, false if missing. A cell split for more detail also has the
+//! `details` of its sub-cells, cells themselves, as many rows as columns of them in row-major
+//! order. A tile merged over a flat block of cells, as many rows as columns of them from its
+//! cell down and right, has the `span` of the block, 1 if missing, and the other cells of the
+//! block a span of 0. `tile_ratio`, the aspect ratio of the tiles, is `[1, 1]` if missing.
+//! `layout` is `"hex"` for a honeycomb of hexagonal cells, whose odd rows are shifted by half
+//! a cell, `"brick"` for rows of rectangles whose odd rows are shifted by half a cell, the last
+//! tile of such a row wrapping around to the left side, and `"grid"` if missing. Only the
+//! cells of a grid can be masked, merged or split.
+
+use crate::error::MosaicError;
+use crate::matching::Layout;
+use serde_derive::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Plan {
+    pub grid_width: usize,
+    pub grid_height: usize,
+    #[serde(default)]
+    pub layout: Layout,
+    #[serde(default = "default_tile_ratio")]
+    pub tile_ratio: (u32, u32),
+    pub cells: Vec<PlanCell>,
+}
+
+fn default_tile_ratio() -> (u32, u32) {
+    (1, 1)
+}
+
+pub(crate) fn default_span() -> u32 {
+    1
+}
+
+pub(crate) fn is_single_span(span: &u32) -> bool {
+    *span == 1
+}
+
+fn is_false(value: &bool) -> bool {
+    !*value
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PlanCell {
+    pub path: String,
+    pub target_color: [u8; 3],
+    #[serde(default)]
+    pub rotation: u32,
+    /// Whether the thumbnail is mirrored left to right before being rotated, false if missing.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub mirrored: bool,
+    /// Whether the cell is left out by a mask, false if missing.
+    #[serde(default)]
+    pub masked: bool,
+    /// Tiles of the sub-cells of the cell, a square number of them in row-major order, shown
+    /// instead of its tile if it is split for more detail. None if missing.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub details: Vec<PlanCell>,
+    /// Number of cells per side the tile covers from this one, 0 for the cells covered by the
+    /// tile of another one. 1 if missing.
+    #[serde(default = "default_span", skip_serializing_if = "is_single_span")]
+    pub span: u32,
+}
+
+impl Plan {
+    pub fn save(&self, path: &Path) -> Result<(), MosaicError> {
+        let writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Plan, MosaicError> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+}
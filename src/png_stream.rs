@@ -0,0 +1,176 @@
+//! PNG encoder fed with row bands, so that a large mosaic doesn't need to be held in memory.
+
+use crate::error::MosaicError;
+use crate::icc;
+use crate::mosaic::{self, ColorSpace, PngOptions, Sample};
+use deflate::write::ZlibEncoder;
+use deflate::Compression;
+use image::{ImageBuffer, Rgba};
+use png::HasParameters;
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::mem;
+use std::rc::Rc;
+
+/// Gamma of sRGB in the gAMA chunk, 1/2.2 scaled by 100000.
+const SRGB_GAMMA: u32 = 45455;
+/// White point and red, green and blue primaries of sRGB in the cHRM chunk, as x, y pairs
+/// scaled by 100000.
+const SRGB_CHROMATICITIES: [u32; 8] = [31270, 32900, 64000, 33000, 30000, 60000, 15000, 6000];
+/// White point and primaries of Adobe RGB (1998), as in `SRGB_CHROMATICITIES`.
+const ADOBE_RGB_CHROMATICITIES: [u32; 8] = [31270, 32900, 64000, 33000, 21000, 71000, 15000, 6000];
+/// Length of an inch in meters, the unit of the pHYs chunk.
+const INCH_IN_METERS: f64 = 0.0254;
+
+/// Compressed data waiting to be written as an IDAT chunk.
+#[derive(Clone, Default)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+pub struct PngStreamWriter<W: Write> {
+    writer: png::Writer<W>,
+    zlib: ZlibEncoder<SharedBuffer>,
+    compressed: SharedBuffer,
+    width: u32,
+    rows_left: u32,
+    sixteen_bit: bool,
+    color_space: ColorSpace,
+}
+
+impl<W: Write> PngStreamWriter<W> {
+    /// Writes the header of a `width`x`height` RGBA image, with the bit depth, color space and
+    /// physical size chunks of `options`.
+    pub fn new(
+        w: W,
+        width: u32,
+        height: u32,
+        options: &PngOptions,
+    ) -> Result<PngStreamWriter<W>, MosaicError> {
+        let mut encoder = png::Encoder::new(w, width, height);
+        let bit_depth = match options.sixteen_bit {
+            true => png::BitDepth::Sixteen,
+            false => png::BitDepth::Eight,
+        };
+        encoder.set(png::ColorType::RGBA).set(bit_depth);
+        let mut writer = encoder.write_header()?;
+        // Each color space comes with the gAMA and cHRM equivalents of its tag, for the
+        // decoders that ignore it.
+        let (gamma, chromaticities) = match options.color_space {
+            ColorSpace::Srgb => {
+                // Perceptual rendering intent.
+                writer.write_chunk(*b"sRGB", &[0])?;
+                (SRGB_GAMMA, SRGB_CHROMATICITIES)
+            }
+            ColorSpace::AdobeRgb => {
+                let mut profile = icc::profile_name(options.color_space).as_bytes().to_vec();
+                profile.extend_from_slice(&[0, 0]); // Name terminator, zlib compression.
+                let compressed = deflate::deflate_bytes_zlib(&icc::profile(options.color_space));
+                profile.extend_from_slice(&compressed);
+                writer.write_chunk(*b"iCCP", &profile)?;
+                let gamma = (100_000.0 / icc::ADOBE_RGB_GAMMA).round() as u32;
+                (gamma, ADOBE_RGB_CHROMATICITIES)
+            }
+        };
+        writer.write_chunk(*b"gAMA", &gamma.to_be_bytes())?;
+        let chromaticities: Vec<u8> = chromaticities
+            .iter()
+            .flat_map(|value| value.to_be_bytes())
+            .collect();
+        writer.write_chunk(*b"cHRM", &chromaticities)?;
+        if let Some(dpi) = options.dpi {
+            let pixels_per_meter = (f64::from(dpi) / INCH_IN_METERS).round() as u32;
+            let mut physical = Vec::with_capacity(9);
+            physical.extend_from_slice(&pixels_per_meter.to_be_bytes());
+            physical.extend_from_slice(&pixels_per_meter.to_be_bytes());
+            physical.push(1); // Meters.
+            writer.write_chunk(*b"pHYs", &physical)?;
+        }
+        let compressed = SharedBuffer::default();
+        Ok(PngStreamWriter {
+            writer,
+            zlib: ZlibEncoder::new(compressed.clone(), Compression::Default),
+            compressed,
+            width,
+            rows_left: height,
+            sixteen_bit: options.sixteen_bit,
+            color_space: options.color_space,
+        })
+    }
+
+    /// Appends the rows of `band`, which must have the width and the bit depth of the image,
+    /// converted from sRGB to the color space of the image.
+    pub fn write_band<S: Sample>(
+        &mut self,
+        band: &ImageBuffer<Rgba<S>, Vec<S>>,
+    ) -> Result<(), MosaicError> {
+        if band.width() != self.width || band.height() > self.rows_left {
+            return Err("band doesn't fit in the image".into());
+        }
+        let bytes_per_sample = mem::size_of::<S>();
+        if bytes_per_sample != if self.sixteen_bit { 2 } else { 1 } {
+            return Err("band doesn't have the bit depth of the image".into());
+        }
+        let converted;
+        let band = match self.color_space {
+            ColorSpace::Srgb => band,
+            ColorSpace::AdobeRgb => {
+                converted = mosaic::to_color_space(band, self.color_space);
+                &converted
+            }
+        };
+
+        let band_row_len = self.width as usize * 4;
+        let bytes_per_pixel = 4 * bytes_per_sample;
+        let row_len = self.width as usize * bytes_per_pixel;
+        let mut filtered = vec![0; row_len + 1];
+        // Sub filter, each byte is stored as the difference with the same channel of the
+        // previous pixel.
+        filtered[0] = 1;
+        for band_row in band.chunks(band_row_len) {
+            let row = S::to_png_bytes(band_row);
+            for i in 0..row_len {
+                let left = if i >= bytes_per_pixel {
+                    row[i - bytes_per_pixel]
+                } else {
+                    0
+                };
+                filtered[i + 1] = row[i].wrapping_sub(left);
+            }
+            self.zlib.write_all(&filtered)?;
+        }
+        self.rows_left -= band.height();
+        self.write_idat()
+    }
+
+    /// Terminates the stream once all the rows have been written.
+    pub fn finish(mut self) -> Result<(), MosaicError> {
+        if self.rows_left != 0 {
+            return Err(format!("{} rows are missing", self.rows_left).into());
+        }
+
+        let zlib = std::mem::replace(
+            &mut self.zlib,
+            ZlibEncoder::new(SharedBuffer::default(), Compression::Default),
+        );
+        zlib.finish()?;
+        self.write_idat()
+    }
+
+    fn write_idat(&mut self) -> Result<(), MosaicError> {
+        let data = std::mem::take(&mut *self.compressed.0.borrow_mut());
+        if !data.is_empty() {
+            self.writer.write_chunk(*b"IDAT", &data)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,1020 @@
+//! Preprocessing of a gallery into the thumbnails a mosaic is made of.
+
+use crate::binary;
+use crate::color::{
+    compute_contrast, compute_histogram, compute_main_color, compute_opaque_color, median_color,
+};
+use crate::error::MosaicError;
+use crate::glob::FileFilter;
+use crate::matching::color_distance;
+use crate::metadata::{
+    find_near_duplicates, load_metadata, metadata_path, save_processed_pictures_metadata,
+    MetadataFormat, NearDuplicate, ProcessedPicture, ProcessedPictureMetadata, METADATA_VERSION,
+};
+use crate::progress::Progress;
+use crate::report::{Outcome, PreprocessReport};
+use crate::zip::{ZipArchive, ZipEntry};
+use crate::{
+    compute_ratio, exif, palette, HISTOGRAM_BINS_PER_CHANNEL, THUMBNAIL_SIZE, TRANSPARENT_ALPHA,
+};
+use crate::{debug, info, warn};
+use image::{self, imageops, DynamicImage, GenericImageView, ImageBuffer, Rgba, SubImage};
+use rayon::prelude::*;
+use serde_derive::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::cmp;
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::mem;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use walkdir::{DirEntry, WalkDir};
+
+/// Fraction of transparent pixels above which a picture is skipped with `skip_transparent`.
+const MAX_TRANSPARENT_RATIO: f32 = 0.5;
+/// Number of colors clustered with `ColorMode::Dominant`.
+pub(crate) const PALETTE_SIZE: usize = 4;
+
+/// How the color of a picture is computed. Recorded in the metadata, for the chunks of the
+/// model to be computed the same way.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ColorMode {
+    #[default]
+    Mean,
+    /// Per channel median, not shifted by a few pixels far from the others.
+    Median,
+    /// Centroid of the largest cluster of colors, so that e.g. a sunset isn't summed up by the
+    /// brown average of its sky and foreground.
+    Dominant,
+}
+
+/// How a picture that isn't square is made so before being reduced to a thumbnail.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TileFit {
+    /// Keeps the centered square, dropping the edges of the longer side.
+    Crop,
+    /// Extends the shorter side evenly with `PreprocessOptions::pad_color`, keeping the whole
+    /// picture.
+    Pad,
+}
+
+/// Options driving how the gallery pictures are preprocessed.
+pub struct PreprocessOptions {
+    pub histogram: bool,
+    pub grayscale: bool,
+    pub contrast_adjustment: f32,
+    /// Factor the HSV saturation of the thumbnails is multiplied by after the contrast
+    /// adjustment, 1 to leave it.
+    pub saturation_boost: f32,
+    pub color_mode: ColorMode,
+    pub tile_fit: TileFit,
+    /// Color of the borders added with `TileFit::Pad`.
+    pub pad_color: [u8; 4],
+    /// Clockwise rotation in degrees, 0, 90, 180 or 270, of all the thumbnails, e.g. for
+    /// portraits shot sideways.
+    pub tile_rotation: u32,
+    /// Extension of the saved thumbnails, the one of the original picture if `None`.
+    pub thumbnail_format: Option<String>,
+    /// Filter the thumbnails are resized with, the fast one of `imageops::thumbnail` if `None`.
+    pub resize_filter: Option<imageops::FilterType>,
+    pub filter: FileFilter,
+    pub walk: WalkOptions,
+    /// Lowercase extensions of the files to decode, all the files if empty.
+    pub extensions: Vec<String>,
+    /// Size in bytes above which a file isn't decoded.
+    pub max_file_size: Option<u64>,
+    /// Length in pixels below which the shorter side of a picture is too small to make a
+    /// sharp thumbnail.
+    pub min_dimension: Option<u32>,
+    /// Whether to skip the pictures more than `MAX_TRANSPARENT_RATIO` transparent.
+    pub skip_transparent: bool,
+    /// Whether the color of a picture is the average of its opaque pixels only, rather than of
+    /// all its pixels weighted by their alpha.
+    pub ignore_transparent: bool,
+    /// Whether the colors of the pictures are averaged in linear light rather than in sRGB.
+    pub linear_light: bool,
+    /// Hamming distance between perceptual hashes up to which a picture is dropped as a
+    /// duplicate of an earlier one, none is if `None`.
+    pub dedupe: Option<u32>,
+    pub metadata_format: MetadataFormat,
+}
+
+/// How the gallery folder is walked.
+#[derive(Debug, Default)]
+pub struct WalkOptions {
+    /// Whether to leave out the files and folders whose name starts with a dot.
+    pub skip_hidden: bool,
+    /// Whether to walk the targets of symbolic links. Links to one of their own parents are
+    /// skipped rather than walked forever.
+    pub follow_symlinks: bool,
+    /// Number of folders deep the files are looked for, 1 for the files at the root of the
+    /// gallery only. Unlimited if `None`.
+    pub max_depth: Option<usize>,
+}
+
+/// Difference hash of `img`: each bit tells whether a pixel of its 9x8 grayscale reduction is
+/// brighter than its right neighbour, so that resized or recompressed copies of a picture hash
+/// the same or nearly.
+fn compute_dhash(img: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> u64 {
+    let small = imageops::resize(
+        &imageops::grayscale(img),
+        9,
+        8,
+        imageops::FilterType::Triangle,
+    );
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = small.get_pixel(x, y).data[0] > small.get_pixel(x + 1, y).data[0];
+            hash = hash << 1 | u64::from(brighter);
+        }
+    }
+    hash
+}
+
+/// Multiplies the HSV saturation of the pixels of `img` by `factor`, up to 1, keeping their
+/// hue and value.
+fn boost_saturation(
+    mut img: ImageBuffer<Rgba<u8>, Vec<u8>>,
+    factor: f32,
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    for pixel in img.pixels_mut() {
+        let max = f32::from(*pixel.data[..3].iter().max().unwrap());
+        let min = f32::from(*pixel.data[..3].iter().min().unwrap());
+        if max == min {
+            continue;
+        }
+        // Scaling the saturation scales the distance of each channel to the maximum one.
+        let saturation = (max - min) / max;
+        let scale = (saturation * factor).min(1.0) / saturation;
+        for channel in pixel.data[..3].iter_mut() {
+            let value = max - (max - f32::from(*channel)) * scale;
+            *channel = value.round().clamp(0.0, 255.0) as u8;
+        }
+    }
+    img
+}
+
+/// Fraction of the pixels of `img` that are mostly transparent.
+fn transparent_ratio(img: &ImageBuffer<Rgba<u8>, Vec<u8>>) -> f32 {
+    let transparent = img
+        .pixels()
+        .filter(|pixel| pixel.data[3] < TRANSPARENT_ALPHA)
+        .count();
+    transparent as f32 / (img.width() * img.height()).max(1) as f32
+}
+
+/// Walks the files of `folder_path`, skipping the folders and files excluded by `filter` and
+/// counting in `hidden` the hidden ones skipped with `walk.skip_hidden`.
+pub fn files_from_folder<'a>(
+    folder_path: &'a Path,
+    filter: &'a FileFilter,
+    walk: &WalkOptions,
+    hidden: &'a Cell<usize>,
+) -> impl Iterator<Item = DirEntry> + 'a {
+    let skip_hidden = walk.skip_hidden;
+    let mut walk_dir = WalkDir::new(folder_path).follow_links(walk.follow_symlinks);
+    if let Some(depth) = walk.max_depth {
+        walk_dir = walk_dir.max_depth(depth);
+    }
+    walk_dir
+        .into_iter()
+        .filter_entry(move |entry| {
+            if entry.depth() == 0 {
+                return true;
+            }
+            if skip_hidden && entry.file_name().to_string_lossy().starts_with('.') {
+                hidden.set(hidden.get() + 1);
+                return false;
+            }
+            match entry.path().strip_prefix(folder_path) {
+                Ok(relative) => !filter.is_excluded(relative, entry.file_type().is_dir()),
+                _ => true,
+            }
+        })
+        .filter_map(Result::ok)
+        .filter(|entry| entry.file_type().is_file())
+}
+
+fn image_square_view(img: &DynamicImage) -> SubImage<&DynamicImage> {
+    let (w, h) = img.dimensions();
+    let square_size = cmp::min(w, h);
+
+    let x_offset = (w - square_size) / 2;
+    let y_offset = (h - square_size) / 2;
+    img.view(x_offset, y_offset, square_size, square_size)
+}
+
+/// Centers `img` on a square of `color` as large as its longer side.
+fn image_square_padded(img: &DynamicImage, color: [u8; 4]) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let (w, h) = img.dimensions();
+    let square_size = cmp::max(w, h);
+
+    let mut square = ImageBuffer::from_pixel(square_size, square_size, Rgba(color));
+    imageops::replace(
+        &mut square,
+        &img.to_rgba(),
+        (square_size - w) / 2,
+        (square_size - h) / 2,
+    );
+    square
+}
+
+/// Reduces `img` to a square thumbnail, then rotates it, as `options` tells.
+fn make_thumbnail(
+    img: &DynamicImage,
+    options: &PreprocessOptions,
+) -> ImageBuffer<Rgba<u8>, Vec<u8>> {
+    let square = match options.tile_fit {
+        TileFit::Crop => image_square_view(img).to_image(),
+        TileFit::Pad => image_square_padded(img, options.pad_color),
+    };
+    let thumb = match options.resize_filter {
+        Some(filter) => imageops::resize(&square, THUMBNAIL_SIZE, THUMBNAIL_SIZE, filter),
+        None => imageops::thumbnail(&square, THUMBNAIL_SIZE, THUMBNAIL_SIZE),
+    };
+    match options.tile_rotation {
+        90 => imageops::rotate90(&thumb),
+        180 => imageops::rotate180(&thumb),
+        270 => imageops::rotate270(&thumb),
+        _ => thumb,
+    }
+}
+
+/// Preprocesses the pictures at `paths`, `load(i)` returning the upright `i`-th picture or
+/// why it can't be decoded. The outcome of each picture is recorded in `report`. Errors if
+/// `output_folder` can't be created. Stops early, returning the pictures processed so far, if
+/// `progress` cancels it.
+fn process_pictures<F>(
+    paths: &[PathBuf],
+    mut load: F,
+    output_folder: &Path,
+    options: &PreprocessOptions,
+    report: &mut PreprocessReport,
+    progress: &dyn Progress,
+) -> Result<Vec<ProcessedPicture>, MosaicError>
+where
+    F: FnMut(usize) -> Result<DynamicImage, String>,
+{
+    if paths.is_empty() {
+        return Err(MosaicError::EmptyGallery);
+    }
+    if !output_folder.exists() {
+        fs::create_dir(output_folder)?;
+    }
+
+    let mut res: Vec<ProcessedPicture> = Vec::new();
+    // Number of pixels of the picture of each element of `res`, the largest copy of a picture
+    // being kept with `dedupe`.
+    let mut resolutions = Vec::new();
+
+    for (i, path) in paths.iter().enumerate() {
+        if progress.is_cancelled() {
+            break;
+        }
+        progress.on_file(i, paths.len(), path);
+
+        let start = Instant::now();
+        let img = load(i);
+        report.timings.add("decode", start.elapsed());
+        let img = match img {
+            Ok(img) => {
+                debug!(
+                    "{}: decoded in {} ms",
+                    path.display(),
+                    start.elapsed().as_millis()
+                );
+                img
+            }
+            Err(e) => {
+                warn!("{}: skipped, can't decode: {}", path.display(), e);
+                report.record(path, Outcome::DecodeError, Some(e));
+                continue;
+            }
+        };
+        // Checked on the upright picture, so that a panorama is judged on its short side.
+        if let Some(min) = options.min_dimension {
+            let (w, h) = img.dimensions();
+            if cmp::min(w, h) < min {
+                let message = format!("{}x{} is smaller than {} px", w, h, min);
+                info!("{}: skipped, {}", path.display(), message);
+                report.record(path, Outcome::TooSmall, Some(message));
+                continue;
+            }
+        }
+        let img = if options.grayscale {
+            img.grayscale()
+        } else {
+            img
+        };
+
+        let (w, h) = img.dimensions();
+        let ratio = compute_ratio(w, h);
+        let resolution = u64::from(w) * u64::from(h);
+
+        let start = Instant::now();
+        let thumb = make_thumbnail(&img, options);
+        let thumb = if options.contrast_adjustment != 0.0 {
+            imageops::contrast(&thumb, options.contrast_adjustment)
+        } else {
+            thumb
+        };
+        let thumb = if options.saturation_boost != 1.0 {
+            boost_saturation(thumb, options.saturation_boost)
+        } else {
+            thumb
+        };
+        let thumb = if options.grayscale {
+            DynamicImage::ImageLuma8(imageops::grayscale(&thumb)).to_rgba()
+        } else {
+            thumb
+        };
+        report.timings.add("resize", start.elapsed());
+        if options.skip_transparent && transparent_ratio(&thumb) > MAX_TRANSPARENT_RATIO {
+            info!("{}: skipped, mostly transparent", path.display());
+            report.record(path, Outcome::Transparent, None);
+            continue;
+        }
+        let start = Instant::now();
+        let phash = compute_dhash(&thumb);
+        report.timings.add("analyze", start.elapsed());
+        let duplicate = options.dedupe.and_then(|max_distance| {
+            res.iter().position(|pic| {
+                pic.phash
+                    .is_some_and(|other| (phash ^ other).count_ones() <= max_distance)
+            })
+        });
+        if let Some(k) = duplicate {
+            if resolution <= resolutions[k] {
+                let message = format!("duplicate of {}", picture_source(&res[k]));
+                info!("{}: skipped, {}", path.display(), message);
+                report.record(path, Outcome::Duplicate, Some(message));
+                continue;
+            }
+        }
+
+        let thumb_name = match thumbnail_name(path, options.thumbnail_format.as_deref()) {
+            Ok(thumb_name) => thumb_name,
+            Err(e) => {
+                warn!("{}: skipped, {}", path.display(), e);
+                report.record(path, Outcome::SaveError, Some(e.to_string()));
+                continue;
+            }
+        };
+        let thumb_path = output_folder.join(&thumb_name);
+        let start = Instant::now();
+        let saved = thumb.save(&thumb_path);
+        report.timings.add("save", start.elapsed());
+        if let Err(e) = saved {
+            warn!(
+                "{}: skipped, can't save the thumbnail: {}",
+                path.display(),
+                e
+            );
+            report.record(path, Outcome::SaveError, Some(e.to_string()));
+            continue;
+        }
+
+        // Computed on the thumbnail rather than the picture, so that it is the color of the
+        // pasted pixels whatever the contrast adjustment.
+        let start = Instant::now();
+        let pixels = || {
+            thumb.pixels().map(|pixel| {
+                let weight = match options.ignore_transparent {
+                    true if pixel.data[3] < TRANSPARENT_ALPHA => 0.0,
+                    true => 1.0,
+                    false => f64::from(pixel.data[3]),
+                };
+                (pixel, weight)
+            })
+        };
+        let palette = match options.color_mode {
+            ColorMode::Mean | ColorMode::Median => None,
+            ColorMode::Dominant => Some(palette::palette(
+                pixels(),
+                PALETTE_SIZE,
+                options.linear_light,
+            )),
+        };
+        let median = match options.color_mode {
+            ColorMode::Median => median_color(pixels()),
+            ColorMode::Mean | ColorMode::Dominant => None,
+        };
+        let color_rgb = match median.or_else(|| palette.as_ref()?.first().copied()) {
+            Some(color) => color,
+            None if options.ignore_transparent => {
+                compute_opaque_color(&thumb, options.linear_light)
+            }
+            None => compute_main_color(&thumb, false, options.linear_light),
+        };
+
+        let processed = ProcessedPicture {
+            path: thumb_name.to_string_lossy().to_string(),
+            color_rgb,
+            ratio_width: ratio.0,
+            ratio_height: ratio.1,
+            color_histogram: if options.histogram {
+                Some(compute_histogram(&thumb))
+            } else {
+                None
+            },
+            source: Some(path.display().to_string()),
+            contrast: Some(compute_contrast(&thumb)),
+            palette,
+            phash: Some(phash),
+        };
+        report.timings.add("analyze", start.elapsed());
+
+        let [r, g, b] = processed.color_rgb;
+        match duplicate {
+            Some(k) => {
+                let replaced = mem::replace(&mut res[k], processed);
+                info!(
+                    "{}: rgb ({}, {}, {}), replaces its smaller duplicate {}",
+                    path.display(),
+                    r,
+                    g,
+                    b,
+                    picture_source(&replaced)
+                );
+                if replaced.path != res[k].path {
+                    let _ = fs::remove_file(output_folder.join(&replaced.path));
+                }
+                resolutions[k] = resolution;
+                let replaced_source = picture_source(&replaced);
+                if let Some(record) = report
+                    .files
+                    .iter_mut()
+                    .find(|record| record.path == replaced_source)
+                {
+                    record.outcome = Outcome::Duplicate;
+                    record.message = Some(format!("duplicate of {}", path.display()));
+                }
+            }
+            None => {
+                info!("{}: rgb ({}, {}, {})", path.display(), r, g, b);
+                res.push(processed);
+                resolutions.push(resolution);
+            }
+        }
+        report.record(path, Outcome::Processed, None);
+        report.timings.add_items(1);
+    }
+
+    Ok(res)
+}
+
+/// Name of the thumbnail of the picture at `path`, in the output folder, with the extension
+/// `format` if given. Errors with `MosaicError::Invalid` if `path` doesn't end with a file
+/// name, such as `..`.
+fn thumbnail_name(path: &Path, format: Option<&str>) -> Result<PathBuf, MosaicError> {
+    let name = match format {
+        Some(ext) => path
+            .file_stem()
+            .map(|stem| Path::new(stem).with_extension(ext)),
+        None => path.file_name().map(PathBuf::from),
+    };
+    name.ok_or_else(|| MosaicError::Invalid(format!("{} has no file name", path.display())))
+}
+
+/// Path of the original of `pic`, for the log.
+fn picture_source(pic: &ProcessedPicture) -> &str {
+    pic.source.as_deref().unwrap_or(&pic.path)
+}
+
+/// Creates the thumbnails of the pictures of `gallery_folder`, a folder or a zip archive, in
+/// `output_folder`, along with the metadata `create_mosaic` matches them with. Also returns
+/// what became of each file of the gallery. If `progress` cancels it, the metadata of the
+/// pictures processed so far is still saved, so that they can be used, and
+/// `MosaicError::Cancelled` is returned. Errors with `MosaicError::EmptyGallery` if the gallery
+/// has no picture to decode.
+pub fn preprocess_gallery(
+    gallery_folder: &Path,
+    output_folder: &Path,
+    options: &PreprocessOptions,
+    progress: &dyn Progress,
+) -> Result<(ProcessedPictureMetadata, PreprocessReport), MosaicError> {
+    let mut report = PreprocessReport::default();
+    let pictures = if gallery_folder.is_file() && is_zip(gallery_folder) {
+        preprocess_zip(
+            gallery_folder,
+            output_folder,
+            options,
+            &mut report,
+            progress,
+        )?
+    } else {
+        let paths = gallery_files(gallery_folder, output_folder, options, &mut report)?;
+        let load = |i: usize| {
+            let img = image::open(&paths[i]).map_err(|e| e.to_string())?;
+            Ok(match exif::read_orientation(&paths[i]) {
+                Some(orientation) => exif::apply_orientation(img, orientation),
+                None => img,
+            })
+        };
+        process_pictures(&paths, load, output_folder, options, &mut report, progress)?
+    };
+    report.print_summary();
+    let metadata = ProcessedPictureMetadata {
+        version: METADATA_VERSION,
+        pictures,
+        contrast_adjustment: options.contrast_adjustment,
+        linear_light: options.linear_light,
+        color_mode: options.color_mode,
+    };
+    let start = Instant::now();
+    save_processed_pictures_metadata(&metadata, output_folder, options.metadata_format)?;
+    report.timings.add("metadata", start.elapsed());
+    if progress.is_cancelled() {
+        return Err(MosaicError::Cancelled);
+    }
+    Ok((metadata, report))
+}
+
+/// Removes from the gallery preprocessed in `processed_folder` the near duplicates found by
+/// `find_near_duplicates`, their thumbnails and their metadata, and returns them. Nothing is
+/// removed if `dry_run`.
+pub fn dedupe_gallery(
+    processed_folder: &Path,
+    max_distance: u32,
+    dry_run: bool,
+) -> Result<Vec<NearDuplicate>, MosaicError> {
+    let format = MetadataFormat::of_path(&metadata_path(processed_folder));
+    let mut metadata = load_metadata(processed_folder)?;
+    let duplicates = find_near_duplicates(&metadata.pictures, max_distance);
+    if dry_run || duplicates.is_empty() {
+        return Ok(duplicates);
+    }
+
+    let removed: HashSet<&str> = duplicates.iter().map(|d| d.path.as_str()).collect();
+    metadata
+        .pictures
+        .retain(|pic| !removed.contains(pic.path.as_str()));
+    save_processed_pictures_metadata(&metadata, processed_folder, format)?;
+    for path in removed {
+        // Thumbnails named after several pictures are kept for the remaining ones.
+        if !metadata.pictures.iter().any(|pic| pic.path == path) {
+            fs::remove_file(processed_folder.join(path))?;
+        }
+    }
+    Ok(duplicates)
+}
+
+/// Why a thumbnail of a preprocessed gallery doesn't match its metadata.
+#[derive(Debug, PartialEq)]
+pub enum ThumbnailProblem {
+    Missing,
+    /// The thumbnail can't be decoded, with why.
+    Undecodable(String),
+    /// Dimensions of a thumbnail that isn't `THUMBNAIL_SIZE` pixels square.
+    WrongDimensions(u32, u32),
+    /// Color of the metadata and color of the thumbnail, further apart than the tolerance.
+    StaleColor {
+        stored: [u8; 3],
+        actual: [u8; 3],
+        distance: u32,
+    },
+}
+
+impl fmt::Display for ThumbnailProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ThumbnailProblem::Missing => write!(f, "missing"),
+            ThumbnailProblem::Undecodable(e) => write!(f, "can't decode: {}", e),
+            ThumbnailProblem::WrongDimensions(w, h) => write!(
+                f,
+                "{}x{} px instead of {}x{}",
+                w, h, THUMBNAIL_SIZE, THUMBNAIL_SIZE
+            ),
+            ThumbnailProblem::StaleColor {
+                stored: [r1, g1, b1],
+                actual: [r2, g2, b2],
+                distance,
+            } => write!(
+                f,
+                "rgb ({}, {}, {}) in the metadata but ({}, {}, {}) in the thumbnail, distance {}",
+                r1, g1, b1, r2, g2, b2, distance
+            ),
+        }
+    }
+}
+
+/// Thumbnail found by `verify_gallery` not to match its metadata.
+#[derive(Debug)]
+pub struct BadThumbnail {
+    pub path: String,
+    pub problem: ThumbnailProblem,
+}
+
+/// Checks that the thumbnail of each picture of the gallery preprocessed in `processed_folder`
+/// exists, can be decoded, is `THUMBNAIL_SIZE` pixels square and still has the color of the
+/// metadata, within the `color_distance` `tolerance`. Returns the ones that don't, in the
+/// order of the metadata.
+///
+/// The color being the average, or the median with `ColorMode::Median`, of either all the
+/// pixels or the opaque ones, whichever the gallery was preprocessed with, a thumbnail passes
+/// if one of them matches. The dominant
+/// colors of `ColorMode::Dominant` depend on their clustering, they aren't checked.
+pub fn verify_gallery(
+    processed_folder: &Path,
+    tolerance: u32,
+) -> Result<Vec<BadThumbnail>, MosaicError> {
+    let metadata = load_metadata(processed_folder)?;
+    Ok(metadata
+        .pictures
+        .par_iter()
+        .filter_map(|pic| {
+            let checked = verify_thumbnail(
+                processed_folder,
+                pic,
+                (metadata.color_mode, metadata.linear_light),
+                tolerance,
+            );
+            checked.err().map(|problem| BadThumbnail {
+                path: pic.path.clone(),
+                problem,
+            })
+        })
+        .collect())
+}
+
+fn verify_thumbnail(
+    processed_folder: &Path,
+    pic: &ProcessedPicture,
+    (color_mode, linear_light): (ColorMode, bool),
+    tolerance: u32,
+) -> Result<(), ThumbnailProblem> {
+    let path = processed_folder.join(&pic.path);
+    if !path.is_file() {
+        return Err(ThumbnailProblem::Missing);
+    }
+    let thumb = image::open(&path)
+        .map_err(|e| ThumbnailProblem::Undecodable(e.to_string()))?
+        .to_rgba();
+    let (w, h) = thumb.dimensions();
+    if (w, h) != (THUMBNAIL_SIZE, THUMBNAIL_SIZE) {
+        return Err(ThumbnailProblem::WrongDimensions(w, h));
+    }
+    if pic.palette.is_some() {
+        return Ok(());
+    }
+
+    let (all, opaque) = match color_mode {
+        ColorMode::Median => {
+            let median = |opaque_only: bool| {
+                let pixels = thumb.pixels().map(|pixel| match opaque_only {
+                    true => (
+                        pixel,
+                        f64::from(u8::from(pixel.data[3] >= TRANSPARENT_ALPHA)),
+                    ),
+                    false => (pixel, f64::from(pixel.data[3])),
+                });
+                median_color(pixels)
+                    .unwrap_or_else(|| compute_main_color(&thumb, false, linear_light))
+            };
+            (median(false), median(true))
+        }
+        ColorMode::Mean | ColorMode::Dominant => (
+            compute_main_color(&thumb, false, linear_light),
+            compute_opaque_color(&thumb, linear_light),
+        ),
+    };
+    let distance = color_distance(all, pic.color_rgb);
+    if distance <= tolerance || color_distance(opaque, pic.color_rgb) <= tolerance {
+        Ok(())
+    } else {
+        Err(ThumbnailProblem::StaleColor {
+            stored: pic.color_rgb,
+            actual: all,
+            distance,
+        })
+    }
+}
+
+/// Lists the pictures of `gallery_folder` that `preprocess_gallery` would decode, without
+/// writing anything, along with an estimate of the space their thumbnails and metadata would
+/// take. The pictures are recorded in the report as `Outcome::Selected`.
+pub fn dry_run_gallery(
+    gallery_folder: &Path,
+    output_folder: &Path,
+    options: &PreprocessOptions,
+) -> Result<DryRun, MosaicError> {
+    let mut report = PreprocessReport::default();
+    let paths = if gallery_folder.is_file() && is_zip(gallery_folder) {
+        let archive = ZipArchive::open(gallery_folder)?;
+        zip_entries(&archive, gallery_folder, options, &mut report)
+            .iter()
+            .map(|entry| gallery_folder.join(&entry.name))
+            .collect()
+    } else {
+        gallery_files(gallery_folder, output_folder, options, &mut report)?
+    };
+    for path in &paths {
+        report.record(path, Outcome::Selected, None);
+    }
+
+    let pixels = u64::from(THUMBNAIL_SIZE) * u64::from(THUMBNAIL_SIZE);
+    Ok(DryRun {
+        report,
+        thumbnail_bytes: paths.len() as u64 * pixels * 4,
+        metadata_bytes: estimate_metadata_size(&paths, options)?,
+    })
+}
+
+/// What `preprocess_gallery` would do, as found by `dry_run_gallery`.
+pub struct DryRun {
+    pub report: PreprocessReport,
+    /// Size of the thumbnails uncompressed.
+    pub thumbnail_bytes: u64,
+    pub metadata_bytes: u64,
+}
+
+/// Size of the metadata of the pictures at `paths`, serialized with placeholder values as wide
+/// as the ones `process_pictures` would compute.
+fn estimate_metadata_size(
+    paths: &[PathBuf],
+    options: &PreprocessOptions,
+) -> Result<u64, MosaicError> {
+    let bins = HISTOGRAM_BINS_PER_CHANNEL.pow(3) as usize;
+    // The pictures without a file name are skipped, and so left out.
+    let pictures = paths
+        .iter()
+        .filter_map(|path| {
+            Some(ProcessedPicture {
+                path: thumbnail_name(path, options.thumbnail_format.as_deref())
+                    .ok()?
+                    .to_string_lossy()
+                    .to_string(),
+                color_rgb: [255; 3],
+                ratio_width: 1,
+                ratio_height: 1,
+                color_histogram: Some(vec![0; bins]).filter(|_| options.histogram),
+                source: Some(path.display().to_string()),
+                contrast: Some(50.123_456),
+                palette: Some(vec![[255; 3]; PALETTE_SIZE])
+                    .filter(|_| options.color_mode == ColorMode::Dominant),
+                phash: Some(u64::MAX),
+            })
+        })
+        .collect();
+    let metadata = ProcessedPictureMetadata {
+        version: METADATA_VERSION,
+        pictures,
+        contrast_adjustment: options.contrast_adjustment,
+        linear_light: options.linear_light,
+        color_mode: options.color_mode,
+    };
+    // Compact JSON is as long as ndjson but for its header line, a few bytes.
+    let bytes = match options.metadata_format {
+        MetadataFormat::Json => serde_json::to_vec_pretty(&metadata)?,
+        MetadataFormat::Ndjson => serde_json::to_vec(&metadata)?,
+        MetadataFormat::Cbor => binary::to_cbor(&metadata)?,
+        MetadataFormat::MessagePack => binary::to_msgpack(&metadata)?,
+    };
+    Ok(bytes.len() as u64)
+}
+
+/// Walks `gallery_folder` for the pictures to decode, recording in `report` the files filtered
+/// out. Sorted, since the walk order depends on the file system, so that preprocessing is
+/// reproducible.
+fn gallery_files(
+    gallery_folder: &Path,
+    output_folder: &Path,
+    options: &PreprocessOptions,
+    report: &mut PreprocessReport,
+) -> Result<Vec<PathBuf>, MosaicError> {
+    let nested_output = nested_output_folder(gallery_folder, output_folder)?;
+    if let Some(dir) = &nested_output {
+        info!("{} is in the gallery, skipping it", dir.display());
+    }
+    let mut paths = Vec::new();
+    let hidden = Cell::new(0);
+    for entry in files_from_folder(gallery_folder, &options.filter, &options.walk, &hidden) {
+        if nested_output
+            .as_ref()
+            .is_some_and(|dir| entry.path().starts_with(dir))
+            || (MetadataFormat::ALL.iter()).any(|format| entry.file_name() == format.filename())
+        {
+            continue;
+        }
+        let size = entry.metadata().map_or(0, |metadata| metadata.len());
+        match skip_reason(entry.path(), size, options) {
+            None => paths.push(entry.into_path()),
+            Some(reason) => report.record(entry.path(), Outcome::Filtered, Some(reason)),
+        }
+    }
+    report.hidden = hidden.get();
+    paths.sort();
+    Ok(paths)
+}
+
+/// Path, under `gallery_folder`, of `output_folder` if it is inside the gallery, so that the
+/// thumbnails of a previous run aren't preprocessed as pictures. Errors if both are the same
+/// folder, where the thumbnails would overwrite the pictures.
+fn nested_output_folder(
+    gallery_folder: &Path,
+    output_folder: &Path,
+) -> Result<Option<PathBuf>, MosaicError> {
+    // Canonicalized to see through symbolic links. If the output folder doesn't exist yet, it
+    // has nothing to walk.
+    let (gallery, output) = match (gallery_folder.canonicalize(), output_folder.canonicalize()) {
+        (Ok(gallery), Ok(output)) => (gallery, output),
+        _ => return Ok(None),
+    };
+    if gallery == output {
+        return Err("the output folder can't be the gallery folder".into());
+    }
+    Ok(output
+        .strip_prefix(&gallery)
+        .ok()
+        .map(|relative| gallery_folder.join(relative)))
+}
+
+/// Why the file at `path`, of `size` bytes, isn't worth decoding given the extensions and size
+/// limit of `options`, if it isn't.
+fn skip_reason(path: &Path, size: u64, options: &PreprocessOptions) -> Option<String> {
+    let has_extension = options.extensions.is_empty()
+        || path.extension().is_some_and(|ext| {
+            options
+                .extensions
+                .contains(&ext.to_string_lossy().to_lowercase())
+        });
+    if !has_extension {
+        return Some("extension not selected".to_string());
+    }
+    match options.max_file_size {
+        Some(max) if size > max => Some(format!("larger than {} bytes", max)),
+        _ => None,
+    }
+}
+
+fn is_zip(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("zip"))
+}
+
+/// Preprocesses the pictures of the zip archive at `zip_path`, decoding them from memory one
+/// at a time rather than unpacking the archive.
+fn preprocess_zip(
+    zip_path: &Path,
+    output_folder: &Path,
+    options: &PreprocessOptions,
+    report: &mut PreprocessReport,
+    progress: &dyn Progress,
+) -> Result<Vec<ProcessedPicture>, MosaicError> {
+    let mut archive = ZipArchive::open(zip_path)?;
+    let entries = zip_entries(&archive, zip_path, options, report);
+    let paths: Vec<_> = entries
+        .iter()
+        .map(|entry| zip_path.join(&entry.name))
+        .collect();
+    let load = |i: usize| {
+        let data = archive.read(&entries[i]).map_err(|e| e.to_string())?;
+        let img = image::load_from_memory(&data).map_err(|e| e.to_string())?;
+        Ok(match exif::read_orientation_from_memory(&data) {
+            Some(orientation) => exif::apply_orientation(img, orientation),
+            None => img,
+        })
+    };
+    process_pictures(&paths, load, output_folder, options, report, progress)
+}
+
+/// Entries of `archive`, at `zip_path`, to decode, sorted by name. The entries filtered out are
+/// recorded in `report`.
+fn zip_entries(
+    archive: &ZipArchive,
+    zip_path: &Path,
+    options: &PreprocessOptions,
+    report: &mut PreprocessReport,
+) -> Vec<ZipEntry> {
+    let mut entries = Vec::new();
+    for entry in archive.entries() {
+        if entry.is_dir()
+            || is_excluded_entry(&entry.name, &options.filter)
+            || options
+                .walk
+                .max_depth
+                .is_some_and(|depth| entry.name.split('/').count() > depth)
+        {
+            continue;
+        }
+        if options.walk.skip_hidden && is_hidden_entry(&entry.name) {
+            report.hidden += 1;
+            continue;
+        }
+        match skip_reason(Path::new(&entry.name), entry.size(), options) {
+            None => entries.push(entry.clone()),
+            Some(reason) => {
+                report.record(&zip_path.join(&entry.name), Outcome::Filtered, Some(reason))
+            }
+        }
+    }
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    entries
+}
+
+/// Whether the archive entry `name`, or one of its folders, is excluded by `filter`.
+fn is_excluded_entry(name: &str, filter: &FileFilter) -> bool {
+    let path = Path::new(name);
+    path.ancestors()
+        .filter(|ancestor| !ancestor.as_os_str().is_empty())
+        .any(|ancestor| filter.is_excluded(ancestor, ancestor != path))
+}
+
+/// Whether the archive entry `name` is hidden, or in a hidden folder such as `__MACOSX/.x`.
+fn is_hidden_entry(name: &str) -> bool {
+    name.split('/').any(|component| component.starts_with('.'))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::temp_dir;
+
+    #[test]
+    fn thumbnail_keeps_the_file_name() {
+        let name = thumbnail_name(Path::new("gallery/cat.jpg"), None);
+        assert_eq!(name.unwrap(), Path::new("cat.jpg"));
+    }
+
+    #[test]
+    fn thumbnail_takes_the_extension_of_its_format() {
+        let name = thumbnail_name(Path::new("gallery/cat.jpg"), Some("png"));
+        assert_eq!(name.unwrap(), Path::new("cat.png"));
+    }
+
+    #[test]
+    fn thumbnail_of_a_path_without_file_name_is_invalid() {
+        for format in [None, Some("png")] {
+            let name = thumbnail_name(Path::new("gallery/.."), format);
+            assert!(matches!(name, Err(MosaicError::Invalid(_))));
+        }
+    }
+
+    /// Temporary folder with a `gallery` folder holding a `thumbs` folder, and a `sibling` one
+    /// next to it.
+    fn folders() -> PathBuf {
+        let root = temp_dir("nested");
+        fs::create_dir_all(root.join("gallery/thumbs")).unwrap();
+        fs::create_dir(root.join("sibling")).unwrap();
+        root
+    }
+
+    #[test]
+    fn output_in_the_gallery_is_nested() {
+        let root = folders();
+        let gallery = root.join("gallery");
+        let nested = nested_output_folder(&gallery, &gallery.join("thumbs")).unwrap();
+        assert_eq!(nested, Some(gallery.join("thumbs")));
+    }
+
+    #[test]
+    fn output_equal_to_the_gallery_is_an_error() {
+        let root = folders();
+        let gallery = root.join("gallery");
+        let nested = nested_output_folder(&gallery, &root.join("sibling/../gallery"));
+        assert!(matches!(nested, Err(MosaicError::Invalid(_))));
+    }
+
+    #[test]
+    fn output_next_to_the_gallery_isnt_nested() {
+        let root = folders();
+        let nested = nested_output_folder(&root.join("gallery"), &root.join("sibling")).unwrap();
+        assert_eq!(nested, None);
+        // Nor the gallery in the output.
+        let nested = nested_output_folder(&root.join("gallery/thumbs"), &root.join("gallery"));
+        assert_eq!(nested.unwrap(), None);
+    }
+
+    #[test]
+    fn output_not_created_yet_isnt_nested() {
+        let root = folders();
+        let gallery = root.join("gallery");
+        let nested = nested_output_folder(&gallery, &gallery.join("new")).unwrap();
+        assert_eq!(nested, None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn symbolic_links_are_seen_through() {
+        use std::os::unix::fs::symlink;
+
+        let root = folders();
+        let gallery = root.join("gallery");
+        symlink(gallery.join("thumbs"), root.join("thumbs_link")).unwrap();
+        symlink(&gallery, root.join("gallery_link")).unwrap();
+
+        // Under the gallery as it was given, for the walk to skip it.
+        let nested = nested_output_folder(&gallery, &root.join("thumbs_link")).unwrap();
+        assert_eq!(nested, Some(gallery.join("thumbs")));
+        let gallery_link = root.join("gallery_link");
+        let nested = nested_output_folder(&gallery_link, &gallery.join("thumbs")).unwrap();
+        assert_eq!(nested, Some(gallery_link.join("thumbs")));
+        let nested = nested_output_folder(&gallery, &root.join("gallery_link"));
+        assert!(matches!(nested, Err(MosaicError::Invalid(_))));
+        let nested = nested_output_folder(&root.join("sibling"), &root.join("thumbs_link"));
+        assert_eq!(nested.unwrap(), None);
+    }
+}
@@ -0,0 +1,31 @@
+//! Preview of a mosaic in a true color terminal, to check the matching before saving a large
+//! image.
+
+use image::{imageops, ImageBuffer, Rgba};
+use std::io::{self, Write};
+
+/// Prints `img` scaled down to `columns` characters wide. Each character shows two pixels
+/// stacked, the upper half block taking the color of the top one and its background the color
+/// of the bottom one, so that the pixels are about square.
+pub fn print_preview(img: &ImageBuffer<Rgba<u8>, Vec<u8>>, columns: u32) -> io::Result<()> {
+    let (w, h) = img.dimensions();
+    let width = columns.min(w).max(1);
+    let height = ((u64::from(h) * u64::from(width) / u64::from(w.max(1))) as u32).max(1);
+    let small = imageops::thumbnail(img, width, height);
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    for y in (0..height).step_by(2) {
+        for x in 0..width {
+            let top = small.get_pixel(x, y).data;
+            write!(out, "\x1b[38;2;{};{};{}m", top[0], top[1], top[2])?;
+            if y + 1 < height {
+                let bottom = small.get_pixel(x, y + 1).data;
+                write!(out, "\x1b[48;2;{};{};{}m", bottom[0], bottom[1], bottom[2])?;
+            }
+            write!(out, "\u{2580}")?;
+        }
+        writeln!(out, "\x1b[0m")?;
+    }
+    out.flush()
+}
@@ -0,0 +1,50 @@
+//! Hooks to follow the progress of the long steps and to stop them, for the programs embedding
+//! the library such as a GUI.
+
+use image::ImageError;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+/// Receives the progress of preprocessing and rendering. Called from several threads while
+/// rendering, hence `Sync`. All the methods do nothing by default.
+pub trait Progress: Sync {
+    /// Called before the `i`-th of the `total` pictures of the gallery, at `path`, is decoded.
+    fn on_file(&self, _i: usize, _total: usize, _path: &Path) {}
+
+    /// Called once the `i`-th of the `total` tiles of the mosaic is drawn, in no particular
+    /// order.
+    fn on_tile(&self, _i: usize, _total: usize) {}
+
+    /// Checked between pictures and between tiles, the step stopping with `Cancelled` once it
+    /// returns true. Typically backed by an `AtomicBool` set from another thread.
+    fn is_cancelled(&self) -> bool {
+        false
+    }
+}
+
+/// Progress that isn't followed.
+pub struct NoProgress;
+
+impl Progress for NoProgress {}
+
+/// Error of a step stopped by `Progress::is_cancelled`. Rendering returns it as an
+/// `ImageError::IoError` of kind `Interrupted`, which converts to `MosaicError::Cancelled` like
+/// the other steps return.
+#[derive(Debug)]
+pub struct Cancelled;
+
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cancelled")
+    }
+}
+
+impl Error for Cancelled {}
+
+impl From<Cancelled> for ImageError {
+    fn from(cancelled: Cancelled) -> ImageError {
+        ImageError::IoError(io::Error::new(io::ErrorKind::Interrupted, cancelled))
+    }
+}
@@ -0,0 +1,105 @@
+//! What became of each file of the gallery during preprocessing, to audit a run afterwards.
+
+use crate::error::MosaicError;
+use crate::info;
+use crate::timings::Timings;
+use serde_derive::Serialize;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Outcome {
+    Processed,
+    /// Would be decoded, with `dry_run_gallery`.
+    Selected,
+    /// Left out by its extension or size, without being decoded.
+    Filtered,
+    DecodeError,
+    /// The thumbnail couldn't be written.
+    SaveError,
+    /// Shorter side below `PreprocessOptions::min_dimension`.
+    TooSmall,
+    /// More transparent than allowed by `PreprocessOptions::skip_transparent`.
+    Transparent,
+    /// Copy of another picture, dropped with `PreprocessOptions::dedupe`.
+    Duplicate,
+}
+
+impl Outcome {
+    const ALL: [Outcome; 8] = [
+        Outcome::Processed,
+        Outcome::Selected,
+        Outcome::Filtered,
+        Outcome::DecodeError,
+        Outcome::SaveError,
+        Outcome::TooSmall,
+        Outcome::Transparent,
+        Outcome::Duplicate,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Outcome::Processed => "processed",
+            Outcome::Selected => "selected",
+            Outcome::Filtered => "filtered",
+            Outcome::DecodeError => "decode-error",
+            Outcome::SaveError => "save-error",
+            Outcome::TooSmall => "too-small",
+            Outcome::Transparent => "transparent",
+            Outcome::Duplicate => "duplicate",
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct FileRecord {
+    pub path: String,
+    pub outcome: Outcome,
+    /// Error, why a file was filtered out, or picture a duplicate is a copy of.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct PreprocessReport {
+    pub files: Vec<FileRecord>,
+    /// Hidden files and folders skipped with `WalkOptions::skip_hidden`, whose content isn't
+    /// listed.
+    pub hidden: usize,
+    /// Time spent decoding, resizing, analyzing and saving the pictures.
+    #[serde(skip)]
+    pub timings: Timings,
+}
+
+impl PreprocessReport {
+    pub fn record(&mut self, path: &Path, outcome: Outcome, message: Option<String>) {
+        self.files.push(FileRecord {
+            path: path.display().to_string(),
+            outcome,
+            message,
+        });
+    }
+
+    pub fn count(&self, outcome: Outcome) -> usize {
+        self.files
+            .iter()
+            .filter(|record| record.outcome == outcome)
+            .count()
+    }
+
+    /// Logs the number of files of each outcome.
+    pub fn print_summary(&self) {
+        for &outcome in Outcome::ALL.iter() {
+            info!("{:<14} {:>8}", outcome.name(), self.count(outcome));
+        }
+        info!("{:<14} {:>8}", "hidden", self.hidden);
+    }
+
+    pub fn save_json(&self, path: &Path) -> Result<(), MosaicError> {
+        let writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(writer, self)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+//! Conversions between sRGB bytes and linear light, the space in which colors have to be
+//! averaged for black and white to average to the gray they look like from afar.
+
+use std::sync::OnceLock;
+
+/// Converts an sRGB channel to linear light, between 0 and 1.
+pub fn to_linear(channel: u8) -> f64 {
+    static TABLE: OnceLock<[f64; 256]> = OnceLock::new();
+    let table = TABLE.get_or_init(|| {
+        let mut table = [0.0; 256];
+        for (i, value) in table.iter_mut().enumerate() {
+            *value = decode(i as f64 / 255.0);
+        }
+        table
+    });
+    table[channel as usize]
+}
+
+/// Converts a 16-bit sRGB channel to linear light, between 0 and 1.
+pub fn to_linear16(channel: u16) -> f64 {
+    decode(f64::from(channel) / 65535.0)
+}
+
+/// Linear light of an sRGB value between 0 and 1.
+fn decode(c: f64) -> f64 {
+    if c <= 0.04045 {
+        c / 12.92
+    } else {
+        ((c + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Converts a linear light value, between 0 and 1, back to an sRGB channel.
+pub fn from_linear(value: f64) -> u8 {
+    let value = value.clamp(0.0, 1.0);
+    let c = if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    };
+    (c * 255.0).round() as u8
+}
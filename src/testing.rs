@@ -0,0 +1,85 @@
+//! Pictures, images and temporary folders shared by the unit tests.
+
+use crate::matching::{Layout, PlacedTile, Placement};
+use crate::metadata::ProcessedPicture;
+use image::{DynamicImage, ImageBuffer, Rgba};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// Square picture named `path`, whose color is `color`.
+pub fn picture(path: &str, color: [u8; 3]) -> ProcessedPicture {
+    ProcessedPicture {
+        path: path.to_owned(),
+        color_rgb: color,
+        ratio_width: 1,
+        ratio_height: 1,
+        color_histogram: None,
+        source: None,
+        contrast: None,
+        palette: None,
+        phash: None,
+    }
+}
+
+/// Image of `w`x`h` pixels of the colors given by `f` for their coordinates.
+pub fn image<F>(w: u32, h: u32, f: F) -> DynamicImage
+where
+    F: Fn(u32, u32) -> [u8; 4],
+{
+    DynamicImage::ImageRgba8(ImageBuffer::from_fn(w, h, |x, y| Rgba(f(x, y))))
+}
+
+/// Empty folder of its own in the temporary folder, `name` telling the tests apart.
+pub fn temp_dir(name: &str) -> PathBuf {
+    static COUNT: AtomicUsize = AtomicUsize::new(0);
+    let count = COUNT.fetch_add(1, Ordering::Relaxed);
+    let dir = env::temp_dir().join(format!("mosaic-{}-{}-{}", name, process::id(), count));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Thumbnails of `size` pixels per side of a single color each, saved in `folder` as `0.png`,
+/// `1.png`... and the pictures matching them.
+pub fn flat_gallery(folder: &Path, colors: &[[u8; 3]], size: u32) -> Vec<ProcessedPicture> {
+    (colors.iter().enumerate())
+        .map(|(i, &[r, g, b])| {
+            let path = format!("{}.png", i);
+            image(size, size, |_, _| [r, g, b, 255])
+                .save(folder.join(&path))
+                .unwrap();
+            picture(&path, [r, g, b])
+        })
+        .collect()
+}
+
+/// Placement of `grid` cells of `thumb_dim` laid out by `layout`, the `i`-th cell showing the
+/// `i % pics.len()`-th picture.
+pub fn placement(
+    pics: &[ProcessedPicture],
+    grid: (usize, usize),
+    layout: Layout,
+    thumb_dim: (u32, u32),
+) -> Placement<'_> {
+    let tiles = (0..grid.0 * grid.1)
+        .map(|i| PlacedTile {
+            pic: &pics[i % pics.len()],
+            target_color: pics[i % pics.len()].color_rgb,
+            rotation: 0,
+            mirrored: false,
+            masked: false,
+            details: Vec::new(),
+            span: 1,
+        })
+        .collect();
+    Placement {
+        grid_width: grid.0,
+        grid_height: grid.1,
+        layout,
+        thumb_dim,
+        tiles,
+    }
+}
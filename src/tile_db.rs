@@ -0,0 +1,513 @@
+//! Database of the preprocessed pictures indexed by color, so that the picture closest to a
+//! color is found reading a few of them rather than loading the whole metadata first.
+//!
+//! The file starts with `MAGIC`, `FORMAT_VERSION` and the fields of the metadata but the
+//! pictures. Then comes the index: the pictures in `BUCKETS` cubes of the RGB space, each
+//! picture as its color, its rank in the metadata and the offset of its record. The records
+//! follow, in the order of the metadata, each one a MessagePack picture after its length.
+//! The integers are little-endian.
+
+use crate::binary;
+use crate::matching::color_distance;
+use crate::metadata::{ProcessedPicture, ProcessedPictureMetadata, METADATA_VERSION};
+use crate::preprocess::ColorMode;
+use crate::MosaicError;
+use serde_derive::{Deserialize, Serialize};
+use std::convert::{TryFrom, TryInto};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const MAGIC: &[u8; 8] = b"MOSAICDB";
+/// Version of the layout of the file, bumped when it changes.
+const FORMAT_VERSION: u32 = 1;
+/// Bits of each channel telling the cube of a color, 8 cubes per channel.
+const BUCKET_BITS: u32 = 3;
+const BUCKETS: usize = 1 << (3 * BUCKET_BITS);
+/// Bytes of a picture in the index: its color and a padding byte, its rank and the offset of
+/// its record.
+const ENTRY_SIZE: usize = 16;
+
+/// Fields of the metadata but the pictures, with their number.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u32,
+    contrast_adjustment: f32,
+    linear_light: bool,
+    color_mode: ColorMode,
+    pictures: u32,
+}
+
+/// Picture of the index.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Entry {
+    color: [u8; 3],
+    /// Rank of the picture in the metadata, ties going to the first one.
+    rank: u32,
+    /// Offset of the record from the start of the records.
+    offset: u64,
+}
+
+fn bucket(color: [u8; 3]) -> usize {
+    let shift = 8 - BUCKET_BITS;
+    color
+        .iter()
+        .fold(0, |acc, &c| acc << BUCKET_BITS | usize::from(c >> shift))
+}
+
+/// Squared RGB distance from `color` to the closest color of `bucket`, none of its pictures
+/// being closer.
+fn bucket_distance(bucket: usize, color: [u8; 3]) -> u32 {
+    let shift = 8 - BUCKET_BITS;
+    let mask = (1 << BUCKET_BITS) - 1;
+    let mut squared = 0;
+    for (i, &c) in color.iter().enumerate() {
+        let cube = (bucket >> (BUCKET_BITS as usize * (2 - i))) & mask;
+        let low = (cube << shift) as u32;
+        let high = low + (1 << shift) - 1;
+        let c = u32::from(c);
+        let gap = low.saturating_sub(c).max(c.saturating_sub(high));
+        squared += gap * gap;
+    }
+    squared
+}
+
+fn read_u32(reader: &mut impl Read) -> Result<u32, MosaicError> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+/// Writes `metadata` to the database at `path`, replacing it.
+pub fn write_tile_db(metadata: &ProcessedPictureMetadata, path: &Path) -> Result<(), MosaicError> {
+    let header = Header {
+        version: metadata.version,
+        contrast_adjustment: metadata.contrast_adjustment,
+        linear_light: metadata.linear_light,
+        color_mode: metadata.color_mode,
+        pictures: u32::try_from(metadata.pictures.len())
+            .map_err(|_| MosaicError::Invalid("too many pictures for a database".to_owned()))?,
+    };
+    let header = binary::to_msgpack(&header)?;
+
+    let mut records = Vec::new();
+    let mut entries = Vec::with_capacity(metadata.pictures.len());
+    for (rank, pic) in metadata.pictures.iter().enumerate() {
+        entries.push(Entry {
+            color: pic.color_rgb,
+            rank: rank as u32,
+            offset: records.len() as u64,
+        });
+        let record = binary::to_msgpack(pic)?;
+        records.extend_from_slice(&(record.len() as u32).to_le_bytes());
+        records.extend_from_slice(&record);
+    }
+    // Stable, the pictures of a bucket keep their rank order.
+    entries.sort_by_key(|entry| bucket(entry.color));
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    writer.write_all(&(header.len() as u32).to_le_bytes())?;
+    writer.write_all(&header)?;
+    // Index of the first entry of each bucket, then the number of entries.
+    let mut start = 0;
+    for b in 0..BUCKETS {
+        writer.write_all(&(start as u32).to_le_bytes())?;
+        start += entries[start..]
+            .iter()
+            .take_while(|entry| bucket(entry.color) == b)
+            .count();
+    }
+    writer.write_all(&(entries.len() as u32).to_le_bytes())?;
+    for entry in &entries {
+        writer.write_all(&entry.color)?;
+        writer.write_all(&[0])?;
+        writer.write_all(&entry.rank.to_le_bytes())?;
+        writer.write_all(&entry.offset.to_le_bytes())?;
+    }
+    writer.write_all(&records)?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Database written by `write_tile_db`, of which only the header and the bucket bounds are
+/// read when opened.
+pub struct TileDb {
+    reader: BufReader<File>,
+    header: Header,
+    /// First entry of each bucket, then the number of entries.
+    buckets: Vec<u32>,
+    entries_start: u64,
+    records_start: u64,
+    /// Size of the file, past which no record is read.
+    size: u64,
+}
+
+impl TileDb {
+    /// Opens the database at `path`. Errors with `MosaicError::UnsupportedVersion` if it was
+    /// written by a newer version of the library, and with `MosaicError::Invalid` if it isn't
+    /// a database.
+    pub fn open(path: &Path) -> Result<TileDb, MosaicError> {
+        let file = File::open(path)?;
+        let size = file.metadata()?.len();
+        let mut reader = BufReader::new(file);
+        let mut magic = [0; 8];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err("not a tile database".into());
+        }
+        let format_version = read_u32(&mut reader)?;
+        if format_version != FORMAT_VERSION {
+            return Err(MosaicError::Invalid(format!(
+                "unsupported tile database format {}",
+                format_version
+            )));
+        }
+        let header_len = u64::from(read_u32(&mut reader)?);
+        if header_len > size {
+            return Err("tile database header past the end of the file".into());
+        }
+        let mut header = vec![0; header_len as usize];
+        reader.read_exact(&mut header)?;
+        let header: Header = binary::from_msgpack(&header)?;
+        if header.version > METADATA_VERSION {
+            return Err(MosaicError::UnsupportedVersion(header.version));
+        }
+
+        let buckets = (0..=BUCKETS)
+            .map(|_| read_u32(&mut reader))
+            .collect::<Result<Vec<_>, _>>()?;
+        let sorted = buckets.windows(2).all(|w| w[0] <= w[1]);
+        if !sorted || buckets[BUCKETS] != header.pictures {
+            return Err("corrupt tile database index".into());
+        }
+        let entries_start = 16 + header_len + 4 * (BUCKETS as u64 + 1);
+        let records_start = entries_start + ENTRY_SIZE as u64 * u64::from(header.pictures);
+        if records_start > size {
+            return Err("tile database index past the end of the file".into());
+        }
+        Ok(TileDb {
+            reader,
+            header,
+            buckets,
+            entries_start,
+            records_start,
+            size,
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.header.pictures as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Pictures of `bucket` in the index.
+    fn entries(&mut self, bucket: usize) -> Result<Vec<Entry>, MosaicError> {
+        let (start, end) = (self.buckets[bucket], self.buckets[bucket + 1]);
+        let pos = self.entries_start + ENTRY_SIZE as u64 * u64::from(start);
+        self.reader.seek(SeekFrom::Start(pos))?;
+        let mut bytes = vec![0; ENTRY_SIZE * (end - start) as usize];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(bytes
+            .chunks(ENTRY_SIZE)
+            .map(|entry| {
+                let (rank, offset) = entry[4..].split_at(4);
+                Entry {
+                    color: [entry[0], entry[1], entry[2]],
+                    rank: u32::from_le_bytes(rank.try_into().expect("4 bytes")),
+                    offset: u64::from_le_bytes(offset.try_into().expect("8 bytes")),
+                }
+            })
+            .collect())
+    }
+
+    /// Reads the record at `offset` from the start of the records.
+    fn record(&mut self, offset: u64) -> Result<ProcessedPicture, MosaicError> {
+        let mut pos = self.records_start + offset;
+        self.reader.seek(SeekFrom::Start(pos))?;
+        self.next_record(&mut pos)
+    }
+
+    /// Reads the record at `pos`, where the reader is, moving `pos` past it.
+    fn next_record(&mut self, pos: &mut u64) -> Result<ProcessedPicture, MosaicError> {
+        let len = u64::from(read_u32(&mut self.reader)?);
+        if *pos + 4 + len > self.size {
+            return Err(MosaicError::Invalid(format!(
+                "tile database record at byte {} past the end of the file",
+                pos
+            )));
+        }
+        let mut record = vec![0; len as usize];
+        self.reader.read_exact(&mut record)?;
+        *pos += 4 + len;
+        binary::from_msgpack(&record)
+    }
+
+    /// Picture of the index closest to `color` by `color_distance`, the one
+    /// `find_closest_pic_by_color` returns with `MatchMode::Color` and no histogram nor
+    /// contrast. Only the buckets that may hold a closer picture than the closest one found
+    /// so far are read, the nearest first. Errors with `MosaicError::EmptyGallery` if the
+    /// database has no picture.
+    fn closest(&mut self, color: [u8; 3]) -> Result<Entry, MosaicError> {
+        let mut buckets: Vec<(u32, usize)> = (0..BUCKETS)
+            .filter(|&b| self.buckets[b] < self.buckets[b + 1])
+            .map(|b| (bucket_distance(b, color), b))
+            .collect();
+        buckets.sort_unstable();
+
+        let mut closest: Option<(u32, Entry)> = None;
+        for (squared, b) in buckets {
+            let bound = f64::from(squared).sqrt() as u32;
+            if closest.is_some_and(|(dist, _)| bound > dist) {
+                break;
+            }
+            for entry in self.entries(b)? {
+                let dist = color_distance(entry.color, color);
+                if closest.is_none_or(|(d, e)| (dist, entry.rank) < (d, e.rank)) {
+                    closest = Some((dist, entry));
+                }
+            }
+        }
+        closest
+            .map(|(_, entry)| entry)
+            .ok_or(MosaicError::EmptyGallery)
+    }
+
+    /// Reads only the pictures closest to `colors`, each once and in the order of the
+    /// metadata. Matching chunks of these colors with them gives the tiles matching them with
+    /// the whole gallery would, when `matches_closest_only` holds. Errors with
+    /// `MosaicError::EmptyGallery` if the database has no picture.
+    pub fn load_closest(
+        &mut self,
+        colors: &[[u8; 3]],
+    ) -> Result<Vec<ProcessedPicture>, MosaicError> {
+        let mut colors = colors.to_vec();
+        colors.sort_unstable();
+        colors.dedup();
+        let mut entries = colors
+            .into_iter()
+            .map(|color| self.closest(color))
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort_unstable_by_key(|entry| entry.rank);
+        entries.dedup_by_key(|entry| entry.rank);
+        entries
+            .iter()
+            .map(|entry| self.record(entry.offset))
+            .collect()
+    }
+
+    /// Fields of the metadata, without its pictures.
+    pub fn metadata(&self) -> ProcessedPictureMetadata {
+        ProcessedPictureMetadata {
+            version: self.header.version,
+            pictures: Vec::new(),
+            contrast_adjustment: self.header.contrast_adjustment,
+            linear_light: self.header.linear_light,
+            color_mode: self.header.color_mode,
+        }
+    }
+
+    /// Reads the whole metadata, passing each picture to `f` as it is read, like
+    /// `load_metadata_with`.
+    pub fn load_metadata_with<F>(
+        mut self,
+        mut f: F,
+    ) -> Result<ProcessedPictureMetadata, MosaicError>
+    where
+        F: FnMut(&mut ProcessedPicture),
+    {
+        let mut pictures = Vec::with_capacity(self.len());
+        let mut pos = self.records_start;
+        self.reader.seek(SeekFrom::Start(pos))?;
+        for _ in 0..self.len() {
+            let mut pic = self.next_record(&mut pos)?;
+            f(&mut pic);
+            pictures.push(pic);
+        }
+        Ok(ProcessedPictureMetadata {
+            pictures,
+            ..self.metadata()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::matching::{
+        chunk_colors, find_closest_pic_by_color, match_tiles, matches_closest_only, MatchMode,
+        MosaicOptions,
+    };
+    use crate::rng::SmallRng;
+    use crate::testing::{image, picture, temp_dir};
+    use std::fs;
+    use std::path::PathBuf;
+    use std::ptr;
+
+    fn metadata(pictures: Vec<ProcessedPicture>) -> ProcessedPictureMetadata {
+        ProcessedPictureMetadata {
+            version: METADATA_VERSION,
+            pictures,
+            contrast_adjustment: 12.5,
+            linear_light: true,
+            color_mode: ColorMode::Dominant,
+        }
+    }
+
+    #[test]
+    fn metadata_round_trips_in_its_order() {
+        let mut full = picture("0.png", [250, 0, 0]);
+        full.color_histogram = Some(vec![2; 512]);
+        full.source = Some("gallery/0.jpg".to_owned());
+        full.contrast = Some(3.5);
+        full.palette = Some(vec![[250, 0, 0], [0, 0, 250]]);
+        full.phash = Some(u64::MAX);
+        let pics = vec![
+            full,
+            picture("1.png", [0, 0, 0]),
+            picture("2.png", [255; 3]),
+        ];
+        let metadata = metadata(pics);
+        let path = temp_dir("tile-db").join("tiles.db");
+        write_tile_db(&metadata, &path).unwrap();
+
+        let db = TileDb::open(&path).unwrap();
+        assert_eq!(db.len(), 3);
+        let mut read = Vec::new();
+        let loaded = db
+            .load_metadata_with(|pic| read.push(pic.path.clone()))
+            .unwrap();
+        assert_eq!(read, ["0.png", "1.png", "2.png"]);
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&metadata).unwrap()
+        );
+    }
+
+    /// Database of 302 pictures of random colors, two of which have the color of an earlier
+    /// one, returned with its path.
+    fn random_db() -> (Vec<ProcessedPicture>, PathBuf) {
+        let mut rng = SmallRng::seed_from_u64(3);
+        let mut color = || [0; 3].map(|_| rng.gen_range(256) as u8);
+        let mut pics: Vec<_> = (0..300)
+            .map(|i| picture(&format!("{}.png", i), color()))
+            .collect();
+        // Same colors, the first one of which wins the ties.
+        pics.push(picture("300.png", pics[10].color_rgb));
+        pics.insert(0, picture("first.png", pics[20].color_rgb));
+        let path = temp_dir("tile-db").join("tiles.db");
+        write_tile_db(&metadata(pics), &path).unwrap();
+        let pics = TileDb::open(&path)
+            .unwrap()
+            .load_metadata_with(|_| ())
+            .unwrap()
+            .pictures;
+        (pics, path)
+    }
+
+    #[test]
+    fn closest_is_the_one_of_find_closest_pic_by_color() {
+        let (pics, path) = random_db();
+        let mut db = TileDb::open(&path).unwrap();
+        let queries = (0..=255).step_by(15).flat_map(|r| {
+            (0..=255)
+                .step_by(15)
+                .flat_map(move |g| (0..=255).step_by(15).map(move |b| [r, g, b]))
+        });
+        for query in queries.chain(pics.iter().map(|pic| pic.color_rgb)) {
+            let expected =
+                find_closest_pic_by_color(&pics, query, None, None, MatchMode::Color).unwrap();
+            let rank = pics.iter().position(|pic| ptr::eq(pic, expected));
+            assert_eq!(
+                Some(db.closest(query).unwrap().rank as usize),
+                rank,
+                "{:?}",
+                query
+            );
+        }
+    }
+
+    #[test]
+    fn closest_pictures_are_matched_like_the_whole_gallery() {
+        let (pics, path) = random_db();
+        let model = image(96, 64, |x, y| {
+            [(x * 2) as u8, (y * 3) as u8, (x + y) as u8, 255]
+        });
+        let options = MosaicOptions {
+            two_pass: true,
+            allow_rotation: true,
+            seed: Some(5),
+            ..MosaicOptions::default()
+        };
+        assert!(matches_closest_only(&options));
+
+        let closest = TileDb::open(&path)
+            .unwrap()
+            .load_closest(&chunk_colors(&model, &options))
+            .unwrap();
+        assert!(closest.len() < pics.len() / 2, "{} pictures", closest.len());
+        let tiles = |pics| {
+            let placement = match_tiles(&model, pics, (1, 1), &options).unwrap();
+            (placement.tiles.iter())
+                .map(|tile| (tile.pic.path.clone(), tile.rotation))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(tiles(&closest), tiles(&pics));
+    }
+
+    #[test]
+    fn no_color_of_a_bucket_is_closer_than_its_distance() {
+        for &query in &[[0, 0, 0], [31, 32, 200], [128, 255, 7]] {
+            for c in (0..=255).step_by(5) {
+                for color in [[c, 0, 255], [255 - c, c, 40], [c, c, c]] {
+                    let squared: i32 = (0..3)
+                        .map(|i| (i32::from(color[i]) - i32::from(query[i])).pow(2))
+                        .sum();
+                    assert!(squared as u32 >= bucket_distance(bucket(color), query));
+                }
+            }
+            assert_eq!(bucket_distance(bucket(query), query), 0);
+        }
+    }
+
+    #[test]
+    fn empty_database_has_no_closest_picture() {
+        let path = temp_dir("tile-db").join("tiles.db");
+        write_tile_db(&metadata(Vec::new()), &path).unwrap();
+        let mut db = TileDb::open(&path).unwrap();
+        assert!(db.is_empty());
+        assert!(matches!(db.closest([0; 3]), Err(MosaicError::EmptyGallery)));
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        let folder = temp_dir("tile-db");
+        let json = folder.join("mosaic.json");
+        fs::write(&json, "{\"version\":3,\"pictures\":[]}").unwrap();
+        assert!(matches!(TileDb::open(&json), Err(MosaicError::Invalid(_))));
+
+        let newer = folder.join("newer.db");
+        let mut metadata = metadata(vec![picture("0.png", [0; 3])]);
+        metadata.version = METADATA_VERSION + 1;
+        write_tile_db(&metadata, &newer).unwrap();
+        assert!(matches!(
+            TileDb::open(&newer),
+            Err(MosaicError::UnsupportedVersion(_))
+        ));
+
+        let truncated = folder.join("truncated.db");
+        metadata.version = METADATA_VERSION;
+        write_tile_db(&metadata, &truncated).unwrap();
+        let bytes = fs::read(&truncated).unwrap();
+        fs::write(&truncated, &bytes[..bytes.len() - 1]).unwrap();
+        let db = TileDb::open(&truncated).unwrap();
+        assert!(matches!(
+            db.load_metadata_with(|_| ()),
+            Err(MosaicError::Invalid(_))
+        ));
+    }
+}
@@ -0,0 +1,67 @@
+//! Time spent in each phase of preprocessing and creating a mosaic, to weigh the options
+//! against their cost.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Time spent in each phase of a step, summed over the pictures or the mosaics it handles.
+/// Filled through a shared reference, from the threads of a step.
+#[derive(Debug, Default)]
+pub struct Timings {
+    /// The phases in the order they were first timed.
+    phases: Mutex<Vec<(&'static str, Duration)>>,
+    /// Number of pictures or chunks handled, for the throughput.
+    items: AtomicUsize,
+}
+
+impl Timings {
+    pub fn new() -> Timings {
+        Timings::default()
+    }
+
+    /// Adds `elapsed` to the time spent in `phase`.
+    pub fn add(&self, phase: &'static str, elapsed: Duration) {
+        let mut phases = self.phases.lock().unwrap();
+        match phases.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, total)) => *total += elapsed,
+            None => phases.push((phase, elapsed)),
+        }
+    }
+
+    /// Counts `n` more pictures or chunks handled.
+    pub fn add_items(&self, n: usize) {
+        self.items.fetch_add(n, Ordering::Relaxed);
+    }
+
+    /// Time spent in all the phases.
+    pub fn total(&self) -> Duration {
+        self.phases.lock().unwrap().iter().map(|(_, d)| *d).sum()
+    }
+
+    /// Prints the time of each phase with its share of the total, then the throughput of the
+    /// step, the items handled, counted in `unit`, over the total time.
+    pub fn print(&self, unit: &str) {
+        let count = self.items.load(Ordering::Relaxed);
+        let total = self.total().as_secs_f64();
+        for (phase, elapsed) in self.phases.lock().unwrap().iter() {
+            let elapsed = elapsed.as_secs_f64();
+            let share = if total > 0.0 { elapsed / total } else { 0.0 };
+            println!(
+                "{:<12} {:>10.1} ms {:>6.1}%",
+                phase,
+                elapsed * 1000.0,
+                share * 100.0
+            );
+        }
+        println!("{:<12} {:>10.1} ms", "total", total * 1000.0);
+        if total > 0.0 {
+            println!(
+                "{:<12} {:>10.1} {}/s",
+                "throughput",
+                count as f64 / total,
+                unit
+            );
+        }
+    }
+}
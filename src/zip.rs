@@ -0,0 +1,189 @@
+//! Minimal zip archive reader, so that a gallery can be preprocessed without being unpacked.
+//! Only stored and deflated entries are supported, with zip64 archives.
+
+use crate::error::MosaicError;
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+const ZIP64_END_OF_CENTRAL_DIRECTORY: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR: u32 = 0x0706_4b50;
+const CENTRAL_DIRECTORY_HEADER: u32 = 0x0201_4b50;
+const LOCAL_FILE_HEADER: u32 = 0x0403_4b50;
+const ZIP64_EXTRA_FIELD: u16 = 0x0001;
+/// The end of central directory record is followed by a comment of at most 64 KiB.
+const MAX_END_RECORD_SEARCH: u64 = 22 + 0xFFFF;
+
+const METHOD_STORED: u16 = 0;
+const METHOD_DEFLATED: u16 = 8;
+
+#[derive(Debug, Clone)]
+pub struct ZipEntry {
+    /// Path of the entry in the archive, with `/` separators.
+    pub name: String,
+    method: u16,
+    encrypted: bool,
+    compressed_size: u64,
+    size: u64,
+    header_offset: u64,
+}
+
+impl ZipEntry {
+    pub fn is_dir(&self) -> bool {
+        self.name.ends_with('/')
+    }
+
+    /// Uncompressed size of the entry.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+}
+
+pub struct ZipArchive {
+    reader: BufReader<File>,
+    entries: Vec<ZipEntry>,
+}
+
+impl ZipArchive {
+    /// Opens the archive at `path` and reads its central directory.
+    pub fn open(path: &Path) -> Result<ZipArchive, MosaicError> {
+        let mut reader = BufReader::new(File::open(path)?);
+        let (count, offset) = read_end_of_central_directory(&mut reader)?;
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut entries = Vec::new();
+        for _ in 0..count {
+            entries.push(read_central_directory_header(&mut reader)?);
+        }
+        Ok(ZipArchive { reader, entries })
+    }
+
+    pub fn entries(&self) -> &[ZipEntry] {
+        &self.entries
+    }
+
+    /// Reads and decompresses the content of `entry`.
+    pub fn read(&mut self, entry: &ZipEntry) -> Result<Vec<u8>, MosaicError> {
+        if entry.encrypted {
+            return Err(format!("{} is encrypted", entry.name).into());
+        }
+
+        self.reader.seek(SeekFrom::Start(entry.header_offset))?;
+        let header = read_bytes(&mut self.reader, 30)?;
+        if le_u32(&header, 0) != LOCAL_FILE_HEADER {
+            return Err(format!("invalid local header for {}", entry.name).into());
+        }
+        let skipped = u64::from(le_u16(&header, 26)) + u64::from(le_u16(&header, 28));
+        self.reader.seek(SeekFrom::Current(skipped as i64))?;
+
+        let data = read_bytes(&mut self.reader, entry.compressed_size as usize)?;
+        let data = match entry.method {
+            METHOD_STORED => data,
+            METHOD_DEFLATED => inflate::inflate_bytes(&data)?,
+            method => {
+                return Err(format!("{} uses compression method {}", entry.name, method).into())
+            }
+        };
+        if data.len() as u64 != entry.size {
+            return Err(format!("{} is truncated", entry.name).into());
+        }
+        Ok(data)
+    }
+}
+
+/// Returns the number of entries and the offset of the central directory.
+fn read_end_of_central_directory<R: Read + Seek>(
+    reader: &mut R,
+) -> Result<(u64, u64), MosaicError> {
+    let len = reader.seek(SeekFrom::End(0))?;
+    let search_len = MAX_END_RECORD_SEARCH.min(len);
+    reader.seek(SeekFrom::Start(len - search_len))?;
+    let tail = read_bytes(reader, search_len as usize)?;
+    let record_pos = (0..tail.len().saturating_sub(21))
+        .rev()
+        .find(|&i| le_u32(&tail, i) == END_OF_CENTRAL_DIRECTORY)
+        .ok_or("not a zip archive")?;
+    let record = &tail[record_pos..];
+    let count = le_u16(record, 10);
+    let offset = le_u32(record, 16);
+    if count != 0xFFFF && offset != 0xFFFF_FFFF {
+        return Ok((u64::from(count), u64::from(offset)));
+    }
+
+    // Zip64 archive, the locator right before the record points to the zip64 record.
+    let locator_pos = (len - search_len + record_pos as u64)
+        .checked_sub(20)
+        .ok_or("invalid zip64 archive")?;
+    reader.seek(SeekFrom::Start(locator_pos))?;
+    let locator = read_bytes(reader, 20)?;
+    if le_u32(&locator, 0) != ZIP64_LOCATOR {
+        return Err("invalid zip64 locator".into());
+    }
+    reader.seek(SeekFrom::Start(le_u64(&locator, 8)))?;
+    let record = read_bytes(reader, 56)?;
+    if le_u32(&record, 0) != ZIP64_END_OF_CENTRAL_DIRECTORY {
+        return Err("invalid zip64 end of central directory".into());
+    }
+    Ok((le_u64(&record, 32), le_u64(&record, 48)))
+}
+
+fn read_central_directory_header<R: Read>(reader: &mut R) -> Result<ZipEntry, MosaicError> {
+    let header = read_bytes(reader, 46)?;
+    if le_u32(&header, 0) != CENTRAL_DIRECTORY_HEADER {
+        return Err("invalid central directory header".into());
+    }
+    let name = read_bytes(reader, le_u16(&header, 28) as usize)?;
+    let extra = read_bytes(reader, le_u16(&header, 30) as usize)?;
+    read_bytes(reader, le_u16(&header, 32) as usize)?;
+
+    let mut size = u64::from(le_u32(&header, 24));
+    let mut compressed_size = u64::from(le_u32(&header, 20));
+    let mut header_offset = u64::from(le_u32(&header, 42));
+    // The zip64 extra field holds, in this order, the values that don't fit in 32 bits.
+    let mut i = 0;
+    while i + 4 <= extra.len() {
+        let id = le_u16(&extra, i);
+        let field_len = le_u16(&extra, i + 2) as usize;
+        if id == ZIP64_EXTRA_FIELD {
+            let mut field = &extra[i + 4..(i + 4 + field_len).min(extra.len())];
+            for value in [&mut size, &mut compressed_size, &mut header_offset] {
+                if *value == 0xFFFF_FFFF && field.len() >= 8 {
+                    *value = le_u64(field, 0);
+                    field = &field[8..];
+                }
+            }
+        }
+        i += 4 + field_len;
+    }
+
+    Ok(ZipEntry {
+        name: String::from_utf8_lossy(&name).into_owned(),
+        method: le_u16(&header, 10),
+        encrypted: le_u16(&header, 8) & 1 != 0,
+        compressed_size,
+        size,
+        header_offset,
+    })
+}
+
+fn read_bytes<R: Read>(reader: &mut R, len: usize) -> Result<Vec<u8>, MosaicError> {
+    let mut buf = vec![0; len];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn le_u16(buf: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([buf[pos], buf[pos + 1]])
+}
+
+fn le_u32(buf: &[u8], pos: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[pos..pos + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn le_u64(buf: &[u8], pos: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[pos..pos + 8]);
+    u64::from_le_bytes(bytes)
+}
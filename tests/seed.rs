@@ -0,0 +1,121 @@
+//! Mosaics created from the same seed, through the command line, being byte-identical.
+
+use image::{ImageBuffer, Rgba};
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+/// Empty folder of its own in the temporary folder.
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("mosaic-{}-{}", name, process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Runs the `mosaic` binary with `args`, failing the test if it fails.
+fn mosaic(args: &[&str]) {
+    let output = Command::new(env!("CARGO_BIN_EXE_mosaic"))
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(
+        output.status.success(),
+        "mosaic {:?} failed: {}",
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+fn hash_file(path: &Path) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    fs::read(path).unwrap().hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Seed recorded in the options file written next to `output`.
+fn recorded_seed(output: &Path) -> String {
+    let options = fs::read_to_string(format!("{}.toml", output.display())).unwrap();
+    let seed = options
+        .lines()
+        .find_map(|line| line.strip_prefix("seed = "))
+        .expect("no seed recorded");
+    seed.to_owned()
+}
+
+/// Gallery of flat pictures of a dozen colors, preprocessed, and a gradient model.
+fn preprocessed_gallery(dir: &Path) -> (PathBuf, PathBuf) {
+    let gallery = dir.join("gallery");
+    fs::create_dir(&gallery).unwrap();
+    for i in 0..12u8 {
+        let color = Rgba([i * 20, 255 - i * 20, (i % 3) * 100, 255]);
+        ImageBuffer::from_pixel(16, 16, color)
+            .save(gallery.join(format!("{}.png", i)))
+            .unwrap();
+    }
+    let thumbnails = dir.join("thumbnails");
+    mosaic(&[
+        "preprocess",
+        gallery.to_str().unwrap(),
+        thumbnails.to_str().unwrap(),
+    ]);
+
+    let model = dir.join("model.png");
+    ImageBuffer::from_fn(64, 64, |x, y| {
+        Rgba([(x * 4) as u8, (y * 4) as u8, 128, 255])
+    })
+    .save(&model)
+    .unwrap();
+    (thumbnails, model)
+}
+
+/// Creates the mosaic of `model` in `dir` as `name`, picking each tile among the 4 closest
+/// pictures, and returns its path.
+fn create(
+    dir: &Path,
+    (thumbnails, model): &(PathBuf, PathBuf),
+    name: &str,
+    seed: Option<&str>,
+) -> PathBuf {
+    let output = dir.join(name);
+    let mut args = vec![
+        "create",
+        thumbnails.to_str().unwrap(),
+        model.to_str().unwrap(),
+        output.to_str().unwrap(),
+        "--randomize-top-k",
+        "4",
+    ];
+    let seed = seed.map(|seed| format!("--seed={}", seed));
+    args.extend(seed.as_deref());
+    mosaic(&args);
+    output
+}
+
+#[test]
+fn same_seed_gives_the_same_mosaic() {
+    let dir = temp_dir("seed");
+    let inputs = preprocessed_gallery(&dir);
+    let first = create(&dir, &inputs, "first.png", Some("7"));
+    let second = create(&dir, &inputs, "second.png", Some("7"));
+    assert_eq!(recorded_seed(&first), "7");
+    assert_eq!(hash_file(&first), hash_file(&second));
+
+    let other = create(&dir, &inputs, "other.png", Some("8"));
+    assert_eq!(recorded_seed(&other), "8");
+    assert_ne!(hash_file(&first), hash_file(&other));
+}
+
+#[test]
+fn recorded_seed_recreates_the_mosaic() {
+    let dir = temp_dir("recorded-seed");
+    let inputs = preprocessed_gallery(&dir);
+    // Without a seed, one is drawn and recorded for the mosaic to be created again.
+    let drawn = create(&dir, &inputs, "drawn.png", None);
+    let seed = recorded_seed(&drawn);
+    let again = create(&dir, &inputs, "again.png", Some(&seed));
+    assert_eq!(hash_file(&drawn), hash_file(&again));
+}